@@ -21,7 +21,10 @@ use cortex_m::{
     interrupt,
     asm::*,
 };    
-use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::digital::v2::{
+    InputPin,
+    OutputPin,
+};
 use rtfm::app;
 use stm32f1xx_hal::{
     prelude::*,
@@ -54,8 +57,11 @@ use usbd_serial::{CdcAcmClass, SerialPort, USB_CLASS_CDC};
 use usbd_mass_storage::USB_CLASS_MSC;
 use usbd_scsi::{
     Scsi,
+    LogicalUnit,
     BlockDevice,
     BlockDeviceError,
+    EventSink,
+    DEFAULT_BUFFER_BYTES,
 };
 use itm_logger::*;
 use usb_bootloader::{
@@ -69,6 +75,12 @@ const USB_VID: u16 = 0x1209;
 const USB_PID: u16 = 0xDB42;
 //const USB_CLASS_MISCELLANEOUS: u8 =  0xEF;
 
+/// Programming this value into user data option byte 0 write protects the drive
+const WRITE_PROTECT_OPTION_BYTE: u8 = 0x57;
+
+/// Most half words programmed per call to write_page before giving USB a chance to run
+const MAX_HALF_WORDS_PER_WRITE: usize = 64;
+
 const TICK_MS: u32 = 10;
 const TICK_HZ: Hertz = Hertz(1000 / TICK_MS);
 
@@ -78,6 +90,8 @@ pub struct FlashWrapper {
     max_address: u32,
     page_buffer: [u8; 2048],
     current_page: Option<u32>,
+    /// Page that start_erase_page has been called for but finish_erase_page hasn't
+    erasing: Option<u32>,
 }
 
 impl Flash for FlashWrapper {
@@ -151,8 +165,8 @@ impl Flash for FlashWrapper {
         true
     }
 
-    // Erase the page at the given address. Don't check if erase is necessary, that's done at a higher level
-    fn erase_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError> {
+    // Start erasing the page at the given address. Don't check if erase is necessary, that's done at a higher level
+    fn start_erase_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError> {
         let flash = unsafe {
             &(*FLASH::ptr())
         };
@@ -169,8 +183,14 @@ impl Flash for FlashWrapper {
         // Kick off the operation
         flash.cr.modify(|_, w| w.strt().set_bit());
 
-        // Wait until it's done
-        self.busy_wait();
+        Ok(())
+    }
+
+    // Finish off an erase once it's no longer busy
+    fn finish_erase_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError> {
+        let flash = unsafe {
+            &(*FLASH::ptr())
+        };
 
         // Clear page erase flag
         flash.cr.modify(|_, w| w.per().clear_bit());
@@ -194,7 +214,13 @@ impl Flash for FlashWrapper {
             if !range.contains(&hw_addr) {
                 Err(BlockDeviceError::InvalidAddress)?;
             }
-            *b = unsafe { read_volatile(hw_addr as *const u8) };
+            *b = match self.current_page {
+                // Written data may still be waiting in the page buffer
+                Some(page_address) if page_address == self.page_address(hw_addr) => {
+                    self.page_buffer[(hw_addr - page_address) as usize]
+                },
+                _ => unsafe { read_volatile(hw_addr as *const u8) },
+            };
         }
 
         Ok(())
@@ -234,6 +260,7 @@ impl Flash for FlashWrapper {
         let buffer = self.page_buffer();
 
         let mut half_word = [0; 2];
+        let mut half_words_written = 0;
         for (i, c) in buffer.chunks_exact(2).enumerate() {
             let hw_addr = page_address + i as u32 * 2;
             
@@ -247,6 +274,13 @@ impl Flash for FlashWrapper {
 
             let old_value = unsafe { read_volatile(hw_addr as *const [u8; 2]) };
             if old_value != half_word {
+                // Half words that are already written are skipped so the next call carries on 
+                // from here
+                if half_words_written == MAX_HALF_WORDS_PER_WRITE {
+                    Err(BlockDeviceError::WouldBlock)?;
+                }
+                half_words_written += 1;
+
                 info!("0x{:X?}: 0x{:X?} => 0x{:X?}", hw_addr, old_value, half_word); 
 
                 // Indicate we want to write to flash
@@ -279,6 +313,15 @@ impl Flash for FlashWrapper {
     fn flush_page(&mut self) -> Result<(), BlockDeviceError> {
         let page_address = self.current_page.ok_or(BlockDeviceError::InvalidAddress)?;
 
+        // Reading flash while it's being erased stalls the CPU so come back later
+        if self.is_operation_pending() {
+            Err(BlockDeviceError::WouldBlock)?;
+        }
+
+        if let Some(erased_page) = self.erasing.take() {
+            self.finish_erase_page(erased_page)?;
+        }
+
         let mut erase_needed = false;
         let mut write_needed = false;
 
@@ -307,7 +350,11 @@ impl Flash for FlashWrapper {
 
         if erase_needed {
             info!("Flush: erase needed (page: 0x{:X})", page_address);
-            self.erase_page(page_address)?;
+            self.start_erase_page(page_address)?;
+            self.erasing = Some(page_address);
+
+            // The write happens on the next call once the erase is done
+            Err(BlockDeviceError::WouldBlock)?;
         }
 
         if erase_needed || write_needed {
@@ -331,11 +378,25 @@ impl Flash for FlashWrapper {
 #[cfg(feature = "itm")] 
 use cortex_m::{iprintln, peripheral::ITM};
 
+/// Notices the host safely removing the drive so the new firmware can be started straight away
+#[derive(Default)]
+struct Events {
+    ejected: bool,
+}
+
+impl EventSink for Events {
+    fn medium_ejected(&mut self, _lun: u8) {
+        self.ejected = true;
+    }
+}
+
+type MscScsi<B> = Scsi<'static, B, LogicalUnit<GhostFat<FlashWrapper>>, DEFAULT_BUFFER_BYTES, Events>;
+
 #[app(device = stm32f1xx_hal::stm32, peripherals = true)]
 const APP: () = {
     struct Resources {
         usb_dev: UsbDevice<'static, UsbBusType>,
-        scsi: Scsi<'static, UsbBusType, GhostFat<FlashWrapper>>,
+        scsi: MscScsi<UsbBusType>,
         tick_timer: CountDownTimer<TIM2>,
     }
 
@@ -401,6 +462,7 @@ const APP: () = {
             page_size,
             page_buffer: [0; 2048],
             current_page: None,
+            erasing: None,
             min_address: 0x08010000,
             max_address: 0x08000000 + flash_kib as u32 * 1024,
         };
@@ -432,12 +494,24 @@ const APP: () = {
             .start_count_down(TICK_HZ);
         tick_timer.listen(Event::Update);
 
-        let ghost_fat = GhostFat::new(
+        let mut ghost_fat = GhostFat::new(
             flash_wrapper,
             bkp,
         );
 
-        let scsi = Scsi::new(
+        // Field units are locked by fitting the BOOT1 jumper (PB2 high) or programming the
+        // option byte so they can't be reflashed by accident
+        let mut gpiob = cx.device.GPIOB.split(&mut rcc.apb2);
+        let boot1 = gpiob.pb2.into_floating_input(&mut gpiob.crl);
+        let write_protected = boot1.is_high().unwrap() ||
+            get_option_byte_data0() == Some(WRITE_PROTECT_OPTION_BYTE);
+        info!("Write protected: {}", write_protected);
+        ghost_fat.set_write_protected(write_protected);
+
+        let serial_number = get_serial_number();
+        info!("Serial number: {}", serial_number);
+
+        let mut scsi = Scsi::new(
             USB_BUS.as_ref().unwrap(), 
             64,
             ghost_fat,
@@ -445,9 +519,8 @@ const APP: () = {
             "Fake product",
             "FK01",
         );
-        
-        let serial_number = get_serial_number();
-        info!("Serial number: {}", serial_number);
+        scsi.logical_units_mut().set_serial_number(serial_number);
+        let scsi = scsi.with_event_sink(Events::default());
 
         let usb_dev = UsbDeviceBuilder::new(USB_BUS.as_ref().unwrap(), UsbVidPid(USB_VID, USB_PID))
             .manufacturer("Fake company")
@@ -480,17 +553,31 @@ const APP: () = {
           .tick_timer
           .clear_update_interrupt_flag();
 
+        if core::mem::take(&mut cx.resources.scsi.event_sink_mut().ejected) {
+            cx.resources
+              .scsi
+              .block_device_mut()
+              .trigger_delayed_restart();
+        }
+
         cx.resources
           .scsi
           .block_device_mut()
           .tick(TICK_MS);
 
+        // Detach the drive while the new firmware is applied so the host stops using it
+        if cx.resources.scsi.block_device_mut().is_restart_pending() {
+            cx.resources
+              .scsi
+              .logical_units_mut()
+              .remove_medium();
+        }
     }
 };
 
 fn usb_poll<B: bus::UsbBus>(
     usb_dev: &mut UsbDevice<'static, B>,
-    scsi: &mut Scsi<'static, B, GhostFat<FlashWrapper>>,
+    scsi: &mut MscScsi<B>,
 ) {
     if !usb_dev.poll(&mut [scsi]) {
         return;
@@ -1,6 +1,11 @@
 use core::ops::RangeInclusive;
 use usbd_scsi::BlockDeviceError;
 
+/// # Flash memory access for [GhostFat](../ghost_fat/struct.GhostFat.html)
+///
+/// Erasing and programming pages takes long enough to hold up USB polling so `flush_page` (and
+/// anything that calls it) can return `BlockDeviceError::WouldBlock` part way through instead
+/// of waiting. Calling it again carries on where it left off.
 pub trait Flash {
     /// Flash page size in bytes
     fn page_size(&self) -> u32;
@@ -28,10 +33,22 @@ pub trait Flash {
         while self.is_operation_pending() {}
     }
 
-    /// Erase the page at the given address
+    /// Erase the page at the given address, waiting for it to finish
     ///
     /// Check the address is valid but don't check if erase is necessary, that's done in flush_page
-    fn erase_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError>;
+    fn erase_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError> {
+        self.start_erase_page(page_address)?;
+        self.busy_wait();
+        self.finish_erase_page(page_address)
+    }
+
+    /// Kick off erasing the page at the given address without waiting for it to finish
+    ///
+    /// `is_operation_pending` is true until the erase is done
+    fn start_erase_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError>;
+
+    /// Tidy up after an erase started by `start_erase_page` has finished and check it worked
+    fn finish_erase_page(&mut self, page_address: u32) -> Result<(), BlockDeviceError>;
 
     /// Check if the page is empty
     fn is_page_erased(&mut self, page_address: u32) -> bool;
@@ -54,13 +71,18 @@ pub trait Flash {
 
     /// Save the current contents of the page buffer to flash at the address it was read from
     ///
-    /// Check that erase and/or write are really necessary
+    /// Check that erase and/or write are really necessary. Can return WouldBlock while erasing or 
+    /// writing, call again until it succeeds
     fn flush_page(&mut self) -> Result<(), BlockDeviceError>;
 
     /// Write the provided bytes to flash at the provided address
     ///
     /// Each touched page will be read into a buffer and flushed back to flash when the next page
-    /// is modified or at the end of the function. 
+    /// is modified. The last page stays in the buffer so consecutive writes to the same page only
+    /// erase and program it once, call `flush_page` to make sure it's in flash.
+    ///
+    /// Can return WouldBlock if flushing a page does, call again with the same arguments until it
+    /// succeeds
     fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), BlockDeviceError> {
         let start_page = self.page_address(address);
         let end_page = self.page_address(address + bytes.len() as u32 - 1);
@@ -87,12 +109,12 @@ pub trait Flash {
                 self.page_buffer()[..count].copy_from_slice(&bytes[offset..(offset + count)]);
             }
         }
-        // Flush the last page 
-        self.flush_page()?;
 
         Ok(())
     }
 
     /// Read bytes from the provided address
+    ///
+    /// Should return the page buffer contents for the current page, it may not have been flushed
     fn read_bytes(&self, address: u32, bytes: &mut [u8]) -> Result<(), BlockDeviceError>;
 }
\ No newline at end of file
@@ -8,6 +8,7 @@ use packing::{
 use usbd_scsi::{
     BlockDevice,
     BlockDeviceError,
+    FirmwareUpdate,
 };
 
 use uf2_block::Block as Uf2Block;
@@ -271,13 +272,26 @@ pub struct GhostFat<F: Flash> {
     tick_ms: u32,
     restart_ms: u32,
     backup_domain: BackupDomain,
+    /// Reject writes, see `set_write_protected`
+    write_protected: bool,
 }
 
 impl<F: Flash> BlockDevice for GhostFat<F> {
     const BLOCK_BYTES: usize = BLOCK_SIZE;
-    fn read_block(&self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+    fn read_block(&self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError> {
         assert_eq!(block.len(), BLOCK_SIZE);
 
+        if lba > self.max_lba() {
+            Err(BlockDeviceError::InvalidAddress)?;
+        }
+        // The whole filesystem fits in 32 bits
+        let lba = lba as u32;
+
+        // Reading flash while it's being erased or written stalls the CPU
+        if self.flash.is_operation_pending() {
+            Err(BlockDeviceError::WouldBlock)?;
+        }
+
         info!("GhostFAT reading block: 0x{:X?}", lba);
 
         // Clear the buffer since we're sending all of it
@@ -373,7 +387,12 @@ impl<F: Flash> BlockDevice for GhostFat<F> {
         }
         Ok(())
     }
-    fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        if lba > self.max_lba() {
+            Err(BlockDeviceError::InvalidAddress)?;
+        }
+        let lba = lba as u32;
+
         info!("GhostFAT writing block: 0x{:X?}", lba);
 
         //TODO: Should BDE have an error to represent this kind of protocol error?
@@ -424,8 +443,68 @@ impl<F: Flash> BlockDevice for GhostFat<F> {
 
         Ok(())
     }
-    fn max_lba(&self) -> u32 {
-        NUM_FAT_BLOCKS - 1
+    fn max_lba(&self) -> u64 {
+        NUM_FAT_BLOCKS as u64 - 1
+    }
+    fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        // UF2 blocks for the same flash page are merged in the page buffer until now
+        if self.flash.current_page().is_some() {
+            self.flash.flush_page()?;
+        }
+        Ok(())
+    }
+    fn abort(&mut self) -> Result<(), BlockDeviceError> {
+        // A page that's been erased but not programmed yet would be lost, finish it off
+        self.flush()
+    }
+    fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+    fn firmware_update(&mut self) -> Option<&mut dyn FirmwareUpdate> {
+        // A locked drive can't be reflashed this way either
+        if self.write_protected {
+            None
+        } else {
+            Some(self)
+        }
+    }
+}
+
+/// Raw application images downloaded with WRITE BUFFER (e.g. `sg_write_buffer -m 5`) for hosts
+/// that won't copy a UF2 file to the drive. The image is written from the start of the
+/// application flash.
+///
+/// Mode 5 restarts into the new application once it's written. There's no telling when a
+/// download with offsets is complete so it runs after the next reset instead
+impl<F: Flash> FirmwareUpdate for GhostFat<F> {
+    fn capacity(&self) -> u32 {
+        let address_range = self.flash.address_range();
+        address_range.end() - address_range.start() + 1
+    }
+
+    fn write_firmware(&mut self, offset: u32, data: &[u8]) -> Result<(), BlockDeviceError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let address = self.app_base_address() + offset;
+        info!("GhostFAT writing {} bytes of firmware at 0x{:X?}", data.len(), address);
+        self.flash.write_bytes(address, data)
+    }
+
+    fn read_firmware(&self, offset: u32, data: &mut [u8]) -> Result<(), BlockDeviceError> {
+        // Reading flash while it's being erased or written stalls the CPU
+        if self.flash.is_operation_pending() {
+            Err(BlockDeviceError::WouldBlock)?;
+        }
+        self.flash.read_bytes(self.app_base_address() + offset, data)
+    }
+
+    fn save_firmware(&mut self) -> Result<(), BlockDeviceError> {
+        self.flush()
+    }
+
+    fn activate_firmware(&mut self) {
+        self.trigger_delayed_restart();
     }
 }
 
@@ -470,6 +549,7 @@ impl<F: Flash> GhostFat<F> {
             tick_ms: 0,
             restart_ms: 0,
             backup_domain,
+            write_protected: false,
         };
 
         gf.bootloader_check();
@@ -477,6 +557,12 @@ impl<F: Flash> GhostFat<F> {
         gf
     }
 
+    /// Lock the drive so it can't be reflashed, e.g. when a protection jumper or option byte
+    /// is set on field units. The host sees a read only drive and writes are rejected
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        self.write_protected = write_protected;
+    }
+
     // Read the command out of the backup register and reset the register to 0
     fn take_backup_command(&self) -> u32 {
         let cmd = read_u32_backup_register(&self.backup_domain, BACKUP_REGISTER);
@@ -541,7 +627,10 @@ impl<F: Flash> GhostFat<F> {
         value & 0x2FFE0000 == 0x20000000 
     }
 
-    fn trigger_delayed_restart(&mut self) {
+    /// Restart after a short delay, starting the application if there's a valid one
+    ///
+    /// Happens by itself once a whole UF2 file has been written
+    pub fn trigger_delayed_restart(&mut self) {
         self.tick_ms = 0;
         self.restart_ms = RESTART_DELAY_MS;
     }
@@ -550,11 +639,24 @@ impl<F: Flash> GhostFat<F> {
         self.bootloader_check();
     }
 
+    /// Returns true once the whole UF2 file has been written and a restart is on its way
+    pub fn is_restart_pending(&self) -> bool {
+        self.restart_ms > 0
+    }
+
     pub fn tick(&mut self, ms_elapsed: u32) {
         if self.restart_ms > 0 {
             self.tick_ms += ms_elapsed;
 
             if self.tick_ms >= self.restart_ms {
+                // Anything the host left in the write cache has to be in flash first, if the
+                // flash is busy try again next tick
+                match self.flush() {
+                    Err(BlockDeviceError::WouldBlock) => return,
+                    Err(e) => warn!("GhostFAT flush before restart failed: {:?}", e),
+                    Ok(()) => {},
+                }
+
                 self.restart_ms = 0;
                 self.tick_ms = 0;
                 self.restart_now();
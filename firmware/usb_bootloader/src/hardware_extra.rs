@@ -48,6 +48,25 @@ pub struct Uid {
 }
 define_ptr_type!(Uid, 0x1FFF_F7E8);
 
+/// User data option byte 0. Stored alongside its complement which is used to check it has
+/// been programmed
+#[derive(Debug)]
+#[repr(C)]
+pub struct OptionByteData0(u16);
+define_ptr_type!(OptionByteData0, 0x1FFF_F804);
+
+impl OptionByteData0 {
+    /// Read the option byte, None if it hasn't been programmed (the complement doesn't match)
+    pub fn value(&self) -> Option<u8> {
+        let [value, complement] = self.0.to_le_bytes();
+        if value == !complement {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
 fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     unsafe {
     core::slice::from_raw_parts(
@@ -92,4 +111,8 @@ pub fn get_serial_number() -> &'static str {
 
 pub fn get_flash_kibi() -> u16 {
     FlashSize::get().kibi_bytes()
-}
\ No newline at end of file
+}
+
+pub fn get_option_byte_data0() -> Option<u8> {
+    OptionByteData0::get().value()
+}
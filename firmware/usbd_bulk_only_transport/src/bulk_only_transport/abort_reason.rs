@@ -0,0 +1,17 @@
+/// Why a transport is asking the command set to drop the command in progress
+///
+/// Passed to the `abort` callback of
+/// [read_with_abort](struct.BulkOnlyTransport.html#method.read_with_abort) and the transports
+/// built on it. A reset first calls it from inside the control request, after that it's always
+/// called from `read`
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum AbortReason {
+    /// The host sent a bulk only mass storage reset
+    MassStorageReset,
+    /// The host sent a CBI command block reset
+    CommandBlockReset,
+    /// A UAS task management function aborted the command
+    TaskManagement,
+    /// The host selected an alternate setting of the interface
+    AlternateSetting,
+}
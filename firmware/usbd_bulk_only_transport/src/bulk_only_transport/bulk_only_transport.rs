@@ -22,22 +22,31 @@ use usbd_mass_storage::{
 use crate::logging::*;
 use super::{
     CommandBlockWrapper,
+    InvalidCbw,
     CommandStatusWrapper,
     Direction,
     CommandStatus,
+    DataStage,
+    AbortReason,
 };
 
 
 const REQ_GET_MAX_LUN: u8 = 0xFE;
 const REQ_BULK_ONLY_RESET: u8 = 0xFF;
 
-const BUFFER_BYTES: usize = 512;
+/// Buffer size used when one isn't specified, enough for a single 512 byte block
+pub const DEFAULT_BUFFER_BYTES: usize = 512;
 
 #[derive(Debug)]
 pub enum Error {
     UsbError(UsbError),
     PackingError(PackingError),
     DataError,
+    /// The CBW wasn't valid or meaningful, the host has to do a reset recovery
+    InvalidCbw(InvalidCbw),
+    /// The host and device disagree on the data stage, see 
+    /// [check_data_stage](struct.BulkOnlyTransport.html#method.check_data_stage)
+    PhaseError,
 }
 
 impl From<UsbError> for Error {
@@ -54,9 +63,9 @@ impl From<PackingError> for Error {
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 enum State {
-    /// Waiting for a command block wrapper to arrive. Throws away data until 
-    /// CBW signature is found. Moves to SendingDataToHost or ReceivingDataFromHost
-    /// depending on CBW direction flag
+    /// Waiting for a command block wrapper to arrive. Moves to SendingDataToHost or 
+    /// ReceivingDataFromHost depending on CBW direction flag, or WaitingForResetRecovery
+    /// if the CBW isn't valid or meaningful
     WaitingForCommand,
     /// Command initiated a transfer to the host (IN in USB parlance). Sends the 
     /// number of bytes the command asked for unless instructed to terminate early. 
@@ -71,6 +80,9 @@ enum State {
     NeedZlp,
     /// Data transfer has finished. Sends a command block status packet
     NeedToSendStatus,
+    /// Received a CBW that wasn't valid or meaningful. Both bulk endpoints are stalled until the 
+    /// host sends a bulk only mass storage reset, then moves to WaitingForCommand
+    WaitingForResetRecovery,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -97,24 +109,78 @@ pub enum TransferState {
 /// [Glossary](index.html#glossary)
 /// 
 /// ## Functionality overview
-/// 1. Reading CBWs
+/// 1. Reading and validating CBWs
 /// 1. Initiating a data transfer with the length and direction from the CBW
+/// 1. Checking the CBW against the data stage the command actually needs
 /// 1. Sending USB packets to the underlaying driver when there is data in the buffer
 /// 1. Terminating the data transfer when enough data is processed or early termination is requested
 /// 1. Adding ZLP when necessary
 /// 1. Sending CSW with correct data residue
+/// 1. Stalling the bulk endpoints when the host expects more data than the command transfers
+/// 1. Stalling both bulk endpoints until a reset recovery when a CBW isn't valid or meaningful
 /// 1. Responding to class specific control requests (bulk only reset and get max lun)
 ///
-/// ## Unimplemented/Untested:
-/// 1. More than 1 LUN - the SCSI implementation used for testing uses LUN 0 and responds to requests
-///    for any other LUN with CommandError. This class will respond to get max lun correctly for any
-///    valid value of `max_lun` but I haven't implemented anything that uses more than 1 LUN
-/// 1. Bulk only mass storage reset that takes any length of time - the spec (Section 3.1 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10))
-///    allows for the reset request to kick off the reset and have the host wait/poll until the reset
-///    is done. This is likely for devices where the reset might take a long (relative to poll rate) time.
-///    This isn't implemented here.
+/// The CBW LUN field is passed through untouched, it's up to the command set (for example
+/// [Scsi](struct.Scsi.html)) to route commands to the right logical unit.
 ///
-pub struct BulkOnlyTransport<'a, B: UsbBus> {
+/// ## Host/device disagreements
+/// The CBW says how much data the host expects and in which direction, the command set knows
+/// what the command actually needs. Section 6.7 of the spec lists what the device does for each
+/// of the thirteen combinations. Once the command set has worked out what the command needs it
+/// calls [check_data_stage](#method.check_data_stage) and the rest is handled here:
+/// 
+/// 1. The host expecting the right amount or more is fine. Sending less data than the host
+///    expected ends in a short packet or ZLP, or a stall if the command doesn't send any data
+///    at all. If the host is still sending data when the command finishes the bulk OUT endpoint
+///    is stalled. Either way the CSW has the difference in its data residue
+/// 1. Anything else is a phase error, the command isn't executed. The endpoint the host wanted 
+///    to use is stalled and the CSW has phase error status. The host then does a reset recovery
+///
+/// The host clears stalls with CLEAR_FEATURE(ENDPOINT_HALT) before reading the CSW, which is
+/// held back until it has.
+///
+/// ## Invalid CBWs and reset recovery
+/// A CBW has to arrive as a single 31 byte packet, start with the signature, have no reserved
+/// bits set, address a LUN no greater than max LUN and have a command block length between 1 
+/// and 16 (Section 6.2 of the spec). Anything else isn't answered at all, the device can't 
+/// know what the host will do next so it refuses to do anything until the host starts again:
+///
+/// 1. Both bulk endpoints are stalled and `read` returns 
+///    [InvalidCbw](enum.Error.html#variant.InvalidCbw) once
+/// 1. Requests to clear the halts are acknowledged but the endpoints stay stalled, see 
+///    [MscClass::latch_halts](struct.MscClass.html#method.latch_halts)
+/// 1. The host sends a bulk only mass storage reset. The transport forgets the command and
+///    goes back to waiting for a CBW
+/// 1. The host clears the halt on the bulk IN endpoint, then on the bulk OUT endpoint
+/// 1. The next CBW is processed as normal
+///
+/// Phase errors are recovered from the same way, the only difference is the host gets a CSW
+/// first.
+///
+/// ## Buffer size
+/// `BUFFER_BYTES` sets the size of the buffer used for data going in both directions. It
+/// defaults to [DEFAULT_BUFFER_BYTES](constant.DEFAULT_BUFFER_BYTES.html) and must be at least 
+/// the max packet size. A larger buffer lets the command set queue up more data at once. When
+/// the free space at the end of the buffer runs out, unprocessed data is moved back to the 
+/// start so the buffer doesn't have to be completely drained before it can be reused.
+///
+/// ## Bulk only mass storage reset
+/// The spec (Section 3.1 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10))
+/// lets the device NAK the status stage of the reset request until it's ready for the next CBW.
+/// [control_out_with_abort](#method.control_out_with_abort) asks the command set to drop the
+/// current command before the request is accepted, so the host only sees the reset complete
+/// once it's done.
+///
+/// The command set may be in the middle of something that can't just be dropped, a half 
+/// written flash page for example. usb-device can't leave a control transfer unanswered between
+/// polls, and waiting inside `control_out` would hold up the interrupt for as long as the
+/// command set takes, so if it isn't ready the reset is accepted anyway and carried out later.
+/// Until then the bulk endpoints are left alone so the peripheral NAKs the next CBW. Each
+/// [read_with_abort](#method.read_with_abort) calls `abort` with
+/// [MassStorageReset](enum.AbortReason.html#variant.MassStorageReset) until it returns true,
+/// then the transport forgets the current command and waits for the next CBW.
+///
+pub struct BulkOnlyTransport<'a, B: UsbBus, const BUFFER_BYTES: usize = DEFAULT_BUFFER_BYTES> {
     inner: MscClass<'a, B>,
     
     /// This is the response this class will give to the Get Max LUN request
@@ -146,24 +212,27 @@ pub struct BulkOnlyTransport<'a, B: UsbBus> {
     /// Indicates we are going to end the current data transaction after draining the current 
     /// buffer regardless of data_residue
     data_done: bool,
+
+    /// The data stage the command needs. Taken from the CBW unless the command set says 
+    /// otherwise with check_data_stage
+    data_stage: DataStage,
+
+    /// A bulk only mass storage reset has been acknowledged but the command set hasn't dropped
+    /// the current command yet
+    reset_pending: bool,
 }
 
-impl<B: UsbBus> BulkOnlyTransport<'_, B> {
-    pub const BUFFER_BYTES: usize = BUFFER_BYTES;
-    pub fn new(
-        alloc: &UsbBusAllocator<B>, 
-        max_packet_size: u16, 
-        subclass: InterfaceSubclass,
-        max_lun: u8,
-    ) -> BulkOnlyTransport<'_, B> {
+impl<'a, B: UsbBus, const BUFFER_BYTES: usize> BulkOnlyTransport<'a, B, BUFFER_BYTES> {
+    /// Runs the transport on an existing `MscClass`, for example one that also offers UAS 
+    /// (see [MscClass::with_uas](struct.MscClass.html#method.with_uas)). `new` is this with
+    /// a plain bulk only interface
+    pub fn from_msc_class(inner: MscClass<'a, B>, max_lun: u8) -> BulkOnlyTransport<'a, B, BUFFER_BYTES> {
         assert!(max_lun < 16);
+        // A whole packet has to fit for any reads to happen
+        assert!(BUFFER_BYTES >= inner.max_packet_size() as usize);
+        assert!(BUFFER_BYTES >= CommandBlockWrapper::BYTES);
         BulkOnlyTransport {
-            inner: MscClass::new(
-                alloc, 
-                max_packet_size, 
-                subclass,
-                InterfaceProtocol::BulkOnlyTransport,
-            ),
+            inner,
             max_lun,
             state: State::WaitingForCommand,
             command_block_wrapper: Default::default(),
@@ -173,9 +242,36 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
             data_i: 0,
             last_packet_full: false,
             data_done: false,
+            data_stage: DataStage::None,
+            reset_pending: false,
         }
     }
 
+    /// Grants access to the underlying class, e.g. for the UAS endpoints
+    pub fn msc_class_mut(&mut self) -> &mut MscClass<'a, B> {
+        &mut self.inner
+    }
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> BulkOnlyTransport<'_, B, BUFFER_BYTES> {
+    pub const BUFFER_BYTES: usize = BUFFER_BYTES;
+    pub fn new(
+        alloc: &UsbBusAllocator<B>, 
+        max_packet_size: u16, 
+        subclass: InterfaceSubclass,
+        max_lun: u8,
+    ) -> BulkOnlyTransport<'_, B, BUFFER_BYTES> {
+        BulkOnlyTransport::from_msc_class(
+            MscClass::new(
+                alloc, 
+                max_packet_size, 
+                subclass,
+                InterfaceProtocol::BulkOnlyTransport,
+            ),
+            max_lun,
+        )
+    }
+
     fn max_packet_size(&self) -> u16 {
         self.inner.max_packet_size()
     }
@@ -185,6 +281,32 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     }
 
     pub fn read(&mut self) -> Result<(), Error> {
+        self.read_with_abort(|_| true)
+    }
+
+    /// Same as `read` except the command set gets to stop what it's doing when the host resets
+    /// the transport or selects an alternate setting. `abort` is called on each read until it
+    /// returns true, nothing is read or written in the meantime. After that the transport
+    /// forgets the current command and waits for the next CBW
+    pub fn read_with_abort<F: FnMut(AbortReason) -> bool>(&mut self, mut abort: F) -> Result<(), Error> {
+        if self.reset_pending {
+            if !abort(AbortReason::MassStorageReset) {
+                return Ok(());
+            }
+            trace_usb_control!("USB_CONTROL> Command set ready for reset");
+            self.reset_pending = false;
+            self.reset_transport();
+        }
+
+        if self.inner.alt_setting_selected() {
+            if !abort(AbortReason::AlternateSetting) {
+                return Ok(());
+            }
+            trace_usb_control!("USB_CONTROL> Command set ready for new alternate setting");
+            self.inner.clear_alt_setting_selected();
+            self.reset_transport();
+        }
+
         match self.state {
             State::WaitingForCommand => self.waiting_for_command(),
             State::ReceivingDataFromHost => self.receiving_data_from_host(),
@@ -192,53 +314,90 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
         }
     }
 
+    /// Same as `UsbClass::control_out` except a bulk only mass storage reset calls `abort` with
+    /// [MassStorageReset](enum.AbortReason.html#variant.MassStorageReset) before the request is
+    /// accepted. If it returns false the request is accepted anyway and the abort carries on
+    /// from [read_with_abort](#method.read_with_abort)
+    pub fn control_out_with_abort<F: FnMut(AbortReason) -> bool>(&mut self, xfer: ControlOut<B>, mut abort: F) {
+        let req = xfer.request();
+
+        if !self.inner.correct_interface_number(req.index) {
+            self.inner.control_out(xfer);
+            return;
+        }
+
+        match req {
+            Request { request_type: RequestType::Class, request: REQ_BULK_ONLY_RESET, .. } => {
+                trace_usb_control!("USB_CONTROL> Bulk only mass storage reset");
+
+                // Unlike a bus reset any stalls stay put, the host clears them afterwards. Latched
+                // halts from an invalid CBW are released so it can
+                self.inner.release_halts();
+
+                if abort(AbortReason::MassStorageReset) {
+                    trace_usb_control!("USB_CONTROL> Command set ready for reset");
+                    self.reset_pending = false;
+                    self.reset_transport();
+                } else {
+                    self.reset_pending = true;
+                }
+
+                if let Err(e) = xfer.accept() {
+                    error!("Error from ControlOut.accept: {:?}", e);
+                }
+            },
+            _ => self.inner.control_out(xfer),
+        }
+    }
+
+    /// True from a reset or new alternate setting until `abort` has returned true. The command
+    /// set shouldn't carry on with the current command in the meantime
+    pub fn abort_pending(&self) -> bool {
+        self.reset_pending || self.inner.alt_setting_selected()
+    }
+
     pub fn write(&mut self) -> Result<(), Error> {
+        // Nothing more goes for a command the host has abandoned
+        if self.abort_pending() {
+            return Ok(());
+        }
+
         match self.state {
             State::SendingDataToHost => self.sending_data_to_host(),
             State::NeedZlp => self.send_zlp(),
+            // The host has to clear the halt before it will read the CSW
+            State::NeedToSendStatus if self.inner.in_halted() => Ok(()),
             State::NeedToSendStatus => self.need_to_send_status(),
             _ => Ok(()),
         }
     }
 
     fn waiting_for_command(&mut self) -> Result<(), Error> {
-        if self.buffer.len() - self.buffer_i < (self.max_packet_size() as usize) {
-            trace_bot_buffer!("BUFFER> too full to read command");
-            Err(WouldBlock)?;
-        }
+        // Each CBW is a packet on its own, anything left over from the last command is gone
+        self.buffer_i = 0;
+        self.data_i = 0;
 
-        let bytes = self.inner.read_packet(&mut self.buffer[self.buffer_i..])?;
+        let bytes = self.inner.read_packet(&mut self.buffer[..])?;
         trace_bot_bytes!("BYTES> Read {} bytes for command", bytes);
-        self.buffer_i += bytes;
-        // This looks for the signature and throws away any bytes until it finds one
-        let new_i = CommandBlockWrapper::truncate_to_signature(&mut self.buffer[..self.buffer_i]);
-        if self.buffer_i != new_i {
-            trace_bot_headers!("HEADER> Discarded {} bytes looking for command block wrapper signature", self.buffer_i - new_i);
-            self.buffer_i = new_i;
-        }
 
-        if self.buffer_i >= CommandBlockWrapper::BYTES {
-            trace_bot_buffer!("BUFFER> full enough to try deserializing command block wrapper");
-            let cbw = CommandBlockWrapper::unpack(&self.buffer)
-                // We don't want to return a PackingError here because that's a fatal error
-                // TODO: Possibly should set PhaseError?
-                .map_err(|_| Error::DataError);
-            if cbw.is_err() {
-                let err = cbw.err().unwrap();
-                warn!("CBW unpack error: {:?}", err);
-                self.buffer_i = 0;
-                return Err(err);
-            }
-            self.transition_to_data(cbw?);
-
-            // After transitioning to data, we need to read but we might not get another interrupt
-            // TODO: dig into this a bit. It seems that we *should* get an interrupt since the last
-            // read that initiated the command should have drained the RX buffer. Depending on if
-            // logging is enabled or not it appears to sometimes work and sometimes not which makes
-            // me think it's a timing issue. In the hardware example I'm testing with RTFM appears
-            // to be clearing the 
-            self.read()?;
-        }
+        let cbw = match CommandBlockWrapper::parse(&self.buffer[..bytes], self.max_lun) {
+            Ok(cbw) => cbw,
+            Err(e) => {
+                warn!("Rejected CBW, waiting for reset recovery: {:?}", e);
+                self.inner.latch_halts();
+                self.change_state(State::WaitingForResetRecovery);
+                Err(Error::InvalidCbw(e))?
+            },
+        };
+        self.transition_to_data(cbw);
+
+        // After transitioning to data, we need to read but we might not get another interrupt
+        // TODO: dig into this a bit. It seems that we *should* get an interrupt since the last
+        // read that initiated the command should have drained the RX buffer. Depending on if
+        // logging is enabled or not it appears to sometimes work and sometimes not which makes
+        // me think it's a timing issue. In the hardware example I'm testing with RTFM appears
+        // to be clearing the 
+        self.read()?;
 
         Ok(())
     }
@@ -272,12 +431,19 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
         // Update the csw so we can send that after the data
         self.prepare_for_command(&cbw);
 
-        // Update the state
-        match cbw.direction {
-            Direction::HostToDevice => {
+        // Update the state. The direction doesn't mean anything if there's no data
+        let len = cbw.data_transfer_length;
+        self.data_stage = match cbw.direction {
+            _ if len == 0 => DataStage::None,
+            Direction::HostToDevice => DataStage::Out(len),
+            Direction::DeviceToHost => DataStage::In(len),
+        };
+        match self.data_stage {
+            DataStage::None |
+            DataStage::Out(_) => {
                 self.change_state(State::ReceivingDataFromHost);
             },
-            Direction::DeviceToHost => {
+            DataStage::In(_) => {
                 self.change_state(State::SendingDataToHost);
             },
         }
@@ -286,14 +452,60 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
         self.command_block_wrapper = cbw;
     }
 
+    /// The CBW of the command being executed
+    ///
+    /// None once the command status has been set with `send_command_ok` or `send_command_error`,
+    /// even if there's still data left to transfer
     pub fn get_current_command(&self) -> Option<&CommandBlockWrapper> {
         match self.state {
             State::SendingDataToHost |
-            State::ReceivingDataFromHost => Some(&self.command_block_wrapper),
+            State::ReceivingDataFromHost if !self.data_done => Some(&self.command_block_wrapper),
             _ => None,
         }
     }
 
+    /// Compares the data stage the current command needs with the one the host asked for in the
+    /// CBW. Call it once the command has been parsed, before transferring any data
+    ///
+    /// Returns `PhaseError` if the two can't be reconciled (see 
+    /// [Host/device disagreements](#hostdevice-disagreements)), in which case the CSW is already
+    /// on its way and the command must be dropped without executing it. Otherwise carry on as
+    /// normal, any difference in length is sorted out when the command finishes.
+    pub fn check_data_stage(&mut self, data_stage: DataStage) -> Result<(), Error> {
+        // Nothing to transfer is the same as no data stage
+        let data_stage = match data_stage {
+            DataStage::In(0) | DataStage::Out(0) => DataStage::None,
+            d => d,
+        };
+
+        let host = self.data_stage;
+        self.data_stage = data_stage;
+
+        let phase_error = match (host, data_stage) {
+            // Hn = Dn, Hi > Dn, Ho > Dn
+            (_, DataStage::None) => false,
+            // Hi >= Di, Ho >= Do
+            (DataStage::In(h), DataStage::In(d)) |
+            (DataStage::Out(h), DataStage::Out(d)) => h < d,
+            // Hn < Di, Hn < Do, Hi <> Do, Ho <> Di
+            _ => true,
+        };
+
+        if phase_error {
+            warn!("Phase error. Host expects {:?}, command needs {:?}", host, data_stage);
+            self.command_status_wrapper.status = CommandStatus::PhaseError;
+            self.data_done = true;
+            match self.end_data_transfer() {
+                // The CSW is queued either way, it's sent later if the endpoint is busy
+                Ok(()) | Err(Error::UsbError(WouldBlock)) => {},
+                Err(e) => Err(e)?,
+            }
+            Err(Error::PhaseError)?;
+        }
+
+        Ok(())
+    }
+
     pub fn data_residue(&self) -> Option<u32> {
         match self.state {
             State::SendingDataToHost |
@@ -307,7 +519,8 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
         match self.state {
             State::ReceivingDataFromHost => TransferState::ReceivingDataFromHost { 
                 bytes_available: self.buffer_i - self.data_i,
-                full: self.buffer_i == self.buffer.len(),
+                // Not enough space for another packet, even after compacting
+                full: self.buffer.len() - (self.buffer_i - self.data_i) < self.max_packet_usize(),
                 done: self.command_status_wrapper.data_residue == 0,
             },
             State::SendingDataToHost => TransferState::SendingDataToHost { 
@@ -321,24 +534,49 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
         }
     }
 
+    /// Moves any data that hasn't been processed yet back to the start of the buffer to free
+    /// up space at the end
+    fn compact_buffer(&mut self) {
+        if self.data_i > 0 {
+            trace_bot_buffer!("BUFFER> compacting, moving {} bytes from {}", self.buffer_i - self.data_i, self.data_i);
+            self.buffer.copy_within(self.data_i..self.buffer_i, 0);
+            self.buffer_i -= self.data_i;
+            self.data_i = 0;
+        }
+    }
+
     /// Gets a mutable slice of the buffer of the specified length
     /// panics if len requested is > the max size of the buffer
     /// returns WouldBlock if there isn't currently space in the buffer
     /// Advances the buffer pointer so don't call it unless you actually
     /// need to put data in the buffer.
     pub fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        self.peek_buffer_space(len)?;
+
+        let s = self.buffer_i;
+        self.commit_buffer_space(len);
+
+        Ok(&mut self.buffer[s..s + len])
+    }
+
+    /// Same as `take_buffer_space` but doesn't advance the buffer pointer. Call 
+    /// `commit_buffer_space` once the data is in the buffer. Useful if filling the buffer
+    /// might fail part way through
+    pub fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
         if len > self.buffer.len() {
-            panic!("BulkOnlyTransport::take_buffer_space called with len > buffer.len() ({} > {}) which can never be successful",
+            panic!("BulkOnlyTransport::peek_buffer_space called with len > buffer.len() ({} > {}) which can never be successful",
                 len, self.buffer.len());
         } 
 
+        if len > self.buffer.len() - self.buffer_i {
+            self.compact_buffer();
+        }
+
         if len <= self.buffer.len() - self.buffer_i {
-            trace_bot_buffer!("BUFFER> successfully allocated {} bytes", len);
+            trace_bot_buffer!("BUFFER> {} bytes available to allocate", len);
             let s = self.buffer_i;
             let e = s + len;
 
-            self.buffer_i += len;
-
             Ok(&mut self.buffer[s..e])
         } else {
             trace_bot_buffer!("BUFFER> insufficient space to allocate {} bytes", len);
@@ -346,13 +584,33 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
         }
     }
 
+    /// Advances the buffer pointer over `len` bytes previously returned by `peek_buffer_space`
+    /// panics if there isn't that much space
+    pub fn commit_buffer_space(&mut self, len: usize) {
+        assert!(len <= self.buffer.len() - self.buffer_i);
+        self.buffer_i += len;
+        trace_bot_buffer!("BUFFER> successfully allocated {} bytes", len);
+    }
+
     /// Returns a slice containing data from the buffer if there is `len` bytes available
     /// panics if len requested is > the max size of the buffer
     /// `take_available` modifies behaviour to return whatever is available, even if that is [u8; 0]
     /// returns WouldBlock if there isn't enough data available
     pub fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        let len = self.peek_buffered_data(len, take_available)?.len();
+
+        let s = self.data_i;
+        self.consume_buffered_data(len);
+
+        Ok(&self.buffer[s..s + len])
+    }
+
+    /// Same as `take_buffered_data` but leaves the data in the buffer. Call 
+    /// `consume_buffered_data` once it has been dealt with. Useful if processing the data
+    /// might fail part way through
+    pub fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], Error> {
         if len > self.buffer.len() {
-            panic!("BulkOnlyTransport::take_buffered_data called with len > buffer.len() ({} > {}) which can never be successful",
+            panic!("BulkOnlyTransport::peek_buffered_data called with len > buffer.len() ({} > {}) which can never be successful",
                 len, self.buffer.len());
         }
 
@@ -366,14 +624,61 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
         let len = len.min(available);
         let e = s + len;
 
+        Ok(&self.buffer[s..e])
+    }
+
+    /// Removes `len` bytes previously returned by `peek_buffered_data` from the buffer
+    /// panics if there isn't that much data
+    pub fn consume_buffered_data(&mut self, len: usize) {
+        assert!(len <= self.buffer_i - self.data_i);
+
         self.data_i += len;
         if self.data_i == self.buffer_i {
             self.data_i = 0;
             self.buffer_i = 0;
         }
         trace_bot_buffer!("BUFFER> took {}, available after: {}", len, self.buffer_i - self.data_i);
+    }
 
-        Ok(&self.buffer[s..e])
+    /// Reads a packet from the bulk OUT endpoint into the buffer, returning its length. `read`
+    /// does this as the transfer needs, this is for transports that share the endpoints and
+    /// buffer (UAS for example) and keep track of the transfer themselves
+    ///
+    /// `WouldBlock` if nothing has arrived or there isn't room in the buffer for a whole packet
+    pub fn read_packet_to_buffer(&mut self) -> Result<usize, Error> {
+        self.peek_buffer_space(self.max_packet_usize())?;
+
+        let bytes = self.inner.read_packet(&mut self.buffer[self.buffer_i..])?;
+        self.buffer_i += bytes;
+        trace_bot_bytes!("BYTES> Read {} bytes into buffer. Buff bytes: {}", bytes, self.buffer_i - self.data_i);
+
+        Ok(bytes)
+    }
+
+    /// Writes a packet of up to `max_len` bytes from the buffer to the bulk IN endpoint, 
+    /// returning its length. The counterpart to `read_packet_to_buffer`, an empty buffer or a
+    /// `max_len` of 0 sends a ZLP
+    pub fn write_packet_from_buffer(&mut self, max_len: usize) -> Result<usize, Error> {
+        let len = (self.buffer_i - self.data_i)
+            .min(max_len)
+            .min(self.max_packet_usize());
+
+        let s = self.data_i;
+        let bytes = self.write_packet(s, s + len)?;
+        self.consume_buffered_data(bytes);
+        trace_bot_bytes!("BYTES> Sent {} bytes from buffer. Buff bytes: {}", bytes, self.buffer_i - self.data_i);
+
+        Ok(bytes)
+    }
+
+    /// Writes `buffer[start..end]` to the bulk IN endpoint, an empty range is a ZLP. Every packet
+    /// goes through here so nothing is sent for a command the host has abandoned, it's
+    /// `WouldBlock` the same as a busy endpoint until the command set has aborted it
+    fn write_packet(&mut self, start: usize, end: usize) -> Result<usize, Error> {
+        if self.abort_pending() {
+            Err(WouldBlock)?;
+        }
+        Ok(self.inner.write_packet(&self.buffer[start..end])?)
     }
 
     fn flush(&mut self) -> Result<(), Error> {
@@ -391,7 +696,7 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
 
             let end = start + len;
 
-            let bytes = self.inner.write_packet(&self.buffer[start..end])?;
+            let bytes = self.write_packet(start, end)?;
 
             self.last_packet_full = bytes == packet_size;
             self.data_i += bytes;
@@ -422,7 +727,7 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     }
 
     fn send_zlp(&mut self) -> Result<(), Error> {
-        match self.inner.write_packet(&[]) {
+        match self.write_packet(0, 0) {
             Ok(_) => trace_bot_zlp!("ZLP> sent"),
             Err(e) => {
                 trace_bot_zlp!("ZLP> sending failed: {:?}", e);
@@ -444,19 +749,46 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     }
 
     fn end_data_transfer(&mut self) -> Result<(), Error> {
+        // We only send a zero length packet if the last write was a full packet (or nothing was sent
+        // at all) AND we are sending less total bytes than the command header asked for. This has
+        // to be checked before packing the CSW because that repurposes data_residue to track 
+        // sending the CSW
+        let residue = self.command_status_wrapper.data_residue;
+        let nothing_sent = residue == self.command_block_wrapper.data_transfer_length;
+        let sending = self.state == State::SendingDataToHost;
+
+        // A command that doesn't send anything, or a phase error, ends the host's data stage
+        // with a stall rather than a ZLP
+        let needs_in_stall = sending && residue > 0 && (
+            self.data_stage == DataStage::None ||
+            self.command_status_wrapper.status == CommandStatus::PhaseError
+        );
+        let needs_zlp = (self.last_packet_full || nothing_sent) && 
+                        sending &&
+                        !needs_in_stall &&
+                        residue > 0;
+
+        // The host is still sending data no one wants. The endpoint is stalled instead of reading 
+        // the rest, anything already read but not used counts towards the residue as well
+        let needs_out_stall = self.state == State::ReceivingDataFromHost && residue > 0;
+        if self.state == State::ReceivingDataFromHost {
+            self.command_status_wrapper.data_residue += (self.buffer_i - self.data_i) as u32;
+        }
+
         // Get the csw ready to send
         self.pack_csw();
 
-        // We only send a zero length packet if the last write was a full packet AND we are sending
-        // less total bytes than the command header asked for
-        let needs_zlp = self.last_packet_full && 
-                        self.state == State::SendingDataToHost &&
-                        self.command_status_wrapper.data_residue > 0;
-
+        if needs_out_stall {
+            self.inner.halt_out();
+        }
 
         // send_zlp or flush are called here because we may not get an interrupt in a timley manner
         // if we don't send immediately and
-        if needs_zlp {            
+        if needs_in_stall {
+            // The CSW goes once the host has cleared the stall
+            self.inner.halt_in();
+            self.change_state(State::NeedToSendStatus);
+        } else if needs_zlp {            
             trace_bot_zlp!("ZLP> required");
             self.change_state(State::NeedZlp);
             self.send_zlp()?;
@@ -490,15 +822,16 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
 
     fn check_end_data_transfer(&mut self) -> Result<(), Error> {
         match self.state {
-            State::ReceivingDataFromHost => {
-                // Check if we've read everything we were expecting
-                if self.command_status_wrapper.data_residue == 0 &&
-                    // AND it's been handled
-                    self.data_i == self.buffer_i
-                {
-                    trace_bot_states!("STATE> Data residue = 0 and buffer empty, all data received");
-                    self.end_data_transfer()?;
+            // Nothing ends until the command has finished. Without this commands with no 
+            // data stage would get a CSW before they were executed
+            State::ReceivingDataFromHost if self.data_done => {
+                // Check if we've read everything we were expecting AND it's been handled
+                if self.command_status_wrapper.data_residue == 0 && self.data_i == self.buffer_i {
+                    trace_bot_states!("STATE> Data residue = 0, buffer empty and command done, all data received");
+                } else {
+                    trace_bot_states!("STATE> Command done with data left over, early termination");
                 }
+                self.end_data_transfer()?;
             },
             State::SendingDataToHost => {
                 if self.command_status_wrapper.data_residue == 0 {
@@ -515,6 +848,10 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     }
 
     fn receiving_data_from_host(&mut self) -> Result<(), Error> {
+        if self.buffer.len() - self.buffer_i < self.max_packet_usize() {
+            self.compact_buffer();
+        }
+
         if self.command_status_wrapper.data_residue > 0 &&
             self.buffer.len() - self.buffer_i >= self.max_packet_usize() 
         {
@@ -544,6 +881,15 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
         Ok(())
     }
 
+    /// Abandons whatever is going on and waits for the next CBW. For transports built on this
+    /// one that switch between it and something else
+    pub fn reset_transport(&mut self) {
+        self.buffer_i = 0;
+        self.data_i = 0;
+        self.data_done = false;
+        self.change_state(State::WaitingForCommand);
+    }
+
     fn need_to_send_status(&mut self) -> Result<(), Error> {
         self.flush()?;
 
@@ -556,17 +902,15 @@ impl<B: UsbBus> BulkOnlyTransport<'_, B> {
     }
 }
 
-impl<B: UsbBus> UsbClass<B> for BulkOnlyTransport<'_, B> {
+impl<B: UsbBus, const BUFFER_BYTES: usize> UsbClass<B> for BulkOnlyTransport<'_, B, BUFFER_BYTES> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
         self.inner.get_configuration_descriptors(writer)
     }
 
     fn reset(&mut self) { 
         trace_usb_control!("USB_CONTROL> reset");
-        self.buffer_i = 0;
-        self.data_i = 0;
-        self.data_done = false;
-        self.change_state(State::WaitingForCommand);
+        self.reset_pending = false;
+        self.reset_transport();
         self.inner.reset()
     }
 
@@ -590,18 +934,6 @@ impl<B: UsbBus> UsbClass<B> for BulkOnlyTransport<'_, B> {
                     Ok(1)
                 })),
 
-            // Bulk only mass storage reset
-            Request { request_type: RequestType::Class, request: REQ_BULK_ONLY_RESET, .. } => {
-                // There's some more functionality around this request to allow the reset to take
-                // more time - NAK the status until the reset is done.
-                // This isn't implemented.
-                // See Section 3.1 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
-                self.reset();
-                Some(xfer.accept(|_| {
-                    trace_usb_control!("USB_CONTROL> Bulk only mass storage reset");
-                    Ok(0)
-                }))
-            },
             _ => {
                 self.inner.control_in(xfer);
                 None
@@ -613,8 +945,10 @@ impl<B: UsbBus> UsbClass<B> for BulkOnlyTransport<'_, B> {
         }
     }
 
+    // Standalone the reset is always carried out from `read_with_abort`, so it goes through the
+    // same callback as everything else
     fn control_out(&mut self, xfer: ControlOut<B>) {
-        self.inner.control_out(xfer)
+        self.control_out_with_abort(xfer, |_| false)
     }
 
     fn poll(&mut self) { 
@@ -1,4 +1,7 @@
-use packing::Packed;
+use packing::{
+    Packed,
+    PackedSize,
+};
 
 use super::Direction;
 
@@ -67,6 +70,22 @@ impl Default for CommandBlockWrapper {
     }
 }
 
+/// Why a CBW was rejected. The first two make it invalid, the rest not meaningful. 
+/// Section 6.2 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum InvalidCbw {
+    /// The packet wasn't 31 bytes long
+    Length(usize),
+    /// The packet didn't start with the CBW signature
+    Signature,
+    /// Reserved bits were set in the flags
+    ReservedBits,
+    /// The LUN is greater than the max LUN
+    Lun(u8),
+    /// The command block length wasn't between 1 and 16
+    CommandBlockLength(u8),
+}
+
 impl CommandBlockWrapper {
     fn check_signature(buf: &[u8]) -> bool {
         buf.len() >= 4 &&
@@ -76,69 +95,80 @@ impl CommandBlockWrapper {
         buf[3] == SIGNATURE_3 
     }
 
-    fn find_signature(buf: &[u8]) -> Option<usize> {
-        if buf.len() < 4 {
-            return None;
+    /// Unpacks the CBW in `packet` if it's valid and meaningful. A CBW is always sent as a 
+    /// packet on its own so `packet` has to be exactly one CBW long
+    pub fn parse(packet: &[u8], max_lun: u8) -> Result<CommandBlockWrapper, InvalidCbw> {
+        if packet.len() != Self::BYTES {
+            Err(InvalidCbw::Length(packet.len()))?;
         }
-        for i in 0..buf.len().saturating_sub(4) {
-            if Self::check_signature(&buf[i..(i+4)]) {
-                return Some(i);
-            } 
+        if !Self::check_signature(packet) {
+            Err(InvalidCbw::Signature)?;
         }
-        None
-    }
-
-    pub fn truncate_to_signature(buf: &mut [u8]) -> usize {
-        let len = buf.len();
-        if len < 4 {
-            return len;
+        // Only the direction bit is defined
+        if packet[12] & 0x7F != 0 {
+            Err(InvalidCbw::ReservedBits)?;
         }
 
-        let sig_index = Self::find_signature(buf)
-            // If we didn't find the signature, leave the last 3 bytes which might be the beginning
-            // of the signature which we'll find next time around
-            .unwrap_or(len - 3);
+        // Can't fail now the length and flags have been checked
+        let cbw = Self::unpack(packet)
+            .map_err(|_| InvalidCbw::ReservedBits)?;
 
-        for i in sig_index..len {
-            buf[i-sig_index] = buf[i];
+        // Reserved bits in the LUN and command block length bytes are caught here too
+        if cbw.lun > max_lun {
+            Err(InvalidCbw::Lun(cbw.lun))?;
+        }
+        if cbw.data_length == 0 || cbw.data_length as usize > cbw.data.len() {
+            Err(InvalidCbw::CommandBlockLength(cbw.data_length))?;
         }
 
-        len - sig_index
+        Ok(cbw)
     }
 }
 
 #[test]
-fn test_truncate_to_signature() {
-    const LEN: usize = 512;
-    let mut buffer = [0; LEN];
-
-    // have to leave the last 3 bytes in case they are the start of a sig
-    assert_eq!(CommandBlockWrapper::truncate_to_signature(&mut buffer[..]), 3);
-
-    buffer[LEN-3] = SIGNATURE_0;
-    buffer[LEN-2] = SIGNATURE_1;
-    buffer[LEN-1] = SIGNATURE_2;
-    assert_eq!(CommandBlockWrapper::truncate_to_signature(&mut buffer[..]), 3);
-    assert_eq!(buffer[0], SIGNATURE_0);
-    assert_eq!(buffer[1], SIGNATURE_1);
-    assert_eq!(buffer[2], SIGNATURE_2);
-
-    let l = 100;
-    let o = 50;
-    buffer[o] = SIGNATURE_0;
-    buffer[o+1] = SIGNATURE_1;
-    buffer[o+2] = SIGNATURE_2;
-    buffer[o+3] = SIGNATURE_3;
-    assert_eq!(CommandBlockWrapper::find_signature(&buffer[..l]), Some(o));
-    let new_len = l-o;
-    // Should truncate down
-    assert_eq!(CommandBlockWrapper::truncate_to_signature(&mut buffer[..l]), new_len);
-
-    let old = buffer.clone();
-    // Shouldn't truncate anything
-    assert_eq!(CommandBlockWrapper::truncate_to_signature(&mut buffer[..new_len]), new_len);
-    // Or modify the buffer
-    for i in 0..LEN {
-        assert_eq!(buffer[i], old[i]);
+fn test_parse() {
+    let mut packet = [0; CommandBlockWrapper::BYTES + 1];
+    packet[0] = SIGNATURE_0;
+    packet[1] = SIGNATURE_1;
+    packet[2] = SIGNATURE_2;
+    packet[3] = SIGNATURE_3;
+    packet[4] = 0x12;
+    packet[8] = 0x24;
+    packet[12] = 0x80;
+    packet[13] = 1;
+    packet[14] = 6;
+    packet[15] = 0x12;
+
+    let cbw = CommandBlockWrapper::parse(&packet[..31], 1).unwrap();
+    assert_eq!(cbw.tag, 0x12);
+    assert_eq!(cbw.data_transfer_length, 0x24);
+    assert_eq!(cbw.direction, Direction::DeviceToHost);
+    assert_eq!(cbw.lun, 1);
+    assert_eq!(cbw.data_length, 6);
+    assert_eq!(cbw.data[0], 0x12);
+
+    // Too short, too long
+    assert_eq!(CommandBlockWrapper::parse(&packet[..30], 1), Err(InvalidCbw::Length(30)));
+    assert_eq!(CommandBlockWrapper::parse(&packet[..], 1), Err(InvalidCbw::Length(32)));
+
+    // LUN past max LUN
+    assert_eq!(CommandBlockWrapper::parse(&packet[..31], 0), Err(InvalidCbw::Lun(1)));
+
+    let mut p = packet;
+    p[0] = 0;
+    assert_eq!(CommandBlockWrapper::parse(&p[..31], 1), Err(InvalidCbw::Signature));
+
+    let mut p = packet;
+    p[12] = 0x81;
+    assert_eq!(CommandBlockWrapper::parse(&p[..31], 1), Err(InvalidCbw::ReservedBits));
+
+    let mut p = packet;
+    p[13] = 0x11;
+    assert_eq!(CommandBlockWrapper::parse(&p[..31], 1), Err(InvalidCbw::Lun(0x11)));
+
+    for &len in [0, 17, 0x26].iter() {
+        let mut p = packet;
+        p[14] = len;
+        assert_eq!(CommandBlockWrapper::parse(&p[..31], 1), Err(InvalidCbw::CommandBlockLength(len)));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,15 @@
+/// The data stage a command needs, worked out by the command set from the command block. `Dn`,
+/// `Di` and `Do` in section 6.7 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
+///
+/// Passed to [check_data_stage](struct.BulkOnlyTransport.html#method.check_data_stage) to compare
+/// it with what the host asked for in the CBW
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum DataStage {
+    /// No data is transferred
+    None,
+    /// Up to this many bytes are sent to the host
+    In(u32),
+    /// This many bytes are needed from the host. For parameter lists whose length depends on
+    /// their contents it's the minimum
+    Out(u32),
+}
@@ -1,6 +1,12 @@
 mod direction;
 pub use direction::*;
 
+mod data_stage;
+pub use data_stage::*;
+
+mod abort_reason;
+pub use abort_reason::*;
+
 mod command_status;
 pub use command_status::*;
 
@@ -15,4 +21,5 @@ pub use bulk_only_transport::{
     BulkOnlyTransport,
     TransferState,
     Error,
+    DEFAULT_BUFFER_BYTES,
 };
\ No newline at end of file
@@ -3,10 +3,20 @@
 mod bulk_only_transport;
 
 pub use bulk_only_transport::{
+    AbortReason,
     BulkOnlyTransport,
     CommandBlockWrapper,
+    DataStage,
+    InvalidCbw,
     TransferState,
     Error,
+    DEFAULT_BUFFER_BYTES,
+};
+
+mod transport;
+pub use transport::{
+    Transport,
+    TransportClass,
 };
 
 mod logging {
@@ -0,0 +1,151 @@
+use usb_device::class_prelude::*;
+use crate::{
+    AbortReason,
+    BulkOnlyTransport,
+    CommandBlockWrapper,
+    DataStage,
+    Error,
+    TransferState,
+};
+
+/// # What a command set needs from a mass storage transport
+///
+/// Implemented by [BulkOnlyTransport](struct.BulkOnlyTransport.html) and the transports built
+/// on it, `UasTransport` in `usbd_uas_transport` and `CbiTransport` in `usbd_cbi_transport`. Command sets like `usbd_scsi` are
+/// written against this rather than a particular transport.
+///
+/// Commands are executed one at a time, the transport delivers the next one once the status of
+/// the last has been set. Data goes through the transport's buffer in both directions, see the
+/// `BulkOnlyTransport` methods of the same names.
+///
+/// Errors are the Bulk Only Transport's whichever transport it is, `WouldBlock` means try again
+/// on the next poll.
+pub trait Transport {
+    /// The command being executed, None once its status has been set
+    ///
+    /// Only the LUN and command block are meaningful to every transport. The data transfer
+    /// length and direction are what a BOT host asked for, other transports leave them 0
+    fn get_current_command(&self) -> Option<&CommandBlockWrapper>;
+
+    /// Tells the transport what data stage the current command needs. Call it once the command
+    /// has been parsed, before transferring any data. Without it the command has no data stage
+    ///
+    /// Returns `PhaseError` if the host expects something else and the command has to be dropped
+    /// without executing it
+    fn check_data_stage(&mut self, data_stage: DataStage) -> Result<(), Error>;
+
+    fn transfer_state(&self) -> TransferState;
+
+    fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error>;
+
+    fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error>;
+
+    fn commit_buffer_space(&mut self, len: usize);
+
+    fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], Error>;
+
+    fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], Error>;
+
+    fn consume_buffered_data(&mut self, len: usize);
+
+    /// The current command passed, its status goes once any data left in the buffer has been sent
+    fn send_command_ok(&mut self) -> Result<(), Error>;
+
+    /// The current command failed with `sense`, fixed format without any additional sense bytes.
+    /// Transports that can't send sense data with the status ignore it, the host asks for it
+    /// with REQUEST SENSE instead
+    fn send_command_error(&mut self, sense: &[u8]) -> Result<(), Error>;
+}
+
+/// # A transport a command set can run on
+///
+/// The USB side of a [Transport](trait.Transport.html). The command set calls `read` and `write`
+/// each poll and passes control requests through `control_out_with_abort`. Control requests that
+/// drop the current command try to abort it before they're accepted, if the command set isn't
+/// ready it's aborted on a later `read` instead.
+pub trait TransportClass<B: UsbBus>: Transport + UsbClass<B> {
+    /// Reads whatever has arrived from the host. The host may abort the current command (a
+    /// reset, UAS task management or selecting an alternate setting for example), `abort` is
+    /// called with the reason to stop it. If `abort` returns false the command set isn't ready
+    /// yet and it's called again on a later read
+    fn read<F: FnMut(AbortReason) -> bool>(&mut self, abort: F) -> Result<(), Error>;
+
+    /// Handles a control request like `UsbClass::control_out`. A request that drops the current
+    /// command (a reset for example) calls `abort` with the reason before it's accepted. usb-device
+    /// can't hold the request between polls, so if `abort` returns false it's accepted anyway and
+    /// the abort carries on from `read`
+    fn control_out_with_abort<F: FnMut(AbortReason) -> bool>(&mut self, xfer: ControlOut<B>, abort: F);
+
+    /// True while the host has abandoned the current command and `abort` hasn't returned true
+    /// yet. The command set leaves the command alone until it's false again, nothing is sent for
+    /// it in the meantime
+    fn abort_pending(&self) -> bool;
+
+    /// Sends whatever is waiting to go to the host
+    fn write(&mut self) -> Result<(), Error>;
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> Transport for BulkOnlyTransport<'_, B, BUFFER_BYTES> {
+    fn get_current_command(&self) -> Option<&CommandBlockWrapper> {
+        BulkOnlyTransport::get_current_command(self)
+    }
+
+    fn check_data_stage(&mut self, data_stage: DataStage) -> Result<(), Error> {
+        BulkOnlyTransport::check_data_stage(self, data_stage)
+    }
+
+    fn transfer_state(&self) -> TransferState {
+        BulkOnlyTransport::transfer_state(self)
+    }
+
+    fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        BulkOnlyTransport::take_buffer_space(self, len)
+    }
+
+    fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], Error> {
+        BulkOnlyTransport::peek_buffer_space(self, len)
+    }
+
+    fn commit_buffer_space(&mut self, len: usize) {
+        BulkOnlyTransport::commit_buffer_space(self, len)
+    }
+
+    fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        BulkOnlyTransport::take_buffered_data(self, len, take_available)
+    }
+
+    fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], Error> {
+        BulkOnlyTransport::peek_buffered_data(self, len, take_available)
+    }
+
+    fn consume_buffered_data(&mut self, len: usize) {
+        BulkOnlyTransport::consume_buffered_data(self, len)
+    }
+
+    fn send_command_ok(&mut self) -> Result<(), Error> {
+        BulkOnlyTransport::send_command_ok(self)
+    }
+
+    // The CSW only has room for the status
+    fn send_command_error(&mut self, _sense: &[u8]) -> Result<(), Error> {
+        BulkOnlyTransport::send_command_error(self)
+    }
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> TransportClass<B> for BulkOnlyTransport<'_, B, BUFFER_BYTES> {
+    fn read<F: FnMut(AbortReason) -> bool>(&mut self, abort: F) -> Result<(), Error> {
+        BulkOnlyTransport::read_with_abort(self, abort)
+    }
+
+    fn control_out_with_abort<F: FnMut(AbortReason) -> bool>(&mut self, xfer: ControlOut<B>, abort: F) {
+        BulkOnlyTransport::control_out_with_abort(self, xfer, abort)
+    }
+
+    fn abort_pending(&self) -> bool {
+        BulkOnlyTransport::abort_pending(self)
+    }
+
+    fn write(&mut self) -> Result<(), Error> {
+        BulkOnlyTransport::write(self)
+    }
+}
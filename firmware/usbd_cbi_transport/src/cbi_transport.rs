@@ -0,0 +1,549 @@
+use usb_device::class_prelude::*;
+use usb_device::{
+    Result as UsbResult,
+    control::{
+        RequestType,
+        Request,
+    },
+};
+use usbd_bulk_only_transport::{
+    AbortReason,
+    BulkOnlyTransport,
+    CommandBlockWrapper,
+    DataStage,
+    Error as BulkOnlyTransportError,
+    TransferState,
+    Transport,
+    TransportClass,
+    DEFAULT_BUFFER_BYTES,
+};
+use usbd_mass_storage::{
+    InterfaceSubclass,
+    MscClass,
+};
+
+use crate::logging::*;
+
+/// Accept device-specific command, the class specific request commands arrive in
+///
+/// CBI 2.2
+const REQ_ACCEPT_DEVICE_SPECIFIC_COMMAND: u8 = 0x00;
+
+/// Longest command block an ADSC can carry, the same as a CBW
+const MAX_COMMAND_BLOCK_BYTES: usize = 16;
+
+/// A command block reset is a SEND DIAGNOSTIC starting with these bytes, the rest are 0xFF
+///
+/// CBI 2.2
+const COMMAND_BLOCK_RESET: [u8; 2] = [0x1D, 0x04];
+
+/// Where ASC and ASCQ are in fixed format sense data
+const SENSE_ASC: usize = 12;
+const SENSE_ASCQ: usize = 13;
+
+/// Interrupt data block for anything other than a UFI device, bType then bValue
+///
+/// CBI 3.4.3.1.1
+const INTERRUPT_COMMAND_COMPLETION: u8 = 0x00;
+const INTERRUPT_PASS: u8 = 0x00;
+const INTERRUPT_FAIL: u8 = 0x01;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum State {
+    /// Nothing being executed, `read` starts the pending command
+    Idle,
+    /// The command set has the command but hasn't said what data stage it needs
+    Started,
+    SendingDataToHost,
+    /// Commands without a data stage stay here with nothing to read until their status is set
+    ReceivingDataFromHost,
+    /// The data in stage was cut short on a packet boundary, a ZLP ends it
+    NeedZlp,
+    /// Waiting for the command completion interrupt to go
+    NeedToSendStatus,
+}
+
+/// # Control/Bulk/Interrupt transport
+///
+/// Commands are sent by the host in ADSC control requests, data goes on the bulk endpoints
+/// and, with a command completion interrupt, the status goes on an interrupt endpoint. A
+/// command set runs on it through `TransportClass`, with `usbd_scsi` that's
+/// `Scsi::with_transport` along with the command profile that matches `subclass`.
+///
+/// ## Functionality overview
+/// 1. Accepting command blocks of up to 16 bytes in ADSC requests and passing them to the
+///    command set one at a time, in the same form as a CBW for LUN 0
+/// 1. Moving the data stage the command set asks for with `check_data_stage` on the bulk
+///    endpoints. A failed command that doesn't move all of its data stalls the bulk endpoint,
+///    as does a passing command that takes less data than it asked for
+/// 1. Sending the interrupt data block once the status is set. UFI devices send the ASC and
+///    ASCQ of the sense data, anything else sends pass or fail
+/// 1. Command block reset: any held command is dropped and the command set is asked to abort
+///    the current command before the ADSC is acknowledged. If it isn't ready the ADSC is
+///    acknowledged anyway and the bulk endpoints are left alone until it has aborted the
+///    command on a later `read`
+///
+/// ## Limitations
+/// CBI has no LUNs, commands are always for LUN 0. The command set should only have the one
+/// logical unit.
+///
+/// The host doesn't say how much data it expects so the data stage is whatever the command set
+/// passes to `check_data_stage`, without it the command has no data stage.
+///
+/// The host isn't meant to send a command before the last one has completed. One that arrives
+/// early is held until then, any more are rejected by stalling the control pipe.
+///
+/// Without a command completion interrupt the host has no way of knowing a command failed
+/// other than a stalled data stage. It's expected to send REQUEST SENSE after each command.
+///
+/// The buffer and bulk endpoints belong to a `BulkOnlyTransport`, so do the endpoint
+/// descriptors. It never sees a CBW.
+pub struct CbiTransport<'a, B: UsbBus, const BUFFER_BYTES: usize = DEFAULT_BUFFER_BYTES> {
+    bot: BulkOnlyTransport<'a, B, BUFFER_BYTES>,
+    max_packet_size: u16,
+    command_completion_interrupt: bool,
+    /// UFI devices report the ASC and ASCQ in the interrupt data block
+    ufi: bool,
+    state: State,
+    /// The command being executed, as a CBW so it looks the same as one from BOT
+    command: CommandBlockWrapper,
+    /// A command that arrived before the current one completed
+    pending: Option<CommandBlockWrapper>,
+    passed: bool,
+    /// Waiting to go on the interrupt endpoint once the status is set
+    interrupt: [u8; 2],
+    /// Bytes of the data stage left to move
+    data_residue: u32,
+    data_length: u32,
+    last_packet_full: bool,
+    /// The command set has set the status, no more data is coming
+    data_done: bool,
+    /// A command block reset has been acknowledged but the command set hasn't dropped the
+    /// current command yet
+    reset_pending: bool,
+}
+
+impl<'a, B: UsbBus, const BUFFER_BYTES: usize> CbiTransport<'a, B, BUFFER_BYTES> {
+    /// Allocates the bulk endpoints and, with `command_completion_interrupt`, the interrupt
+    /// endpoint
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        max_packet_size: u16,
+        subclass: InterfaceSubclass,
+        command_completion_interrupt: bool,
+    ) -> CbiTransport<'a, B, BUFFER_BYTES> {
+        CbiTransport {
+            bot: BulkOnlyTransport::from_msc_class(
+                MscClass::with_cbi(alloc, max_packet_size, subclass, command_completion_interrupt),
+                0,
+            ),
+            max_packet_size,
+            command_completion_interrupt,
+            ufi: subclass == InterfaceSubclass::Ufi,
+            state: State::Idle,
+            command: Default::default(),
+            pending: None,
+            passed: true,
+            interrupt: [0; 2],
+            data_residue: 0,
+            data_length: 0,
+            last_packet_full: false,
+            data_done: false,
+            reset_pending: false,
+        }
+    }
+
+    fn change_state(&mut self, state: State) {
+        trace_cbi!("CBI> {:?} -> {:?}", self.state, state);
+        self.state = state;
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        self.bot.peek_buffered_data(BUFFER_BYTES, true)
+            .map(|data| data.len())
+            .unwrap_or(0)
+    }
+
+    /// Forgets the current and pending commands
+    fn reset_cbi(&mut self) {
+        self.end_command();
+        self.pending = None;
+        self.reset_pending = false;
+    }
+
+    /// Drops the current command whatever it's doing, along with any data in the buffer
+    fn end_command(&mut self) {
+        let bytes = self.buffered_bytes();
+        self.bot.consume_buffered_data(bytes);
+        self.data_done = false;
+        self.change_state(State::Idle);
+    }
+
+    /// Returns false if the command can't be accepted
+    fn receive_command(&mut self, command_block: &[u8]) -> bool {
+        if command_block.is_empty() || command_block.len() > MAX_COMMAND_BLOCK_BYTES {
+            warn!("Invalid CBI command block: {:X?}", command_block);
+            return false;
+        }
+
+        let mut cbw = CommandBlockWrapper {
+            data_length: command_block.len() as u8,
+            ..Default::default()
+        };
+        cbw.data[..command_block.len()].copy_from_slice(command_block);
+        trace_cbi!("CBI> Command: {:X?}", command_block);
+
+        // Nothing starts until a command block reset has finished with the current command
+        if self.state == State::Idle && !self.reset_pending {
+            self.start_command(cbw);
+        } else if self.pending.is_none() {
+            self.pending = Some(cbw);
+        } else {
+            warn!("CBI command arrived with {:?} pending, rejecting it", self.pending);
+            return false;
+        }
+        true
+    }
+
+    fn start_command(&mut self, cbw: CommandBlockWrapper) {
+        self.command = cbw;
+        self.passed = true;
+        self.data_residue = 0;
+        self.data_length = 0;
+        self.last_packet_full = false;
+        self.data_done = false;
+        self.change_state(State::Started);
+    }
+
+    fn receive_data(&mut self) -> Result<(), BulkOnlyTransportError> {
+        if self.data_residue > 0 {
+            let bytes = self.bot.read_packet_to_buffer()?;
+            self.data_residue = self.data_residue.saturating_sub(bytes as u32);
+        }
+        self.check_end_data_transfer();
+        Ok(())
+    }
+
+    /// Drops data out packets that arrive between commands, left over from a command that
+    /// ended early
+    fn discard_data(&mut self) -> Result<(), BulkOnlyTransportError> {
+        loop {
+            let bytes = self.bot.read_packet_to_buffer()?;
+            warn!("Discarding {} bytes of CBI data with no command", bytes);
+            self.bot.consume_buffered_data(bytes);
+        }
+    }
+
+    fn send_data(&mut self) -> Result<(), BulkOnlyTransportError> {
+        if self.data_residue > 0 && self.buffered_bytes() > 0 {
+            let bytes = self.bot.write_packet_from_buffer(self.data_residue as usize)?;
+            self.last_packet_full = bytes == self.max_packet_size as usize;
+            self.data_residue -= bytes as u32;
+        }
+        self.check_end_data_transfer();
+        Ok(())
+    }
+
+    fn send_interrupt(&mut self) -> Result<(), BulkOnlyTransportError> {
+        self.bot.msc_class_mut().write_interrupt_packet(&self.interrupt)?;
+        trace_cbi!("CBI> Sent interrupt: {:X?}", self.interrupt);
+        self.end_command();
+        Ok(())
+    }
+
+    fn check_end_data_transfer(&mut self) {
+        let end = match self.state {
+            State::Started | State::ReceivingDataFromHost => self.data_done,
+            // Anything the command set queued past the end of the data stage is dropped
+            State::SendingDataToHost => {
+                self.data_done && (self.data_residue == 0 || self.buffered_bytes() == 0)
+            },
+            _ => false,
+        };
+
+        if end {
+            self.end_data_transfer();
+        }
+    }
+
+    fn end_data_transfer(&mut self) {
+        // A short data in stage that passed ends with a short packet, a ZLP if it ended on a
+        // full packet (or didn't have any packets). Otherwise the host is told the data stage
+        // is over by stalling the endpoint
+        let short = self.data_residue > 0;
+        let mut need_zlp = false;
+        match self.state {
+            State::SendingDataToHost if short && self.passed => {
+                need_zlp = self.last_packet_full || self.data_residue == self.data_length;
+            },
+            State::SendingDataToHost if short => self.bot.msc_class_mut().halt_in(),
+            State::ReceivingDataFromHost if short => self.bot.msc_class_mut().halt_out(),
+            _ => {},
+        }
+
+        let bytes = self.buffered_bytes();
+        self.bot.consume_buffered_data(bytes);
+
+        if need_zlp {
+            self.change_state(State::NeedZlp);
+        } else {
+            self.end_data_stage();
+        }
+    }
+
+    /// Without a command completion interrupt the command is over once its data has gone
+    fn end_data_stage(&mut self) {
+        if self.command_completion_interrupt {
+            self.change_state(State::NeedToSendStatus);
+        } else {
+            self.end_command();
+        }
+    }
+
+    fn set_command_status(&mut self, passed: bool, sense: &[u8]) {
+        self.passed = passed;
+        self.interrupt = match (self.ufi, passed) {
+            (true, true) => [0, 0],
+            (true, false) => [
+                sense.get(SENSE_ASC).copied().unwrap_or(0),
+                sense.get(SENSE_ASCQ).copied().unwrap_or(0),
+            ],
+            (false, true) => [INTERRUPT_COMMAND_COMPLETION, INTERRUPT_PASS],
+            (false, false) => [INTERRUPT_COMMAND_COMPLETION, INTERRUPT_FAIL],
+        };
+        self.data_done = true;
+        self.check_end_data_transfer();
+    }
+}
+
+/// A command block reset is the only SEND DIAGNOSTIC with these parameters, the rest of the
+/// command block is padding
+fn is_command_block_reset(command_block: &[u8]) -> bool {
+    command_block.len() > COMMAND_BLOCK_RESET.len() &&
+    command_block.starts_with(&COMMAND_BLOCK_RESET) &&
+    command_block[COMMAND_BLOCK_RESET.len()..].iter().all(|&b| b == 0xFF)
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> Transport for CbiTransport<'_, B, BUFFER_BYTES> {
+    fn get_current_command(&self) -> Option<&CommandBlockWrapper> {
+        match self.state {
+            State::Started | State::SendingDataToHost | State::ReceivingDataFromHost if !self.data_done => {
+                Some(&self.command)
+            },
+            _ => None,
+        }
+    }
+
+    fn check_data_stage(&mut self, data_stage: DataStage) -> Result<(), BulkOnlyTransportError> {
+        if self.state != State::Started {
+            warn!("CBI data stage set twice");
+            return Ok(());
+        }
+
+        // Whatever the command needs is what the host gets, there's nothing to check against
+        let (state, len) = match data_stage {
+            DataStage::In(len) if len > 0 => (State::SendingDataToHost, len),
+            DataStage::Out(len) if len > 0 => (State::ReceivingDataFromHost, len),
+            _ => (State::ReceivingDataFromHost, 0),
+        };
+        self.data_residue = len;
+        self.data_length = len;
+        self.change_state(state);
+        Ok(())
+    }
+
+    fn transfer_state(&self) -> TransferState {
+        let bytes = self.buffered_bytes();
+        match self.state {
+            State::ReceivingDataFromHost => TransferState::ReceivingDataFromHost {
+                bytes_available: bytes,
+                // Not enough space for another packet, even after compacting
+                full: BUFFER_BYTES - bytes < self.max_packet_size as usize,
+                done: self.data_residue == 0,
+            },
+            State::SendingDataToHost => TransferState::SendingDataToHost {
+                bytes_remaining: bytes,
+                empty: bytes == 0,
+            },
+            _ => TransferState::NotTransferring {
+                bytes_remaining: bytes,
+                empty: bytes == 0,
+            },
+        }
+    }
+
+    fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], BulkOnlyTransportError> {
+        self.bot.take_buffer_space(len)
+    }
+
+    fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], BulkOnlyTransportError> {
+        self.bot.peek_buffer_space(len)
+    }
+
+    fn commit_buffer_space(&mut self, len: usize) {
+        self.bot.commit_buffer_space(len)
+    }
+
+    fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], BulkOnlyTransportError> {
+        self.bot.take_buffered_data(len, take_available)
+    }
+
+    fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], BulkOnlyTransportError> {
+        self.bot.peek_buffered_data(len, take_available)
+    }
+
+    fn consume_buffered_data(&mut self, len: usize) {
+        self.bot.consume_buffered_data(len)
+    }
+
+    fn send_command_ok(&mut self) -> Result<(), BulkOnlyTransportError> {
+        self.set_command_status(true, &[]);
+        Ok(())
+    }
+
+    fn send_command_error(&mut self, sense: &[u8]) -> Result<(), BulkOnlyTransportError> {
+        self.set_command_status(false, sense);
+        Ok(())
+    }
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> TransportClass<B> for CbiTransport<'_, B, BUFFER_BYTES> {
+    fn read<F: FnMut(AbortReason) -> bool>(&mut self, mut abort: F) -> Result<(), BulkOnlyTransportError> {
+        // Any command the host sent after the command block reset is kept, it was only the one
+        // held at the time that the reset dropped
+        if self.reset_pending {
+            if !abort(AbortReason::CommandBlockReset) {
+                return Ok(());
+            }
+            trace_cbi!("CBI> Command set ready for reset");
+            self.reset_pending = false;
+            self.end_command();
+        }
+
+        // SET_INTERFACE starts everything again once the command set has dropped the current
+        // command
+        if self.bot.msc_class_mut().alt_setting_selected() {
+            if !abort(AbortReason::AlternateSetting) {
+                return Ok(());
+            }
+            trace_cbi!("CBI> Alternate setting selected");
+            self.bot.msc_class_mut().clear_alt_setting_selected();
+            self.reset_cbi();
+        }
+
+        if self.state == State::Idle {
+            if let Some(cbw) = self.pending.take() {
+                self.start_command(cbw);
+            }
+        }
+
+        match self.state {
+            State::ReceivingDataFromHost => self.receive_data(),
+            State::Idle => self.discard_data(),
+            _ => Ok(()),
+        }
+    }
+
+    fn control_out_with_abort<F: FnMut(AbortReason) -> bool>(&mut self, xfer: ControlOut<B>, mut abort: F) {
+        let req = xfer.request();
+
+        let adsc = match req {
+            Request { request_type: RequestType::Class, request: REQ_ACCEPT_DEVICE_SPECIFIC_COMMAND, .. } => {
+                self.bot.msc_class_mut().correct_interface_number(req.index)
+            },
+            _ => false,
+        };
+        if !adsc {
+            self.bot.msc_class_mut().control_out(xfer);
+            return;
+        }
+
+        let command_block = xfer.data();
+        let accepted = if is_command_block_reset(command_block) {
+            // If the command set isn't ready the current command is dropped on a later read.
+            // Any stalls stay put, the host clears them afterwards
+            trace_cbi!("CBI> Command block reset");
+            self.pending = None;
+            if abort(AbortReason::CommandBlockReset) {
+                trace_cbi!("CBI> Command set ready for reset");
+                self.reset_pending = false;
+                self.end_command();
+            } else {
+                self.reset_pending = true;
+            }
+            true
+        } else {
+            self.receive_command(command_block)
+        };
+
+        let result = if accepted {
+            xfer.accept()
+        } else {
+            xfer.reject()
+        };
+        if let Err(e) = result {
+            error!("Error from ControlOut: {:?}", e);
+        }
+    }
+
+    fn abort_pending(&self) -> bool {
+        self.reset_pending || self.bot.abort_pending()
+    }
+
+    fn write(&mut self) -> Result<(), BulkOnlyTransportError> {
+        if self.abort_pending() {
+            return Ok(());
+        }
+
+        match self.state {
+            // Writing to a stalled endpoint un-stalls it on some peripherals. The interrupt
+            // endpoint is separate, the host clears the stall once it has the status
+            State::SendingDataToHost | State::NeedZlp if self.bot.msc_class_mut().in_halted() => Ok(()),
+            State::SendingDataToHost => self.send_data(),
+            State::NeedZlp => {
+                self.bot.write_packet_from_buffer(0)?;
+                self.end_data_stage();
+                Ok(())
+            },
+            State::NeedToSendStatus => self.send_interrupt(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> UsbClass<B> for CbiTransport<'_, B, BUFFER_BYTES> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
+        self.bot.get_configuration_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        self.reset_cbi();
+        self.bot.reset()
+    }
+
+    // There's no GET MAX LUN under CBI
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        self.bot.msc_class_mut().control_in(xfer)
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        self.control_out_with_abort(xfer, |_| false)
+    }
+
+    fn poll(&mut self) {
+        panic!("CbiTransport::poll should never be called. Consumers (SCSI for example) should use TransportClass::read and TransportClass::write");
+    }
+}
+
+#[test]
+fn test_command_block_reset() {
+    let mut reset = [0xFF; 12];
+    reset[..2].copy_from_slice(&COMMAND_BLOCK_RESET);
+    assert!(is_command_block_reset(&reset));
+
+    // A SEND DIAGNOSTIC that's only padded with zeros is a real command
+    let mut send_diagnostic = [0; 12];
+    send_diagnostic[..2].copy_from_slice(&COMMAND_BLOCK_RESET);
+    assert!(!is_command_block_reset(&send_diagnostic));
+    assert!(!is_command_block_reset(&COMMAND_BLOCK_RESET));
+}
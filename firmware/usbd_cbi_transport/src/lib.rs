@@ -0,0 +1,13 @@
+#![no_std]
+
+mod cbi_transport;
+pub use cbi_transport::CbiTransport;
+
+mod logging {
+    pub use itm_logger::*;
+
+    #[cfg(feature = "trace-cbi")]
+    pub use itm_logger::trace as trace_cbi;
+    #[cfg(not(feature = "trace-cbi"))]
+    pub use itm_logger::stub as trace_cbi;
+}
@@ -7,9 +7,17 @@
 //! | CBW    | Command block wrapper. Header that contains information about the data that is expected to be sent/received next | Section 5.1 [USB Bulk Only Transport Spec][USBBot] |
 //! | CSW    | Command status wrapper. Status sent after data transfer to indicate success/failure and confirm length of data sent | Section 5.2 [USB Bulk Only Transport Spec][USBBot] |
 //! | Data Residue | Data residue (bytes) is the difference in the length requested in the CBW and the actual amount of data sent/received | Section 5.2 [USB Bulk Only Transport Spec][USBBot] |
+//! | UAS    | USB Attached SCSI. Commands, status and data each have their own pipe and the host can queue up several commands by tag | [USB Attached SCSI Protocol][USBUas] |
+//! | IU     | Information unit. What UAS sends on the command and status pipes, a command, its status, a task management function, etc. | Section 6.2 [USB Attached SCSI Protocol][USBUas] |
+//! | CBI    | Control/Bulk/Interrupt transport. Commands go over the control pipe, data over the bulk pipes and the status on an interrupt pipe | [USB CBI Transport Spec][USBCbi] |
+//! | ADSC   | Accept device-specific command. The class specific control request CBI sends commands with | Section 2.2 [USB CBI Transport Spec][USBCbi] |
+//! | UFI    | USB floppy interface. The command set used by USB floppy drives, a cut down SCSI with 12 byte commands | [USB UFI Command Specification][USBUfi] |
 //!
 //! [USB2Bus]: https://www.usb.org/document-library/usb-20-specification
 //! [USBBot]: https://www.usb.org/document-library/mass-storage-bulk-only-10
+//! [USBUas]: https://www.usb.org/document-library/usb-attached-scsi-protocol-uasp-v10-and-adopters-agreement
+//! [USBCbi]: https://www.usb.org/document-library/mass-storage-controlbulkinterrupt-cbi-transport-11
+//! [USBUfi]: https://www.usb.org/document-library/mass-storage-ufi-command-specification-10
 //!
 
 #![no_std]
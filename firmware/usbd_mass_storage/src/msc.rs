@@ -1,5 +1,10 @@
 use usb_device::{
     class_prelude::*,
+    control::{
+        Recipient,
+        RequestType,
+        Request,
+    },
     Result,
 };
 
@@ -14,16 +19,66 @@ use crate::{
 /// Section 4.3 [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
 pub const USB_CLASS_MSC: u8 = 0x08;
 
+/// The alternate setting UAS is offered on, BOT is always alternate setting 0
+///
+/// Section 4.4 [USB Attached SCSI Protocol](https://www.usb.org/document-library/usb-attached-scsi-protocol-uasp-v10-and-adopters-agreement)
+pub const UAS_ALTERNATE_SETTING: u8 = 1;
+
+/// How often the host polls the CBI command completion interrupt endpoint (ms), every frame so
+/// commands aren't held up waiting for their status
+const CBI_INTERRUPT_INTERVAL: u8 = 1;
+
+/// The CBI interrupt data block is always 2 bytes
+///
+/// Section 3.4.3 [USB CBI Transport Spec](https://www.usb.org/document-library/mass-storage-controlbulkinterrupt-cbi-transport-11)
+const CBI_INTERRUPT_PACKET_BYTES: u16 = 2;
+
+/// Descriptor type of the pipe usage descriptor that follows each UAS endpoint descriptor
+const PIPE_USAGE_DESCRIPTOR: u8 = 0x24;
+
+/// What a UAS endpoint is used for, from the pipe usage descriptor
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum PipeId {
+    Command = 0x01,
+    Status = 0x02,
+    DataIn = 0x03,
+    DataOut = 0x04,
+}
+
 /// # USB Mass Storage Class Device
 ///
 /// So far only tested with the Bulk Only protocol and the SCSI transparent command set - see 
 /// [Scsi](struct.Scsi.html) and [Bulk Only Transport](struct.BulkOnlyTransport.html)
+///
+/// Created with [with_uas](#method.with_uas) the interface has a second alternate setting for 
+/// UAS. It uses the same bulk endpoints for data plus a command and a status endpoint. The host
+/// picks one with SET_INTERFACE, [alt_setting](#method.alt_setting) says which.
+///
+/// Created with [with_cbi](#method.with_cbi) the interface uses the Control/Bulk/Interrupt
+/// protocol instead. It has the same bulk endpoints for data and, unless the host is told there
+/// isn't one, an interrupt endpoint for command completion.
 pub struct MscClass<'a, B: UsbBus> {
     pub(crate) msc_if: InterfaceNumber,
     pub(crate) read_ep: EndpointOut<'a, B>,
     pub(crate) write_ep: EndpointIn<'a, B>,
     pub(crate) subclass: InterfaceSubclass,
     pub(crate) protocol: InterfaceProtocol,
+    /// The bulk IN endpoint has been stalled and the host hasn't cleared it yet
+    pub(crate) in_halted: bool,
+    /// The bulk OUT endpoint has been stalled and the host hasn't cleared it yet
+    pub(crate) out_halted: bool,
+    /// Both bulk endpoints stay stalled whatever the host does until release_halts is called
+    pub(crate) halts_latched: bool,
+    /// UAS command pipe, only allocated if UAS is offered
+    pub(crate) command_ep: Option<EndpointOut<'a, B>>,
+    /// UAS status pipe, only allocated if UAS is offered
+    pub(crate) status_ep: Option<EndpointIn<'a, B>>,
+    /// CBI command completion interrupt, only allocated for CBI with command completion interrupt
+    pub(crate) interrupt_ep: Option<EndpointIn<'a, B>>,
+    /// The alternate setting selected by the host
+    pub(crate) alt_setting: u8,
+    /// The host has sent SET_INTERFACE and the transport hasn't caught up yet
+    pub(crate) alt_setting_selected: bool,
 }
 
 impl<B: UsbBus> MscClass<'_, B> {
@@ -39,9 +94,52 @@ impl<B: UsbBus> MscClass<'_, B> {
             read_ep: alloc.bulk(max_packet_size),
             subclass,
             protocol,
+            in_halted: false,
+            out_halted: false,
+            halts_latched: false,
+            command_ep: None,
+            status_ep: None,
+            interrupt_ep: None,
+            alt_setting: 0,
+            alt_setting_selected: false,
         }
     }
 
+    /// Offers the bulk only transport as alternate setting 0 and UAS as alternate setting 1, 
+    /// see [UAS_ALTERNATE_SETTING](constant.UAS_ALTERNATE_SETTING.html)
+    pub fn with_uas(
+        alloc: &UsbBusAllocator<B>, 
+        max_packet_size: u16, 
+        subclass: InterfaceSubclass,
+    ) -> MscClass<'_, B> {
+        // The data endpoints are allocated first, the same as without UAS
+        let mut msc = MscClass::new(alloc, max_packet_size, subclass, InterfaceProtocol::BulkOnlyTransport);
+        msc.command_ep = Some(alloc.bulk(max_packet_size));
+        msc.status_ep = Some(alloc.bulk(max_packet_size));
+        msc
+    }
+
+    /// Uses the Control/Bulk/Interrupt protocol. Commands arrive as ADSC control requests, see
+    /// `CbiTransport` in `usbd_cbi_transport`. With `command_completion_interrupt` the status
+    /// of each command is sent on an interrupt endpoint, allocated after the bulk endpoints
+    pub fn with_cbi(
+        alloc: &UsbBusAllocator<B>, 
+        max_packet_size: u16, 
+        subclass: InterfaceSubclass,
+        command_completion_interrupt: bool,
+    ) -> MscClass<'_, B> {
+        let protocol = if command_completion_interrupt {
+            InterfaceProtocol::CbiWithCCInterrupt
+        } else {
+            InterfaceProtocol::CbiNoCCInterrupt
+        };
+        let mut msc = MscClass::new(alloc, max_packet_size, subclass, protocol);
+        if command_completion_interrupt {
+            msc.interrupt_ep = Some(alloc.interrupt(CBI_INTERRUPT_PACKET_BYTES, CBI_INTERRUPT_INTERVAL));
+        }
+        msc
+    }
+
     pub fn max_packet_size(&self) -> u16 {
         // The size is the same for both endpoints.
         self.read_ep.max_packet_size()
@@ -55,6 +153,116 @@ impl<B: UsbBus> MscClass<'_, B> {
         self.write_ep.write(buf)
     }
 
+    /// Reads an information unit from the UAS command pipe. `Unsupported` if UAS isn't offered
+    pub fn read_command_packet(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.command_ep.as_ref().ok_or(UsbError::Unsupported)?.read(buf)
+    }
+
+    /// Writes an information unit to the UAS status pipe. `Unsupported` if UAS isn't offered
+    pub fn write_status_packet(&mut self, buf: &[u8]) -> Result<usize> {
+        self.status_ep.as_ref().ok_or(UsbError::Unsupported)?.write(buf)
+    }
+
+    /// Writes a CBI interrupt data block to the interrupt endpoint. `Unsupported` if there isn't
+    /// one
+    pub fn write_interrupt_packet(&mut self, buf: &[u8]) -> Result<usize> {
+        self.interrupt_ep.as_ref().ok_or(UsbError::Unsupported)?.write(buf)
+    }
+
+    /// The alternate setting selected by the host, 0 until it sends SET_INTERFACE
+    pub fn alt_setting(&self) -> u8 {
+        self.alt_setting
+    }
+
+    /// True from when the host sends SET_INTERFACE, even for the current setting, until
+    /// [clear_alt_setting_selected](#method.clear_alt_setting_selected) is called. Whatever
+    /// the transport was doing has to be abandoned and it starts again from scratch on the
+    /// selected setting
+    ///
+    /// usb-device 0.2 leaves alternate settings to the classes, SET_INTERFACE and
+    /// GET_INTERFACE for this interface are answered here
+    pub fn alt_setting_selected(&self) -> bool {
+        self.alt_setting_selected
+    }
+
+    /// The transport has started again on the selected setting
+    pub fn clear_alt_setting_selected(&mut self) {
+        self.alt_setting_selected = false;
+    }
+
+    /// Selects `alternative` if it's one we offer
+    fn set_alt_setting(&mut self, alternative: u8) -> bool {
+        match alternative {
+            0 => {},
+            UAS_ALTERNATE_SETTING if self.command_ep.is_some() => {},
+            _ => return false,
+        }
+
+        trace_usb_control!("USB_CONTROL> Alternate setting {} -> {}", self.alt_setting, alternative);
+        self.alt_setting = alternative;
+        self.alt_setting_selected = true;
+
+        // Selecting a setting, even the current one, starts its endpoints from scratch
+        self.write_ep.unstall();
+        self.read_ep.unstall();
+        self.in_halted = false;
+        self.out_halted = false;
+        self.halts_latched = false;
+
+        true
+    }
+
+    /// Stalls the bulk IN endpoint. It stays halted until the host clears it with a 
+    /// CLEAR_FEATURE(ENDPOINT_HALT) request, which usb-device answers for us
+    pub fn halt_in(&mut self) {
+        trace_usb_control!("USB_CONTROL> Halting bulk IN endpoint");
+        self.write_ep.stall();
+        self.in_halted = true;
+    }
+
+    /// Stalls the bulk OUT endpoint, see [halt_in](#method.halt_in)
+    pub fn halt_out(&mut self) {
+        trace_usb_control!("USB_CONTROL> Halting bulk OUT endpoint");
+        self.read_ep.stall();
+        self.out_halted = true;
+    }
+
+    /// Stalls both bulk endpoints and keeps them stalled. Requests to clear the halts are
+    /// acknowledged but the endpoints stay stalled until [release_halts](#method.release_halts)
+    /// is called, after which the host has to clear them as normal. 
+    ///
+    /// This is what BOT needs after an invalid CBW, section 6.6.1 
+    /// [USB Bulk Only Transport Spec](https://www.usb.org/document-library/mass-storage-bulk-only-10)
+    pub fn latch_halts(&mut self) {
+        self.halt_in();
+        self.halt_out();
+        self.halts_latched = true;
+    }
+
+    /// Lets the host clear the halts set by [latch_halts](#method.latch_halts)
+    pub fn release_halts(&mut self) {
+        if self.halts_latched {
+            trace_usb_control!("USB_CONTROL> Releasing latched halts");
+        }
+        self.halts_latched = false;
+    }
+
+    /// True between `latch_halts` and `release_halts`
+    pub fn halts_latched(&self) -> bool {
+        self.halts_latched
+    }
+
+    /// True from `halt_in` until the host clears the halt. Don't write anything in the meantime,
+    /// writing to a stalled endpoint un-stalls it on some peripherals
+    pub fn in_halted(&self) -> bool {
+        self.in_halted
+    }
+
+    /// True from `halt_out` until the host clears the halt
+    pub fn out_halted(&self) -> bool {
+        self.out_halted
+    }
+
     pub fn correct_interface_number(&self, interface_number: u16) -> bool {
          interface_number == u8::from(self.msc_if) as u16
     }
@@ -71,23 +279,117 @@ impl<B: UsbBus> UsbClass<B> for MscClass<'_, B> {
 
         writer.endpoint(&self.read_ep)?;
         writer.endpoint(&self.write_ep)?;
+        if let Some(interrupt_ep) = &self.interrupt_ep {
+            writer.endpoint(interrupt_ep)?;
+        }
+
+        if let (Some(command_ep), Some(status_ep)) = (&self.command_ep, &self.status_ep) {
+            writer.interface_alt(
+                self.msc_if,
+                UAS_ALTERNATE_SETTING,
+                USB_CLASS_MSC,
+                self.subclass.to_primitive(),
+                InterfaceProtocol::Uas.to_primitive(),
+                None,
+            )?;
+
+            // Each endpoint is followed by what it's for, section 5.3.3.4 of the UAS spec
+            writer.endpoint(command_ep)?;
+            writer.write(PIPE_USAGE_DESCRIPTOR, &[PipeId::Command as u8, 0])?;
+            writer.endpoint(status_ep)?;
+            writer.write(PIPE_USAGE_DESCRIPTOR, &[PipeId::Status as u8, 0])?;
+            writer.endpoint(&self.write_ep)?;
+            writer.write(PIPE_USAGE_DESCRIPTOR, &[PipeId::DataIn as u8, 0])?;
+            writer.endpoint(&self.read_ep)?;
+            writer.write(PIPE_USAGE_DESCRIPTOR, &[PipeId::DataOut as u8, 0])?;
+        }
 
         Ok(())
     }
 
-    fn reset(&mut self) { }
+    fn reset(&mut self) {
+        // A bus reset clears any stalls and goes back to the default setting
+        self.in_halted = false;
+        self.out_halted = false;
+        self.halts_latched = false;
+        self.alt_setting = 0;
+        self.alt_setting_selected = false;
+    }
 
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
-        if self.correct_interface_number(req.index) {
-           trace_usb_control!("USB_CONTROL> Unhandled control-IN: {:?}", req);
+        if !self.correct_interface_number(req.index) {
+            return;
+        }
+
+        // usb-device only answers GET_INTERFACE if no class does, always with setting 0
+        if let Request { 
+            request_type: RequestType::Standard, 
+            recipient: Recipient::Interface,
+            request: Request::GET_INTERFACE,
+            ..
+        } = req {
+            if let Err(e) = xfer.accept_with(&[self.alt_setting]) {
+                error!("Error from ControlIn.accept_with: {:?}", e);
+            }
+            return;
         }
+
+        trace_usb_control!("USB_CONTROL> Unhandled control-IN: {:?}", req);
     }
 
     fn control_out(&mut self, xfer: ControlOut<B>) {
         let req = xfer.request();
-        if self.correct_interface_number(req.index) {
-            trace_usb_control!("USB_CONTRO> Unhandled control-OUT: {:?}", req);
-        };
+
+        // usb-device clears halts itself, we just need to know it has happened. Unless the halts
+        // are latched, in which case the request is accepted here so usb-device never sees it. 
+        // The low byte of the index is the endpoint address
+        if let Request { 
+            request_type: RequestType::Standard, 
+            recipient: Recipient::Endpoint,
+            request: Request::CLEAR_FEATURE,
+            value: Request::FEATURE_ENDPOINT_HALT,
+            ..
+        } = req {
+            let ep_addr = EndpointAddress::from(req.index as u8 & 0x8F);
+            let ours = ep_addr == self.write_ep.address() || ep_addr == self.read_ep.address();
+            if ours && self.halts_latched {
+                trace_usb_control!("USB_CONTROL> Endpoint halts are latched, not clearing {:?}", ep_addr);
+                if let Err(e) = xfer.accept() {
+                    error!("Error from ControlOut.accept: {:?}", e);
+                }
+            } else if ep_addr == self.write_ep.address() {
+                trace_usb_control!("USB_CONTROL> Bulk IN endpoint halt cleared");
+                self.in_halted = false;
+            } else if ep_addr == self.read_ep.address() {
+                trace_usb_control!("USB_CONTROL> Bulk OUT endpoint halt cleared");
+                self.out_halted = false;
+            }
+            return;
+        }
+
+        if !self.correct_interface_number(req.index) {
+            return;
+        }
+
+        // usb-device only accepts SET_INTERFACE for setting 0 if no class answers it
+        if let Request { 
+            request_type: RequestType::Standard, 
+            recipient: Recipient::Interface,
+            request: Request::SET_INTERFACE,
+            ..
+        } = req {
+            let result = if req.value <= u8::MAX as u16 && self.set_alt_setting(req.value as u8) {
+                xfer.accept()
+            } else {
+                xfer.reject()
+            };
+            if let Err(e) = result {
+                error!("Error from ControlOut: {:?}", e);
+            }
+            return;
+        }
+
+        trace_usb_control!("USB_CONTRO> Unhandled control-OUT: {:?}", req);
     }
 }
\ No newline at end of file
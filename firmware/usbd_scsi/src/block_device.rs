@@ -1,3 +1,10 @@
+use core::ops::Range;
+use crate::{
+    device_health::DeviceHealth,
+    firmware_update::FirmwareUpdate,
+    mode_pages::ModePages,
+};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BlockDeviceError {
     /// Hardware didn't behave as expected, unrecoverable
@@ -14,19 +21,155 @@ pub enum BlockDeviceError {
 
     /// Address is invalid or out of range
     InvalidAddress,
+
+    /// The device is busy, for example erasing flash or waiting on an SPI transfer. Nothing was
+    /// read or written; the same call will be made again later
+    WouldBlock,
+
+    /// The device doesn't support the operation
+    Unsupported,
 }
 
+/// # A block addressed storage device
+///
+/// ## Non-blocking devices
+/// Reads and writes happen in the USB interrupt (or wherever `poll` is called from) so devices
+/// that can't finish a block quickly shouldn't wait for the hardware. Return 
+/// [WouldBlock](enum.BlockDeviceError.html#variant.WouldBlock) instead and the call will be
+/// repeated with the same arguments on a later poll, once the operation is done the call should 
+/// succeed. The host keeps polling for data in the meantime rather than timing out.
 pub trait BlockDevice {
     /// The number of bytes per block. This determines the size of the buffer passed
     /// to read/write functions
     const BLOCK_BYTES: usize;
 
     /// Read the block indicated by `lba` into the provided buffer
-    fn read_block(&self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError>;
+    ///
+    /// May return `WouldBlock`, see above
+    fn read_block(&self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError>;
 
     /// Write the `block` buffer to the block indicated by `lba`
-    fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError>;
+    ///
+    /// May return `WouldBlock`, see above
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError>;
     
     /// Get the maxium valid lba (logical block address)
-    fn max_lba(&self) -> u32;
+    fn max_lba(&self) -> u64;
+
+    /// Compare `data` with the block indicated by `lba`, returning the offset of the first byte
+    /// that differs or None if they match. Used by VERIFY commands with byte checking
+    ///
+    /// May return `WouldBlock`, see above
+    ///
+    /// There's nowhere to read a block into without using another block of RAM so the default
+    /// returns `Unsupported`. Devices that can compare in place (memory mapped flash for 
+    /// example) or that have a spare buffer should implement it
+    fn compare_block(&self, lba: u64, data: &[u8]) -> Result<Option<usize>, BlockDeviceError> {
+        let _ = (lba, data);
+        Err(BlockDeviceError::Unsupported)
+    }
+
+    /// Make sure everything written so far is on the medium
+    ///
+    /// May return `WouldBlock`, see above
+    ///
+    /// Devices that buffer writes, for example to merge several blocks into one flash page,
+    /// should write the buffer out here. Called at the end of every WRITE unless the host has
+    /// enabled the write cache (see [CachingModePage](struct.CachingModePage.html)), and on
+    /// SYNCHRONIZE CACHE, when the medium is stopped or ejected and after a bus reset.
+    ///
+    /// The default does nothing, for devices that write straight through
+    fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// Stop whatever the device is doing for the current command because the host has reset
+    /// the mass storage interface or otherwise abandoned the command. A call that returned
+    /// `WouldBlock` won't be repeated so anything it started, erasing a flash page for example,
+    /// has to be finished or undone here to leave the medium in a sensible state
+    ///
+    /// May return `WouldBlock`, it's called again on the next poll until it returns something
+    /// else. In the meantime no more of the current command is read, written or sent and the
+    /// transport doesn't take any more commands. For a reset the first call comes from inside
+    /// the control request, which is only acknowledged if it succeeds, so don't wait there.
+    /// Returning `WouldBlock` lets the request complete and the later calls come from `poll`
+    ///
+    /// The default does nothing, for devices that finish everything they start
+    fn abort(&mut self) -> Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// Tell the device that the blocks in `lbas` no longer hold anything useful, usually
+    /// because the host deleted the files in them. Used by UNMAP and WRITE SAME with the unmap
+    /// bit
+    ///
+    /// May return `WouldBlock`, see above
+    ///
+    /// Flash devices can erase whole pages in the range so later writes don't have to, blocks
+    /// that only partly cover a page can be left alone. Discarded blocks may read back as
+    /// anything. Only called if [discard_granularity](#method.discard_granularity) returns
+    /// `Some`
+    fn discard(&mut self, lbas: Range<u64>) -> Result<(), BlockDeviceError> {
+        let _ = lbas;
+        Err(BlockDeviceError::Unsupported)
+    }
+
+    /// The number of blocks the device discards at once, for example the blocks in a flash
+    /// erase page, or None if [discard](#method.discard) isn't supported. Returning `Some`
+    /// advertises logical block provisioning to the host
+    ///
+    /// The default is None
+    fn discard_granularity(&self) -> Option<u32> {
+        None
+    }
+
+    /// Erase the whole medium, used by FORMAT UNIT
+    ///
+    /// May return `WouldBlock`, see above. Formats usually take a while so it's fine to do a
+    /// bit each time and return `WouldBlock` until the end, reporting how far it's got with
+    /// [format_progress](#method.format_progress). It's called until it returns something else,
+    /// once the host has asked for a format it carries on in the background even if the host
+    /// goes away.
+    ///
+    /// The default returns `Unsupported` and FORMAT UNIT is rejected
+    fn format(&mut self) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::Unsupported)
+    }
+
+    /// How far through [format](#method.format) the device is, in 65536ths. Reported to the
+    /// host in the sense data while the format runs
+    ///
+    /// The default is None, no progress is reported
+    fn format_progress(&self) -> Option<u16> {
+        None
+    }
+
+    /// Returns true if the device is read only. Writes are rejected and the write protect bit
+    /// is set in MODE SENSE responses
+    ///
+    /// The default is writable
+    fn is_write_protected(&self) -> bool {
+        false
+    }
+
+    /// Mode pages provided by the device, see [ModePages](trait.ModePages.html)
+    ///
+    /// The default provides none
+    fn mode_pages(&mut self) -> Option<&mut dyn ModePages> {
+        None
+    }
+
+    /// Device specific values for the LOG SENSE pages, see [DeviceHealth](struct.DeviceHealth.html)
+    ///
+    /// The default doesn't know anything
+    fn health(&self) -> DeviceHealth {
+        DeviceHealth::default()
+    }
+
+    /// Firmware update support, see [FirmwareUpdate](trait.FirmwareUpdate.html)
+    ///
+    /// The default is None, WRITE BUFFER and READ BUFFER are rejected
+    fn firmware_update(&mut self) -> Option<&mut dyn FirmwareUpdate> {
+        None
+    }
 }
\ No newline at end of file
@@ -0,0 +1,77 @@
+use usbd_bulk_only_transport::{
+    Error as BulkOnlyTransportError,
+    Transport,
+};
+
+/// How far a [CommandHandler](trait.CommandHandler.html) has got with a command
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum CommandProgress {
+    /// The command passed
+    Done,
+    /// Call again on the next poll
+    Ongoing,
+}
+
+/// Reasons a [CommandHandler](trait.CommandHandler.html) can fail a command. Each is reported to
+/// the host as the sense data noted
+#[derive(Debug)]
+pub enum CommandHandlerError {
+    /// ILLEGAL REQUEST, INVALID COMMAND OPERATION CODE. The handler doesn't know the command
+    UnsupportedOpCode,
+    /// ILLEGAL REQUEST, INVALID FIELD IN CDB
+    InvalidFieldInCommand,
+    /// ILLEGAL REQUEST, INVALID FIELD IN PARAMETER LIST
+    InvalidFieldInParameterList,
+    /// HARDWARE ERROR
+    HardwareError,
+    /// Error from the transport. `WouldBlock` isn't a failure, the handler is called again on
+    /// the next poll
+    BulkOnlyTransportError(BulkOnlyTransportError),
+}
+
+impl From<BulkOnlyTransportError> for CommandHandlerError {
+    fn from(e: BulkOnlyTransportError) -> CommandHandlerError {
+        CommandHandlerError::BulkOnlyTransportError(e)
+    }
+}
+
+/// # Handler for commands `Scsi` doesn't implement
+///
+/// Passed to [Scsi::with_command_handler](struct.Scsi.html#method.with_command_handler) to add
+/// vendor specific commands (op codes 0xC0 to 0xFF are set aside for them) for calibration,
+/// logging and so on. Host tools can send them through SG_IO or similar without needing another
+/// USB interface. `()` is the handler that doesn't know any commands.
+///
+/// ## Data
+/// Data from the host is taken with `transport.take_buffered_data` and data for the host is
+/// queued with `transport.take_buffer_space`. Both return `WouldBlock` until there's enough data
+/// or space, returning that error from `process_command` (with `?`) means it's called again on
+/// the next poll. Anything taken before then is gone so take what's needed in one go, or keep
+/// track of what's been done and return `Ongoing`. The command status is sent once `Done` or an
+/// error is returned, don't send it on the transport directly. A Bulk-Only Mass Storage Reset 
+/// or UAS task management abandons the command without telling the handler, the next call has
+/// `new_command` set.
+///
+/// `Scsi` can't tell how much data a custom command needs so the CBW isn't checked. Handlers
+/// that care should pass the [DataStage](enum.DataStage.html) to `transport.check_data_stage`
+/// when `new_command` is true and return any error (with `?`), a mismatch fails the command
+/// with a phase error. Under UAS the data stage is whatever is passed, commands with data have
+/// to call it or none is moved.
+pub trait CommandHandler {
+    /// Process `cdb`, which was addressed to `lun`. `new_command` is only true on the first call
+    /// for each command
+    ///
+    /// The default returns `UnsupportedOpCode`
+    fn process_command<T: Transport>(
+        &mut self,
+        lun: u8,
+        cdb: &[u8],
+        new_command: bool,
+        transport: &mut T,
+    ) -> Result<CommandProgress, CommandHandlerError> {
+        let _ = (lun, cdb, new_command, transport);
+        Err(CommandHandlerError::UnsupportedOpCode)
+    }
+}
+
+impl CommandHandler for () {}
@@ -0,0 +1,41 @@
+/// # Device specific health values
+///
+/// Returned by [BlockDevice::health](trait.BlockDevice.html#method.health) and reported alongside
+/// the [ErrorCounters](struct.ErrorCounters.html) kept by `Scsi` in the LOG SENSE pages that
+/// `smartctl` and `sg_logs` read. Anything the device doesn't know about can be left at the
+/// default.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct DeviceHealth {
+    /// Current temperature in degrees Celsius, reported in the temperature and informational
+    /// exceptions pages
+    pub temperature: Option<u8>,
+    /// Maximum temperature the device is rated to run at, in degrees Celsius
+    pub reference_temperature: Option<u8>,
+    /// Errors the device recovered from by itself, e.g. flash bit errors fixed by ECC
+    pub read_errors_corrected: u32,
+    pub write_errors_corrected: u32,
+    pub verify_errors_corrected: u32,
+    /// The device expects to fail soon, for example flash that's nearly worn out. Reported in
+    /// the informational exceptions page as FAILURE PREDICTION THRESHOLD EXCEEDED
+    pub failure_predicted: bool,
+}
+
+/// Bytes moved and errors reported to the host by one kind of command
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct ErrorCounter {
+    /// Bytes successfully read, written or verified
+    pub bytes_processed: u64,
+    /// Commands that failed with a MEDIUM ERROR or HARDWARE ERROR
+    pub uncorrected_errors: u32,
+}
+
+/// # Error counters for a logical unit
+///
+/// Kept by `Scsi` for each [LogicalUnit](struct.LogicalUnit.html) since power on and reported
+/// in the read, write and verify error counter log pages. WRITE SAME counts as a write.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct ErrorCounters {
+    pub read: ErrorCounter,
+    pub write: ErrorCounter,
+    pub verify: ErrorCounter,
+}
@@ -0,0 +1,53 @@
+/// # Notifications of what the host is doing
+///
+/// Passed to [Scsi::with_event_sink](struct.Scsi.html#method.with_event_sink) to drive activity
+/// LEDs, find out when the host has safely removed the drive and so on. Every method has an empty
+/// default so only the interesting ones need implementing. `()` is the sink that ignores
+/// everything.
+///
+/// The methods are called from `poll` so should return quickly.
+pub trait EventSink {
+    /// The host sent command `op_code` to `lun`
+    fn command_started(&mut self, lun: u8, op_code: u8) {
+        let _ = (lun, op_code);
+    }
+
+    /// Command `op_code` to `lun` finished, `passed` is false if it failed and the host will
+    /// look at the sense data
+    fn command_finished(&mut self, lun: u8, op_code: u8, passed: bool) {
+        let _ = (lun, op_code, passed);
+    }
+
+    /// `blocks` blocks from `lba` have been read. Long reads are reported a few blocks at a
+    /// time as they happen
+    fn blocks_read(&mut self, lun: u8, lba: u64, blocks: u32) {
+        let _ = (lun, lba, blocks);
+    }
+
+    /// `blocks` blocks from `lba` have been written. Long writes are reported a few blocks at a
+    /// time as they happen
+    fn blocks_written(&mut self, lun: u8, lba: u64, blocks: u32) {
+        let _ = (lun, lba, blocks);
+    }
+
+    /// The host ejected the medium, after writing out anything cached. This is what safely
+    /// removing the drive does
+    fn medium_ejected(&mut self, lun: u8) {
+        let _ = lun;
+    }
+
+    /// The host prevented or allowed removal of the medium, usually when it mounts and unmounts
+    /// the filesystem
+    fn medium_removal_prevented(&mut self, lun: u8, prevented: bool) {
+        let _ = (lun, prevented);
+    }
+
+    /// USB bus reset. Commands in progress are abandoned and every LUN reports a unit attention
+    fn bus_reset(&mut self) {}
+
+    /// Bulk-Only Mass Storage Reset. The command in progress is abandoned but unlike a bus reset
+    /// the LUNs don't notice
+    fn mass_storage_reset(&mut self) {}
+}
+
+impl EventSink for () {}
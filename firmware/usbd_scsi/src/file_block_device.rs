@@ -0,0 +1,150 @@
+use std::{
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::{
+        self,
+        Read,
+        Seek,
+        SeekFrom,
+        Write,
+    },
+    path::Path,
+};
+use crate::block_device::{
+    BlockDevice,
+    BlockDeviceError,
+};
+
+const BLOCK_BYTES: usize = 512;
+
+/// # A block device backed by a disk image file
+///
+/// Only available with the `std` feature. Intended for host side tests and simulators; each
+/// block is read from/written straight to the file at `lba * 512`.
+pub struct FileBlockDevice {
+    file: File,
+    blocks: u64,
+}
+
+impl FileBlockDevice {
+    /// Opens an existing image for reading and writing
+    ///
+    /// The file must be a non-zero multiple of 512 bytes long
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)?;
+        Self::from_file(file)
+    }
+
+    /// Creates (or truncates) an image `blocks` blocks long filled with zeros
+    pub fn create<P: AsRef<Path>>(path: P, blocks: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(blocks * BLOCK_BYTES as u64)?;
+        Self::from_file(file)
+    }
+
+    /// Uses an already open file as the image
+    ///
+    /// The file must be a non-zero multiple of 512 bytes long
+    pub fn from_file(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        if len == 0 || len % BLOCK_BYTES as u64 != 0 {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "Image length must be a non-zero multiple of 512 bytes"))?;
+        }
+
+        Ok(Self {
+            file,
+            blocks: len / BLOCK_BYTES as u64,
+        })
+    }
+
+    /// Gives back the underlying file
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    fn seek_to(&self, lba: u64) -> Result<(), BlockDeviceError> {
+        if lba >= self.blocks {
+            Err(BlockDeviceError::InvalidAddress)?;
+        }
+        (&self.file).seek(SeekFrom::Start(lba * BLOCK_BYTES as u64))
+            .map_err(|_| BlockDeviceError::HardwareError)?;
+        Ok(())
+    }
+}
+
+impl BlockDevice for FileBlockDevice {
+    const BLOCK_BYTES: usize = BLOCK_BYTES;
+
+    fn read_block(&self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.seek_to(lba)?;
+        (&self.file).read_exact(block)
+            .map_err(|_| BlockDeviceError::HardwareError)
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.seek_to(lba)?;
+        self.file.write_all(block)
+            .map_err(|_| BlockDeviceError::WriteError)
+    }
+
+    fn max_lba(&self) -> u64 {
+        self.blocks - 1
+    }
+
+    fn compare_block(&self, lba: u64, data: &[u8]) -> Result<Option<usize>, BlockDeviceError> {
+        let mut block = [0; BLOCK_BYTES];
+        self.read_block(lba, &mut block)?;
+        Ok(block.iter().zip(data).position(|(a, b)| a != b))
+    }
+
+    fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        self.file.sync_data()
+            .map_err(|_| BlockDeviceError::WriteError)
+    }
+
+    /// Truncates the image and extends it again, leaving it full of zeros
+    fn format(&mut self) -> Result<(), BlockDeviceError> {
+        self.file.set_len(0)
+            .and_then(|_| self.file.set_len(self.blocks * BLOCK_BYTES as u64))
+            .map_err(|_| BlockDeviceError::EraseError)
+    }
+}
+
+#[test]
+fn test_file_block_device() {
+    let path = std::env::temp_dir().join(format!("usbd_scsi_test_file_block_device_{}.img", std::process::id()));
+
+    let mut fbd = FileBlockDevice::create(&path, 4).unwrap();
+    assert_eq!(fbd.max_lba(), 3);
+
+    let mut block = [0xCD; BLOCK_BYTES];
+    fbd.write_block(3, &block).unwrap();
+    drop(fbd);
+
+    let fbd = FileBlockDevice::open(&path).unwrap();
+    fbd.read_block(3, &mut block).unwrap();
+    assert_eq!(block[..], [0xCD; BLOCK_BYTES][..]);
+    fbd.read_block(0, &mut block).unwrap();
+    assert_eq!(block[..], [0; BLOCK_BYTES][..]);
+    assert_eq!(fbd.read_block(4, &mut block), Err(BlockDeviceError::InvalidAddress));
+
+    let bytes = std::fs::read(&path).unwrap();
+    assert_eq!(bytes.len(), 4 * BLOCK_BYTES);
+    assert!(bytes[3 * BLOCK_BYTES..].iter().all(|&b| b == 0xCD));
+
+    let mut fbd = FileBlockDevice::open(&path).unwrap();
+    fbd.format().unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), [0; 4 * BLOCK_BYTES]);
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,56 @@
+use crate::block_device::BlockDeviceError;
+
+/// # Firmware updates over WRITE BUFFER
+///
+/// Lets a [BlockDevice](trait.BlockDevice.html) accept new firmware from WRITE BUFFER's
+/// download microcode modes, for example from `sg_write_buffer` on Linux. Returned by
+/// [BlockDevice::firmware_update](trait.BlockDevice.html#method.firmware_update).
+///
+/// The image is written in segments at increasing offsets, either all in one command (mode 5)
+/// or split over several (modes 6 and 7). READ BUFFER reads it back and reports the capacity
+/// and offset boundary so the host can split the image up.
+///
+/// Every method that touches the image may return
+/// [WouldBlock](enum.BlockDeviceError.html#variant.WouldBlock), the same call is made again on a
+/// later poll.
+pub trait FirmwareUpdate {
+    /// The largest image accepted in bytes, at most 0xFFFFFF
+    fn capacity(&self) -> u32;
+
+    /// Segments have to start on a multiple of 2 to the power of this many bytes, e.g. a flash
+    /// page
+    ///
+    /// The default is 0, any offset
+    fn offset_boundary(&self) -> u8 {
+        0
+    }
+
+    /// Write `data` to the image at `offset`. It's already been checked to fit in the capacity
+    fn write_firmware(&mut self, offset: u32, data: &[u8]) -> Result<(), BlockDeviceError>;
+
+    /// Read the image at `offset` into `data`, for READ BUFFER
+    fn read_firmware(&self, offset: u32, data: &mut [u8]) -> Result<(), BlockDeviceError>;
+
+    /// Make sure everything written so far survives a power cycle, called at the end of each
+    /// download that saves (modes 5 and 7)
+    ///
+    /// The default does nothing, for devices that write straight through
+    fn save_firmware(&mut self) -> Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// Returns true if the image is complete after a segment ending at `end` was written. Only
+    /// asked about downloads with offsets as there's no telling which segment is the last one,
+    /// devices might check the length in an image header for example
+    ///
+    /// The default is false, the new firmware isn't activated until something else restarts
+    /// the device
+    fn is_download_complete(&self, end: u32) -> bool {
+        let _ = end;
+        false
+    }
+
+    /// Start running the new firmware. Called before the command status is sent so anything
+    /// that resets the device should wait a little, otherwise the host sees the command fail
+    fn activate_firmware(&mut self);
+}
@@ -1,11 +1,56 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 mod scsi;
 pub use scsi::*;
 
+/// Transport buffer size `Scsi` uses unless told otherwise
+pub use usbd_bulk_only_transport::DEFAULT_BUFFER_BYTES;
+
 mod block_device;
 pub use block_device::*;
 
+mod mode_pages;
+pub use mode_pages::*;
+
+mod firmware_update;
+pub use firmware_update::*;
+
+mod device_health;
+pub use device_health::*;
+
+mod event_sink;
+pub use event_sink::*;
+
+mod command_handler;
+pub use command_handler::*;
+
+/// Passed to [CommandHandler](trait.CommandHandler.html)s for their data
+pub use usbd_bulk_only_transport::{
+    AbortReason,
+    BulkOnlyTransport,
+    CommandBlockWrapper,
+    DataStage,
+    Error as BulkOnlyTransportError,
+    TransferState,
+};
+
+/// What `Scsi` needs from a transport, for running it on something other than BOT
+pub use usbd_bulk_only_transport::{
+    Transport,
+    TransportClass,
+};
+
+mod ram_block_device;
+pub use ram_block_device::*;
+
+#[cfg(feature = "std")]
+mod file_block_device;
+#[cfg(feature = "std")]
+pub use file_block_device::*;
+
+mod logical_unit;
+pub use logical_unit::*;
+
 mod logging {
     pub use itm_logger::*;
 
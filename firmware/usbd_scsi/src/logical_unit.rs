@@ -0,0 +1,221 @@
+use crate::{
+    block_device::BlockDevice,
+    device_health::ErrorCounters,
+    scsi::{
+        InquiryResponse,
+        SenseData,
+        SerialNumber,
+        UnitAttention,
+        UnitAttentionQueue,
+    },
+};
+
+/// Whether a logical unit's medium is there as far as the host is concerned
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum MediumState {
+    Present,
+    /// The host ejected it with START STOP UNIT. It can load it again
+    Ejected,
+    /// The application removed it, see
+    /// [remove_medium](struct.LogicalUnit.html#method.remove_medium)
+    Removed,
+}
+
+/// # A single SCSI logical unit
+///
+/// Pairs a [BlockDevice](trait.BlockDevice.html) with the state SCSI keeps for each LUN (inquiry
+/// data, serial number, sense data, medium state and the write cache setting). A
+/// [Scsi](struct.Scsi.html) instance exposes one or more of these, see
+/// [LogicalUnits](trait.LogicalUnits.html).
+///
+/// ## Removable media
+/// Commands that access the medium fail with NOT READY, MEDIUM NOT PRESENT unless it's present.
+/// The host can eject it and load it again with START STOP UNIT, unless it has prevented that
+/// with PREVENT ALLOW MEDIUM REMOVAL. The application can remove it and insert it at any time
+/// (an SD card detect pin, or detaching the drive while firmware is applied for example), a
+/// UNIT ATTENTION tells the host the medium may have changed when it's inserted.
+///
+/// ## Formatting
+/// FORMAT UNIT calls [BlockDevice::format](trait.BlockDevice.html#method.format) until it's
+/// done. If the host sets the IMMED bit the command completes straight away and the format
+/// carries on in the background, commands that access the medium fail with NOT READY, FORMAT
+/// IN PROGRESS in the meantime and REQUEST SENSE reports the progress. If the format fails
+/// they fail with MEDIUM FORMAT CORRUPTED until the host formats it again.
+///
+/// ## Log pages
+/// LOG SENSE reports the [ErrorCounters](struct.ErrorCounters.html) kept for each logical unit
+/// along with the block device's [health](trait.BlockDevice.html#method.health). Nothing is
+/// saved, the counters start from zero at power on.
+pub struct LogicalUnit<BD: BlockDevice> {
+    pub(crate) block_device: BD,
+    pub(crate) inquiry_response: InquiryResponse,
+    pub(crate) serial_number: SerialNumber,
+    pub(crate) sense: SenseData,
+    pub(crate) write_cache_enabled: bool,
+    pub(crate) medium_state: MediumState,
+    pub(crate) prevent_removal: bool,
+    pub(crate) unit_attentions: UnitAttentionQueue,
+    /// A FORMAT UNIT is running, see `Scsi::update`
+    pub(crate) formatting: bool,
+    /// The last format failed
+    pub(crate) format_corrupted: bool,
+    pub(crate) error_counters: ErrorCounters,
+}
+
+impl<BD: BlockDevice> LogicalUnit<BD> {
+    /// Creates a new logical unit
+    ///
+    /// `block_device` provides reading and writing of blocks to the underlying filesystem
+    ///
+    /// `vendor_identification` is an ASCII string that forms part of the SCSI inquiry response.
+    ///      Should come from [t10](https://www.t10.org/lists/2vid.htm). Any semi-unique non-blank
+    ///      string should work fine for local development. Panics if > 8 characters are supplied.
+    ///
+    /// `product_identification` is an ASCII string that forms part of the SCSI inquiry response.
+    ///      Vendor (probably you...) defined so pick whatever you want. Panics if > 16 characters
+    ///      are supplied.
+    ///
+    /// `product_revision_level` is an ASCII string that forms part of the SCSI inquiry response.
+    ///      Vendor (probably you...) defined so pick whatever you want. Typically a version number.
+    ///      Panics if > 4 characters are supplied.
+    pub fn new<V: AsRef<[u8]>, P: AsRef<[u8]>, R: AsRef<[u8]>> (
+        block_device: BD,
+        vendor_identification: V,
+        product_identification: P,
+        product_revision_level: R,
+    ) -> Self {
+        let mut inquiry_response = InquiryResponse::default();
+        inquiry_response.set_vendor_identification(vendor_identification);
+        inquiry_response.set_product_identification(product_identification);
+        inquiry_response.set_product_revision_level(product_revision_level);
+
+        let mut unit_attentions = UnitAttentionQueue::default();
+        unit_attentions.push(UnitAttention::PowerOnOrReset);
+
+        Self {
+            block_device,
+            inquiry_response,
+            serial_number: Default::default(),
+            sense: Default::default(),
+            write_cache_enabled: false,
+            medium_state: MediumState::Present,
+            prevent_removal: false,
+            unit_attentions,
+            formatting: false,
+            format_corrupted: false,
+            error_counters: Default::default(),
+        }
+    }
+
+    /// Sets the serial number reported in the unit serial number and device identification VPD
+    /// pages. Typically derived from a unique ID in the MCU. Panics if > 32 characters are supplied.
+    ///
+    /// Without a serial number the unit serial number page isn't advertised.
+    pub fn set_serial_number<S: AsRef<[u8]>>(&mut self, serial_number: S) {
+        self.serial_number = SerialNumber::new(serial_number);
+    }
+
+    /// Grants access to the block device for the purposes of housekeeping etc.
+    pub fn block_device_mut(&mut self) -> &mut BD {
+        &mut self.block_device
+    }
+
+    /// Whether the medium is present, ejected by the host or removed by the application
+    pub fn medium_state(&self) -> MediumState {
+        self.medium_state
+    }
+
+    /// Returns true if the host has prevented the medium being removed, e.g. while it's mounted
+    pub fn is_medium_removal_prevented(&self) -> bool {
+        self.prevent_removal
+    }
+
+    /// Returns true while a FORMAT UNIT sent by the host is running
+    pub fn is_formatting(&self) -> bool {
+        self.formatting
+    }
+
+    /// Bytes moved and errors reported since power on, see
+    /// [ErrorCounters](struct.ErrorCounters.html)
+    pub fn error_counters(&self) -> ErrorCounters {
+        self.error_counters
+    }
+
+    /// Makes the medium present, for example when an SD card is inserted
+    ///
+    /// If it wasn't already present the host is told the medium may have changed.
+    pub fn insert_medium(&mut self) {
+        if self.medium_state != MediumState::Present {
+            self.medium_state = MediumState::Present;
+            self.unit_attentions.push(UnitAttention::MediumChanged);
+        }
+    }
+
+    /// Makes the medium not present until `insert_medium` is called, for example when an SD
+    /// card is removed
+    ///
+    /// This happens even if the host has prevented removal. Anything the block device has
+    /// cached isn't flushed, flush it first if the medium is still there.
+    pub fn remove_medium(&mut self) {
+        self.medium_state = MediumState::Removed;
+    }
+}
+
+/// Something that can operate on a [LogicalUnit](struct.LogicalUnit.html) regardless of the type
+/// of block device behind it
+///
+/// Used by [Scsi](struct.Scsi.html) to route commands to the LUN addressed by the CBW.
+pub trait LogicalUnitVisitor {
+    type Output;
+    fn visit<BD: BlockDevice>(self, logical_unit: &mut LogicalUnit<BD>) -> Self::Output;
+}
+
+/// # A set of logical units addressed by LUN
+///
+/// Implemented for a single [LogicalUnit](struct.LogicalUnit.html) (LUN 0 only) and for tuples of
+/// up to 8 logical units, where the tuple index is the LUN. Each element can have a different
+/// [BlockDevice](trait.BlockDevice.html) type.
+pub trait LogicalUnits {
+    /// Number of logical units. Must be between 1 and 16 (the most Bulk Only Transport supports)
+    const COUNT: u8;
+
+    /// Calls `visitor` with the logical unit for `lun`
+    ///
+    /// Returns `None` if there is no such logical unit
+    fn visit<V: LogicalUnitVisitor>(&mut self, lun: u8, visitor: V) -> Option<V::Output>;
+}
+
+impl<BD: BlockDevice> LogicalUnits for LogicalUnit<BD> {
+    const COUNT: u8 = 1;
+
+    fn visit<V: LogicalUnitVisitor>(&mut self, lun: u8, visitor: V) -> Option<V::Output> {
+        match lun {
+            0 => Some(visitor.visit(self)),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! impl_logical_units_for_tuple {
+    ($count: expr; $($lun: tt => $bd: ident),+) => (
+        impl<$($bd: BlockDevice),+> LogicalUnits for ($(LogicalUnit<$bd>,)+) {
+            const COUNT: u8 = $count;
+
+            fn visit<V: LogicalUnitVisitor>(&mut self, lun: u8, visitor: V) -> Option<V::Output> {
+                match lun {
+                    $($lun => Some(visitor.visit(&mut self.$lun)),)+
+                    _ => None,
+                }
+            }
+        }
+    )
+}
+
+impl_logical_units_for_tuple!(1; 0 => A);
+impl_logical_units_for_tuple!(2; 0 => A, 1 => B);
+impl_logical_units_for_tuple!(3; 0 => A, 1 => B, 2 => C);
+impl_logical_units_for_tuple!(4; 0 => A, 1 => B, 2 => C, 3 => D);
+impl_logical_units_for_tuple!(5; 0 => A, 1 => B, 2 => C, 3 => D, 4 => E);
+impl_logical_units_for_tuple!(6; 0 => A, 1 => B, 2 => C, 3 => D, 4 => E, 5 => F);
+impl_logical_units_for_tuple!(7; 0 => A, 1 => B, 2 => C, 3 => D, 4 => E, 5 => F, 6 => G);
+impl_logical_units_for_tuple!(8; 0 => A, 1 => B, 2 => C, 3 => D, 4 => E, 5 => F, 6 => G, 7 => H);
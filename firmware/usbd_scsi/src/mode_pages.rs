@@ -0,0 +1,58 @@
+use crate::scsi::PageControl;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModePageError {
+    /// Saved values were requested or the host asked for the page to be saved but the page
+    /// can't be saved
+    SavingParametersNotSupported,
+
+    /// A field in a page sent by MODE SELECT has a value that isn't supported
+    InvalidField,
+
+    /// The page doesn't fit in the buffer passed to `sense_page`
+    InsufficientSpace,
+}
+
+/// # Mode pages provided by a block device
+///
+/// Lets a [BlockDevice](trait.BlockDevice.html) report its own mode pages (caching, read-write
+/// error recovery, informational exceptions etc.) in response to MODE SENSE and accept changes
+/// to them from MODE SELECT. Returned by
+/// [BlockDevice::mode_pages](trait.BlockDevice.html#method.mode_pages).
+///
+/// Pages are passed around as raw bytes including the 2 byte page header (page code and page
+/// length) so any page can be provided. Structs for the common pages, e.g.
+/// [CachingModePage](struct.CachingModePage.html), can be packed into the buffer with
+/// `packing::Packed::pack`. Subpages aren't supported.
+///
+/// If the device doesn't provide a caching mode page, one with both caches disabled is reported
+/// on its behalf.
+pub trait ModePages {
+    /// Returns true if the device provides page `page_code`
+    fn has_page(&self, page_code: u8) -> bool;
+
+    /// Pack page `page_code` into `buf`, returning the length of the page including the header
+    ///
+    /// For `PageControl::ChangeableValues` the page is a mask with a 1 in each bit the host can
+    /// change with MODE SELECT. For `PageControl::SavedValues` return
+    /// `ModePageError::SavingParametersNotSupported` if the page can't be saved.
+    ///
+    /// `buf` always has room for the largest possible page, return
+    /// `ModePageError::InsufficientSpace` if it's somehow too short. The whole MODE SENSE
+    /// response has to fit in 256 bytes, when all pages are requested any that don't fit are
+    /// left out along with the pages after them.
+    fn sense_page(
+        &self,
+        page_code: u8,
+        page_control: PageControl,
+        buf: &mut [u8],
+    ) -> Result<usize, ModePageError>;
+
+    /// Apply `page`, as sent by the host with MODE SELECT, to the current values
+    ///
+    /// Before this is called the page length is checked against the current values and any
+    /// change to a bit not set in the changeable values mask is rejected. If `save` is set the
+    /// new values should also be saved, return `ModePageError::SavingParametersNotSupported`
+    /// if that isn't possible.
+    fn select_page(&mut self, page: &[u8], save: bool) -> Result<(), ModePageError>;
+}
@@ -0,0 +1,100 @@
+use crate::block_device::{
+    BlockDevice,
+    BlockDeviceError,
+};
+
+const BLOCK_BYTES: usize = 512;
+
+/// # A RAM backed block device
+///
+/// `N` 512 byte blocks held in memory. Useful as a scratch disk or for testing. The contents
+/// are lost on reset and start out zeroed; the host will need to format it before use.
+///
+/// Needs `N * 512` bytes of RAM so it's typically placed in a `static` rather than on the stack.
+/// `new` is a `const fn` to allow that.
+pub struct RamBlockDevice<const N: usize> {
+    blocks: [[u8; BLOCK_BYTES]; N],
+}
+
+impl<const N: usize> RamBlockDevice<N> {
+    pub const fn new() -> Self {
+        assert!(N > 0);
+        Self {
+            blocks: [[0; BLOCK_BYTES]; N],
+        }
+    }
+
+    /// The contents of the device, one entry per block
+    pub fn blocks(&self) -> &[[u8; BLOCK_BYTES]; N] {
+        &self.blocks
+    }
+
+    /// Mutable access to the contents of the device, for example to pre-populate a filesystem
+    pub fn blocks_mut(&mut self) -> &mut [[u8; BLOCK_BYTES]; N] {
+        &mut self.blocks
+    }
+
+    fn block(&self, lba: u64) -> Result<&[u8; BLOCK_BYTES], BlockDeviceError> {
+        self.blocks.get(lba as usize).ok_or(BlockDeviceError::InvalidAddress)
+    }
+}
+
+impl<const N: usize> Default for RamBlockDevice<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> BlockDevice for RamBlockDevice<N> {
+    const BLOCK_BYTES: usize = BLOCK_BYTES;
+
+    fn read_block(&self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        block.copy_from_slice(self.block(lba)?);
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.blocks.get_mut(lba as usize)
+            .ok_or(BlockDeviceError::InvalidAddress)?
+            .copy_from_slice(block);
+        Ok(())
+    }
+
+    fn max_lba(&self) -> u64 {
+        N as u64 - 1
+    }
+
+    fn compare_block(&self, lba: u64, data: &[u8]) -> Result<Option<usize>, BlockDeviceError> {
+        Ok(self.block(lba)?.iter().zip(data).position(|(a, b)| a != b))
+    }
+
+    /// Zeros every block
+    fn format(&mut self) -> Result<(), BlockDeviceError> {
+        self.blocks = [[0; BLOCK_BYTES]; N];
+        Ok(())
+    }
+}
+
+#[test]
+fn test_ram_block_device() {
+    let mut rbd = RamBlockDevice::<4>::new();
+    assert_eq!(rbd.max_lba(), 3);
+
+    let mut block = [0xAB; BLOCK_BYTES];
+    rbd.write_block(2, &block).unwrap();
+    assert_eq!(rbd.blocks()[2], [0xAB; BLOCK_BYTES]);
+    assert_eq!(rbd.blocks()[1], [0; BLOCK_BYTES]);
+
+    rbd.read_block(1, &mut block).unwrap();
+    assert_eq!(block, [0; BLOCK_BYTES]);
+
+    block[100] = 1;
+    assert_eq!(rbd.compare_block(1, &block), Ok(Some(100)));
+    assert_eq!(rbd.compare_block(2, &[0xAB; BLOCK_BYTES]), Ok(None));
+
+    assert_eq!(rbd.read_block(4, &mut block), Err(BlockDeviceError::InvalidAddress));
+    assert_eq!(rbd.write_block(4, &block), Err(BlockDeviceError::InvalidAddress));
+
+    rbd.format().unwrap();
+    assert_eq!(rbd.blocks()[2], [0; BLOCK_BYTES]);
+}
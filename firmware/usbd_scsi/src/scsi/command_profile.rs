@@ -0,0 +1,30 @@
+use usbd_mass_storage::InterfaceSubclass;
+
+/// # The command set `Scsi` answers with
+///
+/// UFI is a cut down SCSI used by USB floppy drives. Some hosts, older BIOSes in particular,
+/// will only boot from a UFI device. The profile decides which commands are recognised and how
+/// a few of them are answered, the logical units and block devices are the same either way.
+///
+/// [Glossary](index.html#glossary)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum CommandProfile {
+    /// The SCSI transparent command set, SPC and SBC as used by USB mass storage
+    #[default]
+    ScsiTransparent,
+    /// The UFI command set. Anything that isn't a UFI command goes to the command handler.
+    /// INQUIRY, REQUEST SENSE and MODE SENSE are answered the way UFI describes them, with the
+    /// flexible disk page describing a floppy of the same capacity, and FORMAT UNIT can be sent
+    /// a track at a time
+    Ufi,
+}
+
+impl CommandProfile {
+    /// The interface subclass that tells the host which command set it is
+    pub fn subclass(self) -> InterfaceSubclass {
+        match self {
+            CommandProfile::ScsiTransparent => InterfaceSubclass::ScsiTransparentCommandSet,
+            CommandProfile::Ufi => InterfaceSubclass::Ufi,
+        }
+    }
+}
@@ -1,10 +1,18 @@
-use packing::Packed;
+use packing::{
+    Packed,
+    PackedSize,
+};
 
-use usbd_bulk_only_transport::CommandBlockWrapper as CommandBlockWrapper_NEW;
+use usbd_bulk_only_transport::{
+    CommandBlockWrapper as CommandBlockWrapper_NEW,
+    DataStage,
+};
 use crate::scsi::{
     commands::*,
     enums::*,
+    responses::ReadCapacity10Response,
     Error,
+    CommandProfile,
     packing::ParsePackedStruct,
 };
 
@@ -15,6 +23,7 @@ pub enum Command {
     Inquiry(InquiryCommand),
     TestUnitReady(TestUnitReadyCommand),
     ReadCapacity(ReadCapacity10Command),
+    ReadCapacity16(ReadCapacity16Command),
     ModeSense(ModeSenseXCommand),
     PreventAllowMediumRemoval(PreventAllowMediumRemovalCommand),
     RequestSense(RequestSenseCommand),
@@ -26,18 +35,75 @@ pub enum Command {
     ModeSelect(ModeSelectXCommand),
     StartStopUnit(StartStopUnitCommand),
     ReadFormatCapacities(ReadFormatCapacitiesCommand),
-    Verify(Verify10Command),
-    SynchronizeCache(SynchronizeCache10Command),
+    Verify(VerifyXCommand),
+    SynchronizeCache(SynchronizeCacheXCommand),
+    WriteSame(WriteSameXCommand),
+    Unmap(UnmapCommand),
+    WriteBuffer(WriteBufferCommand),
+    ReadBuffer(ReadBufferCommand),
+    LogSense(LogSenseCommand),
+    Seek(SeekXCommand),
+    /// Anything else, for the command handler
+    Custom(CustomCommand),
 }
 
 impl Command {
-    pub fn extract_from_cbw(cbw: &CommandBlockWrapper_NEW) -> Result<Command, Error> {
+    /// Parses the command in `cbw`. Anything that isn't recognised, or isn't part of 
+    /// `profile`, is a `Custom` command
+    pub fn extract_from_cbw(cbw: &CommandBlockWrapper_NEW, profile: CommandProfile) -> Result<Command, Error> {
+        let result = match profile {
+            CommandProfile::ScsiTransparent => Self::extract_standard_from_cbw(cbw),
+            CommandProfile::Ufi => Self::extract_ufi_from_cbw(cbw),
+        };
+        match result {
+            Err(Error::UnhandledOpCode) => Ok(Command::Custom(CustomCommand::new(cbw.data, cbw.data_length))),
+            r => r,
+        }
+    }
+
+    /// The UFI command set. Other than REZERO UNIT and SEEK(10) the commands are
+    /// parsed the same as their SCSI equivalents
+    fn extract_ufi_from_cbw(cbw: &CommandBlockWrapper_NEW) -> Result<Command, Error> {
+        let op_code = OpCode::from_primitive(cbw.data[0]).map_err(|_| Error::UnhandledOpCode)?;
+        match op_code {
+            OpCode::RezeroUnit => Ok(Command::Seek(checked_extract::<RezeroUnitCommand>(cbw)?.into())),
+            OpCode::Seek10 => Ok(Command::Seek(checked_extract::<Seek10Command>(cbw)?.into())),
+            OpCode::TestUnitReady |
+            OpCode::RequestSense |
+            OpCode::Format |
+            OpCode::Inquiry |
+            OpCode::StartStopUnit |
+            OpCode::SendDiagnostic |
+            OpCode::PreventAllowMediumRemoval |
+            OpCode::ReadFormatCapacities |
+            OpCode::ReadCapacity10 |
+            OpCode::Read10 |
+            OpCode::Read12 |
+            OpCode::Write10 |
+            OpCode::Write12 |
+            OpCode::WriteAndVerify10 |
+            OpCode::Verify10 |
+            OpCode::ModeSelect10 |
+            OpCode::ModeSense10 => Self::extract_standard_from_cbw(cbw),
+            _ => Err(Error::UnhandledOpCode),
+        }
+    }
+
+    fn extract_standard_from_cbw(cbw: &CommandBlockWrapper_NEW) -> Result<Command, Error> {
         let op_code = OpCode::from_primitive(cbw.data[0]).map_err(|_| Error::UnhandledOpCode)?;
         match op_code {
             OpCode::Read6 => Ok(Command::Read(checked_extract::<Read6Command>(cbw)?.into())),
             OpCode::Read10 => Ok(Command::Read(checked_extract::<Read10Command>(cbw)?.into())),
             OpCode::Read12 => Ok(Command::Read(checked_extract::<Read12Command>(cbw)?.into())),
+            OpCode::Read16 => Ok(Command::Read(checked_extract::<Read16Command>(cbw)?.into())),
             OpCode::ReadCapacity10 => Ok(Command::ReadCapacity(checked_extract(cbw)?)), 
+            OpCode::ServiceActionIn16 => {
+                let service_action = ServiceActionIn16::from_primitive(cbw.data[1] & 0x1F)
+                    .map_err(|_| Error::InvalidFieldInCommand)?;
+                match service_action {
+                    ServiceActionIn16::ReadCapacity16 => Ok(Command::ReadCapacity16(checked_extract(cbw)?)),
+                }
+            },
             OpCode::ReadFormatCapacities => Ok(Command::ReadFormatCapacities(checked_extract(cbw)?)),
             OpCode::Inquiry => Ok(Command::Inquiry(checked_extract(cbw)?)),
             OpCode::TestUnitReady => Ok(Command::TestUnitReady(checked_extract(cbw)?)),
@@ -50,18 +116,92 @@ impl Command {
             OpCode::Write6 => Ok(Command::Write(checked_extract::<Write6Command>(cbw)?.into())),
             OpCode::Write10 => Ok(Command::Write(checked_extract::<Write10Command>(cbw)?.into())),
             OpCode::Write12 => Ok(Command::Write(checked_extract::<Write12Command>(cbw)?.into())),
+            OpCode::Write16 => Ok(Command::Write(checked_extract::<Write16Command>(cbw)?.into())),
+            // The data is on the medium once it has been flushed, there's nothing more to verify.
+            // The byte check bit is where WRITE(10) has FUA_NV, which is ignored
+            OpCode::WriteAndVerify10 => Ok(Command::Write(WriteXCommand {
+                force_unit_access: true,
+                ..checked_extract::<Write10Command>(cbw)?.into()
+            })),
             OpCode::Format => Ok(Command::Format(checked_extract(cbw)?)),
             OpCode::SendDiagnostic => Ok(Command::SendDiagnostic(checked_extract(cbw)?)),
             OpCode::ReportLuns => Ok(Command::ReportLuns(checked_extract(cbw)?)),
             OpCode::StartStopUnit => Ok(Command::StartStopUnit(checked_extract(cbw)?)),
-            OpCode::Verify10 => Ok(Command::Verify(checked_extract(cbw)?)),
-            OpCode::SynchronizeCache10 => Ok(Command::SynchronizeCache(checked_extract(cbw)?)),
+            OpCode::Verify10 => Ok(Command::Verify(checked_extract::<Verify10Command>(cbw)?.into())),
+            OpCode::Verify16 => Ok(Command::Verify(checked_extract::<Verify16Command>(cbw)?.into())),
+            OpCode::SynchronizeCache10 => Ok(Command::SynchronizeCache(checked_extract::<SynchronizeCache10Command>(cbw)?.into())),
+            OpCode::SynchronizeCache16 => Ok(Command::SynchronizeCache(checked_extract::<SynchronizeCache16Command>(cbw)?.into())),
+            OpCode::WriteSame10 => Ok(Command::WriteSame(checked_extract::<WriteSame10Command>(cbw)?.into())),
+            OpCode::WriteSame16 => Ok(Command::WriteSame(checked_extract::<WriteSame16Command>(cbw)?.into())),
+            OpCode::Unmap => Ok(Command::Unmap(checked_extract(cbw)?)),
+            OpCode::WriteBuffer => Ok(Command::WriteBuffer(checked_extract(cbw)?)),
+            OpCode::ReadBuffer => Ok(Command::ReadBuffer(checked_extract(cbw)?)),
+            OpCode::LogSense => Ok(Command::LogSense(checked_extract(cbw)?)),
             _ => Err(Error::UnhandledOpCode),
         }
     }
 }
 
 
+impl Command {
+    /// Returns true for commands that access the medium, they fail if it isn't present
+    pub fn needs_medium(&self) -> bool {
+        matches!(self,
+            Command::TestUnitReady(_) |
+            Command::ReadCapacity(_) |
+            Command::ReadCapacity16(_) |
+            Command::Read(_) |
+            Command::Write(_) |
+            Command::Format(_) |
+            Command::Verify(_) |
+            Command::Seek(_) |
+            Command::SynchronizeCache(_) |
+            Command::WriteSame(_) |
+            Command::Unmap(_)
+        )
+    }
+
+    /// The data stage the command needs, for checking against the CBW. Block counts are turned
+    /// into bytes with `block_bytes`, the block size of the logical unit it's addressed to
+    ///
+    /// None for custom commands, only the command handler knows what they need
+    pub fn data_stage(&self, block_bytes: usize) -> Option<DataStage> {
+        // Anything that doesn't fit in the CBW is more than the host could have asked for
+        let blocks = |n: u32| (n as u64 * block_bytes as u64).min(u32::MAX as u64) as u32;
+
+        Some(match *self {
+            Command::Inquiry(i) => DataStage::In(i.allocation_length.into()),
+            Command::RequestSense(r) => DataStage::In(r.allocation_length.into()),
+            Command::ModeSense(m) => DataStage::In(m.allocation_length.into()),
+            Command::LogSense(l) => DataStage::In(l.allocation_length.into()),
+            Command::ReadCapacity(_) => DataStage::In(ReadCapacity10Response::BYTES as u32),
+            Command::ReadCapacity16(r) => DataStage::In(r.allocation_length),
+            Command::ReadFormatCapacities(r) => DataStage::In(r.allocation_length.into()),
+            Command::ReportLuns(r) => DataStage::In(r.allocation_length),
+            Command::ReadBuffer(r) => DataStage::In(r.allocation_length),
+            Command::Read(r) => DataStage::In(blocks(r.transfer_length)),
+
+            Command::Write(w) => DataStage::Out(blocks(w.transfer_length)),
+            Command::Verify(v) => match v.byte_check {
+                ByteCheck::None => DataStage::None,
+                ByteCheck::Compare => DataStage::Out(blocks(v.verification_length)),
+                ByteCheck::CompareSingleBlock => DataStage::Out(blocks(1)),
+            },
+            Command::WriteSame(w) if w.no_data_out => DataStage::None,
+            Command::WriteSame(_) => DataStage::Out(blocks(1)),
+            Command::ModeSelect(m) => DataStage::Out(m.parameter_list_length.into()),
+            Command::Unmap(u) => DataStage::Out(u.parameter_list_length.into()),
+            Command::WriteBuffer(w) => DataStage::Out(w.parameter_list_length),
+            // The rest of the parameter list depends on what's in the header
+            Command::Format(f) if f.format_data && f.long_list => DataStage::Out(LongFormatParameterListHeader::BYTES as u32),
+            Command::Format(f) if f.format_data => DataStage::Out(FormatParameterListHeader::BYTES as u32),
+
+            Command::Custom(_) => return None,
+            _ => DataStage::None,
+        })
+    }
+}
+
 fn checked_extract<T>(cbw: &CommandBlockWrapper_NEW) -> Result<T, Error> 
 where 
     T: ParsePackedStruct,
@@ -0,0 +1,20 @@
+/// A command `Scsi` doesn't implement, passed to the
+/// [CommandHandler](../../trait.CommandHandler.html)
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct CustomCommand {
+    data: [u8; 16],
+    len: u8,
+}
+
+impl CustomCommand {
+    pub fn new(data: [u8; 16], len: u8) -> Self {
+        Self {
+            data,
+            len: len.min(16),
+        }
+    }
+
+    pub fn cdb(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
@@ -24,8 +24,153 @@ pub struct FormatCommand {
     
     #[pkd(2, 0, 1, 1)]
     pub defect_list_format: u8,
+
+    /// Vendor specific in SBC. UFI's track number, for formatting a track at a time
+    #[pkd(7, 0, 2, 2)]
+    pub track_number: u8,
             
     #[pkd(7, 0, 5, 5)]
     pub control: Control,
 }
-impl ParsePackedStruct for FormatCommand {}
\ No newline at end of file
+impl ParsePackedStruct for FormatCommand {}
+
+/// Sent with FORMAT UNIT when `format_data` is set and `long_list` isn't, followed by
+/// `defect_list_length` bytes of defect list
+///
+/// SBC-3 5.3.2.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct FormatParameterListHeader {
+    #[pkd(2, 0, 0, 0)]
+    pub protection_field_usage: u8,
+
+    /// The bits up to `disable_save_parameters` are only meaningful if this is set
+    #[pkd(7, 7, 1, 1)]
+    pub format_options_valid: bool,
+
+    #[pkd(6, 6, 1, 1)]
+    pub disable_primary: bool,
+
+    #[pkd(5, 5, 1, 1)]
+    pub disable_certification: bool,
+
+    /// UFI's single track bit, only the track in the command is formatted
+    #[pkd(4, 4, 1, 1)]
+    pub stop_format: bool,
+
+    /// An initialization pattern descriptor follows the header
+    #[pkd(3, 3, 1, 1)]
+    pub initialization_pattern: bool,
+
+    #[pkd(2, 2, 1, 1)]
+    pub disable_save_parameters: bool,
+
+    /// Complete the command once the format has started rather than when it's done
+    #[pkd(1, 1, 1, 1)]
+    pub immediate: bool,
+
+    /// UFI's side bit, which side of the track to format
+    #[pkd(0, 0, 1, 1)]
+    pub vendor_specific: bool,
+
+    #[pkd(7, 0, 2, 3)]
+    pub defect_list_length: u16,
+}
+
+/// Same as [FormatParameterListHeader](struct.FormatParameterListHeader.html) when `long_list`
+/// is set
+///
+/// SBC-3 5.3.2.3
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct LongFormatParameterListHeader {
+    #[pkd(2, 0, 0, 0)]
+    pub protection_field_usage: u8,
+
+    #[pkd(7, 7, 1, 1)]
+    pub format_options_valid: bool,
+
+    #[pkd(6, 6, 1, 1)]
+    pub disable_primary: bool,
+
+    #[pkd(5, 5, 1, 1)]
+    pub disable_certification: bool,
+
+    #[pkd(4, 4, 1, 1)]
+    pub stop_format: bool,
+
+    #[pkd(3, 3, 1, 1)]
+    pub initialization_pattern: bool,
+
+    #[pkd(2, 2, 1, 1)]
+    pub disable_save_parameters: bool,
+
+    #[pkd(1, 1, 1, 1)]
+    pub immediate: bool,
+
+    #[pkd(0, 0, 1, 1)]
+    pub vendor_specific: bool,
+
+    #[pkd(7, 4, 3, 3)]
+    pub protection_information: u8,
+
+    #[pkd(3, 0, 3, 3)]
+    pub protection_interval_exponent: u8,
+
+    #[pkd(7, 0, 4, 7)]
+    pub defect_list_length: u32,
+}
+
+/// UFI hosts (Windows formatting a floppy style device for example) send one of these as the
+/// defect list, with defect list format 7, to say which of the capacities reported by READ
+/// FORMAT CAPACITIES to format to. The formattable capacity descriptors in that response are the
+/// same
+///
+/// UFI 4.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct FormatDescriptor {
+    #[pkd(7, 0, 0, 3)]
+    pub number_of_blocks: u32,
+
+    #[pkd(7, 0, 4, 4)]
+    _reserved: u8,
+
+    #[pkd(7, 0, 5, 7)]
+    pub block_length: u32,
+}
+
+impl FormatDescriptor {
+    /// `number_of_blocks` is saturated if it doesn't fit
+    pub fn new(number_of_blocks: u64, block_length: u32) -> Self {
+        Self {
+            number_of_blocks: number_of_blocks.min(u32::MAX as u64) as u32,
+            _reserved: Default::default(),
+            block_length,
+        }
+    }
+}
+
+/// Defect list format that means the defect list is a `FormatDescriptor`
+pub const UFI_FORMAT_DESCRIPTOR_DEFECT_LIST_FORMAT: u8 = 7;
+
+#[test]
+fn test_format_parameter_list_parse() {
+    use packing::PackedSize;
+
+    let header = FormatParameterListHeader::unpack(&[0x00, 0x82, 0x00, 0x08]).unwrap();
+    assert!(header.format_options_valid && header.immediate && !header.initialization_pattern);
+    assert_eq!(header.defect_list_length, 8);
+
+    // UFI single track format of side 1
+    let header = FormatParameterListHeader::unpack(&[0x00, 0xB1, 0x00, 0x08]).unwrap();
+    assert!(header.stop_format && header.vendor_specific && !header.immediate);
+
+    let header = LongFormatParameterListHeader::unpack(&[0x00, 0x08, 0x00, 0x00, 0, 0, 0x01, 0x00]).unwrap();
+    assert!(header.initialization_pattern && !header.immediate);
+    assert_eq!(header.defect_list_length, 0x100);
+
+    assert_eq!(FormatDescriptor::BYTES, 8);
+    let descriptor = FormatDescriptor::unpack(&[0, 0, 0x0B, 0x40, 0, 0, 0x02, 0]).unwrap();
+    assert_eq!((descriptor.number_of_blocks, descriptor.block_length), (2880, 512));
+}
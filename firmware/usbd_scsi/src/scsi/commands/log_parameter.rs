@@ -0,0 +1,97 @@
+use packing::Packed;
+
+/// LOG SENSE page control for the current cumulative values, the only ones that are kept
+pub const LOG_CUMULATIVE_VALUES: u8 = 0x01;
+
+/// Log pages reported by LOG SENSE
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum LogPageCode {
+    /// SPC-4 7.3.18
+    SupportedLogPages = 0x00,
+    /// SPC-4 7.3.22
+    WriteErrorCounter = 0x02,
+    /// SPC-4 7.3.15
+    ReadErrorCounter = 0x03,
+    /// SPC-4 7.3.21
+    VerifyErrorCounter = 0x05,
+    /// SPC-4 7.3.20
+    Temperature = 0x0D,
+    /// SPC-4 7.3.16
+    SelfTestResults = 0x10,
+    /// SPC-4 7.3.8
+    InformationalExceptions = 0x2F,
+}
+
+/// Start of every log page, followed by `page_length` bytes of parameters
+///
+/// SPC-4 7.3.1
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct LogPageHeader {
+    /// Set as nothing can be saved
+    #[pkd(7, 7, 0, 0)]
+    pub disable_save: bool,
+
+    #[pkd(6, 6, 0, 0)]
+    pub subpage_format: bool,
+
+    #[pkd(5, 0, 0, 0)]
+    pub page_code: u8,
+
+    #[pkd(7, 0, 1, 1)]
+    pub subpage_code: u8,
+
+    #[pkd(7, 0, 2, 3)]
+    pub page_length: u16,
+}
+
+/// Start of each log parameter, followed by `parameter_length` bytes of value
+///
+/// SPC-4 7.3.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct LogParameterHeader {
+    #[pkd(7, 0, 0, 1)]
+    pub parameter_code: u16,
+
+    #[pkd(7, 7, 2, 2)]
+    pub disable_update: bool,
+
+    #[pkd(5, 5, 2, 2)]
+    pub target_save_disable: bool,
+
+    #[pkd(4, 4, 2, 2)]
+    pub enable_threshold_comparison: bool,
+
+    #[pkd(3, 2, 2, 2)]
+    pub threshold_met_criteria: u8,
+
+    /// See [LOG_BOUNDED_DATA_COUNTER](constant.LOG_BOUNDED_DATA_COUNTER.html) and
+    /// [LOG_BINARY_FORMAT_LIST](constant.LOG_BINARY_FORMAT_LIST.html)
+    #[pkd(1, 0, 2, 2)]
+    pub format_and_linking: u8,
+
+    #[pkd(7, 0, 3, 3)]
+    pub parameter_length: u8,
+}
+
+/// Format and linking for counters
+pub const LOG_BOUNDED_DATA_COUNTER: u8 = 0b00;
+
+/// Format and linking for everything else
+pub const LOG_BINARY_FORMAT_LIST: u8 = 0b11;
+
+
+#[test]
+fn test_log_parameter_header() {
+    use packing::PackedSize;
+
+    let mut bytes = [0; LogParameterHeader::BYTES];
+    LogParameterHeader {
+        parameter_code: 0x0102,
+        format_and_linking: LOG_BINARY_FORMAT_LIST,
+        parameter_length: 4,
+        ..Default::default()
+    }.pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x01, 0x02, 0x03, 4]);
+}
@@ -0,0 +1,51 @@
+use packing::Packed;
+use crate::scsi::{
+    packing::ParsePackedStruct,
+    commands::Control,
+};
+
+/// SPC-4 6.6
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct LogSenseCommand {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(1, 1, 1, 1)]
+    pub parameter_pointer_control: bool,
+
+    #[pkd(0, 0, 1, 1)]
+    pub save_parameters: bool,
+
+    /// See [LOG_CUMULATIVE_VALUES](constant.LOG_CUMULATIVE_VALUES.html)
+    #[pkd(7, 6, 2, 2)]
+    pub page_control: u8,
+
+    #[pkd(5, 0, 2, 2)]
+    pub page_code: u8,
+
+    #[pkd(7, 0, 3, 3)]
+    pub subpage_code: u8,
+
+    /// Only parameters with this code or higher are returned
+    #[pkd(7, 0, 5, 6)]
+    pub parameter_pointer: u16,
+
+    #[pkd(7, 0, 7, 8)]
+    pub allocation_length: u16,
+
+    #[pkd(7, 0, 9, 9)]
+    pub control: Control,
+}
+impl ParsePackedStruct for LogSenseCommand {}
+
+
+#[test]
+fn test_log_sense_parse() {
+    let data = [0x4D, 0, 0x43, 0, 0, 0, 0x05, 0x01, 0x00, 0];
+    let cmd = LogSenseCommand::parse(&data).unwrap();
+    assert_eq!(cmd.page_control, 0x01);
+    assert_eq!(cmd.page_code, 0x03);
+    assert_eq!(cmd.parameter_pointer, 0x05);
+    assert_eq!(cmd.allocation_length, 0x100);
+}
@@ -31,6 +31,9 @@ pub use read_format_capacities::*;
 mod read;
 pub use read::*;
 
+mod seek;
+pub use seek::*;
+
 mod report_luns;
 pub use report_luns::*;
 
@@ -55,5 +58,26 @@ pub use verify::*;
 mod write;
 pub use write::*;
 
+mod write_same;
+pub use write_same::*;
+
+mod unmap;
+pub use unmap::*;
+
+mod log_sense;
+pub use log_sense::*;
+
+mod log_parameter;
+pub use log_parameter::*;
+
+mod write_buffer;
+pub use write_buffer::*;
+
+mod read_buffer;
+pub use read_buffer::*;
+
+mod custom;
+pub use custom::*;
+
 mod mode_parameter;
 pub use mode_parameter::*;
@@ -4,6 +4,9 @@ use packing::{
 };
 use crate::scsi::enums::MediumType;
 
+/// Mode parameter header for MODE SENSE(6)/MODE SELECT(6)
+///
+/// Followed by `block_descriptor_length` bytes of block descriptors then the mode pages
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 #[packed(big_endian, lsb0)]
 pub struct ModeParameterHeader6 {
@@ -29,16 +32,10 @@ impl Default for ModeParameterHeader6 {
         }
     }
 }
-impl ModeParameterHeader6 {
-    /// Increase the relevant length fields to indicate the provided page follows this header
-    /// can be called multiple times but be aware of the max length allocated by CBW
-    pub fn increase_length_for_page(&mut self, page_code: PageCode) {
-        self.mode_data_length += match page_code {
-            PageCode::CachingModePage => CachingModePage::BYTES as u8,
-        };
-    }
-}
 
+/// Mode parameter header for MODE SENSE(10)/MODE SELECT(10)
+///
+/// Followed by `block_descriptor_length` bytes of block descriptors then the mode pages
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 #[packed(big_endian, lsb0)]
 pub struct ModeParameterHeader10 {
@@ -68,16 +65,6 @@ impl Default for ModeParameterHeader10 {
         }
     }
 }
-impl ModeParameterHeader10 {
-    /// Increase the relevant length fields to indicate the provided page follows this header
-    /// can be called multiple times but be aware of the max length allocated by CBW
-    #[allow(dead_code)]
-    pub fn increase_length_for_page(&mut self, page_code: PageCode) {
-        self.mode_data_length += match page_code {
-            PageCode::CachingModePage => CachingModePage::BYTES as u16,
-        };
-    }
-}
 
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed, Default)]
@@ -90,36 +77,535 @@ pub struct SbcDeviceSpecificParameter {
     pub disable_page_out_and_force_unit_access_available: bool,
 }
 
+/// Block descriptor returned when the long LBA format isn't requested
+///
+/// SBC-3 6.4.2.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ShortLbaModeParameterBlockDescriptor {
+    /// 0xFFFFFFFF if the number of blocks doesn't fit
+    #[pkd(7, 0, 0, 3)]
+    pub number_of_blocks: u32,
+
+    #[pkd(7, 0, 5, 7)]
+    pub logical_block_length: u32,
+}
+impl ShortLbaModeParameterBlockDescriptor {
+    pub fn new(number_of_blocks: u64, logical_block_length: u32) -> Self {
+        Self {
+            number_of_blocks: number_of_blocks.min(u32::MAX as u64) as u32,
+            logical_block_length,
+        }
+    }
+}
+
+/// Block descriptor returned by MODE SENSE(10) when the long LBA format is accepted
+///
+/// SBC-3 6.4.2.3
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct LongLbaModeParameterBlockDescriptor {
+    #[pkd(7, 0, 0, 7)]
+    pub number_of_blocks: u64,
+
+    #[pkd(7, 0, 12, 15)]
+    pub logical_block_length: u32,
+}
+impl LongLbaModeParameterBlockDescriptor {
+    pub fn new(number_of_blocks: u64, logical_block_length: u32) -> Self {
+        Self {
+            number_of_blocks,
+            logical_block_length,
+        }
+    }
+}
+
+/// Mode pages with a struct in this crate
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 pub enum PageCode {
+    /// SBC-3 6.4.9
+    ReadWriteErrorRecoveryModePage = 0x01,
+    /// UFI 4.5
+    FlexibleDiskModePage = 0x05,
+    /// SBC-3 6.4.5
     CachingModePage = 0x08,
+    /// UFI 4.5
+    RemovableBlockAccessCapabilitiesModePage = 0x1B,
+    /// SPC-4 7.5.12. UFI uses the same page code for its
+    /// [TimerAndProtectModePage](struct.TimerAndProtectModePage.html)
+    InformationalExceptionsControlModePage = 0x1C,
 }
 
-/// This is only a partial implementation, there are a whole load of extra
-/// fields defined in SBC-3 6.4.5
-/// Default config is no read or write cache
+/// Controls read and write caching
+///
+/// SBC-3 6.4.5. Default config is no read or write cache
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 #[packed(big_endian, lsb0)]
 pub struct CachingModePage {
+    #[pkd(7, 7, 0, 0)]
+    pub parameters_saveable: bool,
+
     #[pkd(5, 0, 0, 0)]
     pub page_code: PageCode,
 
     #[pkd(7, 0, 1, 1)]
     pub page_length: u8,
 
+    #[pkd(7, 7, 2, 2)]
+    pub initiator_control: bool,
+
+    #[pkd(6, 6, 2, 2)]
+    pub abort_prefetch: bool,
+
+    #[pkd(5, 5, 2, 2)]
+    pub caching_analysis_permitted: bool,
+
+    #[pkd(4, 4, 2, 2)]
+    pub discontinuity: bool,
+
+    #[pkd(3, 3, 2, 2)]
+    pub size_enable: bool,
+
     #[pkd(2, 2, 2, 2)]
     pub write_cache_enabled: bool,
 
+    #[pkd(1, 1, 2, 2)]
+    pub multiplication_factor: bool,
+
     #[pkd(0, 0, 2, 2)]
     pub read_cache_disable: bool,
+
+    #[pkd(7, 4, 3, 3)]
+    pub demand_read_retention_priority: u8,
+
+    #[pkd(3, 0, 3, 3)]
+    pub write_retention_priority: u8,
+
+    #[pkd(7, 0, 4, 5)]
+    pub disable_prefetch_transfer_length: u16,
+
+    #[pkd(7, 0, 6, 7)]
+    pub minimum_prefetch: u16,
+
+    #[pkd(7, 0, 8, 9)]
+    pub maximum_prefetch: u16,
+
+    #[pkd(7, 0, 10, 11)]
+    pub maximum_prefetch_ceiling: u16,
+
+    #[pkd(7, 7, 12, 12)]
+    pub force_sequential_write: bool,
+
+    #[pkd(6, 6, 12, 12)]
+    pub logical_block_cache_segment_size: bool,
+
+    #[pkd(5, 5, 12, 12)]
+    pub disable_read_ahead: bool,
+
+    #[pkd(0, 0, 12, 12)]
+    pub non_volatile_cache_disabled: bool,
+
+    #[pkd(7, 0, 13, 13)]
+    pub number_of_cache_segments: u8,
+
+    #[pkd(7, 0, 14, 15)]
+    pub cache_segment_size: u16,
+
+    /// Obsolete
+    #[pkd(7, 0, 17, 19)]
+    pub non_cache_segment_size: u32,
 }
 impl Default for CachingModePage {
     fn default() -> Self {
         Self {
+            parameters_saveable: false,
             page_code: PageCode::CachingModePage,
-            page_length: Self::BYTES as u8,
+            page_length: Self::BYTES as u8 - 2,
+            initiator_control: false,
+            abort_prefetch: false,
+            caching_analysis_permitted: false,
+            discontinuity: false,
+            size_enable: false,
             write_cache_enabled: false,
+            multiplication_factor: false,
             read_cache_disable: true,
+            demand_read_retention_priority: 0,
+            write_retention_priority: 0,
+            disable_prefetch_transfer_length: 0,
+            minimum_prefetch: 0,
+            maximum_prefetch: 0,
+            maximum_prefetch_ceiling: 0,
+            force_sequential_write: false,
+            logical_block_cache_segment_size: false,
+            disable_read_ahead: false,
+            non_volatile_cache_disabled: false,
+            number_of_cache_segments: 0,
+            cache_segment_size: 0,
+            non_cache_segment_size: 0,
         }
     }
-}
\ No newline at end of file
+}
+impl CachingModePage {
+    /// A page with no fields set, used as the starting point for a changeable values mask
+    pub fn unchangeable() -> Self {
+        Self {
+            read_cache_disable: false,
+            ..Default::default()
+        }
+    }
+}
+
+/// Controls error recovery during reads and writes
+///
+/// SBC-3 6.4.9. Default config is all recovery options off
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReadWriteErrorRecoveryModePage {
+    #[pkd(7, 7, 0, 0)]
+    pub parameters_saveable: bool,
+
+    #[pkd(5, 0, 0, 0)]
+    pub page_code: PageCode,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_length: u8,
+
+    #[pkd(7, 7, 2, 2)]
+    pub automatic_write_reallocation_enabled: bool,
+
+    #[pkd(6, 6, 2, 2)]
+    pub automatic_read_reallocation_enabled: bool,
+
+    #[pkd(5, 5, 2, 2)]
+    pub transfer_block: bool,
+
+    #[pkd(4, 4, 2, 2)]
+    pub read_continuous: bool,
+
+    #[pkd(3, 3, 2, 2)]
+    pub enable_early_recovery: bool,
+
+    #[pkd(2, 2, 2, 2)]
+    pub post_error: bool,
+
+    #[pkd(1, 1, 2, 2)]
+    pub data_terminate_on_error: bool,
+
+    #[pkd(0, 0, 2, 2)]
+    pub disable_correction: bool,
+
+    #[pkd(7, 0, 3, 3)]
+    pub read_retry_count: u8,
+
+    #[pkd(7, 7, 7, 7)]
+    pub logical_block_provisioning_error_reporting_enabled: bool,
+
+    #[pkd(7, 0, 8, 8)]
+    pub write_retry_count: u8,
+
+    #[pkd(7, 0, 10, 11)]
+    pub recovery_time_limit: u16,
+}
+impl Default for ReadWriteErrorRecoveryModePage {
+    fn default() -> Self {
+        Self {
+            parameters_saveable: false,
+            page_code: PageCode::ReadWriteErrorRecoveryModePage,
+            page_length: Self::BYTES as u8 - 2,
+            automatic_write_reallocation_enabled: false,
+            automatic_read_reallocation_enabled: false,
+            transfer_block: false,
+            read_continuous: false,
+            enable_early_recovery: false,
+            post_error: false,
+            data_terminate_on_error: false,
+            disable_correction: false,
+            read_retry_count: 0,
+            logical_block_provisioning_error_reporting_enabled: false,
+            write_retry_count: 0,
+            recovery_time_limit: 0,
+        }
+    }
+}
+
+/// Controls reporting of informational exception conditions (e.g. failure prediction)
+///
+/// SPC-4 7.5.12. Default config is exceptions disabled
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct InformationalExceptionsControlModePage {
+    #[pkd(7, 7, 0, 0)]
+    pub parameters_saveable: bool,
+
+    #[pkd(5, 0, 0, 0)]
+    pub page_code: PageCode,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_length: u8,
+
+    #[pkd(7, 7, 2, 2)]
+    pub performance: bool,
+
+    #[pkd(5, 5, 2, 2)]
+    pub enable_background_function: bool,
+
+    #[pkd(4, 4, 2, 2)]
+    pub enable_warning: bool,
+
+    #[pkd(3, 3, 2, 2)]
+    pub disable_exception_control: bool,
+
+    #[pkd(2, 2, 2, 2)]
+    pub test: bool,
+
+    #[pkd(1, 1, 2, 2)]
+    pub enable_background_error: bool,
+
+    #[pkd(0, 0, 2, 2)]
+    pub log_errors: bool,
+
+    /// Method of reporting informational exceptions, 0h = no reporting
+    #[pkd(3, 0, 3, 3)]
+    pub method_of_reporting: u8,
+
+    /// In 100ms units
+    #[pkd(7, 0, 4, 7)]
+    pub interval_timer: u32,
+
+    #[pkd(7, 0, 8, 11)]
+    pub report_count: u32,
+}
+impl Default for InformationalExceptionsControlModePage {
+    fn default() -> Self {
+        Self {
+            parameters_saveable: false,
+            page_code: PageCode::InformationalExceptionsControlModePage,
+            page_length: Self::BYTES as u8 - 2,
+            performance: false,
+            enable_background_function: false,
+            enable_warning: false,
+            disable_exception_control: true,
+            test: false,
+            enable_background_error: false,
+            log_errors: false,
+            method_of_reporting: 0,
+            interval_timer: 0,
+            report_count: 0,
+        }
+    }
+}
+
+/// Geometry of a floppy disk, reported by UFI devices
+///
+/// UFI 4.5
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct FlexibleDiskModePage {
+    #[pkd(7, 7, 0, 0)]
+    pub parameters_saveable: bool,
+
+    #[pkd(5, 0, 0, 0)]
+    pub page_code: PageCode,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_length: u8,
+
+    /// In kbit/s
+    #[pkd(7, 0, 2, 3)]
+    pub transfer_rate: u16,
+
+    #[pkd(7, 0, 4, 4)]
+    pub number_of_heads: u8,
+
+    #[pkd(7, 0, 5, 5)]
+    pub sectors_per_track: u8,
+
+    #[pkd(7, 0, 6, 7)]
+    pub data_bytes_per_sector: u16,
+
+    #[pkd(7, 0, 8, 9)]
+    pub number_of_cylinders: u16,
+
+    /// In 100ms units
+    #[pkd(7, 0, 19, 19)]
+    pub motor_on_delay: u8,
+
+    /// In 100ms units, FFh means never turn the motor off
+    #[pkd(7, 0, 20, 20)]
+    pub motor_off_delay: u8,
+
+    /// In rpm
+    #[pkd(7, 0, 28, 29)]
+    pub medium_rotation_rate: u16,
+
+    #[pkd(7, 0, 30, 31)]
+    pub(crate) _reserved: u16,
+}
+impl Default for FlexibleDiskModePage {
+    fn default() -> Self {
+        Self {
+            parameters_saveable: false,
+            page_code: PageCode::FlexibleDiskModePage,
+            page_length: Self::BYTES as u8 - 2,
+            transfer_rate: 0,
+            number_of_heads: 0,
+            sectors_per_track: 0,
+            data_bytes_per_sector: 0,
+            number_of_cylinders: 0,
+            motor_on_delay: 0,
+            motor_off_delay: 0,
+            medium_rotation_rate: 0,
+            _reserved: 0,
+        }
+    }
+}
+
+/// What kind of removable device this is, reported by UFI devices
+///
+/// UFI 4.5
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct RemovableBlockAccessCapabilitiesModePage {
+    #[pkd(7, 7, 0, 0)]
+    pub parameters_saveable: bool,
+
+    #[pkd(5, 0, 0, 0)]
+    pub page_code: PageCode,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_length: u8,
+
+    /// The device can be used as a system floppy
+    #[pkd(7, 7, 2, 2)]
+    pub system_floppy: bool,
+
+    /// REQUEST SENSE reports the progress of a format
+    #[pkd(6, 6, 2, 2)]
+    pub supports_reporting_format_progress: bool,
+
+    #[pkd(7, 7, 3, 3)]
+    pub non_cd_optical: bool,
+
+    #[pkd(6, 6, 3, 3)]
+    pub single_multiple_lun: bool,
+
+    #[pkd(2, 0, 3, 3)]
+    pub total_luns: u8,
+
+    #[pkd(7, 0, 4, 11)]
+    pub(crate) _reserved: [u8; 8],
+}
+impl Default for RemovableBlockAccessCapabilitiesModePage {
+    fn default() -> Self {
+        Self {
+            parameters_saveable: false,
+            page_code: PageCode::RemovableBlockAccessCapabilitiesModePage,
+            page_length: Self::BYTES as u8 - 2,
+            system_floppy: false,
+            supports_reporting_format_progress: false,
+            non_cd_optical: false,
+            single_multiple_lun: false,
+            total_luns: 0,
+            _reserved: [0; 8],
+        }
+    }
+}
+
+/// Inactivity timer and write protection, reported by UFI devices
+///
+/// UFI 4.5
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct TimerAndProtectModePage {
+    #[pkd(7, 7, 0, 0)]
+    pub parameters_saveable: bool,
+
+    #[pkd(5, 0, 0, 0)]
+    pub page_code: PageCode,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_length: u8,
+
+    /// How long the device stays ready without any commands, 0 is vendor specific
+    #[pkd(3, 0, 3, 3)]
+    pub inactivity_time_multiplier: u8,
+
+    /// Disable media access until power cycle
+    #[pkd(1, 1, 4, 4)]
+    pub disable_media_access: bool,
+
+    #[pkd(0, 0, 4, 4)]
+    pub software_write_protect: bool,
+
+    #[pkd(7, 0, 5, 7)]
+    pub(crate) _reserved: [u8; 3],
+}
+impl Default for TimerAndProtectModePage {
+    fn default() -> Self {
+        Self {
+            parameters_saveable: false,
+            page_code: PageCode::InformationalExceptionsControlModePage,
+            page_length: Self::BYTES as u8 - 2,
+            inactivity_time_multiplier: 0,
+            disable_media_access: false,
+            software_write_protect: false,
+            _reserved: [0; 3],
+        }
+    }
+}
+
+#[test]
+fn test_caching_mode_page() {
+    let mut bytes = [0; CachingModePage::BYTES];
+    let page = CachingModePage {
+        write_cache_enabled: true,
+        read_cache_disable: false,
+        ..Default::default()
+    };
+    page.pack(&mut bytes).unwrap();
+    assert_eq!(bytes[..4], [0x08, 0x12, 0b0000_0100, 0x00]);
+
+    CachingModePage::default().pack(&mut bytes).unwrap();
+    assert_eq!(bytes[..4], [0x08, 0x12, 0b0000_0001, 0x00]);
+}
+
+#[test]
+fn test_ufi_mode_pages() {
+    assert_eq!(FlexibleDiskModePage::BYTES, 32);
+    let mut bytes = [0; FlexibleDiskModePage::BYTES];
+    FlexibleDiskModePage {
+        transfer_rate: 500,
+        number_of_heads: 2,
+        sectors_per_track: 18,
+        data_bytes_per_sector: 512,
+        number_of_cylinders: 80,
+        medium_rotation_rate: 300,
+        ..Default::default()
+    }.pack(&mut bytes).unwrap();
+    assert_eq!(bytes[..10], [0x05, 0x1E, 0x01, 0xF4, 2, 18, 0x02, 0x00, 0, 80]);
+    assert_eq!(bytes[28..], [0x01, 0x2C, 0, 0]);
+
+    assert_eq!(RemovableBlockAccessCapabilitiesModePage::BYTES, 12);
+    let mut bytes = [0; RemovableBlockAccessCapabilitiesModePage::BYTES];
+    RemovableBlockAccessCapabilitiesModePage {
+        supports_reporting_format_progress: true,
+        total_luns: 1,
+        ..Default::default()
+    }.pack(&mut bytes).unwrap();
+    assert_eq!(bytes[..4], [0x1B, 0x0A, 0x40, 0x01]);
+
+    let mut bytes = [0; TimerAndProtectModePage::BYTES];
+    TimerAndProtectModePage::default().pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x1C, 0x06, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_block_descriptors() {
+    let mut bytes = [0; ShortLbaModeParameterBlockDescriptor::BYTES];
+    ShortLbaModeParameterBlockDescriptor::new(0x1_0000_0000, 512).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x02, 0x00]);
+
+    let mut bytes = [0; LongLbaModeParameterBlockDescriptor::BYTES];
+    LongLbaModeParameterBlockDescriptor::new(0x1_0000_0000, 512).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02, 0x00]);
+}
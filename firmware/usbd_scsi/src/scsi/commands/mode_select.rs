@@ -1,12 +1,20 @@
 use packing::Packed;
 use crate::scsi::{
     packing::ParsePackedStruct,
-    commands::Control,
+    commands::{
+        Control,
+        CommandLength,
+    },
 };
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct ModeSelectXCommand {
-    // TBD
+    pub command_length: CommandLength,
+    /// Parameters after the header and block descriptors are mode pages rather than vendor
+    /// specific data
+    pub page_format: bool,
+    pub save_pages: bool,
+    pub parameter_list_length: u16,
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
@@ -29,8 +37,13 @@ pub struct ModeSelect6Command {
 }
 impl ParsePackedStruct for ModeSelect6Command {}
 impl From<ModeSelect6Command> for ModeSelectXCommand {
-    fn from(_m: ModeSelect6Command) -> Self {
-        Self { }
+    fn from(m: ModeSelect6Command) -> Self {
+        Self {
+            command_length: CommandLength::C6,
+            page_format: m.page_format,
+            save_pages: m.save_pages,
+            parameter_list_length: m.parameter_list_length.into(),
+        }
     }
 }
 
@@ -55,7 +68,12 @@ pub struct ModeSelect10Command {
 }
 impl ParsePackedStruct for ModeSelect10Command {}
 impl From<ModeSelect10Command> for ModeSelectXCommand {
-    fn from(_m: ModeSelect10Command) -> Self {
-        Self { }
+    fn from(m: ModeSelect10Command) -> Self {
+        Self {
+            command_length: CommandLength::C10,
+            page_format: m.page_format,
+            save_pages: m.save_pages,
+            parameter_list_length: m.parameter_list_length,
+        }
     }
 }
\ No newline at end of file
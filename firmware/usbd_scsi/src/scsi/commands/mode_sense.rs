@@ -25,7 +25,13 @@ B) the default values.
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct ModeSenseXCommand {
     pub command_length: CommandLength,
-    pub page_control: PageControl
+    pub disable_block_descriptors: bool,
+    /// Always false for MODE SENSE(6)
+    pub long_lba_accepted: bool,
+    pub page_control: PageControl,
+    pub page_code: u8,
+    pub subpage_code: u8,
+    pub allocation_length: u16,
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
@@ -57,7 +63,12 @@ impl From<ModeSense6Command> for ModeSenseXCommand {
     fn from(m: ModeSense6Command) -> Self {
         Self { 
             command_length: CommandLength::C6,
+            disable_block_descriptors: m.disable_block_descriptors,
+            long_lba_accepted: false,
             page_control: m.page_control,
+            page_code: m.page_code,
+            subpage_code: m.subpage_code,
+            allocation_length: m.allocation_length.into(),
         }
     }
 }
@@ -83,10 +94,10 @@ pub struct ModeSense10Command {
     #[pkd(7, 0, 3, 3)]
     pub subpage_code: u8,
 
-    #[pkd(7, 0, 8, 9)]
+    #[pkd(7, 0, 7, 8)]
     pub allocation_length: u16,
 
-    #[pkd(7, 0, 10, 10)]
+    #[pkd(7, 0, 9, 9)]
     pub control: Control,
 }
 impl ParsePackedStruct for ModeSense10Command {}
@@ -94,7 +105,12 @@ impl From<ModeSense10Command> for ModeSenseXCommand {
     fn from(m: ModeSense10Command) -> Self {
         Self {
             command_length: CommandLength::C10,
-            page_control: m.page_control, 
+            disable_block_descriptors: m.disable_block_descriptors,
+            long_lba_accepted: m.long_lba_accepted,
+            page_control: m.page_control,
+            page_code: m.page_code,
+            subpage_code: m.subpage_code,
+            allocation_length: m.allocation_length,
         }
     }
 }
\ No newline at end of file
@@ -6,7 +6,8 @@ use crate::scsi::{
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct ReadXCommand {
-    pub lba: u32,
+    pub lba: u64,
+    /// Number of blocks
     pub transfer_length: u32,
 }
 
@@ -32,7 +33,11 @@ impl From<Read6Command> for ReadXCommand {
     fn from(r: Read6Command) -> Self {
         Self {
             lba: r.lba.into(),
-            transfer_length: r.transfer_length.into(),
+            // A transfer length of 0 means 256 blocks for the 6 byte command
+            transfer_length: match r.transfer_length {
+                0 => 256,
+                l => l.into(),
+            },
         }
     }
 }
@@ -120,11 +125,58 @@ impl From<Read12Command> for ReadXCommand {
 }
 
 
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct Read16Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(7, 5, 1, 1)]
+    pub rd_protect: u8,
+
+    #[pkd(4, 4, 1, 1)]
+    pub dpo: bool,
+
+    #[pkd(3, 3, 1, 1)]
+    pub fua: bool,
+
+    #[pkd(1, 1, 1, 1)]
+    pub fua_nv: bool,
+
+    #[pkd(7, 0, 2, 9)]
+    pub lba: u64,
+
+    #[pkd(7, 0, 10, 13)]
+    pub transfer_length: u32,
+
+    #[pkd(4, 0, 14, 14)]
+    pub group_number: u8,
+
+    #[pkd(7, 0, 15, 15)]
+    pub control: Control,
+}
+impl ParsePackedStruct for Read16Command {}
+impl From<Read16Command> for ReadXCommand {
+    fn from(r: Read16Command) -> Self {
+        Self {
+            lba: r.lba,
+            transfer_length: r.transfer_length,
+        }
+    }
+}
 
 
 #[test]
 fn test_read10_parse() {
-    let data = [0, 0, 0, 0x1E, 0x80, 0, 0, 0x8, 0, 0, 0, 0, 0, 0, 0];
+    let data = [0x28, 0, 0, 0, 0x1E, 0x80, 0, 0, 0x8, 0, 0, 0, 0, 0, 0, 0];
     let cmd = Read10Command::parse(&data).unwrap();
     assert_eq!(cmd.lba, 0x1E80);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_read16_parse() {
+    let data = [0x88, 0, 0, 0, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0, 0, 0x01, 0x00, 0, 0];
+    let cmd: ReadXCommand = Read16Command::parse(&data).unwrap().into();
+    assert_eq!(cmd.lba, 0x0102_0304_0506);
+    assert_eq!(cmd.transfer_length, 0x100);
+}
@@ -0,0 +1,33 @@
+use packing::Packed;
+use crate::scsi::{
+    packing::ParsePackedStruct,
+    commands::Control,
+};
+
+/// SPC-4 6.15
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReadBufferCommand {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(7, 5, 1, 1)]
+    pub mode_specific: u8,
+
+    /// See [ReadBufferMode](enum.ReadBufferMode.html)
+    #[pkd(4, 0, 1, 1)]
+    pub mode: u8,
+
+    #[pkd(7, 0, 2, 2)]
+    pub buffer_id: u8,
+
+    #[pkd(7, 0, 3, 5)]
+    pub buffer_offset: u32,
+
+    #[pkd(7, 0, 6, 8)]
+    pub allocation_length: u32,
+
+    #[pkd(7, 0, 9, 9)]
+    pub control: Control,
+}
+impl ParsePackedStruct for ReadBufferCommand {}
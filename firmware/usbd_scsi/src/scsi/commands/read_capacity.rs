@@ -2,6 +2,7 @@ use packing::Packed;
 use crate::scsi::{
     packing::ParsePackedStruct,
     commands::Control,
+    enums::ServiceActionIn16,
 };
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
@@ -16,4 +17,28 @@ pub struct ReadCapacity10Command {
     #[pkd(7, 0, 9, 9)]
     pub control: Control,
 }
-impl ParsePackedStruct for ReadCapacity10Command {}
\ No newline at end of file
+impl ParsePackedStruct for ReadCapacity10Command {}
+
+/// READ CAPACITY(16), the service action is always `ServiceActionIn16::ReadCapacity16`
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReadCapacity16Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(4, 0, 1, 1)]
+    pub service_action: ServiceActionIn16,
+
+    #[pkd(7, 0, 2, 9)]
+    pub lba: u64,
+
+    #[pkd(7, 0, 10, 13)]
+    pub allocation_length: u32,
+
+    #[pkd(0, 0, 14, 14)]
+    pub partial_medium_indicator: bool,
+
+    #[pkd(7, 0, 15, 15)]
+    pub control: Control,
+}
+impl ParsePackedStruct for ReadCapacity16Command {}
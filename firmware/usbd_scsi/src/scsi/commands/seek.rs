@@ -0,0 +1,54 @@
+use packing::Packed;
+use crate::scsi::{
+    packing::ParsePackedStruct,
+    commands::Control,
+};
+
+/// Moves the head to `lba`. Both commands are obsolete in SBC, UFI still has them
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct SeekXCommand {
+    pub lba: u64,
+}
+
+/// UFI 4.13
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct Seek10Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(7, 0, 2, 5)]
+    pub lba: u32,
+
+    #[pkd(7, 0, 9, 9)]
+    pub control: Control,
+}
+impl ParsePackedStruct for Seek10Command {}
+impl From<Seek10Command> for SeekXCommand {
+    fn from(s: Seek10Command) -> Self {
+        Self {
+            lba: s.lba.into(),
+        }
+    }
+}
+
+/// A seek to block 0
+///
+/// UFI 4.12
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct RezeroUnitCommand {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(7, 0, 5, 5)]
+    pub control: Control,
+}
+impl ParsePackedStruct for RezeroUnitCommand {}
+impl From<RezeroUnitCommand> for SeekXCommand {
+    fn from(_: RezeroUnitCommand) -> Self {
+        Self {
+            lba: 0,
+        }
+    }
+}
@@ -4,6 +4,14 @@ use crate::scsi::{
     commands::Control,
 };
 
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct SynchronizeCacheXCommand {
+    pub lba: u64,
+    /// Number of blocks, 0 means everything from `lba` to the end of the medium
+    pub number_of_blocks: u32,
+    pub immediate: bool,
+}
+
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 #[packed(big_endian, lsb0)]
 pub struct SynchronizeCache10Command {
@@ -25,4 +33,58 @@ pub struct SynchronizeCache10Command {
     #[pkd(7, 0, 9, 9)]
     pub control: Control,
 }
-impl ParsePackedStruct for SynchronizeCache10Command {}
\ No newline at end of file
+impl ParsePackedStruct for SynchronizeCache10Command {}
+impl From<SynchronizeCache10Command> for SynchronizeCacheXCommand {
+    fn from(s: SynchronizeCache10Command) -> Self {
+        Self {
+            lba: s.lba.into(),
+            number_of_blocks: s.number_of_blocks.into(),
+            immediate: s.immediate,
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct SynchronizeCache16Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+    
+    #[pkd(1, 1, 1, 1)]
+    pub immediate: bool,
+    
+    #[pkd(7, 0, 2, 9)]
+    pub lba: u64,
+    
+    #[pkd(7, 0, 10, 13)]
+    pub number_of_blocks: u32,
+    
+    #[pkd(4, 0, 14, 14)]
+    pub group_number: u8,
+    
+    #[pkd(7, 0, 15, 15)]
+    pub control: Control,
+}
+impl ParsePackedStruct for SynchronizeCache16Command {}
+impl From<SynchronizeCache16Command> for SynchronizeCacheXCommand {
+    fn from(s: SynchronizeCache16Command) -> Self {
+        Self {
+            lba: s.lba,
+            number_of_blocks: s.number_of_blocks,
+            immediate: s.immediate,
+        }
+    }
+}
+
+
+#[test]
+fn test_synchronize_cache_parse() {
+    let data = [0x35, 0x02, 0, 0, 0x12, 0x34, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0];
+    let cmd: SynchronizeCacheXCommand = SynchronizeCache10Command::parse(&data).unwrap().into();
+    assert_eq!(cmd, SynchronizeCacheXCommand { lba: 0x1234, number_of_blocks: 0x10, immediate: true });
+
+    let data = [0x91, 0, 0, 0, 0, 0x01, 0, 0, 0, 0, 0, 0, 0, 0x02, 0, 0];
+    let cmd: SynchronizeCacheXCommand = SynchronizeCache16Command::parse(&data).unwrap().into();
+    assert_eq!(cmd, SynchronizeCacheXCommand { lba: 0x01_0000_0000, number_of_blocks: 2, immediate: false });
+}
@@ -0,0 +1,58 @@
+use packing::Packed;
+use crate::scsi::{
+    packing::ParsePackedStruct,
+    commands::Control,
+};
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct UnmapCommand {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(0, 0, 1, 1)]
+    pub anchor: bool,
+
+    #[pkd(4, 0, 6, 6)]
+    pub group_number: u8,
+
+    #[pkd(7, 0, 7, 8)]
+    pub parameter_list_length: u16,
+
+    #[pkd(7, 0, 9, 9)]
+    pub control: Control,
+}
+impl ParsePackedStruct for UnmapCommand {}
+
+/// Start of the UNMAP parameter list, followed by the block descriptors
+///
+/// SBC-3 5.28.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct UnmapParameterListHeader {
+    /// Number of bytes that follow this field
+    #[pkd(7, 0, 0, 1)]
+    pub unmap_data_length: u16,
+
+    #[pkd(7, 0, 2, 3)]
+    pub unmap_block_descriptor_data_length: u16,
+
+    #[pkd(7, 0, 4, 7)]
+    _reserved: u32,
+}
+
+/// A range of blocks to unmap
+///
+/// SBC-3 5.28.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct UnmapBlockDescriptor {
+    #[pkd(7, 0, 0, 7)]
+    pub lba: u64,
+
+    #[pkd(7, 0, 8, 11)]
+    pub number_of_blocks: u32,
+
+    #[pkd(7, 0, 12, 15)]
+    _reserved: u32,
+}
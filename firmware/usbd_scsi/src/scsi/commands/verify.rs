@@ -2,8 +2,17 @@ use packing::Packed;
 use crate::scsi::{
     packing::ParsePackedStruct,
     commands::Control,
+    enums::ByteCheck,
 };
 
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct VerifyXCommand {
+    pub lba: u64,
+    /// Number of blocks
+    pub verification_length: u32,
+    pub byte_check: ByteCheck,
+}
+
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 #[packed(big_endian, lsb0)]
 pub struct Verify10Command {
@@ -16,8 +25,8 @@ pub struct Verify10Command {
     #[pkd(4, 4, 1, 1)]
     pub dpo: bool,
     
-    #[pkd(1, 1, 1, 1)]
-    pub byte_check: u8,
+    #[pkd(2, 1, 1, 1)]
+    pub byte_check: ByteCheck,
     
     #[pkd(7, 0, 2, 5)]
     pub lba: u32,
@@ -31,4 +40,68 @@ pub struct Verify10Command {
     #[pkd(7, 0, 9, 9)]
     pub control: Control,
 }
-impl ParsePackedStruct for Verify10Command {}
\ No newline at end of file
+impl ParsePackedStruct for Verify10Command {}
+impl From<Verify10Command> for VerifyXCommand {
+    fn from(v: Verify10Command) -> Self {
+        Self {
+            lba: v.lba.into(),
+            verification_length: v.verification_length.into(),
+            byte_check: v.byte_check,
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct Verify16Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+    
+    #[pkd(7, 5, 1, 1)]
+    pub vr_protect: u8,
+    
+    #[pkd(4, 4, 1, 1)]
+    pub dpo: bool,
+    
+    #[pkd(2, 1, 1, 1)]
+    pub byte_check: ByteCheck,
+    
+    #[pkd(7, 0, 2, 9)]
+    pub lba: u64,
+    
+    #[pkd(7, 0, 10, 13)]
+    pub verification_length: u32,
+    
+    #[pkd(4, 0, 14, 14)]
+    pub group_number: u8,
+    
+    #[pkd(7, 0, 15, 15)]
+    pub control: Control,
+}
+impl ParsePackedStruct for Verify16Command {}
+impl From<Verify16Command> for VerifyXCommand {
+    fn from(v: Verify16Command) -> Self {
+        Self {
+            lba: v.lba,
+            verification_length: v.verification_length,
+            byte_check: v.byte_check,
+        }
+    }
+}
+
+
+#[test]
+fn test_verify_parse() {
+    let data = [0x2F, 0x02, 0, 0, 0x12, 0x34, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0];
+    let cmd: VerifyXCommand = Verify10Command::parse(&data).unwrap().into();
+    assert_eq!(cmd, VerifyXCommand { lba: 0x1234, verification_length: 0x10, byte_check: ByteCheck::Compare });
+
+    let data = [0x8F, 0x06, 0, 0, 0, 0x01, 0, 0, 0, 0, 0, 0, 0, 0x02, 0, 0];
+    let cmd: VerifyXCommand = Verify16Command::parse(&data).unwrap().into();
+    assert_eq!(cmd, VerifyXCommand { lba: 0x01_0000_0000, verification_length: 2, byte_check: ByteCheck::CompareSingleBlock });
+
+    // 0b10 is reserved
+    let data = [0x2F, 0x04, 0, 0, 0, 0, 0, 0, 0x01, 0, 0, 0, 0, 0, 0, 0];
+    assert!(Verify10Command::parse(&data).is_err());
+}
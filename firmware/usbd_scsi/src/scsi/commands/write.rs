@@ -6,8 +6,12 @@ use crate::scsi::{
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct WriteXCommand {
-    pub lba: u32,
+    pub lba: u64,
+    /// Number of blocks
     pub transfer_length: u32,
+    /// The data has to be on the medium before the command completes, even with the write
+    /// cache enabled
+    pub force_unit_access: bool,
 }
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
@@ -30,7 +34,12 @@ impl From<Write6Command> for WriteXCommand {
     fn from(w: Write6Command) -> Self {
         Self {
             lba: w.lba.into(),
-            transfer_length: w.transfer_length.into(),
+            // A transfer length of 0 means 256 blocks for the 6 byte command
+            transfer_length: match w.transfer_length {
+                0 => 256,
+                l => l.into(),
+            },
+            force_unit_access: false,
         }
     }
 }
@@ -71,6 +80,7 @@ impl From<Write10Command> for WriteXCommand {
         Self {
             lba: w.lba.into(),
             transfer_length: w.transfer_length.into(),
+            force_unit_access: w.fua,
         }
     }
 }
@@ -111,6 +121,49 @@ impl From<Write12Command> for WriteXCommand {
         Self {
             lba: w.lba.into(),
             transfer_length: w.transfer_length.into(),
+            force_unit_access: w.fua,
         }
     }
-}
\ No newline at end of file
+}
+
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct Write16Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(7, 5, 1, 1)]
+    pub wr_protect: u8,
+
+    #[pkd(4, 4, 1, 1)]
+    pub dpo: bool,
+
+    #[pkd(3, 3, 1, 1)]
+    pub fua: bool,
+
+    #[pkd(1, 1, 1, 1)]
+    pub fua_nv: bool,
+
+    #[pkd(7, 0, 2, 9)]
+    pub lba: u64,
+
+    #[pkd(7, 0, 10, 13)]
+    pub transfer_length: u32,
+
+    #[pkd(4, 0, 14, 14)]
+    pub group_number: u8,
+
+    #[pkd(7, 0, 15, 15)]
+    pub control: Control,
+}
+impl ParsePackedStruct for Write16Command {}
+impl From<Write16Command> for WriteXCommand {
+    fn from(w: Write16Command) -> Self {
+        Self {
+            lba: w.lba,
+            transfer_length: w.transfer_length,
+            force_unit_access: w.fua,
+        }
+    }
+}
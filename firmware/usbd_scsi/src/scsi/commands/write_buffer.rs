@@ -0,0 +1,44 @@
+use packing::Packed;
+use crate::scsi::{
+    packing::ParsePackedStruct,
+    commands::Control,
+};
+
+/// SPC-4 6.49
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct WriteBufferCommand {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(7, 5, 1, 1)]
+    pub mode_specific: u8,
+
+    /// See [WriteBufferMode](enum.WriteBufferMode.html)
+    #[pkd(4, 0, 1, 1)]
+    pub mode: u8,
+
+    #[pkd(7, 0, 2, 2)]
+    pub buffer_id: u8,
+
+    #[pkd(7, 0, 3, 5)]
+    pub buffer_offset: u32,
+
+    #[pkd(7, 0, 6, 8)]
+    pub parameter_list_length: u32,
+
+    #[pkd(7, 0, 9, 9)]
+    pub control: Control,
+}
+impl ParsePackedStruct for WriteBufferCommand {}
+
+
+#[test]
+fn test_write_buffer_parse() {
+    let data = [0x3B, 0x07, 0, 0x01, 0x02, 0x00, 0, 0x10, 0x00, 0];
+    let cmd = WriteBufferCommand::parse(&data).unwrap();
+    assert_eq!(cmd.mode, 0x07);
+    assert_eq!(cmd.buffer_id, 0);
+    assert_eq!(cmd.buffer_offset, 0x010200);
+    assert_eq!(cmd.parameter_list_length, 0x1000);
+}
@@ -0,0 +1,110 @@
+use packing::Packed;
+use crate::scsi::{
+    packing::ParsePackedStruct,
+    commands::Control,
+};
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct WriteSameXCommand {
+    pub lba: u64,
+    /// Number of blocks, 0 means to the end of the medium
+    pub number_of_blocks: u32,
+    /// The blocks can be unmapped (discarded) instead of written
+    pub unmap: bool,
+    /// There's no data out, the blocks are written with zeros
+    pub no_data_out: bool,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct WriteSame10Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(7, 5, 1, 1)]
+    pub wr_protect: u8,
+
+    #[pkd(4, 4, 1, 1)]
+    pub anchor: bool,
+
+    #[pkd(3, 3, 1, 1)]
+    pub unmap: bool,
+
+    #[pkd(7, 0, 2, 5)]
+    pub lba: u32,
+
+    #[pkd(4, 0, 6, 6)]
+    pub group_number: u8,
+
+    #[pkd(7, 0, 7, 8)]
+    pub number_of_blocks: u16,
+
+    #[pkd(7, 0, 9, 9)]
+    pub control: Control,
+}
+impl ParsePackedStruct for WriteSame10Command {}
+impl From<WriteSame10Command> for WriteSameXCommand {
+    fn from(w: WriteSame10Command) -> Self {
+        Self {
+            lba: w.lba.into(),
+            number_of_blocks: w.number_of_blocks.into(),
+            unmap: w.unmap,
+            no_data_out: false,
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct WriteSame16Command {
+    #[pkd(7, 0, 0, 0)]
+    pub op_code: u8,
+
+    #[pkd(7, 5, 1, 1)]
+    pub wr_protect: u8,
+
+    #[pkd(4, 4, 1, 1)]
+    pub anchor: bool,
+
+    #[pkd(3, 3, 1, 1)]
+    pub unmap: bool,
+
+    #[pkd(0, 0, 1, 1)]
+    pub no_data_out_buffer: bool,
+
+    #[pkd(7, 0, 2, 9)]
+    pub lba: u64,
+
+    #[pkd(7, 0, 10, 13)]
+    pub number_of_blocks: u32,
+
+    #[pkd(4, 0, 14, 14)]
+    pub group_number: u8,
+
+    #[pkd(7, 0, 15, 15)]
+    pub control: Control,
+}
+impl ParsePackedStruct for WriteSame16Command {}
+impl From<WriteSame16Command> for WriteSameXCommand {
+    fn from(w: WriteSame16Command) -> Self {
+        Self {
+            lba: w.lba,
+            number_of_blocks: w.number_of_blocks,
+            unmap: w.unmap,
+            no_data_out: w.no_data_out_buffer,
+        }
+    }
+}
+
+
+#[test]
+fn test_write_same_parse() {
+    let data = [0x41, 0x08, 0, 0, 0x12, 0x34, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0];
+    let cmd: WriteSameXCommand = WriteSame10Command::parse(&data).unwrap().into();
+    assert_eq!(cmd, WriteSameXCommand { lba: 0x1234, number_of_blocks: 0x10, unmap: true, no_data_out: false });
+
+    let data = [0x93, 0x09, 0, 0, 0, 0x01, 0, 0, 0, 0, 0, 0, 0, 0x02, 0, 0];
+    let cmd: WriteSameXCommand = WriteSame16Command::parse(&data).unwrap().into();
+    assert_eq!(cmd, WriteSameXCommand { lba: 0x01_0000_0000, number_of_blocks: 2, unmap: true, no_data_out: true });
+}
@@ -16,6 +16,36 @@ pub enum AdditionalSenseCode {
     EraseFailure,
     /// ASC 0x21, ASCQ: 0x0 - LOGICAL BLOCK ADDRESS OUT OF RANGE
     LogicalBlockAddressOutOfRange,
+    /// ASC 0x25, ASCQ: 0x0 - LOGICAL UNIT NOT SUPPORTED
+    LogicalUnitNotSupported,
+    /// ASC 0x26, ASCQ: 0x0 - INVALID FIELD IN PARAMETER LIST
+    InvalidFieldInParameterList,
+    /// ASC 0x1A, ASCQ: 0x0 - PARAMETER LIST LENGTH ERROR
+    ParameterListLengthError,
+    /// ASC 0x39, ASCQ: 0x0 - SAVING PARAMETERS NOT SUPPORTED
+    SavingParametersNotSupported,
+    /// ASC 0x27, ASCQ: 0x0 - WRITE PROTECTED
+    WriteProtected,
+    /// ASC 0x4, ASCQ: 0x7 - LOGICAL UNIT NOT READY, OPERATION IN PROGRESS
+    LogicalUnitNotReadyOperationInProgress,
+    /// ASC 0x1D, ASCQ: 0x0 - MISCOMPARE DURING VERIFY OPERATION
+    MiscompareDuringVerifyOperation,
+    /// ASC 0x3A, ASCQ: 0x0 - MEDIUM NOT PRESENT
+    MediumNotPresent,
+    /// ASC 0x28, ASCQ: 0x0 - NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED
+    NotReadyToReadyChangeMediumMayHaveChanged,
+    /// ASC 0x29, ASCQ: 0x0 - POWER ON, RESET, OR BUS DEVICE RESET OCCURRED
+    PowerOnResetOrBusDeviceResetOccurred,
+    /// ASC 0x53, ASCQ: 0x2 - MEDIUM REMOVAL PREVENTED
+    MediumRemovalPrevented,
+    /// ASC 0x4, ASCQ: 0x4 - LOGICAL UNIT NOT READY, FORMAT IN PROGRESS
+    LogicalUnitNotReadyFormatInProgress,
+    /// ASC 0x31, ASCQ: 0x0 - MEDIUM FORMAT CORRUPTED
+    MediumFormatCorrupted,
+    /// ASC 0x31, ASCQ: 0x1 - FORMAT COMMAND FAILED
+    FormatCommandFailed,
+    /// ASC 0x5D, ASCQ: 0x0 - FAILURE PREDICTION THRESHOLD EXCEEDED
+    FailurePredictionThresholdExceeded,
 }
 
 impl AdditionalSenseCode {
@@ -29,6 +59,21 @@ impl AdditionalSenseCode {
             AdditionalSenseCode::WriteError => 12,
             AdditionalSenseCode::EraseFailure => 81,
             AdditionalSenseCode::LogicalBlockAddressOutOfRange => 33,
+            AdditionalSenseCode::LogicalUnitNotSupported => 37,
+            AdditionalSenseCode::InvalidFieldInParameterList => 38,
+            AdditionalSenseCode::ParameterListLengthError => 26,
+            AdditionalSenseCode::SavingParametersNotSupported => 57,
+            AdditionalSenseCode::WriteProtected => 39,
+            AdditionalSenseCode::LogicalUnitNotReadyOperationInProgress => 4,
+            AdditionalSenseCode::MiscompareDuringVerifyOperation => 29,
+            AdditionalSenseCode::MediumNotPresent => 58,
+            AdditionalSenseCode::NotReadyToReadyChangeMediumMayHaveChanged => 40,
+            AdditionalSenseCode::PowerOnResetOrBusDeviceResetOccurred => 41,
+            AdditionalSenseCode::MediumRemovalPrevented => 83,
+            AdditionalSenseCode::LogicalUnitNotReadyFormatInProgress => 4,
+            AdditionalSenseCode::MediumFormatCorrupted => 49,
+            AdditionalSenseCode::FormatCommandFailed => 49,
+            AdditionalSenseCode::FailurePredictionThresholdExceeded => 93,
         }
     }
     /// Returns the ASCQ code for this variant
@@ -41,6 +86,21 @@ impl AdditionalSenseCode {
             AdditionalSenseCode::WriteError => 0,
             AdditionalSenseCode::EraseFailure => 0,
             AdditionalSenseCode::LogicalBlockAddressOutOfRange => 0,
+            AdditionalSenseCode::LogicalUnitNotSupported => 0,
+            AdditionalSenseCode::InvalidFieldInParameterList => 0,
+            AdditionalSenseCode::ParameterListLengthError => 0,
+            AdditionalSenseCode::SavingParametersNotSupported => 0,
+            AdditionalSenseCode::WriteProtected => 0,
+            AdditionalSenseCode::LogicalUnitNotReadyOperationInProgress => 7,
+            AdditionalSenseCode::MiscompareDuringVerifyOperation => 0,
+            AdditionalSenseCode::MediumNotPresent => 0,
+            AdditionalSenseCode::NotReadyToReadyChangeMediumMayHaveChanged => 0,
+            AdditionalSenseCode::PowerOnResetOrBusDeviceResetOccurred => 0,
+            AdditionalSenseCode::MediumRemovalPrevented => 2,
+            AdditionalSenseCode::LogicalUnitNotReadyFormatInProgress => 4,
+            AdditionalSenseCode::MediumFormatCorrupted => 0,
+            AdditionalSenseCode::FormatCommandFailed => 1,
+            AdditionalSenseCode::FailurePredictionThresholdExceeded => 0,
         }
     }
     /// Returns the ASCQ code for this variant
@@ -53,6 +113,21 @@ impl AdditionalSenseCode {
             (12, 0) => Some(AdditionalSenseCode::WriteError),
             (81, 0) => Some(AdditionalSenseCode::EraseFailure),
             (33, 0) => Some(AdditionalSenseCode::LogicalBlockAddressOutOfRange),
+            (37, 0) => Some(AdditionalSenseCode::LogicalUnitNotSupported),
+            (38, 0) => Some(AdditionalSenseCode::InvalidFieldInParameterList),
+            (26, 0) => Some(AdditionalSenseCode::ParameterListLengthError),
+            (57, 0) => Some(AdditionalSenseCode::SavingParametersNotSupported),
+            (39, 0) => Some(AdditionalSenseCode::WriteProtected),
+            (4, 7) => Some(AdditionalSenseCode::LogicalUnitNotReadyOperationInProgress),
+            (29, 0) => Some(AdditionalSenseCode::MiscompareDuringVerifyOperation),
+            (58, 0) => Some(AdditionalSenseCode::MediumNotPresent),
+            (40, 0) => Some(AdditionalSenseCode::NotReadyToReadyChangeMediumMayHaveChanged),
+            (41, 0) => Some(AdditionalSenseCode::PowerOnResetOrBusDeviceResetOccurred),
+            (83, 2) => Some(AdditionalSenseCode::MediumRemovalPrevented),
+            (4, 4) => Some(AdditionalSenseCode::LogicalUnitNotReadyFormatInProgress),
+            (49, 0) => Some(AdditionalSenseCode::MediumFormatCorrupted),
+            (49, 1) => Some(AdditionalSenseCode::FormatCommandFailed),
+            (93, 0) => Some(AdditionalSenseCode::FailurePredictionThresholdExceeded),
             _ => None,
         }
     }
@@ -0,0 +1,25 @@
+use packing::Packed;
+
+/// Supported READ BUFFER modes
+///
+/// SPC-4 6.15.1
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum ReadBufferMode {
+    /// The firmware image, from the buffer offset
+    Data = 0x02,
+    /// The capacity and offset boundary of the firmware image
+    Descriptor = 0x03,
+}
+
+/// Supported WRITE BUFFER modes, all of them download firmware
+///
+/// SPC-4 6.49.1
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum WriteBufferMode {
+    /// The whole image in one command, saved then activated
+    DownloadMicrocode = 0x05,
+    /// A segment of the image at the buffer offset, activated once the download is complete
+    WithOffsets = 0x06,
+    /// Same as above, saved after each segment
+    WithOffsetsAndSave = 0x07,
+}
@@ -0,0 +1,12 @@
+use packing::Packed;
+
+/// BYTCHK field of the VERIFY commands
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum ByteCheck {
+    /// Check the blocks can be read, there's no data stage
+    None = 0b00,
+    /// Compare each block with the data sent by the host
+    Compare = 0b01,
+    /// Compare each block with a single block of data sent by the host
+    CompareSingleBlock = 0b11,
+}
@@ -0,0 +1,12 @@
+use packing::Packed;
+
+/// What the current/maximum capacity descriptor returned by READ FORMAT CAPACITIES describes
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum CapacityDescriptorType {
+    /// The medium isn't formatted, the capacity is the most it can be formatted to
+    UnformattedMedia = 0b01,
+    /// The capacity of the formatted medium
+    FormattedMedia = 0b10,
+    /// There's no medium, the capacity is the most that can be formatted
+    NoMediaPresent = 0b11,
+}
@@ -3,6 +3,13 @@ use packing::Packed;
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 pub enum MediumType {
     Sbc = 0x00,
+    // The floppy types reported by UFI devices, UFI 4.5
+    /// 720 KB, 1440 512 byte blocks
+    Floppy720K = 0x1E,
+    /// 1.25 MB, 1232 1024 byte blocks
+    Floppy1M25 = 0x93,
+    /// 1.44 MB, 2880 512 byte blocks
+    Floppy1M44 = 0x94,
 }
 
 impl Default for MediumType {
@@ -32,4 +32,19 @@ mod spc_version;
 pub use spc_version::*;
 
 mod response_data_format;
-pub use response_data_format::*;
\ No newline at end of file
+pub use response_data_format::*;
+
+mod vital_product_data_page;
+pub use vital_product_data_page::*;
+
+mod service_action_in_16;
+pub use service_action_in_16::*;
+
+mod byte_check;
+pub use byte_check::*;
+
+mod capacity_descriptor_type;
+pub use capacity_descriptor_type::*;
+
+mod buffer_mode;
+pub use buffer_mode::*;
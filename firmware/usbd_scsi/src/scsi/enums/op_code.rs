@@ -4,6 +4,8 @@ use packing::Packed;
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 pub enum OpCode {
     TestUnitReady = 0x00,
+    /// Obsolete in SBC, UFI still has it
+    RezeroUnit = 0x01,
     RequestSense = 0x03,
     Format = 0x04,
     Read6 = 0x08,
@@ -22,10 +24,24 @@ pub enum OpCode {
     PreventAllowMediumRemoval = 0x1E,
     ReadFormatCapacities = 0x23,
     Write10 = 0x2A,
+    /// Obsolete in SBC, UFI still has it
+    Seek10 = 0x2B,
+    WriteAndVerify10 = 0x2E,
     Verify10 = 0x2F,
     SynchronizeCache10 = 0x35,
     ReadTocPmaAtip = 0x43,
     ModeSelect10 = 0x55,
     Read12 = 0xA8,
     Write12 = 0xAA,
+    Read16 = 0x88,
+    Write16 = 0x8A,
+    ServiceActionIn16 = 0x9E,
+    Verify16 = 0x8F,
+    SynchronizeCache16 = 0x91,
+    WriteSame10 = 0x41,
+    WriteSame16 = 0x93,
+    Unmap = 0x42,
+    WriteBuffer = 0x3B,
+    ReadBuffer = 0x3C,
+    LogSense = 0x4D,
 }
\ No newline at end of file
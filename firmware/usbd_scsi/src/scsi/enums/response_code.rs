@@ -1,12 +1,20 @@
 use packing::Packed;
 
+// Named after the response codes in SPC, which are all kinds of sense data
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 pub enum ResponseCode {
+    /// Fixed format sense data for the current command
     FixedSenseData = 0x70,
+    /// Fixed format sense data for an error from an earlier command (e.g. a cached write)
+    DeferredFixedSenseData = 0x71,
+    /// Descriptor format sense data for the current command
     DescriptorSenseData = 0x72,    
+    /// Descriptor format sense data for an error from an earlier command
+    DeferredDescriptorSenseData = 0x73,
 }
 impl Default for ResponseCode {
     fn default() -> Self {
         ResponseCode::FixedSenseData
     }
-}
\ No newline at end of file
+}
@@ -4,6 +4,8 @@ use packing::Packed;
 pub enum ResponseDataFormat {
     /// A RESPONSE DATA FORMAT field set to 2h indicates that the standard INQUIRY data
     Standard = 0x2,
+    /// UFI devices report 1h, the obsolete SCSI-2 format
+    Ufi = 0x1,
 }
 
 impl Default for ResponseDataFormat {
@@ -0,0 +1,8 @@
+use packing::Packed;
+
+/// Service actions of the SERVICE ACTION IN(16) op code (0x9E)
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum ServiceActionIn16 {
+    /// SBC-3 5.16
+    ReadCapacity16 = 0x10,
+}
@@ -0,0 +1,18 @@
+use packing::Packed;
+
+/// Vital product data pages returned by INQUIRY when the EVPD bit is set
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum VitalProductDataPage {
+    /// List of the VPD pages supported (SPC-4 7.8.16)
+    SupportedVpdPages = 0x00,
+    /// ASCII serial number of the logical unit (SPC-4 7.8.17)
+    UnitSerialNumber = 0x80,
+    /// Designators that uniquely identify the logical unit (SPC-4 7.8.6)
+    DeviceIdentification = 0x83,
+    /// Limits on transfer lengths etc (SBC-3 6.5.3)
+    BlockLimits = 0xB0,
+    /// Medium rotation rate and form factor (SBC-3 6.5.2)
+    BlockDeviceCharacteristics = 0xB1,
+    /// Which commands can unmap blocks (SBC-3 6.5.4)
+    LogicalBlockProvisioning = 0xB2,
+}
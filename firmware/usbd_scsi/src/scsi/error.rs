@@ -1,18 +1,63 @@
 use packing::Error as PackingError;
 use usbd_bulk_only_transport::Error as BulkOnlyTransportError;
 use usb_device::UsbError;
-use crate::block_device::BlockDeviceError;
+use crate::{
+    block_device::BlockDeviceError,
+    command_handler::CommandHandlerError,
+    mode_pages::ModePageError,
+    scsi::UnitAttention,
+};
 
 #[derive(Debug)]
 pub enum Error {
     UnhandledOpCode,
     /// The identified opcode requires more data than was sent
     InsufficientDataForCommand,
+    /// A field in the command block has a value that isn't supported
+    InvalidFieldInCommand,
+    /// The CBW addressed a LUN that doesn't exist
+    LogicalUnitNotSupported,
+    /// A field in the parameter data sent by the host has a value that isn't supported
+    InvalidFieldInParameterList,
+    /// The parameter list length doesn't agree with the parameter data
+    ParameterListLength,
+    /// The block device is write protected
+    WriteProtected,
+    /// Saved values were requested or the host asked for parameters to be saved but they
+    /// can't be
+    SavingParametersNotSupported,
+    /// The data sent with a VERIFY command didn't match the medium. Contains the offset of the 
+    /// first byte that didn't match from the start of the data
+    Miscompare(u64),
+    /// The command needs the medium and it isn't present
+    MediumNotPresent,
+    /// The host tried to eject the medium after preventing its removal
+    MediumRemovalPrevented,
+    /// A unit attention condition was waiting to be reported
+    UnitAttention(UnitAttention),
+    /// The medium is being formatted. Contains the progress if the block device reports it
+    FormatInProgress(Option<u16>),
+    /// The last format failed so the medium can't be used until it's formatted again
+    MediumFormatCorrupted,
+    /// The block device failed to format the medium
+    FormatFailed,
     PackingError(PackingError),
     BlockDeviceError(BlockDeviceError),
     BulkOnlyTransportError(BulkOnlyTransportError),
 }
 
+impl From<CommandHandlerError> for Error {
+    fn from(e: CommandHandlerError) -> Error {
+        match e {
+            CommandHandlerError::UnsupportedOpCode => Error::UnhandledOpCode,
+            CommandHandlerError::InvalidFieldInCommand => Error::InvalidFieldInCommand,
+            CommandHandlerError::InvalidFieldInParameterList => Error::InvalidFieldInParameterList,
+            CommandHandlerError::HardwareError => Error::BlockDeviceError(BlockDeviceError::HardwareError),
+            CommandHandlerError::BulkOnlyTransportError(e) => Error::BulkOnlyTransportError(e),
+        }
+    }
+}
+
 impl From<PackingError> for Error {
     fn from(e: PackingError) -> Error {
         Error::PackingError(e)
@@ -25,6 +70,17 @@ impl From<BlockDeviceError> for Error {
     }
 }
 
+impl From<ModePageError> for Error {
+    fn from(e: ModePageError) -> Error {
+        match e {
+            ModePageError::SavingParametersNotSupported => Error::SavingParametersNotSupported,
+            ModePageError::InvalidField => Error::InvalidFieldInParameterList,
+            // Only MODE SENSE fills in pages, the one asked for can't be reported
+            ModePageError::InsufficientSpace => Error::InvalidFieldInCommand,
+        }
+    }
+}
+
 impl From<BulkOnlyTransportError> for Error {
     fn from(e: BulkOnlyTransportError) -> Error {
         Error::BulkOnlyTransportError(e)
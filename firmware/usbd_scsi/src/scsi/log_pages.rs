@@ -0,0 +1,175 @@
+use packing::{
+    Packed,
+    PackedSize,
+};
+
+use crate::{
+    block_device::BlockDevice,
+    device_health::ErrorCounter,
+    logical_unit::LogicalUnit,
+    scsi::{
+        commands::*,
+        enums::AdditionalSenseCode,
+        Error,
+    },
+};
+
+/// Log pages in the order they're listed in the supported log pages page
+const SUPPORTED_LOG_PAGES: [LogPageCode; 7] = [
+    LogPageCode::SupportedLogPages,
+    LogPageCode::WriteErrorCounter,
+    LogPageCode::ReadErrorCounter,
+    LogPageCode::VerifyErrorCounter,
+    LogPageCode::Temperature,
+    LogPageCode::SelfTestResults,
+    LogPageCode::InformationalExceptions,
+];
+
+/// Error counter page parameter codes, SPC-4 7.3.15.2
+const TOTAL_ERRORS_CORRECTED: u16 = 0x0003;
+const TOTAL_BYTES_PROCESSED: u16 = 0x0005;
+const TOTAL_UNCORRECTED_ERRORS: u16 = 0x0006;
+
+/// Temperature page parameter codes, SPC-4 7.3.20.2
+const TEMPERATURE: u16 = 0x0000;
+const REFERENCE_TEMPERATURE: u16 = 0x0001;
+
+/// Reported in place of a temperature the device doesn't know
+const TEMPERATURE_NOT_AVAILABLE: u8 = 0xFF;
+
+/// The self-test results page always has this many entries, numbered from 1
+const SELF_TEST_RESULTS: u16 = 20;
+const SELF_TEST_RESULT_BYTES: usize = 16;
+
+/// Informational exceptions page parameter code, SPC-4 7.3.8.2
+const INFORMATIONAL_EXCEPTIONS_GENERAL: u16 = 0x0000;
+
+/// Large enough for the longest page, the self-test results
+pub(crate) const LOG_SENSE_BUFFER_BYTES: usize = LogPageHeader::BYTES
+    + SELF_TEST_RESULTS as usize * (LogParameterHeader::BYTES + SELF_TEST_RESULT_BYTES);
+
+/// Packs the LOG SENSE response for `command` into `buf`, returning the number of bytes used
+pub(crate) fn log_sense<BD: BlockDevice>(
+    lu: &LogicalUnit<BD>,
+    command: LogSenseCommand,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    // Nothing is saved or reset, there are no thresholds and no subpages
+    if command.parameter_pointer_control || command.save_parameters || command.subpage_code != 0 {
+        Err(Error::InvalidFieldInCommand)?;
+    }
+    if command.page_control != LOG_CUMULATIVE_VALUES {
+        Err(Error::InvalidFieldInCommand)?;
+    }
+    let page_code = LogPageCode::from_primitive(command.page_code)
+        .map_err(|_| Error::InvalidFieldInCommand)?;
+
+    let health = lu.block_device.health();
+    let temperature = health.temperature.unwrap_or(TEMPERATURE_NOT_AVAILABLE);
+
+    let (header, rest) = buf.split_at_mut(LogPageHeader::BYTES);
+    let mut parameters = LogParameters {
+        buf: rest,
+        len: 0,
+        parameter_pointer: command.parameter_pointer,
+        highest: None,
+    };
+
+    match page_code {
+        // Just a list of page codes, there are no parameters
+        LogPageCode::SupportedLogPages => {
+            for (b, page) in parameters.buf.iter_mut().zip(SUPPORTED_LOG_PAGES.iter()) {
+                *b = *page as u8;
+            }
+            parameters.len = SUPPORTED_LOG_PAGES.len();
+        },
+        LogPageCode::WriteErrorCounter => {
+            error_counter(&mut parameters, lu.error_counters.write, health.write_errors_corrected)?;
+        },
+        LogPageCode::ReadErrorCounter => {
+            error_counter(&mut parameters, lu.error_counters.read, health.read_errors_corrected)?;
+        },
+        LogPageCode::VerifyErrorCounter => {
+            error_counter(&mut parameters, lu.error_counters.verify, health.verify_errors_corrected)?;
+        },
+        LogPageCode::Temperature => {
+            let reference = health.reference_temperature.unwrap_or(TEMPERATURE_NOT_AVAILABLE);
+            parameters.push(TEMPERATURE, LOG_BINARY_FORMAT_LIST, &[0, temperature])?;
+            parameters.push(REFERENCE_TEMPERATURE, LOG_BINARY_FORMAT_LIST, &[0, reference])?;
+        },
+        // SEND DIAGNOSTIC self-tests aren't supported so the entries are all empty
+        LogPageCode::SelfTestResults => {
+            for parameter_code in 1..=SELF_TEST_RESULTS {
+                parameters.push(parameter_code, LOG_BINARY_FORMAT_LIST, &[0; SELF_TEST_RESULT_BYTES])?;
+            }
+        },
+        LogPageCode::InformationalExceptions => {
+            let additional_sense_code = if health.failure_predicted {
+                AdditionalSenseCode::FailurePredictionThresholdExceeded
+            } else {
+                AdditionalSenseCode::NoAdditionalSenseInformation
+            };
+            let value = [additional_sense_code.asc(), additional_sense_code.ascq(), temperature];
+            parameters.push(INFORMATIONAL_EXCEPTIONS_GENERAL, LOG_BINARY_FORMAT_LIST, &value)?;
+        },
+    }
+
+    // Pointing past the last parameter is an error rather than an empty page
+    if command.parameter_pointer > parameters.highest.unwrap_or(0) {
+        Err(Error::InvalidFieldInCommand)?;
+    }
+
+    let page_length = parameters.len;
+    LogPageHeader {
+        disable_save: true,
+        page_code: page_code as u8,
+        page_length: page_length as u16,
+        ..Default::default()
+    }.pack(header)?;
+
+    Ok(LogPageHeader::BYTES + page_length)
+}
+
+/// Adds the parameters of a read, write or verify error counter page
+fn error_counter(
+    parameters: &mut LogParameters,
+    counter: ErrorCounter,
+    errors_corrected: u32,
+) -> Result<(), Error> {
+    parameters.push(TOTAL_ERRORS_CORRECTED, LOG_BOUNDED_DATA_COUNTER, &errors_corrected.to_be_bytes())?;
+    parameters.push(TOTAL_BYTES_PROCESSED, LOG_BOUNDED_DATA_COUNTER, &counter.bytes_processed.to_be_bytes())?;
+    parameters.push(TOTAL_UNCORRECTED_ERRORS, LOG_BOUNDED_DATA_COUNTER, &counter.uncorrected_errors.to_be_bytes())
+}
+
+/// Packs the parameters of a log page, leaving out any before the parameter pointer
+struct LogParameters<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+    parameter_pointer: u16,
+    /// Highest parameter code in the page so far, left out or not
+    highest: Option<u16>,
+}
+
+impl LogParameters<'_> {
+    /// Parameters have to be pushed in ascending order
+    fn push(&mut self, parameter_code: u16, format_and_linking: u8, value: &[u8]) -> Result<(), Error> {
+        self.highest = Some(parameter_code);
+        if parameter_code < self.parameter_pointer {
+            return Ok(());
+        }
+
+        let start = self.len + LogParameterHeader::BYTES;
+        let end = start + value.len();
+        LogParameterHeader {
+            parameter_code,
+            // Nothing is saved
+            target_save_disable: true,
+            format_and_linking,
+            parameter_length: value.len() as u8,
+            ..Default::default()
+        }.pack(&mut self.buf[self.len..start])?;
+        self.buf[start..end].copy_from_slice(value);
+        self.len = end;
+        Ok(())
+    }
+}
@@ -1,10 +1,42 @@
 mod commands;
+pub use commands::{
+    CachingModePage,
+    InformationalExceptionsControlModePage,
+    PageCode,
+    ReadWriteErrorRecoveryModePage,
+};
 mod responses;
+pub(crate) use responses::{
+    InquiryResponse,
+    SerialNumber,
+};
 mod enums;
+pub use enums::PageControl;
 mod packing;
 
+mod sense_data;
+pub(crate) use sense_data::{
+    SenseData,
+    SHORT_SENSE_BYTES,
+};
+
+mod unit_attention;
+pub(crate) use unit_attention::{
+    UnitAttention,
+    UnitAttentionQueue,
+};
+
 mod error;
 use error::Error;
 
+mod mode_parameters;
+
+mod log_pages;
+
+mod command_profile;
+pub use command_profile::CommandProfile;
+
+mod ufi;
+
 mod scsi;
-pub use scsi::Scsi;
\ No newline at end of file
+pub use scsi::Scsi;
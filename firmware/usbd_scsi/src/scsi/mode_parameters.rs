@@ -0,0 +1,327 @@
+use packing::{
+    Packed,
+    PackedSize,
+};
+
+use crate::{
+    block_device::BlockDevice,
+    logical_unit::LogicalUnit,
+    mode_pages::ModePageError,
+    scsi::{
+        commands::*,
+        enums::PageControl,
+        Error,
+    },
+};
+
+/// Large enough for the longest possible MODE SENSE(6) response. MODE SENSE(10) responses
+/// are limited to the same length
+pub(crate) const MODE_SENSE_BUFFER_BYTES: usize = 256;
+
+/// Page header plus the largest page length
+const MAX_PAGE_BYTES: usize = 2 + u8::MAX as usize;
+
+/// Page code that requests all pages
+pub(crate) const ALL_PAGES: u8 = 0x3F;
+
+/// Subpage code that requests all subpages
+const ALL_SUBPAGES: u8 = 0xFF;
+
+/// Subpage format bit in the first byte of a page
+const SUBPAGE_FORMAT: u8 = 0x40;
+
+/// Packs the MODE SENSE response for `command` into `buf`, returning the number of bytes used
+pub(crate) fn mode_sense<BD: BlockDevice>(
+    lu: &mut LogicalUnit<BD>,
+    command: ModeSenseXCommand,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let header_bytes = match command.command_length {
+        CommandLength::C6 => ModeParameterHeader6::BYTES,
+        CommandLength::C10 => ModeParameterHeader10::BYTES,
+    };
+    let mut i = header_bytes;
+
+    if !command.disable_block_descriptors {
+        let number_of_blocks = lu.block_device.max_lba().saturating_add(1);
+        let logical_block_length = BD::BLOCK_BYTES as u32;
+
+        // Long LBA accepted is only ever set for MODE SENSE(10)
+        if command.long_lba_accepted {
+            let end = i + LongLbaModeParameterBlockDescriptor::BYTES;
+            LongLbaModeParameterBlockDescriptor::new(number_of_blocks, logical_block_length)
+                .pack(&mut buf[i..end])?;
+            i = end;
+        } else {
+            let end = i + ShortLbaModeParameterBlockDescriptor::BYTES;
+            ShortLbaModeParameterBlockDescriptor::new(number_of_blocks, logical_block_length)
+                .pack(&mut buf[i..end])?;
+            i = end;
+        }
+    }
+    let block_descriptor_length = i - header_bytes;
+
+    // There are no subpages so all subpages is the same as just the page
+    if command.subpage_code != 0 && command.subpage_code != ALL_SUBPAGES {
+        Err(Error::InvalidFieldInCommand)?;
+    }
+
+    // Each page is packed on its own first so one that doesn't fit is never half written
+    let mut page = [0; MAX_PAGE_BYTES];
+    if command.page_code == ALL_PAGES {
+        // Page 0 is vendor specific and has to come last, we don't have one
+        for page_code in 1..ALL_PAGES {
+            if let Some(len) = sense_page(lu, page_code, command.page_control, &mut page)? {
+                // Out of room, the rest of the pages are left out
+                match buf.get_mut(i..i + len) {
+                    Some(dest) => dest.copy_from_slice(&page[..len]),
+                    None => break,
+                }
+                i += len;
+            }
+        }
+    } else {
+        let len = sense_page(lu, command.page_code, command.page_control, &mut page)?
+            .ok_or(Error::InvalidFieldInCommand)?;
+        buf.get_mut(i..i + len)
+            .ok_or(ModePageError::InsufficientSpace)?
+            .copy_from_slice(&page[..len]);
+        i += len;
+    }
+
+    let device_specific_parameter = SbcDeviceSpecificParameter {
+        write_protect: lu.block_device.is_write_protected(),
+        ..Default::default()
+    };
+
+    // Mode data length doesn't include itself
+    match command.command_length {
+        CommandLength::C6 => ModeParameterHeader6 {
+            mode_data_length: (i - 1) as u8,
+            device_specific_parameter,
+            block_descriptor_length: block_descriptor_length as u8,
+            ..Default::default()
+        }.pack(&mut buf[..header_bytes])?,
+        CommandLength::C10 => ModeParameterHeader10 {
+            mode_data_length: (i - 2) as u16,
+            device_specific_parameter,
+            long_lba: command.long_lba_accepted && !command.disable_block_descriptors,
+            block_descriptor_length: block_descriptor_length as u16,
+            ..Default::default()
+        }.pack(&mut buf[..header_bytes])?,
+    }
+
+    Ok(i)
+}
+
+/// Applies the MODE SELECT parameter list `data` to `lu`
+///
+/// Every page is checked before any are applied so a bad parameter list leaves the current
+/// values untouched.
+pub(crate) fn mode_select<BD: BlockDevice>(
+    lu: &mut LogicalUnit<BD>,
+    command: ModeSelectXCommand,
+    data: &[u8],
+) -> Result<(), Error> {
+    if data.is_empty() {
+        // Nothing to change
+        return Ok(());
+    }
+
+    let (header_bytes, block_descriptor_length, long_lba) = match command.command_length {
+        CommandLength::C6 => {
+            let header = ModeParameterHeader6::unpack(data.get(..ModeParameterHeader6::BYTES)
+                .ok_or(Error::ParameterListLength)?)
+                .map_err(|_| Error::InvalidFieldInParameterList)?;
+            (ModeParameterHeader6::BYTES, header.block_descriptor_length as usize, false)
+        },
+        CommandLength::C10 => {
+            let header = ModeParameterHeader10::unpack(data.get(..ModeParameterHeader10::BYTES)
+                .ok_or(Error::ParameterListLength)?)
+                .map_err(|_| Error::InvalidFieldInParameterList)?;
+            (ModeParameterHeader10::BYTES, header.block_descriptor_length as usize, header.long_lba)
+        },
+    };
+
+    let descriptors = data.get(header_bytes..header_bytes + block_descriptor_length)
+        .ok_or(Error::ParameterListLength)?;
+    check_block_descriptors(lu, descriptors, long_lba)?;
+
+    let pages = &data[header_bytes + block_descriptor_length..];
+    if !pages.is_empty() && !command.page_format {
+        // Vendor specific (SCSI-1) parameters aren't supported
+        Err(Error::InvalidFieldInCommand)?;
+    }
+
+    let mut remaining = pages;
+    while !remaining.is_empty() {
+        let (page, rest) = split_page(remaining)?;
+        check_page(lu, page, command.save_pages)?;
+        remaining = rest;
+    }
+
+    let mut remaining = pages;
+    while !remaining.is_empty() {
+        let (page, rest) = split_page(remaining)?;
+        let page_code = page[0] & ALL_PAGES;
+        let device_page = match lu.block_device.mode_pages() {
+            Some(mode_pages) if mode_pages.has_page(page_code) => {
+                mode_pages.select_page(page, command.save_pages)?;
+                true
+            },
+            _ => false,
+        };
+        // The write cache enabled bit is the only changeable field of the built in pages
+        if !device_page && page_code == PageCode::CachingModePage as u8 {
+            lu.write_cache_enabled = CachingModePage::unpack(page)?.write_cache_enabled;
+        }
+        remaining = rest;
+    }
+
+    Ok(())
+}
+
+/// Returns true if the host has enabled the write cache in the current caching mode page,
+/// whether that's the block device's page or the built in one
+pub(crate) fn write_cache_enabled<BD: BlockDevice>(lu: &mut LogicalUnit<BD>) -> Result<bool, Error> {
+    let mut page = [0; MAX_PAGE_BYTES];
+    sense_page(lu, PageCode::CachingModePage as u8, PageControl::CurrentValues, &mut page)?;
+    Ok(CachingModePage::unpack(&page[..CachingModePage::BYTES])?.write_cache_enabled)
+}
+
+/// Packs page `page_code` into `buf` from the block device or the built in pages
+///
+/// Returns None if there's no such page
+fn sense_page<BD: BlockDevice>(
+    lu: &mut LogicalUnit<BD>,
+    page_code: u8,
+    page_control: PageControl,
+    buf: &mut [u8],
+) -> Result<Option<usize>, Error> {
+    if let Some(mode_pages) = lu.block_device.mode_pages() {
+        if mode_pages.has_page(page_code) {
+            let len = mode_pages.sense_page(page_code, page_control, buf)
+                .map_err(|e| match e {
+                    // Nothing from the parameter list is involved in MODE SENSE
+                    ModePageError::InvalidField => Error::InvalidFieldInCommand,
+                    e => e.into(),
+                })?;
+            if len > buf.len() {
+                Err(ModePageError::InsufficientSpace)?;
+            }
+            return Ok(Some(len));
+        }
+    }
+
+    if page_code == PageCode::CachingModePage as u8 {
+        let page = match page_control {
+            PageControl::CurrentValues => CachingModePage {
+                write_cache_enabled: lu.write_cache_enabled,
+                ..Default::default()
+            },
+            PageControl::DefaultValues => CachingModePage::default(),
+            PageControl::ChangeableValues => CachingModePage {
+                write_cache_enabled: true,
+                ..CachingModePage::unchangeable()
+            },
+            PageControl::SavedValues => Err(Error::SavingParametersNotSupported)?,
+        };
+        page.pack(buf.get_mut(..CachingModePage::BYTES).ok_or(ModePageError::InsufficientSpace)?)?;
+        Ok(Some(CachingModePage::BYTES))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Splits the first page off a list of mode pages
+pub(crate) fn split_page(pages: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    if pages.len() < 2 {
+        Err(Error::ParameterListLength)?;
+    }
+    if pages[0] & SUBPAGE_FORMAT != 0 {
+        Err(Error::InvalidFieldInParameterList)?;
+    }
+
+    let len = 2 + pages[1] as usize;
+    if len > pages.len() {
+        Err(Error::ParameterListLength)?;
+    }
+    Ok(pages.split_at(len))
+}
+
+/// Checks `page` is the same length as the current page and only changes bits the host is
+/// allowed to change
+fn check_page<BD: BlockDevice>(
+    lu: &mut LogicalUnit<BD>,
+    page: &[u8],
+    save: bool,
+) -> Result<(), Error> {
+    let page_code = page[0] & ALL_PAGES;
+
+    let mut current = [0; MAX_PAGE_BYTES];
+    let mut changeable = [0; MAX_PAGE_BYTES];
+    let len = sense_page(lu, page_code, PageControl::CurrentValues, &mut current)?
+        .ok_or(Error::InvalidFieldInParameterList)?;
+    sense_page(lu, page_code, PageControl::ChangeableValues, &mut changeable)?;
+
+    if len != page.len() {
+        Err(Error::InvalidFieldInParameterList)?;
+    }
+
+    // Skip the header, the parameters saveable bit is reserved in MODE SELECT
+    let unchangeable_bits_changed = page.iter()
+        .zip(current.iter().zip(changeable.iter()))
+        .skip(2)
+        .any(|(new, (current, changeable))| (new ^ current) & !changeable != 0);
+    if unchangeable_bits_changed {
+        Err(Error::InvalidFieldInParameterList)?;
+    }
+
+    let device_page = match lu.block_device.mode_pages() {
+        Some(mode_pages) => mode_pages.has_page(page_code),
+        None => false,
+    };
+    if save && !device_page {
+        Err(Error::SavingParametersNotSupported)?;
+    }
+
+    Ok(())
+}
+
+/// The block length and number of blocks can't be changed so descriptors are only accepted if
+/// they match the current values (or zero number of blocks meaning no change)
+fn check_block_descriptors<BD: BlockDevice>(
+    lu: &LogicalUnit<BD>,
+    descriptors: &[u8],
+    long_lba: bool,
+) -> Result<(), Error> {
+    let descriptor_bytes = if long_lba {
+        LongLbaModeParameterBlockDescriptor::BYTES
+    } else {
+        ShortLbaModeParameterBlockDescriptor::BYTES
+    };
+    let descriptors = descriptors.chunks_exact(descriptor_bytes);
+    if !descriptors.remainder().is_empty() {
+        Err(Error::InvalidFieldInParameterList)?;
+    }
+
+    let number_of_blocks = lu.block_device.max_lba().saturating_add(1);
+    let logical_block_length = BD::BLOCK_BYTES as u32;
+
+    for descriptor in descriptors {
+        let (expected_blocks, blocks, block_length) = if long_lba {
+            let d = LongLbaModeParameterBlockDescriptor::unpack(descriptor)?;
+            (number_of_blocks, d.number_of_blocks, d.logical_block_length)
+        } else {
+            let d = ShortLbaModeParameterBlockDescriptor::unpack(descriptor)?;
+            let expected = ShortLbaModeParameterBlockDescriptor::new(number_of_blocks, 0).number_of_blocks;
+            (expected as u64, d.number_of_blocks as u64, d.logical_block_length)
+        };
+
+        if block_length != logical_block_length || (blocks != 0 && blocks != expected_blocks) {
+            Err(Error::InvalidFieldInParameterList)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,185 @@
+use packing::{
+    Packed,
+    PackedSize,
+};
+
+use crate::scsi::enums::{
+    ResponseCode,
+    SenseKey,
+    AdditionalSenseCode,
+};
+
+/// Header of descriptor format sense data. Followed by `additional_sense_length` bytes of
+/// sense data descriptors
+///
+/// SPC-4 4.5.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct DescriptorSenseHeader {
+    #[pkd(6, 0, 0, 0)]
+    pub response_code: ResponseCode,
+
+    #[pkd(3, 0, 1, 1)]
+    pub sense_key: SenseKey,
+
+    #[pkd(7, 0, 2, 3)]
+    pub additional_sense_code: AdditionalSenseCode,
+
+    #[pkd(7, 0, 4, 6)]
+    _reserved: [u8; 3],
+
+    /// Total length of the descriptors that follow
+    #[pkd(7, 0, 7, 7)]
+    pub additional_sense_length: u8,
+}
+
+impl DescriptorSenseHeader {
+    pub fn new(
+        response_code: ResponseCode,
+        sense_key: SenseKey,
+        additional_sense_code: AdditionalSenseCode,
+        additional_sense_length: u8,
+    ) -> Self {
+        Self {
+            response_code,
+            sense_key,
+            additional_sense_code,
+            additional_sense_length,
+            ..Default::default()
+        }
+    }
+}
+
+/// Information sense data descriptor. For medium errors this is the failing LBA
+///
+/// SPC-4 4.5.2.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct InformationSenseDescriptor {
+    /// 0x00
+    #[pkd(7, 0, 0, 0)]
+    pub descriptor_type: u8,
+
+    /// 0x0A
+    #[pkd(7, 0, 1, 1)]
+    pub additional_length: u8,
+
+    /// Must be set
+    #[pkd(7, 7, 2, 2)]
+    pub valid: bool,
+
+    #[pkd(7, 0, 3, 3)]
+    _reserved: u8,
+
+    #[pkd(7, 0, 4, 11)]
+    pub information: u64,
+}
+
+impl InformationSenseDescriptor {
+    pub fn new(information: u64) -> Self {
+        Self {
+            descriptor_type: 0x00,
+            additional_length: Self::BYTES as u8 - 2,
+            valid: true,
+            information,
+            ..Default::default()
+        }
+    }
+}
+
+/// Command-specific information sense data descriptor
+///
+/// SPC-4 4.5.2.3
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct CommandSpecificInformationSenseDescriptor {
+    /// 0x01
+    #[pkd(7, 0, 0, 0)]
+    pub descriptor_type: u8,
+
+    /// 0x0A
+    #[pkd(7, 0, 1, 1)]
+    pub additional_length: u8,
+
+    #[pkd(7, 0, 2, 3)]
+    _reserved: u16,
+
+    #[pkd(7, 0, 4, 11)]
+    pub command_specific_information: u64,
+}
+
+impl CommandSpecificInformationSenseDescriptor {
+    pub fn new(command_specific_information: u64) -> Self {
+        Self {
+            descriptor_type: 0x01,
+            additional_length: Self::BYTES as u8 - 2,
+            command_specific_information,
+            ..Default::default()
+        }
+    }
+}
+
+/// Sense key specific sense data descriptor. The meaning of `sense_key_specific` depends on
+/// the sense key (field pointer for ILLEGAL REQUEST, progress indication for NOT READY, etc)
+///
+/// SPC-4 4.5.2.4
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct SenseKeySpecificSenseDescriptor {
+    /// 0x02
+    #[pkd(7, 0, 0, 0)]
+    pub descriptor_type: u8,
+
+    /// 0x06
+    #[pkd(7, 0, 1, 1)]
+    pub additional_length: u8,
+
+    #[pkd(7, 0, 2, 3)]
+    _reserved: u16,
+
+    /// Must be set
+    #[pkd(7, 7, 4, 4)]
+    pub sense_key_specific_valid: bool,
+
+    #[pkd(6, 0, 4, 6)]
+    pub sense_key_specific: u32,
+
+    #[pkd(7, 0, 7, 7)]
+    _reserved2: u8,
+}
+
+impl SenseKeySpecificSenseDescriptor {
+    pub fn new(sense_key_specific: u32) -> Self {
+        Self {
+            descriptor_type: 0x02,
+            additional_length: Self::BYTES as u8 - 2,
+            sense_key_specific_valid: true,
+            sense_key_specific,
+            ..Default::default()
+        }
+    }
+}
+
+#[test]
+fn test_descriptor_sense() {
+    let mut bytes = [0xFF; 8];
+    DescriptorSenseHeader::new(
+        ResponseCode::DescriptorSenseData, 
+        SenseKey::MediumError, 
+        AdditionalSenseCode::WriteError, 
+        12,
+    ).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x72, 0x03, 0x0C, 0x00, 0, 0, 0, 12]);
+
+    let mut bytes = [0xFF; 12];
+    InformationSenseDescriptor::new(0x12_3456_789A).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x00, 0x0A, 0x80, 0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78, 0x9A]);
+
+    let mut bytes = [0xFF; 12];
+    CommandSpecificInformationSenseDescriptor::new(0xAB).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x01, 0x0A, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xAB]);
+
+    let mut bytes = [0xFF; 8];
+    SenseKeySpecificSenseDescriptor::new(0x0A_BCDE).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x02, 0x06, 0, 0, 0x8A, 0xBC, 0xDE, 0]);
+}
@@ -181,6 +181,38 @@ impl InquiryResponse {
         assert!(product_revision_level.as_ref().len() <= self.product_revision_level.len());
         set_ascii_str(&mut self.product_revision_level, product_revision_level);
     }
+    pub fn vendor_identification(&self) -> &[u8; 8] {
+        &self.vendor_identification
+    }
+    pub fn product_identification(&self) -> &[u8; 16] {
+        &self.product_identification
+    }
+
+    /// The same identification in the form UFI devices use. Only the first 36 bytes are sent,
+    /// UFI has nothing after the product revision level
+    pub fn ufi(&self) -> Self {
+        Self {
+            peripheral_device_type: self.peripheral_device_type,
+            removable_medium: self.removable_medium,
+            version: SpcVersion::None,
+            response_data_format: ResponseDataFormat::Ufi,
+            additional_length: 0x1F,
+            vendor_identification: self.vendor_identification,
+            product_identification: self.product_identification,
+            product_revision_level: self.product_revision_level,
+            ..Default::default()
+        }
+    }
+
+    /// Response for a LUN that isn't present. Peripheral qualifier 011b and device type 1Fh
+    /// tell the host there will never be a device at this LUN
+    pub fn logical_unit_not_supported() -> Self {
+        Self {
+            peripheral_qualifier: PeripheralQualifier::Incapable,
+            peripheral_device_type: PeripheralDeviceType::UnknownOrNone,
+            ..Default::default()
+        }
+    }
 }
 
 impl Default for InquiryResponse {
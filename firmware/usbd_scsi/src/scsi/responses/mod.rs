@@ -1,8 +1,23 @@
 mod read_capacity;
 pub use read_capacity::*;
 
+mod read_format_capacities;
+pub use read_format_capacities::*;
+
 mod inquiry;
 pub use inquiry::*;
 
 mod request_sense;
-pub use request_sense::*;
\ No newline at end of file
+pub use request_sense::*;
+
+mod descriptor_sense;
+pub use descriptor_sense::*;
+
+mod report_luns;
+pub use report_luns::*;
+
+mod vital_product_data;
+pub use vital_product_data::*;
+
+mod read_buffer;
+pub use read_buffer::*;
@@ -0,0 +1,25 @@
+use packing::Packed;
+
+/// READ BUFFER response in descriptor mode
+///
+/// SPC-4 6.15.5
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReadBufferDescriptor {
+    /// Segments start on a multiple of 2 to the power of this many bytes
+    #[pkd(7, 0, 0, 0)]
+    pub offset_boundary: u8,
+
+    #[pkd(7, 0, 1, 3)]
+    pub buffer_capacity: u32,
+}
+
+
+#[test]
+fn test_read_buffer_descriptor() {
+    use packing::PackedSize;
+
+    let mut bytes = [0; ReadBufferDescriptor::BYTES];
+    ReadBufferDescriptor { offset_boundary: 10, buffer_capacity: 0x010000 }.pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [10, 0x01, 0x00, 0x00]);
+}
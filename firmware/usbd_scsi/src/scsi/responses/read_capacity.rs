@@ -1,11 +1,85 @@
 use packing::Packed;
 
+/// Returned by READ CAPACITY(10)
+///
+/// SBC-3 5.15.2
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
 #[packed(big_endian, lsb0)]
 pub struct ReadCapacity10Response {
+    /// 0xFFFFFFFF if the max LBA doesn't fit, the host should use READ CAPACITY(16) instead
     #[pkd(7, 0, 0, 3)]
     pub max_lba: u32,
 
     #[pkd(7, 0, 4, 7)]
     pub block_size: u32,
-}
\ No newline at end of file
+}
+impl ReadCapacity10Response {
+    pub fn new(max_lba: u64, block_size: u32) -> Self {
+        Self {
+            max_lba: max_lba.min(u32::MAX as u64) as u32,
+            block_size,
+        }
+    }
+}
+
+/// Returned by READ CAPACITY(16)
+///
+/// SBC-3 5.16.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReadCapacity16Response {
+    #[pkd(7, 0, 0, 7)]
+    pub max_lba: u64,
+
+    #[pkd(7, 0, 8, 11)]
+    pub block_size: u32,
+
+    #[pkd(3, 1, 12, 12)]
+    pub protection_type: u8,
+
+    #[pkd(0, 0, 12, 12)]
+    pub protection_enable: bool,
+
+    #[pkd(7, 4, 13, 13)]
+    pub protection_information_intervals_exponent: u8,
+
+    /// 2^n logical blocks per physical block
+    #[pkd(3, 0, 13, 13)]
+    pub logical_blocks_per_physical_block_exponent: u8,
+
+    #[pkd(7, 7, 14, 14)]
+    pub logical_block_provisioning_management_enabled: bool,
+
+    #[pkd(6, 6, 14, 14)]
+    pub logical_block_provisioning_read_zeros: bool,
+
+    #[pkd(5, 0, 14, 15)]
+    pub lowest_aligned_lba: u16,
+
+    #[pkd(7, 0, 16, 31)]
+    _reserved: [u8; 16],
+}
+
+impl ReadCapacity16Response {
+    pub fn new(max_lba: u64, block_size: u32) -> Self {
+        Self {
+            max_lba,
+            block_size,
+            ..Default::default()
+        }
+    }
+}
+
+#[test]
+fn test_read_capacity_responses() {
+    use packing::PackedSize;
+
+    let mut bytes = [0; ReadCapacity10Response::BYTES];
+    ReadCapacity10Response::new(0x1_0000_0000, 512).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x02, 0x00]);
+
+    let mut bytes = [0; ReadCapacity16Response::BYTES];
+    ReadCapacity16Response::new(0x1_0000_0000, 512).pack(&mut bytes).unwrap();
+    assert_eq!(bytes[..12], [0, 0, 0, 1, 0, 0, 0, 0, 0x00, 0x00, 0x02, 0x00]);
+    assert!(bytes[12..].iter().all(|&b| b == 0));
+}
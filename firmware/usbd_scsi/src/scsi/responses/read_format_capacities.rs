@@ -0,0 +1,63 @@
+use packing::Packed;
+
+use crate::scsi::enums::CapacityDescriptorType;
+
+/// Start of the READ FORMAT CAPACITIES response, followed by `capacity_list_length` bytes of
+/// capacity descriptors. The current/maximum capacity descriptor comes first then any
+/// formattable capacity descriptors, which are laid out the same as a
+/// [FormatDescriptor](struct.FormatDescriptor.html)
+///
+/// UFI 4.10
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct CapacityListHeader {
+    #[pkd(7, 0, 0, 2)]
+    _reserved: [u8; 3],
+
+    #[pkd(7, 0, 3, 3)]
+    pub capacity_list_length: u8,
+}
+impl CapacityListHeader {
+    pub fn new(capacity_list_length: u8) -> Self {
+        Self {
+            _reserved: Default::default(),
+            capacity_list_length,
+        }
+    }
+}
+
+/// UFI 4.10.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct CurrentMaximumCapacityDescriptor {
+    #[pkd(7, 0, 0, 3)]
+    pub number_of_blocks: u32,
+
+    #[pkd(1, 0, 4, 4)]
+    pub descriptor_type: CapacityDescriptorType,
+
+    #[pkd(7, 0, 5, 7)]
+    pub block_length: u32,
+}
+impl CurrentMaximumCapacityDescriptor {
+    /// `number_of_blocks` is saturated if it doesn't fit
+    pub fn new(number_of_blocks: u64, block_length: u32, descriptor_type: CapacityDescriptorType) -> Self {
+        Self {
+            number_of_blocks: number_of_blocks.min(u32::MAX as u64) as u32,
+            descriptor_type,
+            block_length,
+        }
+    }
+}
+
+#[test]
+fn test_read_format_capacities_response() {
+    let mut bytes = [0; 4];
+    CapacityListHeader::new(16).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0, 0, 0, 16]);
+
+    let mut bytes = [0; 8];
+    CurrentMaximumCapacityDescriptor::new(0x1_0000_0000, 512, CapacityDescriptorType::NoMediaPresent)
+        .pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0xFF, 0xFF, 0xFF, 0xFF, 0x03, 0x00, 0x02, 0x00]);
+}
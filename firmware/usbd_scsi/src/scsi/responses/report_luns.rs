@@ -0,0 +1,65 @@
+use packing::{
+    Packed,
+    PackedSize,
+};
+
+/// Header of the REPORT LUNS parameter data. Followed by one `LunListEntry` per LUN
+///
+/// SPC-4 6.33
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReportLunsResponseHeader {
+    /// Number of bytes of LUN list that follow the header (8 * number of LUNs)
+    #[pkd(7, 0, 0, 3)]
+    pub lun_list_length: u32,
+
+    #[pkd(7, 0, 4, 7)]
+    _reserved: u32,
+}
+
+impl ReportLunsResponseHeader {
+    /// Header for a list containing `luns` entries
+    pub fn new(luns: u8) -> Self {
+        Self {
+            lun_list_length: luns as u32 * LunListEntry::BYTES as u32,
+            ..Default::default()
+        }
+    }
+}
+
+/// A single level LUN using the peripheral device addressing method
+///
+/// SAM-5 4.7.7
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct LunListEntry {
+    /// 00b = peripheral device addressing method
+    #[pkd(7, 6, 0, 0)]
+    pub address_method: u8,
+
+    #[pkd(5, 0, 0, 0)]
+    pub bus_identifier: u8,
+
+    #[pkd(7, 0, 1, 1)]
+    pub lun: u8,
+
+    #[pkd(7, 0, 2, 7)]
+    _reserved: [u8; 6],
+}
+
+impl LunListEntry {
+    pub fn new(lun: u8) -> Self {
+        Self {
+            lun,
+            ..Default::default()
+        }
+    }
+}
+
+#[test]
+fn test_report_luns_response() {
+    let mut bytes = [0xFF; 16];
+    ReportLunsResponseHeader::new(1).pack(&mut bytes[..8]).unwrap();
+    LunListEntry::new(3).pack(&mut bytes[8..]).unwrap();
+    assert_eq!(bytes, [0, 0, 0, 8, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0]);
+}
@@ -68,7 +68,8 @@ impl Default for RequestSenseResponse {
     fn default() -> Self {
         Self {
             valid: true,
-            additional_sense_length: Self::BYTES as u8 - 7,
+            // n - 7 where n is the index of the last byte
+            additional_sense_length: Self::BYTES as u8 - 8,
             sense_key_specific_valid: true,
             additional_sense_data: [0; 235],
 
@@ -86,12 +87,6 @@ impl Default for RequestSenseResponse {
     }
 }
 
-impl RequestSenseResponse {
-    pub fn reset_status(&mut self) {
-        *self = Default::default()
-    }
-}
-
 /*
     if !descriptor_format
         return fixed sense data
@@ -0,0 +1,382 @@
+use packing::{
+    Packed,
+    PackedSize,
+};
+
+use crate::scsi::enums::{
+    PeripheralQualifier,
+    PeripheralDeviceType,
+    VitalProductDataPage,
+};
+
+/// Longest serial number that can be reported in the unit serial number VPD page
+pub const MAX_SERIAL_NUMBER_BYTES: usize = 32;
+
+/// Header common to all VPD pages. Followed by `page_length` bytes of page specific data
+///
+/// SPC-4 7.8.1
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct VitalProductDataHeader {
+    #[pkd(7, 5, 0, 0)]
+    pub peripheral_qualifier: PeripheralQualifier,
+
+    #[pkd(4, 0, 0, 0)]
+    pub peripheral_device_type: PeripheralDeviceType,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_code: VitalProductDataPage,
+
+    #[pkd(7, 0, 2, 3)]
+    pub page_length: u16,
+}
+
+impl VitalProductDataHeader {
+    pub fn new(page_code: VitalProductDataPage, page_length: u16) -> Self {
+        Self {
+            peripheral_qualifier: Default::default(),
+            peripheral_device_type: Default::default(),
+            page_code,
+            page_length,
+        }
+    }
+}
+
+/// Header of a designation descriptor in the device identification VPD page. Followed by
+/// `designator_length` bytes of designator
+///
+/// SPC-4 7.8.6.1
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct DesignationDescriptorHeader {
+    /// Only valid if `protocol_identifier_valid` is set
+    #[pkd(7, 4, 0, 0)]
+    pub protocol_identifier: u8,
+
+    /// 1h = binary, 2h = ASCII, 3h = UTF-8
+    #[pkd(3, 0, 0, 0)]
+    pub code_set: u8,
+
+    #[pkd(7, 7, 1, 1)]
+    pub protocol_identifier_valid: bool,
+
+    /// 00b = the logical unit, 01b = the target port, 10b = the target device
+    #[pkd(5, 4, 1, 1)]
+    pub association: u8,
+
+    /// 1h = T10 vendor ID based, 2h = EUI-64, 3h = NAA, etc
+    #[pkd(3, 0, 1, 1)]
+    pub designator_type: u8,
+
+    #[pkd(7, 0, 2, 2)]
+    _reserved: u8,
+
+    #[pkd(7, 0, 3, 3)]
+    pub designator_length: u8,
+}
+
+impl DesignationDescriptorHeader {
+    /// An ASCII T10 vendor ID based designator for the logical unit. The designator is the
+    /// 8 byte vendor identification followed by a vendor specific identifier
+    ///
+    /// SPC-4 7.8.6.4
+    pub fn t10_vendor_id(designator_length: u8) -> Self {
+        Self {
+            code_set: 0x2,
+            association: 0b00,
+            designator_type: 0x1,
+            designator_length,
+            ..Default::default()
+        }
+    }
+}
+
+/// Block limits VPD page
+///
+/// SBC-3 6.5.3
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct BlockLimitsPage {
+    #[pkd(7, 5, 0, 0)]
+    pub peripheral_qualifier: PeripheralQualifier,
+
+    #[pkd(4, 0, 0, 0)]
+    pub peripheral_device_type: PeripheralDeviceType,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_code: VitalProductDataPage,
+
+    #[pkd(7, 0, 2, 3)]
+    pub page_length: u16,
+
+    /// Write same with a number of logical blocks set to 0 isn't supported
+    #[pkd(0, 0, 4, 4)]
+    pub write_same_non_zero: bool,
+
+    /// 0 = COMPARE AND WRITE isn't supported
+    #[pkd(7, 0, 5, 5)]
+    pub maximum_compare_and_write_length: u8,
+
+    /// Transfers that aren't a multiple of this many blocks may incur delays
+    #[pkd(7, 0, 6, 7)]
+    pub optimal_transfer_length_granularity: u16,
+
+    /// Largest transfer length in blocks. 0 = no limit reported
+    #[pkd(7, 0, 8, 11)]
+    pub maximum_transfer_length: u32,
+
+    /// 0 = no optimal transfer length reported
+    #[pkd(7, 0, 12, 15)]
+    pub optimal_transfer_length: u32,
+
+    #[pkd(7, 0, 16, 19)]
+    pub maximum_prefetch_length: u32,
+
+    /// 0 = UNMAP isn't supported
+    #[pkd(7, 0, 20, 23)]
+    pub maximum_unmap_lba_count: u32,
+
+    /// 0 = UNMAP isn't supported
+    #[pkd(7, 0, 24, 27)]
+    pub maximum_unmap_block_descriptor_count: u32,
+
+    #[pkd(7, 0, 28, 31)]
+    pub optimal_unmap_granularity: u32,
+
+    #[pkd(7, 7, 32, 32)]
+    pub unmap_granularity_alignment_valid: bool,
+
+    #[pkd(6, 0, 32, 35)]
+    pub unmap_granularity_alignment: u32,
+
+    /// 0 = no limit reported
+    #[pkd(7, 0, 36, 43)]
+    pub maximum_write_same_length: u64,
+
+    #[pkd(7, 0, 44, 63)]
+    _reserved: [u8; 20],
+}
+
+impl Default for BlockLimitsPage {
+    fn default() -> Self {
+        Self {
+            peripheral_qualifier: Default::default(),
+            peripheral_device_type: Default::default(),
+            page_code: VitalProductDataPage::BlockLimits,
+            page_length: (Self::BYTES - VitalProductDataHeader::BYTES) as u16,
+            write_same_non_zero: Default::default(),
+            maximum_compare_and_write_length: Default::default(),
+            optimal_transfer_length_granularity: 1,
+            maximum_transfer_length: Default::default(),
+            optimal_transfer_length: Default::default(),
+            maximum_prefetch_length: Default::default(),
+            maximum_unmap_lba_count: Default::default(),
+            maximum_unmap_block_descriptor_count: Default::default(),
+            optimal_unmap_granularity: Default::default(),
+            unmap_granularity_alignment_valid: Default::default(),
+            unmap_granularity_alignment: Default::default(),
+            maximum_write_same_length: Default::default(),
+            _reserved: Default::default(),
+        }
+    }
+}
+
+/// Block device characteristics VPD page
+///
+/// SBC-3 6.5.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct BlockDeviceCharacteristicsPage {
+    #[pkd(7, 5, 0, 0)]
+    pub peripheral_qualifier: PeripheralQualifier,
+
+    #[pkd(4, 0, 0, 0)]
+    pub peripheral_device_type: PeripheralDeviceType,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_code: VitalProductDataPage,
+
+    #[pkd(7, 0, 2, 3)]
+    pub page_length: u16,
+
+    /// 0000h = not reported, 0001h = non-rotating medium (solid state), otherwise RPM
+    #[pkd(7, 0, 4, 5)]
+    pub medium_rotation_rate: u16,
+
+    /// 00h = not indicated
+    #[pkd(7, 0, 6, 6)]
+    pub product_type: u8,
+
+    #[pkd(7, 6, 7, 7)]
+    pub write_after_block_erase_required: u8,
+
+    #[pkd(5, 4, 7, 7)]
+    pub write_after_cryptographic_erase_required: u8,
+
+    /// 0h = not reported, 1h = 5.25", 2h = 3.5", 3h = 2.5", 4h = 1.8", 5h = less than 1.8"
+    #[pkd(3, 0, 7, 7)]
+    pub nominal_form_factor: u8,
+
+    /// SYNCHRONIZE CACHE is honoured for FUA writes
+    #[pkd(1, 1, 8, 8)]
+    pub force_unit_access_behaviour: bool,
+
+    /// Verify byte check unmapped LBA supported
+    #[pkd(0, 0, 8, 8)]
+    pub verify_byte_check_unmapped_lba_supported: bool,
+
+    #[pkd(7, 0, 9, 63)]
+    _reserved: [u8; 55],
+}
+
+impl Default for BlockDeviceCharacteristicsPage {
+    fn default() -> Self {
+        Self {
+            peripheral_qualifier: Default::default(),
+            peripheral_device_type: Default::default(),
+            page_code: VitalProductDataPage::BlockDeviceCharacteristics,
+            page_length: (Self::BYTES - VitalProductDataHeader::BYTES) as u16,
+            // Flash, RAM etc. are all non-rotating
+            medium_rotation_rate: 0x0001,
+            product_type: Default::default(),
+            write_after_block_erase_required: Default::default(),
+            write_after_cryptographic_erase_required: Default::default(),
+            nominal_form_factor: Default::default(),
+            force_unit_access_behaviour: Default::default(),
+            verify_byte_check_unmapped_lba_supported: Default::default(),
+            _reserved: [0; 55],
+        }
+    }
+}
+
+/// Logical block provisioning VPD page
+///
+/// SBC-3 6.5.4
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct LogicalBlockProvisioningPage {
+    #[pkd(7, 5, 0, 0)]
+    pub peripheral_qualifier: PeripheralQualifier,
+
+    #[pkd(4, 0, 0, 0)]
+    pub peripheral_device_type: PeripheralDeviceType,
+
+    #[pkd(7, 0, 1, 1)]
+    pub page_code: VitalProductDataPage,
+
+    #[pkd(7, 0, 2, 3)]
+    pub page_length: u16,
+
+    /// Only meaningful with thresholds, which aren't supported
+    #[pkd(7, 0, 4, 4)]
+    pub threshold_exponent: u8,
+
+    /// UNMAP is supported
+    #[pkd(7, 7, 5, 5)]
+    pub unmap_supported: bool,
+
+    /// WRITE SAME(16) with the unmap bit is supported
+    #[pkd(6, 6, 5, 5)]
+    pub write_same_16_unmap_supported: bool,
+
+    /// WRITE SAME(10) with the unmap bit is supported
+    #[pkd(5, 5, 5, 5)]
+    pub write_same_10_unmap_supported: bool,
+
+    /// Unmapped blocks read as zeros
+    #[pkd(2, 2, 5, 5)]
+    pub read_zeros: bool,
+
+    #[pkd(1, 1, 5, 5)]
+    pub anchor_supported: bool,
+
+    /// A provisioning group designator follows the page
+    #[pkd(0, 0, 5, 5)]
+    pub descriptor_present: bool,
+
+    /// 0h = not reported, 1h = resource provisioned, 2h = thin provisioned
+    #[pkd(2, 0, 6, 6)]
+    pub provisioning_type: u8,
+
+    #[pkd(7, 0, 7, 7)]
+    _reserved: u8,
+}
+
+impl Default for LogicalBlockProvisioningPage {
+    fn default() -> Self {
+        Self {
+            peripheral_qualifier: Default::default(),
+            peripheral_device_type: Default::default(),
+            page_code: VitalProductDataPage::LogicalBlockProvisioning,
+            page_length: (Self::BYTES - VitalProductDataHeader::BYTES) as u16,
+            threshold_exponent: Default::default(),
+            unmap_supported: Default::default(),
+            write_same_16_unmap_supported: Default::default(),
+            write_same_10_unmap_supported: Default::default(),
+            read_zeros: Default::default(),
+            anchor_supported: Default::default(),
+            descriptor_present: Default::default(),
+            provisioning_type: Default::default(),
+            _reserved: Default::default(),
+        }
+    }
+}
+
+/// An ASCII serial number of up to `MAX_SERIAL_NUMBER_BYTES`
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct SerialNumber {
+    bytes: [u8; MAX_SERIAL_NUMBER_BYTES],
+    len: usize,
+}
+
+impl SerialNumber {
+    /// Panics if more than `MAX_SERIAL_NUMBER_BYTES` are supplied
+    pub fn new<T: AsRef<[u8]>>(serial_number: T) -> Self {
+        let serial_number = serial_number.as_ref();
+        assert!(serial_number.len() <= MAX_SERIAL_NUMBER_BYTES);
+
+        let mut bytes = [0; MAX_SERIAL_NUMBER_BYTES];
+        bytes[..serial_number.len()].copy_from_slice(serial_number);
+
+        Self {
+            bytes,
+            len: serial_number.len(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+#[test]
+fn test_vital_product_data_header() {
+    let mut bytes = [0xFF; 4];
+    VitalProductDataHeader::new(VitalProductDataPage::UnitSerialNumber, 0x0102).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x00, 0x80, 0x01, 0x02]);
+
+    DesignationDescriptorHeader::t10_vendor_id(24).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x02, 0x01, 0x00, 24]);
+}
+
+#[test]
+fn test_block_vpd_pages() {
+    let mut bytes = [0xFF; 64];
+    BlockLimitsPage::default().pack(&mut bytes).unwrap();
+    assert_eq!(bytes[..8], [0x00, 0xB0, 0x00, 0x3C, 0x00, 0x00, 0x00, 0x01]);
+    assert!(bytes[8..].iter().all(|&b| b == 0));
+
+    let mut bytes = [0xFF; 64];
+    BlockDeviceCharacteristicsPage::default().pack(&mut bytes).unwrap();
+    assert_eq!(bytes[..6], [0x00, 0xB1, 0x00, 0x3C, 0x00, 0x01]);
+    assert!(bytes[6..].iter().all(|&b| b == 0));
+
+    let mut bytes = [0xFF; 8];
+    LogicalBlockProvisioningPage {
+        unmap_supported: true,
+        write_same_16_unmap_supported: true,
+        provisioning_type: 1,
+        ..Default::default()
+    }.pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x00, 0xB2, 0x00, 0x04, 0x00, 0xC0, 0x01, 0x00]);
+}
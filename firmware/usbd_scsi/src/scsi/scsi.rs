@@ -1,3 +1,4 @@
+use core::marker::PhantomData;
 use packing::{
     Packed,
     PackedSize,
@@ -7,9 +8,13 @@ use usb_device::class_prelude::*;
 use usb_device::Result as UsbResult;
 
 use usbd_bulk_only_transport::{
+    AbortReason,
     BulkOnlyTransport,
     Error as BulkOnlyTransportError,
     TransferState,
+    Transport,
+    TransportClass,
+    DEFAULT_BUFFER_BYTES,
 };
 
 use usbd_mass_storage::InterfaceSubclass;
@@ -20,14 +25,73 @@ use crate::{
         BlockDevice,
         BlockDeviceError,
     },
+    event_sink::EventSink,
+    device_health::{
+        ErrorCounter,
+        ErrorCounters,
+    },
+    command_handler::{
+        CommandHandler,
+        CommandProgress,
+    },
+    logical_unit::{
+        LogicalUnit,
+        LogicalUnits,
+        LogicalUnitVisitor,
+        MediumState,
+    },
     scsi::{
         commands::*,
         responses::*,
         enums::*,
+        sense_data::MAX_SENSE_BYTES,
+        mode_parameters::{
+            MODE_SENSE_BUFFER_BYTES,
+            mode_select,
+            mode_sense,
+            write_cache_enabled,
+        },
+        log_pages::{
+            LOG_SENSE_BUFFER_BYTES,
+            log_sense,
+        },
+        ufi::{
+            self,
+            FloppyGeometry,
+            UFI_INQUIRY_BYTES,
+        },
+        CommandProfile,
         Error,
+        SenseData,
+        SHORT_SENSE_BYTES,
+        UnitAttention,
     },
 };
 
+/// Capacity list header, current/maximum capacity descriptor and one formattable capacity
+const READ_FORMAT_CAPACITIES_BYTES: usize = 20;
+
+/// Large enough for the standard inquiry data or any of the VPD pages
+const INQUIRY_BUFFER_BYTES: usize = 96;
+
+/// The longest response other than read data. These are queued in one go so the transport's
+/// buffer has to hold them
+const MAX_RESPONSE_BYTES: usize = if MODE_SENSE_BUFFER_BYTES > LOG_SENSE_BUFFER_BYTES {
+    MODE_SENSE_BUFFER_BYTES
+} else {
+    LOG_SENSE_BUFFER_BYTES
+};
+
+/// VPD pages in the order they are listed in the supported VPD pages page
+const SUPPORTED_VPD_PAGES: [VitalProductDataPage; 6] = [
+    VitalProductDataPage::SupportedVpdPages,
+    VitalProductDataPage::UnitSerialNumber,
+    VitalProductDataPage::DeviceIdentification,
+    VitalProductDataPage::BlockLimits,
+    VitalProductDataPage::BlockDeviceCharacteristics,
+    VitalProductDataPage::LogicalBlockProvisioning,
+];
+
 enum CommandState {
     None,
     Done,
@@ -36,21 +100,56 @@ enum CommandState {
 
 /// # Scsi Transparent Command Set implementation
 ///
-/// Built on top of [BulkOnlyTransport](struct.BulkOnlyTransport.html)
+/// Built on top of a [Transport](trait.Transport.html) `T`, which is
+/// [BulkOnlyTransport](struct.BulkOnlyTransport.html) unless created with
+/// [with_transport](#method.with_transport)
+///
+/// Answers with the SCSI transparent command set unless given another
+/// [CommandProfile](enum.CommandProfile.html) with
+/// [with_command_profile](#method.with_command_profile).
+///
+/// Each CBW is routed to the logical unit indicated by its LUN field. See
+/// [LogicalUnits](trait.LogicalUnits.html) for exposing more than one LUN.
+///
+/// `BUFFER_BYTES` is the size of the transport's buffer. It has to fit at least one block of 
+/// every logical unit. Reads and writes move as many blocks as fit in the buffer each poll so
+/// making it a few blocks long keeps the host busy between polls. For example
+/// `Scsi<'a, B, L, 4096>` for 4096 byte blocks or to queue up 8 512 byte blocks.
+///
+/// `E` is told what the host is doing, see [with_event_sink](#method.with_event_sink). The
+/// default `()` ignores it.
+///
+/// `H` handles commands that aren't implemented here, see
+/// [with_command_handler](#method.with_command_handler). The default `()` rejects them.
 ///
 /// [Glossary](index.html#glossary)
-pub struct Scsi<'a, B: UsbBus, BD: BlockDevice> {
-    inner: BulkOnlyTransport<'a, B>,
+pub struct Scsi<
+    'a,
+    B: UsbBus,
+    L: LogicalUnits,
+    const BUFFER_BYTES: usize = DEFAULT_BUFFER_BYTES,
+    E: EventSink = (),
+    H: CommandHandler = (),
+    T: TransportClass<B> = BulkOnlyTransport<'a, B, BUFFER_BYTES>,
+> {
+    inner: T,
     current_command: Command,
-    inquiry_response: InquiryResponse,
-    request_sense_response: RequestSenseResponse,
-    block_device: BD,
-    lba: u32,
-    lba_end: u32,
+    current_lun: u8,
+    current_op_code: u8,
+    logical_units: L,
+    lba: u64,
+    lba_end: u64,
+    /// LUNs that still need flushing after a bus reset, one bit per LUN
+    flush_pending: u16,
+    event_sink: E,
+    command_handler: H,
+    profile: CommandProfile,
+    /// The bus and allocator lifetime are only used by the transport
+    bus: PhantomData<&'a B>,
 }
 
-impl<B: UsbBus, BD: BlockDevice> Scsi<'_, B, BD> {
-    /// Creates a new Scsi block device
+impl<B: UsbBus, BD: BlockDevice, const BUFFER_BYTES: usize> Scsi<'_, B, LogicalUnit<BD>, BUFFER_BYTES> {
+    /// Creates a new Scsi block device with a single LUN
     ///
     /// `block_device` provides reading and writing of blocks to the underlying filesystem
     ///
@@ -72,34 +171,143 @@ impl<B: UsbBus, BD: BlockDevice> Scsi<'_, B, BD> {
         vendor_identification: V,
         product_identification: P,
         product_revision_level: R,
-    ) -> Scsi<'_, B, BD> {
-        let mut inquiry_response = InquiryResponse::default();
-        inquiry_response.set_vendor_identification(vendor_identification);
-        inquiry_response.set_product_identification(product_identification);
-        inquiry_response.set_product_revision_level(product_revision_level);
-
-        //TODO: This is reasonable for FAT but not FAT32 or others. BOT buffer should probably be 
-        //configurable from here, perhaps passing in BD::BLOCK_BYTES.max(BOT::MIN_BUFFER) or something 
-        assert!(BD::BLOCK_BYTES <= BulkOnlyTransport::<B>::BUFFER_BYTES);
-        Scsi {
-            inner: BulkOnlyTransport::new(
+    ) -> Scsi<'_, B, LogicalUnit<BD>, BUFFER_BYTES> {
+        Scsi::with_logical_units(
+            alloc,
+            max_packet_size,
+            LogicalUnit::new(
+                block_device,
+                vendor_identification,
+                product_identification,
+                product_revision_level,
+            ),
+        )
+    }
+}
+
+impl<B: UsbBus, BD: BlockDevice, const BUFFER_BYTES: usize, E: EventSink, H: CommandHandler, T: TransportClass<B>> Scsi<'_, B, LogicalUnit<BD>, BUFFER_BYTES, E, H, T> {
+    /// Grants access to the block device for the purposes of housekeeping etc.
+    pub fn block_device_mut(&mut self) -> &mut BD {
+        self.logical_units.block_device_mut()
+    }
+}
+
+impl<B: UsbBus, L: LogicalUnits, const BUFFER_BYTES: usize> Scsi<'_, B, L, BUFFER_BYTES> {
+    /// Creates a new Scsi block device exposing `logical_units`
+    ///
+    /// LUNs are numbered in the order they appear in `logical_units`, starting from 0. The 
+    /// response to the get max lun request is derived from the number of logical units.
+    pub fn with_logical_units(
+        alloc: &UsbBusAllocator<B>, 
+        max_packet_size: u16, 
+        logical_units: L,
+    ) -> Scsi<'_, B, L, BUFFER_BYTES> {
+        Scsi::with_transport(
+            BulkOnlyTransport::new(
                 alloc, 
                 max_packet_size, 
                 InterfaceSubclass::ScsiTransparentCommandSet,
-                0,
+                max_lun::<L>(),
             ),
+            logical_units,
+        )
+    }
+}
+
+impl<B: UsbBus, L: LogicalUnits, const BUFFER_BYTES: usize, T: TransportClass<B>> Scsi<'_, B, L, BUFFER_BYTES, (), (), T> {
+    /// Creates a new Scsi block device exposing `logical_units` over `transport`, for
+    /// transports other than plain BOT. `UasTransport` from `usbd_uas_transport` for example,
+    /// which lets the host pick USB Attached SCSI with SET_INTERFACE, or `CbiTransport` from
+    /// `usbd_cbi_transport`. Otherwise the same as [with_logical_units](#method.with_logical_units)
+    ///
+    /// The transport's max LUN has to be one less than the number of logical units,
+    /// `L::COUNT - 1`. Transports without LUNs only ever reach the first logical unit.
+    /// `BUFFER_BYTES` must match the transport's buffer size
+    pub fn with_transport(inner: T, mut logical_units: L) -> Self {
+        assert!(BUFFER_BYTES >= MAX_RESPONSE_BYTES);
+        for lun in 0..L::COUNT {
+            let block_bytes = logical_units.visit(lun, BlockBytes).unwrap();
+            assert!(block_bytes <= BUFFER_BYTES);
+        }
+
+        Scsi {
+            inner,
             current_command: Command::None,
-            inquiry_response,
-            request_sense_response: Default::default(),
-            block_device,
+            current_lun: 0,
+            current_op_code: 0,
+            logical_units,
             lba: 0,
             lba_end: 0,
+            flush_pending: 0,
+            event_sink: (),
+            command_handler: (),
+            profile: CommandProfile::ScsiTransparent,
+            bus: PhantomData,
         }
     }
+}
 
-    /// Grants access to the block device for the purposes of housekeeping etc.
-    pub fn block_device_mut(&mut self) -> &mut BD {
-        &mut self.block_device
+impl<'a, B: UsbBus, L: LogicalUnits, const BUFFER_BYTES: usize, E: EventSink, H: CommandHandler, T: TransportClass<B>> Scsi<'a, B, L, BUFFER_BYTES, E, H, T> {
+    /// Reports what the host is doing to `event_sink`, see [EventSink](trait.EventSink.html)
+    pub fn with_event_sink<S: EventSink>(self, event_sink: S) -> Scsi<'a, B, L, BUFFER_BYTES, S, H, T> {
+        Scsi {
+            inner: self.inner,
+            current_command: self.current_command,
+            current_lun: self.current_lun,
+            current_op_code: self.current_op_code,
+            logical_units: self.logical_units,
+            lba: self.lba,
+            lba_end: self.lba_end,
+            flush_pending: self.flush_pending,
+            event_sink,
+            command_handler: self.command_handler,
+            profile: self.profile,
+            bus: self.bus,
+        }
+    }
+
+    /// Passes commands that aren't implemented here to `command_handler`, see
+    /// [CommandHandler](trait.CommandHandler.html)
+    pub fn with_command_handler<C: CommandHandler>(self, command_handler: C) -> Scsi<'a, B, L, BUFFER_BYTES, E, C, T> {
+        Scsi {
+            inner: self.inner,
+            current_command: self.current_command,
+            current_lun: self.current_lun,
+            current_op_code: self.current_op_code,
+            logical_units: self.logical_units,
+            lba: self.lba,
+            lba_end: self.lba_end,
+            flush_pending: self.flush_pending,
+            event_sink: self.event_sink,
+            command_handler,
+            profile: self.profile,
+            bus: self.bus,
+        }
+    }
+
+    /// Answers with the `profile` command set, see [CommandProfile](enum.CommandProfile.html).
+    /// The transport's interface subclass should match, see
+    /// [CommandProfile::subclass](enum.CommandProfile.html#method.subclass)
+    pub fn with_command_profile(self, profile: CommandProfile) -> Self {
+        Scsi {
+            profile,
+            ..self
+        }
+    }
+
+    /// Grants access to the event sink, e.g. to check what it has recorded
+    pub fn event_sink_mut(&mut self) -> &mut E {
+        &mut self.event_sink
+    }
+
+    /// Grants access to the command handler
+    pub fn command_handler_mut(&mut self) -> &mut H {
+        &mut self.command_handler
+    }
+
+    /// Grants access to the logical units for the purposes of housekeeping etc.
+    pub fn logical_units_mut(&mut self) -> &mut L {
+        &mut self.logical_units
     }
 
     fn get_new_command(&mut self) -> Result<bool, Error> {
@@ -107,7 +315,17 @@ impl<B: UsbBus, BD: BlockDevice> Scsi<'_, B, BD> {
             Ok(false)
         } else {
             if let Some(cbw) = self.inner.get_current_command() {
-                self.current_command = Command::extract_from_cbw(cbw)?;
+                self.current_lun = cbw.lun;
+                self.current_op_code = cbw.data[0];
+                self.event_sink.command_started(self.current_lun, self.current_op_code);
+                self.current_command = Command::extract_from_cbw(cbw, self.profile)?;
+
+                // Make sure the host is expecting the data the command needs. LUNs that don't
+                // exist only answer commands without any blocks
+                let block_bytes = self.logical_units.visit(self.current_lun, BlockBytes).unwrap_or(0);
+                if let Some(data_stage) = self.current_command.data_stage(block_bytes) {
+                    self.inner.check_data_stage(data_stage)?;
+                }
                 Ok(true)
             } else {
                 Ok(false)
@@ -116,278 +334,1517 @@ impl<B: UsbBus, BD: BlockDevice> Scsi<'_, B, BD> {
     }
 
     fn process_command(&mut self, new_command: bool) -> Result<CommandState, Error> {
+        trace_scsi_command!("COMMAND> LUN {}: {:?}", self.current_lun, self.current_command);
+
+        match self.current_command {
+            // No command, nothing to do
+            Command::None => Ok(CommandState::None),
+
+            // Report luns is answered the same regardless of which LUN it's addressed to
+            Command::ReportLuns(r) => self.report_luns(r),
+
+            // Anything else is up to the handler, whichever LUN it's addressed to
+            Command::Custom(c) => {
+                match self.command_handler.process_command(self.current_lun, c.cdb(), new_command, &mut self.inner)? {
+                    CommandProgress::Done => Ok(CommandState::Done),
+                    CommandProgress::Ongoing => Ok(CommandState::Ongoing),
+                }
+            },
+
+            command => {
+                let processor = CommandProcessor::<_, BUFFER_BYTES, _> {
+                    inner: &mut self.inner,
+                    event_sink: &mut self.event_sink,
+                    command,
+                    profile: self.profile,
+                    lun: self.current_lun,
+                    new_command,
+                    blocks_moved: 0,
+                    lba: &mut self.lba,
+                    lba_end: &mut self.lba_end,
+                };
+                match self.logical_units.visit(self.current_lun, processor) {
+                    Some(r) => r,
+                    None => self.process_unsupported_lun_command(),
+                }
+            },
+        }
+    }
+
+    fn report_luns(&mut self, command: ReportLunsCommand) -> Result<CommandState, Error> {
+        // 0x00 and 0x02 are all LUNs, 0x01 is well known LUNs of which we have none
+        let luns = match command.select_report {
+            0x00 | 0x02 => L::COUNT,
+            0x01 => 0,
+            _ => Err(Error::InvalidFieldInCommand)?,
+        };
+
+        let len = ReportLunsResponseHeader::BYTES + luns as usize * LunListEntry::BYTES;
+        let buf = self.inner.take_buffer_space(len)?;
+
+        ReportLunsResponseHeader::new(luns).pack(&mut buf[..ReportLunsResponseHeader::BYTES])?;
+
+        let entries = buf[ReportLunsResponseHeader::BYTES..].chunks_exact_mut(LunListEntry::BYTES);
+        for (lun, entry) in entries.enumerate() {
+            LunListEntry::new(lun as u8).pack(entry)?;
+        }
+
+        Ok(CommandState::Done)
+    }
+
+    // The host shouldn't address a LUN greater than max lun. BOT rejects CBWs that do before
+    // they get here but if one does INQUIRY and REQUEST SENSE have to indicate that the LUN 
+    // isn't there. Anything else is an error
+    fn process_unsupported_lun_command(&mut self) -> Result<CommandState, Error> {
+        match self.current_command {
+            Command::Inquiry(i) => {
+                let mut bytes = [0; InquiryResponse::BYTES];
+                InquiryResponse::logical_unit_not_supported().pack(&mut bytes)?;
+                send_truncated(&mut self.inner, &bytes, i.allocation_length as usize)?;
+                Ok(CommandState::Done)
+            },
+            Command::RequestSense(r) => {
+                let sense = map_error_to_sense_data(&Error::LogicalUnitNotSupported);
+                send_sense_data(&mut self.inner, &sense, r)?;
+                Ok(CommandState::Done)
+            },
+            _ => Err(Error::LogicalUnitNotSupported),
+        }
+    }
+
+    /// Flushes the block devices of the LUNs left over from a bus reset. Busy devices are tried
+    /// again next time
+    fn flush_after_reset(&mut self) {
+        for lun in 0..L::COUNT {
+            let bit = 1 << lun;
+            if self.flush_pending & bit == 0 {
+                continue;
+            }
+            match self.logical_units.visit(lun, Flush) {
+                Some(Err(BlockDeviceError::WouldBlock)) => {},
+                r => {
+                    if let Some(Err(e)) = r {
+                        error!("Flush after reset failed for LUN {}: {:?}", lun, e);
+                    }
+                    self.flush_pending &= !bit;
+                },
+            }
+        }
+    }
+
+    fn receive_command(&mut self) -> Result<(), Error> {
+        let transfer_state = self.inner.transfer_state();
+        // Reads and writes move as many blocks as the buffer allows each time so during a 
+        // transfer the command is processed whenever there's some data or space. Commands
+        // that need a certain amount of either return WouldBlock until it's available
+        let skip = match transfer_state {
+            TransferState::ReceivingDataFromHost { bytes_available, done, .. } => {
+                bytes_available == 0 && !done
+            },
+            TransferState::SendingDataToHost { .. } => false,
+            // We still need to check if the buffer is empty because if a CSW is being sent
+            // we won't be able to grab a full block buffer if the next command happens to be
+            // a Read
+            TransferState::NotTransferring { empty, .. } => {
+                !empty
+            }
+        };
+
+        if skip {
+            Err(UsbError::WouldBlock)?;
+        }
+
+        // A CBW that doesn't parse fails the same way as a command that fails to execute
+        let result = self.get_new_command()
+            .and_then(|new_command| self.process_command(new_command));
+
+        match result {
+            Ok(CommandState::Done) => {
+                // Command is done, send CommandOk
+                self.inner.send_command_ok()?;
+                // Clear the command so we don't try and execute it again
+                self.current_command = Command::None;
+                self.event_sink.command_finished(self.current_lun, self.current_op_code, true);
+
+                // Reset sense code to good
+                self.logical_units.visit(self.current_lun, ResetSenseData);
+            },
+            // WouldBlock error is handled the same as ongoing (i.e. do nothing)
+            Ok(CommandState::None) |
+            Ok(CommandState::Ongoing) |
+            Err(Error::BulkOnlyTransportError(
+                BulkOnlyTransportError::UsbError(
+                    UsbError::WouldBlock))) |
+            Err(Error::BlockDeviceError(BlockDeviceError::WouldBlock)) => {
+                // No command, command is ongoing, we couldn't get a buffer/some other WouldBlock issue
+                // or the block device is busy. Do nothing, the command is retried next time
+            },
+            Err(Error::BulkOnlyTransportError(BulkOnlyTransportError::PhaseError)) => {
+                // The transport has already sent the CSW and the host is going to reset so
+                // the command is just dropped, without touching the sense data
+                self.current_command = Command::None;
+                self.event_sink.command_finished(self.current_lun, self.current_op_code, false);
+            },
+            Err(e) => {
+                // For block device errors during reads and writes the LBA that failed is reported
+                // in the information field of the sense data
+                let information = match (&e, self.current_command) {
+                    (Error::BlockDeviceError(BlockDeviceError::Unsupported), _) => None,
+                    // A flush at the end of a write isn't down to any one block
+                    (Error::BlockDeviceError(_), Command::Write(_)) |
+                    (Error::BlockDeviceError(_), Command::WriteSame(_)) if self.lba > self.lba_end => None,
+                    (Error::BlockDeviceError(_), Command::Read(_)) |
+                    (Error::BlockDeviceError(_), Command::Write(_)) |
+                    (Error::BlockDeviceError(_), Command::WriteSame(_)) |
+                    (Error::BlockDeviceError(_), Command::Verify(_)) => Some(self.lba),
+                    // Miscompares report the offset into the data instead
+                    (Error::Miscompare(offset), _) => Some(*offset),
+                    _ => None,
+                };
+
+                let sense = SenseData {
+                    information,
+                    ..map_error_to_sense_data(&e)
+                };
+
+                // Command failed, send CommandErr. Transports that can send the sense data with
+                // the status do
+                let mut bytes = [0; SHORT_SENSE_BYTES];
+                let len = sense.pack_short(&mut bytes)?;
+                self.inner.send_command_error(&bytes[..len])?;
+                // Clear the command so we don't try and execute it again
+                // All errors immediately terminate the command and cause the host to
+                // retry or issue RequestSense to find out more info
+                let command = self.current_command;
+                self.current_command = Command::None;
+                self.event_sink.command_finished(self.current_lun, self.current_op_code, false);
+
+                // Update the sense data so the host can find out what went wrong
+                self.logical_units.visit(self.current_lun, UpdateSenseData {
+                    sense,
+                    command,
+                });
+
+                // Return the error to the caller so it can get logged
+                Err(e)?;
+            },
+        }
+
+        Ok(())
+    }
+
+    /// The transport along with what it needs to abort the command in progress
+    fn transport_and_aborter(&mut self) -> (&mut T, CommandAborter<'_, L, E>) {
+        let Scsi { inner, logical_units, current_command, current_lun, current_op_code, lba, lba_end, event_sink, .. } = self;
+        let aborter = CommandAborter {
+            logical_units,
+            event_sink,
+            current_command,
+            current_lun: *current_lun,
+            current_op_code: *current_op_code,
+            lba,
+            lba_end,
+        };
+        (inner, aborter)
+    }
+
+    fn update(&mut self) -> Result<(), Error> {
+
+        // Send anything that's already queued
+        accept_would_block(
+            self.inner.write()
+                .map_err(|e| e.into())
+        )?;
+
+        // Read new data if available. The transport may abort the command in progress (a reset,
+        // UAS task management or a new alternate setting), once its block device has finished
+        // with it
+        {
+            let (inner, mut aborter) = self.transport_and_aborter();
+            let result = inner.read(|reason| aborter.abort(reason));
+            accept_would_block(result.map_err(|e| e.into()))?;
+        }
+
+        if self.flush_pending != 0 {
+            self.flush_after_reset();
+        }
+
+        // Formats carry on whatever the host is doing
+        for lun in 0..L::COUNT {
+            self.logical_units.visit(lun, ContinueFormat);
+        }
+
+        // Recieve and execute a command if one is available. A command being aborted goes no
+        // further while the block device is still finishing with it
+        if !self.inner.abort_pending() {
+            accept_would_block(self.receive_command())?;
+        }
+
+        // Send anything we may have generated this go around
+        accept_would_block(
+            self.inner.write()
+                .map_err(|e| e.into())
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Executes a command against the logical unit it was addressed to
+struct CommandProcessor<'s, T: Transport, const BUFFER_BYTES: usize, E: EventSink> {
+    inner: &'s mut T,
+    event_sink: &'s mut E,
+    command: Command,
+    profile: CommandProfile,
+    lun: u8,
+    new_command: bool,
+    /// Blocks read, written or verified this time, they end at `lba`
+    blocks_moved: u32,
+    lba: &'s mut u64,
+    lba_end: &'s mut u64,
+}
+
+impl<T: Transport, const BUFFER_BYTES: usize, E: EventSink> LogicalUnitVisitor for CommandProcessor<'_, T, BUFFER_BYTES, E> {
+    type Output = Result<CommandState, Error>;
+    fn visit<BD: BlockDevice>(mut self, logical_unit: &mut LogicalUnit<BD>) -> Self::Output {
+        let result = self.process_command(logical_unit);
+
+        // Reported and counted whether or not the command got to the end
+        if self.blocks_moved > 0 {
+            let lba = *self.lba - self.blocks_moved as u64;
+            match self.command {
+                Command::Read(_) => self.event_sink.blocks_read(self.lun, lba, self.blocks_moved),
+                Command::Write(_) => self.event_sink.blocks_written(self.lun, lba, self.blocks_moved),
+                _ => {},
+            }
+
+            if let Some(counter) = error_counter(&mut logical_unit.error_counters, self.command) {
+                counter.bytes_processed += self.blocks_moved as u64 * BD::BLOCK_BYTES as u64;
+            }
+        }
+
+        result
+    }
+}
+
+impl<T: Transport, const BUFFER_BYTES: usize, E: EventSink> CommandProcessor<'_, T, BUFFER_BYTES, E> {
+    fn process_command<BD: BlockDevice>(&mut self, lu: &mut LogicalUnit<BD>) -> Result<CommandState, Error> {
         use CommandState::*;
 
-        trace_scsi_command!("COMMAND> {:?}", self.current_command);
+        let new_command = self.new_command;
 
-        Ok(match self.current_command {
+        if new_command {
+            // A unit attention condition fails the next command, other than the ones the host
+            // uses to find out what's going on
+            match self.command {
+                Command::Inquiry(_) | Command::RequestSense(_) => {},
+                _ => if let Some(condition) = lu.unit_attentions.pop() {
+                    Err(Error::UnitAttention(condition))?;
+                },
+            }
+
+            if self.command.needs_medium() {
+                if lu.medium_state != MediumState::Present {
+                    Err(Error::MediumNotPresent)?;
+                }
+                if lu.formatting {
+                    Err(Error::FormatInProgress(lu.block_device.format_progress()))?;
+                }
+                // Formatting again is the way out
+                if lu.format_corrupted && !matches!(self.command, Command::Format(_)) {
+                    Err(Error::MediumFormatCorrupted)?;
+                }
+            }
+        }
+
+        Ok(match self.command {
             // No command, nothing to do
             Command::None => None,
 
-            // Inquiry, send back inquiry response
-            // TODO: This always responds with "standard" response data but the req might be
-            // for descriptor based response data.
-            Command::Inquiry(_) => {
-                let buf = self.inner.take_buffer_space(InquiryResponse::BYTES)?;
-                self.inquiry_response.pack(buf)?;
+            // Inquiry, send back standard inquiry data or the requested VPD page
+            Command::Inquiry(i) => {
+                let mut bytes = [0; INQUIRY_BUFFER_BYTES];
+                let len = if self.profile == CommandProfile::Ufi {
+                    ufi::inquiry(lu, i, &mut bytes[..UFI_INQUIRY_BYTES])?
+                } else if i.enable_vital_product_data {
+                    pack_vital_product_data(lu, i.page_code, BUFFER_BYTES, &mut bytes)?
+                } else if i.page_code != 0 {
+                    // Page code is only valid when requesting VPD
+                    Err(Error::InvalidFieldInCommand)?
+                } else {
+                    lu.inquiry_response.pack(&mut bytes[..InquiryResponse::BYTES])?;
+                    InquiryResponse::BYTES
+                };
+
+                send_truncated(self.inner, &bytes[..len], i.allocation_length as usize)?;
                 Done
             },
 
-            // Testing if the unit is ready. Responding CommandOk is sufficient for this request
-            // TODO: There's a situation where after enough errors the host will keep sending TUR
-            // requests and nothing else, regardless of the response. There may be additional data
-            // in the request that indicates you should respond CommandError and prepare sense
-            // response data with more info but not looked in detail yet.
+            // Testing if the unit is ready. It is if there's a medium, that's checked above
             Command::TestUnitReady(_) => Done,
 
-            // Prevent the user removing the disk, not implemented. Just responding CommandOk sufficient
-            // for flash based device.
-            Command::PreventAllowMediumRemoval(_) => Done,
+            // Prevent the host ejecting the medium, e.g. while it's mounted. The application can
+            // still remove it
+            Command::PreventAllowMediumRemoval(p) => {
+                lu.prevent_removal = match p.prevent {
+                    0 => false,
+                    1 => true,
+                    // Obsolete and reserved values
+                    _ => Err(Error::InvalidFieldInCommand)?,
+                };
+                self.event_sink.medium_removal_prevented(self.lun, lu.prevent_removal);
+                Done
+            },
 
             // Read the capacity and block size of the device
             Command::ReadCapacity(_)  => {
-                let max_lba = self.block_device.max_lba();
-                let block_size = BD::BLOCK_BYTES as u32;
-                let cap = ReadCapacity10Response {
-                    max_lba,
-                    block_size,
-                };
+                // Max LBA is 0xFFFFFFFF if it doesn't fit, telling the host to use READ CAPACITY(16)
+                let cap = ReadCapacity10Response::new(lu.block_device.max_lba(), BD::BLOCK_BYTES as u32);
                 
                 let buf = self.inner.take_buffer_space(ReadCapacity10Response::BYTES)?;
                 cap.pack(buf)?;
                 Done
             },
 
-            // Check the readonly and cache (potentially other info) about the device
-            Command::ModeSense(ModeSenseXCommand { command_length: CommandLength::C6, page_control: PageControl::CurrentValues })  => {
-                let mut header = ModeParameterHeader6::default();
-                header.increase_length_for_page(PageCode::CachingModePage);
-                
-                // Default is both caches disabled
-                let cache_page = CachingModePage::default();
+            // Same as above but with room for a 64 bit LBA
+            Command::ReadCapacity16(r) => {
+                let mut bytes = [0; ReadCapacity16Response::BYTES];
+                let mut cap = ReadCapacity16Response::new(lu.block_device.max_lba(), BD::BLOCK_BYTES as u32);
+                cap.logical_block_provisioning_management_enabled = lu.block_device.discard_granularity().is_some();
+                cap.pack(&mut bytes)?;
+                send_truncated(self.inner, &bytes, r.allocation_length as usize)?;
+                Done
+            },
+
+            // Report the capacity and what the medium can be formatted to. Answered without a
+            // medium so the host can find out there isn't one
+            Command::ReadFormatCapacities(r) => {
+                let blocks = lu.block_device.max_lba().saturating_add(1);
+                let block_length = BD::BLOCK_BYTES as u32;
+                let descriptor_type = match (lu.medium_state, lu.format_corrupted) {
+                    (MediumState::Present, false) => CapacityDescriptorType::FormattedMedia,
+                    (MediumState::Present, true) => CapacityDescriptorType::UnformattedMedia,
+                    _ => CapacityDescriptorType::NoMediaPresent,
+                };
+
+                let mut bytes = [0; READ_FORMAT_CAPACITIES_BYTES];
+                let mut i = CapacityListHeader::BYTES;
+                let end = i + CurrentMaximumCapacityDescriptor::BYTES;
+                CurrentMaximumCapacityDescriptor::new(blocks, block_length, descriptor_type).pack(&mut bytes[i..end])?;
+                i = end;
 
-                let buf = self.inner.take_buffer_space(
-                    ModeParameterHeader6::BYTES + CachingModePage::BYTES
-                )?;   
+                // The current capacity is the only one it can be formatted to
+                if descriptor_type != CapacityDescriptorType::NoMediaPresent {
+                    let end = i + FormatDescriptor::BYTES;
+                    FormatDescriptor::new(blocks, block_length).pack(&mut bytes[i..end])?;
+                    i = end;
+                }
 
-                header.pack(&mut buf[..ModeParameterHeader6::BYTES])?;
-                cache_page.pack(&mut buf[ModeParameterHeader6::BYTES..])?;
+                let capacity_list_length = (i - CapacityListHeader::BYTES) as u8;
+                CapacityListHeader::new(capacity_list_length).pack(&mut bytes[..CapacityListHeader::BYTES])?;
+                send_truncated(self.inner, &bytes[..i], r.allocation_length as usize)?;
+                Done
+            },
+
+            // Report the block descriptor and mode pages (caching etc.) of the device
+            Command::ModeSense(m) => {
+                let mut bytes = [0; MODE_SENSE_BUFFER_BYTES];
+                let len = match self.profile {
+                    CommandProfile::ScsiTransparent => mode_sense(lu, m, &mut bytes)?,
+                    CommandProfile::Ufi => ufi::mode_sense(lu, m, &mut bytes)?,
+                };
+                send_truncated(self.inner, &bytes[..len], m.allocation_length as usize)?;
+                Done
+            },
+
+            // Change mode pages, the whole parameter list has to be received first
+            Command::ModeSelect(m) => {
+                let len = m.parameter_list_length as usize;
+                let data = match self.inner.transfer_state() {
+                    _ if len == 0 => &[],
+                    TransferState::ReceivingDataFromHost { bytes_available, done, .. }
+                        if len <= BUFFER_BYTES && (bytes_available >= len || !done) => 
+                    {
+                        self.inner.take_buffered_data(len, false)?
+                    },
+                    // The host didn't send as much data as the parameter list length says
+                    _ => Err(Error::ParameterListLength)?,
+                };
+                match self.profile {
+                    CommandProfile::ScsiTransparent => mode_select(lu, m, data)?,
+                    CommandProfile::Ufi => ufi::mode_select(lu, m, data)?,
+                }
+                Done
+            },
+
+            // Report the error counters and how the device is doing
+            Command::LogSense(l) => {
+                let mut bytes = [0; LOG_SENSE_BUFFER_BYTES];
+                let len = log_sense(lu, l, &mut bytes)?;
+                send_truncated(self.inner, &bytes[..len], l.allocation_length as usize)?;
                 Done
             },
 
             // Request sense is how more info about the state of the device is returned
             // Returning CommandError will cause the host to perform a request sense
             // to get more details.
-            Command::RequestSense(_) => {
-                let buf = self.inner.take_buffer_space(RequestSenseResponse::BYTES)?;
-                self.request_sense_response.pack(buf)?;
+            Command::RequestSense(r) => {
+                // Unit attention conditions are reported here too if nothing more recent went wrong
+                if new_command && lu.sense == SenseData::default() {
+                    if let Some(condition) = lu.unit_attentions.pop() {
+                        lu.sense = map_error_to_sense_data(&Error::UnitAttention(condition));
+                    } else if lu.formatting {
+                        lu.sense = map_error_to_sense_data(&Error::FormatInProgress(lu.block_device.format_progress()));
+                    }
+                }
+                match self.profile {
+                    CommandProfile::ScsiTransparent => send_sense_data(self.inner, &lu.sense, r)?,
+                    // UFI only has the fixed format, with no sense key specific bytes
+                    CommandProfile::Ufi => {
+                        let mut bytes = [0; SHORT_SENSE_BYTES];
+                        let len = lu.sense.pack_short(&mut bytes)?;
+                        send_truncated(self.inner, &bytes[..len], r.allocation_length as usize)?;
+                    },
+                }
                 Done
             },
 
             // Read `transfer_length` blocks from `lba`
             Command::Read(r) => {
-                // Record the end condition
+                // Zero blocks is valid and does nothing
+                if r.transfer_length == 0 {
+                    return Ok(Done);
+                }
+
+                // Record the end condition. A range that runs off the end of the device fails
+                // before any data is sent
                 if new_command {
-                    self.lba = r.lba;
-                    self.lba_end = r.lba + r.transfer_length - 1;
+                    *self.lba = r.lba;
+                    *self.lba_end = r.lba.saturating_add(r.transfer_length as u64 - 1);
+                    if *self.lba_end > lu.block_device.max_lba() {
+                        Err(BlockDeviceError::InvalidAddress)?;
+                    }
                 }
 
                 trace_scsi_fs!("FS> Read; new: {}, lba: 0x{:X?}, lba_end: 0x{:X?}, done: {}",
-                    new_command, self.lba, self.lba_end, self.lba == self.lba_end);
+                    new_command, *self.lba, *self.lba_end, *self.lba == *self.lba_end);
 
-                // We only get here if the buffer is empty 
-                let buf = self.inner.take_buffer_space(BD::BLOCK_BYTES)?;
-                self.block_device.read_block(self.lba, buf)?;
-                self.lba += 1;
+                // Queue up as many blocks as there's space for
+                loop {
+                    let buf = match self.inner.peek_buffer_space(BD::BLOCK_BYTES) {
+                        Ok(buf) => buf,
+                        // Carry on once some of the buffer has been sent
+                        Err(BulkOnlyTransportError::UsbError(UsbError::WouldBlock)) => break Ongoing,
+                        Err(e) => Err(e)?,
+                    };
+                    // The space is only used once the block has been read, if the device is busy
+                    // the same block is read again next time
+                    lu.block_device.read_block(*self.lba, buf)?;
+                    self.inner.commit_buffer_space(BD::BLOCK_BYTES);
+                    *self.lba += 1;
+                    self.blocks_moved += 1;
 
-                if self.lba <= self.lba_end {
-                    Ongoing
-                } else {
-                    Done
+                    if *self.lba > *self.lba_end {
+                        break Done;
+                    }
                 }
             },
 
             // Write `transfer_length` blocks from `lba`
             Command::Write(w) => {
-                // Record the end condition
+                // Zero blocks is valid and does nothing
+                if w.transfer_length == 0 {
+                    return Ok(Done);
+                }
+
+                // Record the end condition. A range that runs off the end of the device fails
+                // before any data is taken so none of it counts as processed in the residue
                 if new_command {
-                    self.lba = w.lba;
-                    self.lba_end = w.lba + w.transfer_length - 1;
+                    *self.lba = w.lba;
+                    *self.lba_end = w.lba.saturating_add(w.transfer_length as u64 - 1);
+                    if *self.lba_end > lu.block_device.max_lba() {
+                        Err(BlockDeviceError::InvalidAddress)?;
+                    }
                 }
 
                 trace_scsi_fs!("FS> Write; new: {}, lba: 0x{:X?}, lba_end: 0x{:X?}, done: {}",
-                    new_command, self.lba, self.lba_end, self.lba == self.lba_end);
+                    new_command, *self.lba, *self.lba_end, *self.lba == *self.lba_end);
 
-                let len = match self.inner.transfer_state() {
-                    TransferState::ReceivingDataFromHost { done: true, full: false, bytes_available: b } => b,
-                    // TODO: Does this ever happen?
-                    _ => BD::BLOCK_BYTES,
-                };
+                // The data is still taken when write protected so the transfer can complete, the
+                // command fails once it has all arrived
+                let write_protected = lu.block_device.is_write_protected();
+
+                // Write as many blocks as have been received
+                loop {
+                    if *self.lba > *self.lba_end {
+                        if write_protected {
+                            Err(Error::WriteProtected)?;
+                        }
+                        // Without the write cache the data has to be on the medium before the
+                        // command completes. A busy device is flushed again next time
+                        if w.force_unit_access || !write_cache_enabled(lu)? {
+                            lu.block_device.flush()?;
+                        }
+                        break Done;
+                    }
 
-                let buf = self.inner.take_buffered_data(len, false).expect("Buffer should have enough data");
-                self.block_device.write_block(self.lba, buf)?;
-                self.lba += 1;
+                    let len = match self.inner.transfer_state() {
+                        TransferState::ReceivingDataFromHost { bytes_available, .. } 
+                            if bytes_available >= BD::BLOCK_BYTES => BD::BLOCK_BYTES,
+                        // TODO: Does this ever happen?
+                        TransferState::ReceivingDataFromHost { done: true, bytes_available: b, .. } 
+                            if b > 0 => b,
+                        // Carry on once more data arrives
+                        _ => break Ongoing,
+                    };
+
+                    let buf = self.inner.peek_buffered_data(len, false).expect("Buffer should have enough data");
+
+                    let result = if write_protected {
+                        Ok(())
+                    } else {
+                        lu.block_device.write_block(*self.lba, buf)
+                    };
+
+                    // If the device is busy the data stays in the buffer and the same block is 
+                    // written again next time. A block that failed stays there too, anything left
+                    // in the buffer counts towards the residue as it was never written
+                    result?;
+                    self.inner.consume_buffered_data(len);
+                    *self.lba += 1;
+                    if !write_protected {
+                        self.blocks_moved += 1;
+                    }
+                }
+            },
+
+            // Write out anything the device has cached. The whole cache is flushed whatever the
+            // range, IMMED is ignored as the command doesn't complete until the flush does
+            Command::SynchronizeCache(s) => {
+                let blocks = lu.block_device.max_lba().saturating_add(1);
+                if s.lba >= blocks || s.lba.saturating_add(s.number_of_blocks as u64) > blocks {
+                    Err(BlockDeviceError::InvalidAddress)?;
+                }
+                lu.block_device.flush()?;
+                Done
+            },
+
+            // There's nothing to spin up or down but stopping or ejecting the medium writes out
+            // anything cached first, unless the host says not to
+            Command::StartStopUnit(s) => {
+                // Power conditions aren't supported, start and load eject are ignored with them
+                if s.power_condition != 0 {
+                    return Ok(Done);
+                }
+
+                if s.load_eject && !s.start && lu.prevent_removal {
+                    Err(Error::MediumRemovalPrevented)?;
+                }
+                if !s.start && !s.no_flush && lu.medium_state == MediumState::Present {
+                    lu.block_device.flush()?;
+                }
 
-                if self.lba <= self.lba_end {
-                    Ongoing
+                if s.load_eject {
+                    match (s.start, lu.medium_state) {
+                        (false, MediumState::Present) => {
+                            lu.medium_state = MediumState::Ejected;
+                            self.event_sink.medium_ejected(self.lun);
+                        },
+                        (true, MediumState::Ejected) => lu.medium_state = MediumState::Present,
+                        // Can't load what the application has removed
+                        (true, MediumState::Removed) => Err(Error::MediumNotPresent)?,
+                        _ => {},
+                    }
+                }
+                Done
+            },
+
+            // Check `verification_length` blocks from `lba` can be read or that they match the
+            // data sent by the host
+            Command::Verify(v) => {
+                // Zero blocks is valid and does nothing
+                if v.verification_length == 0 {
+                    return Ok(Done);
+                }
+
+                // Record the end condition. A range that runs off the end of the device fails
+                // before any blocks are checked or data is taken
+                if new_command {
+                    *self.lba = v.lba;
+                    *self.lba_end = v.lba.saturating_add(v.verification_length as u64 - 1);
+                    if *self.lba_end > lu.block_device.max_lba() {
+                        Err(BlockDeviceError::InvalidAddress)?;
+                    }
+                }
+
+                trace_scsi_fs!("FS> Verify; new: {}, lba: 0x{:X?}, lba_end: 0x{:X?}, byte_check: {:?}",
+                    new_command, *self.lba, *self.lba_end, v.byte_check);
+
+                // Without a data stage to wait for, check about as many blocks as a read would
+                // each time so polling isn't held up by long verifies
+                let blocks_per_poll = BUFFER_BYTES / BD::BLOCK_BYTES;
+
+                match v.byte_check {
+                    ByteCheck::None => {
+                        // Nothing is being sent so the buffer is free to read into
+                        for _ in 0..blocks_per_poll {
+                            let buf = self.inner.peek_buffer_space(BD::BLOCK_BYTES)?;
+                            lu.block_device.read_block(*self.lba, buf)?;
+                            *self.lba += 1;
+                            self.blocks_moved += 1;
+
+                            if *self.lba > *self.lba_end {
+                                return Ok(Done);
+                            }
+                        }
+                        Ongoing
+                    },
+                    ByteCheck::Compare => loop {
+                        let data = match self.inner.peek_buffered_data(BD::BLOCK_BYTES, false) {
+                            Ok(data) => data,
+                            // Carry on once more data arrives
+                            Err(BulkOnlyTransportError::UsbError(UsbError::WouldBlock)) => break Ongoing,
+                            Err(e) => Err(e)?,
+                        };
+
+                        // If the device is busy the same block is compared again next time
+                        let result = lu.block_device.compare_block(*self.lba, data);
+                        if result != Err(BlockDeviceError::WouldBlock) {
+                            self.inner.consume_buffered_data(BD::BLOCK_BYTES);
+                        }
+
+                        if let Some(offset) = result? {
+                            let data_offset = (*self.lba - v.lba) * BD::BLOCK_BYTES as u64 + offset as u64;
+                            Err(Error::Miscompare(data_offset))?;
+                        }
+                        *self.lba += 1;
+                        self.blocks_moved += 1;
+
+                        if *self.lba > *self.lba_end {
+                            break Done;
+                        }
+                    },
+                    ByteCheck::CompareSingleBlock => {
+                        // Every block is compared with the same data so it stays in the buffer
+                        // until the end
+                        let data = self.inner.peek_buffered_data(BD::BLOCK_BYTES, false)?;
+
+                        let mut result = Ok(Ongoing);
+                        for _ in 0..blocks_per_poll {
+                            match lu.block_device.compare_block(*self.lba, data) {
+                                Ok(Option::None) => {},
+                                Ok(Some(offset)) => {
+                                    result = Err(Error::Miscompare(offset as u64));
+                                    break;
+                                },
+                                Err(e) => {
+                                    result = Err(e.into());
+                                    break;
+                                },
+                            }
+                            *self.lba += 1;
+                            self.blocks_moved += 1;
+
+                            if *self.lba > *self.lba_end {
+                                result = Ok(Done);
+                                break;
+                            }
+                        }
+
+                        match result {
+                            Ok(Ongoing) | Err(Error::BlockDeviceError(BlockDeviceError::WouldBlock)) => {},
+                            _ => self.inner.consume_buffered_data(BD::BLOCK_BYTES),
+                        }
+                        result?
+                    },
+                }
+            },
+
+            // Write the block sent by the host to `number_of_blocks` blocks from `lba`. If the host
+            // allows it zeros are discarded instead, the blocks don't have to read back as zeros
+            // (LBPRZ isn't set) so nothing is lost
+            Command::WriteSame(w) => {
+                // Zero meaning to the end of the medium isn't supported, see the block limits page
+                if w.number_of_blocks == 0 {
+                    Err(Error::InvalidFieldInCommand)?;
+                }
+
+                let discard_supported = lu.block_device.discard_granularity().is_some();
+                // Without data the blocks can only be discarded
+                if w.no_data_out && !(w.unmap && discard_supported) {
+                    Err(Error::InvalidFieldInCommand)?;
+                }
+
+                // Record the end condition
+                if new_command {
+                    *self.lba = w.lba;
+                    *self.lba_end = w.lba.saturating_add(w.number_of_blocks as u64 - 1);
+                }
+
+                trace_scsi_fs!("FS> WriteSame; new: {}, lba: 0x{:X?}, lba_end: 0x{:X?}, unmap: {}",
+                    new_command, *self.lba, *self.lba_end, w.unmap);
+
+                // Every block is written with the same data so it stays in the buffer until the end
+                let data = if w.no_data_out {
+                    &[]
                 } else {
-                    Done
+                    self.inner.peek_buffered_data(BD::BLOCK_BYTES, false)?
+                };
+                let discard = w.unmap && discard_supported && data.iter().all(|&b| b == 0);
+
+                let result = if *self.lba_end > lu.block_device.max_lba() {
+                    Err(BlockDeviceError::InvalidAddress.into())
+                } else if lu.block_device.is_write_protected() {
+                    Err(Error::WriteProtected)
+                } else if discard {
+                    lu.block_device.discard(*self.lba..*self.lba_end + 1)
+                        .map(|_| Done)
+                        .map_err(Into::into)
+                } else {
+                    // Like VERIFY there's no more data to wait for so only do a few blocks at a time
+                    let blocks_per_poll = BUFFER_BYTES / BD::BLOCK_BYTES;
+                    let mut result = Ok(Ongoing);
+                    for _ in 0..=blocks_per_poll {
+                        if *self.lba > *self.lba_end {
+                            // Flushed for the same reason as a write
+                            result = write_cache_enabled(lu)
+                                .and_then(|enabled| match enabled {
+                                    true => Ok(Done),
+                                    false => lu.block_device.flush().map(|_| Done).map_err(Into::into),
+                                });
+                            break;
+                        }
+                        if let Err(e) = lu.block_device.write_block(*self.lba, data) {
+                            result = Err(e.into());
+                            break;
+                        }
+                        *self.lba += 1;
+                        self.blocks_moved += 1;
+                    }
+                    result
+                };
+
+                match result {
+                    Ok(Ongoing) | Err(Error::BlockDeviceError(BlockDeviceError::WouldBlock)) => {},
+                    _ if w.no_data_out => {},
+                    _ => self.inner.consume_buffered_data(BD::BLOCK_BYTES),
+                }
+                result?
+            },
+
+            // Discard the ranges of blocks in the parameter list
+            Command::Unmap(u) => {
+                // Not advertised without discard support so it's treated as an unknown command
+                if lu.block_device.discard_granularity().is_none() {
+                    Err(Error::UnhandledOpCode)?;
+                }
+                if u.anchor {
+                    Err(Error::InvalidFieldInCommand)?;
+                }
+
+                let len = u.parameter_list_length as usize;
+                if len == 0 {
+                    return Ok(Done);
+                }
+
+                // `lba` counts the descriptors that have been dealt with
+                if new_command {
+                    *self.lba = 0;
+                }
+
+                // The whole parameter list has to be received first. It stays in the buffer until
+                // every range has been discarded in case the device is busy
+                let data = peek_parameter_list::<_, BUFFER_BYTES>(self.inner, len)?;
+                let result = unmap(lu, data, self.lba);
+                if !matches!(result, Err(Error::BlockDeviceError(BlockDeviceError::WouldBlock))) {
+                    self.inner.consume_buffered_data(len);
+                }
+                result?;
+                Done
+            },
+
+            // Download firmware, see `FirmwareUpdate`. `lba` counts the bytes written
+            Command::WriteBuffer(w) => {
+                let mode = WriteBufferMode::from_primitive(w.mode).map_err(|_| Error::InvalidFieldInCommand)?;
+                let firmware = match lu.block_device.firmware_update() {
+                    Some(f) => f,
+                    Option::None => Err(Error::UnhandledOpCode)?,
+                };
+
+                // The buffer offset is reserved in mode 5, the whole image comes at once
+                let offset = match mode {
+                    WriteBufferMode::DownloadMicrocode => 0,
+                    _ => w.buffer_offset,
+                };
+                let len = w.parameter_list_length;
+
+                if new_command {
+                    let boundary_mask = (1u32 << firmware.offset_boundary().min(31)) - 1;
+                    if w.buffer_id != 0 || offset & boundary_mask != 0 || offset.saturating_add(len) > firmware.capacity() {
+                        Err(Error::InvalidFieldInCommand)?;
+                    }
+                    *self.lba = 0;
+                }
+
+                loop {
+                    if *self.lba == len as u64 {
+                        if mode != WriteBufferMode::WithOffsets {
+                            firmware.save_firmware()?;
+                        }
+                        if mode == WriteBufferMode::DownloadMicrocode || firmware.is_download_complete(offset + len) {
+                            firmware.activate_firmware();
+                        }
+                        break Done;
+                    }
+
+                    let remaining = (len as u64 - *self.lba) as usize;
+                    let data = self.inner.peek_buffered_data(remaining.min(BUFFER_BYTES), true)?;
+                    if data.is_empty() {
+                        match self.inner.transfer_state() {
+                            // Carry on once more data arrives
+                            TransferState::ReceivingDataFromHost { done: false, .. } => break Ongoing,
+                            // The host didn't send as much data as the parameter list length says
+                            _ => Err(Error::ParameterListLength)?,
+                        }
+                    }
+
+                    // If the device is busy the data stays in the buffer and is written again
+                    // next time
+                    let data_len = data.len();
+                    let result = firmware.write_firmware(offset + *self.lba as u32, data);
+                    if result != Err(BlockDeviceError::WouldBlock) {
+                        self.inner.consume_buffered_data(data_len);
+                    }
+                    result?;
+                    *self.lba += data_len as u64;
                 }
             },
 
+            // Read back the firmware or its capacity. `lba` counts the bytes sent
+            Command::ReadBuffer(r) => {
+                let mode = ReadBufferMode::from_primitive(r.mode).map_err(|_| Error::InvalidFieldInCommand)?;
+                let firmware = match lu.block_device.firmware_update() {
+                    Some(f) => f,
+                    Option::None => Err(Error::UnhandledOpCode)?,
+                };
+                if r.buffer_id != 0 {
+                    Err(Error::InvalidFieldInCommand)?;
+                }
+
+                match mode {
+                    ReadBufferMode::Descriptor => {
+                        let mut bytes = [0; ReadBufferDescriptor::BYTES];
+                        ReadBufferDescriptor {
+                            offset_boundary: firmware.offset_boundary(),
+                            buffer_capacity: firmware.capacity(),
+                        }.pack(&mut bytes)?;
+                        send_truncated(self.inner, &bytes, r.allocation_length as usize)?;
+                        Done
+                    },
+                    ReadBufferMode::Data => {
+                        if r.buffer_offset > firmware.capacity() {
+                            Err(Error::InvalidFieldInCommand)?;
+                        }
+                        let len = r.allocation_length.min(firmware.capacity() - r.buffer_offset);
+
+                        if new_command {
+                            *self.lba = 0;
+                        }
+
+                        // Queue up as much as there's space for, like a read
+                        loop {
+                            if *self.lba == len as u64 {
+                                break Done;
+                            }
+
+                            let chunk = ((len as u64 - *self.lba) as usize).min(BUFFER_BYTES);
+                            let buf = match self.inner.peek_buffer_space(chunk) {
+                                Ok(buf) => buf,
+                                // Carry on once some of the buffer has been sent
+                                Err(BulkOnlyTransportError::UsbError(UsbError::WouldBlock)) => break Ongoing,
+                                Err(e) => Err(e)?,
+                            };
+                            firmware.read_firmware(r.buffer_offset + *self.lba as u32, buf)?;
+                            self.inner.commit_buffer_space(chunk);
+                            *self.lba += chunk as u64;
+                        }
+                    },
+                }
+            },
+
+            // Format the medium, see `LogicalUnit`. Nothing is addressed so `lba` is set once
+            // the format has started
+            Command::Format(f) => {
+                if new_command {
+                    if f.format_protection_information != 0 {
+                        Err(Error::InvalidFieldInCommand)?;
+                    }
+                    *self.lba = 0;
+                }
+
+                if *self.lba == 0 {
+                    let parameters = if f.format_data {
+                        receive_format_parameters::<_, _, BUFFER_BYTES>(self.inner, lu, f)?
+                    } else {
+                        FormatParameters::default()
+                    };
+
+                    // UFI hosts can format a track at a time. The whole medium is formatted
+                    // with the first side of the first track, the rest have nothing left to do
+                    if self.profile == CommandProfile::Ufi && parameters.single_track {
+                        if f.track_number as u16 >= FloppyGeometry::of(lu).cylinders {
+                            Err(Error::InvalidFieldInCommand)?;
+                        }
+                        if f.track_number != 0 || parameters.side {
+                            return Ok(Done);
+                        }
+                    }
+
+                    *self.lba = 1;
+                    match lu.block_device.format() {
+                        Ok(()) => {
+                            lu.format_corrupted = false;
+                            return Ok(Done);
+                        },
+                        Err(BlockDeviceError::WouldBlock) => lu.formatting = true,
+                        Err(BlockDeviceError::Unsupported) => Err(Error::UnhandledOpCode)?,
+                        Err(e) => {
+                            error!("Format failed: {:?}", e);
+                            lu.format_corrupted = true;
+                            Err(Error::FormatFailed)?;
+                        },
+                    }
+
+                    if parameters.immediate {
+                        return Ok(Done);
+                    }
+                }
+
+                // Otherwise wait for it to finish in the background
+                match (lu.formatting, lu.format_corrupted) {
+                    (true, _) => Ongoing,
+                    (false, false) => Done,
+                    (false, true) => Err(Error::FormatFailed)?,
+                }
+            },
+
+            // Move the head to a block. There's no head so the address is only checked
+            Command::Seek(s) => {
+                if s.lba > lu.block_device.max_lba() {
+                    Err(BlockDeviceError::InvalidAddress)?;
+                }
+                Done
+            },
+
+            // There's nothing to test so the default self test always passes. Other self tests
+            // and diagnostic pages aren't supported
+            Command::SendDiagnostic(s) => {
+                if !s.self_test || s.self_test_code != 0 || s.parameter_list_length != 0 {
+                    Err(Error::InvalidFieldInCommand)?;
+                }
+                Done
+            },
+
             _ => Err(Error::UnhandledOpCode)?,
         })
     }
+}
 
-    fn receive_command(&mut self) -> Result<(), Error> {
-        let transfer_state = self.inner.transfer_state();
-        // These calls all assume only a single block will fit in the buffer which 
-        // is true here because we configure BOT that way but we could make the inner
-        // buffer length a multiple of BLOCK_SIZE and queue up more than one block
-        // at a time. I don't know if there's any benefit to that but the option is there
-        let skip = match transfer_state {
-            TransferState::ReceivingDataFromHost { full, done, .. } => {
-                !(full || done)
-            },
-            TransferState::SendingDataToHost { empty, .. } => {
-                !empty
+struct BlockBytes;
+impl LogicalUnitVisitor for BlockBytes {
+    type Output = usize;
+    fn visit<BD: BlockDevice>(self, _: &mut LogicalUnit<BD>) -> usize {
+        BD::BLOCK_BYTES
+    }
+}
+
+struct Flush;
+impl LogicalUnitVisitor for Flush {
+    type Output = Result<(), BlockDeviceError>;
+    fn visit<BD: BlockDevice>(self, logical_unit: &mut LogicalUnit<BD>) -> Self::Output {
+        logical_unit.block_device.flush()
+    }
+}
+
+struct Abort;
+impl LogicalUnitVisitor for Abort {
+    type Output = Result<(), BlockDeviceError>;
+    fn visit<BD: BlockDevice>(self, logical_unit: &mut LogicalUnit<BD>) -> Self::Output {
+        logical_unit.block_device.abort()
+    }
+}
+
+struct BusReset;
+impl LogicalUnitVisitor for BusReset {
+    type Output = ();
+    fn visit<BD: BlockDevice>(self, logical_unit: &mut LogicalUnit<BD>) {
+        logical_unit.sense = Default::default();
+        logical_unit.prevent_removal = false;
+        logical_unit.unit_attentions.push(UnitAttention::PowerOnOrReset);
+    }
+}
+
+/// Moves a running format along
+struct ContinueFormat;
+impl LogicalUnitVisitor for ContinueFormat {
+    type Output = ();
+    fn visit<BD: BlockDevice>(self, logical_unit: &mut LogicalUnit<BD>) {
+        if !logical_unit.formatting {
+            return;
+        }
+        match logical_unit.block_device.format() {
+            Err(BlockDeviceError::WouldBlock) => {},
+            r => {
+                if let Err(e) = r {
+                    error!("Format failed: {:?}", e);
+                }
+                logical_unit.formatting = false;
+                logical_unit.format_corrupted = r.is_err();
             },
-            // We still need to check if the buffer is empty because if a CSW is being sent
-            // we won't be able to grab a full block buffer if the next command happens to be
-            // a Read
-            TransferState::NotTransferring { empty, .. } => {
-                !empty
+        }
+    }
+}
+
+struct ResetSenseData;
+impl LogicalUnitVisitor for ResetSenseData {
+    type Output = ();
+    fn visit<BD: BlockDevice>(self, logical_unit: &mut LogicalUnit<BD>) {
+        logical_unit.sense = Default::default();
+    }
+}
+
+/// Also counts medium and hardware errors during reads, writes and verifies for the error
+/// counter log pages
+struct UpdateSenseData {
+    sense: SenseData,
+    command: Command,
+}
+impl LogicalUnitVisitor for UpdateSenseData {
+    type Output = ();
+    fn visit<BD: BlockDevice>(self, logical_unit: &mut LogicalUnit<BD>) {
+        logical_unit.sense = self.sense;
+
+        if let SenseKey::MediumError | SenseKey::HardwareError = logical_unit.sense.sense_key {
+            if let Some(counter) = error_counter(&mut logical_unit.error_counters, self.command) {
+                counter.uncorrected_errors = counter.uncorrected_errors.saturating_add(1);
             }
+        }
+    }
+}
+
+/// The counter `command` is counted in, if any
+fn error_counter(counters: &mut ErrorCounters, command: Command) -> Option<&mut ErrorCounter> {
+    match command {
+        Command::Read(_) => Some(&mut counters.read),
+        Command::Write(_) | Command::WriteSame(_) => Some(&mut counters.write),
+        Command::Verify(_) => Some(&mut counters.verify),
+        _ => None,
+    }
+}
+
+/// Queues `bytes` to send to the host, truncated to the command's allocation length
+fn send_truncated<T: Transport>(
+    inner: &mut T, 
+    bytes: &[u8], 
+    allocation_length: usize,
+) -> Result<(), Error> {
+    let len = bytes.len().min(allocation_length);
+    let buf = inner.take_buffer_space(len)?;
+    buf.copy_from_slice(&bytes[..len]);
+    Ok(())
+}
+
+/// Sends `sense` in the format requested by `command`, truncated to the allocation length
+fn send_sense_data<T: Transport>(
+    inner: &mut T, 
+    sense: &SenseData, 
+    command: RequestSenseCommand,
+) -> Result<(), Error> {
+    let mut bytes = [0; MAX_SENSE_BYTES];
+    let len = sense.pack(command.descriptor_format, &mut bytes)?;
+    send_truncated(inner, &bytes[..len], command.allocation_length as usize)
+}
+
+/// Returns the first `len` bytes of the parameter list sent by the host, which stay in the buffer.
+/// `WouldBlock` until they've arrived
+fn peek_parameter_list<T: Transport, const BUFFER_BYTES: usize>(
+    inner: &T,
+    len: usize,
+) -> Result<&[u8], Error> {
+    match inner.transfer_state() {
+        TransferState::ReceivingDataFromHost { bytes_available, done, .. }
+            if len <= BUFFER_BYTES && (bytes_available >= len || !done) =>
+        {
+            Ok(inner.peek_buffered_data(len, false)?)
+        },
+        // The host didn't send as much data as the parameter list length says
+        _ => Err(Error::ParameterListLength),
+    }
+}
+
+/// The parts of a FORMAT UNIT parameter list that affect how the format is done
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+struct FormatParameters {
+    immediate: bool,
+    /// UFI's single track bit, the format is only for the track in the command
+    single_track: bool,
+    /// UFI's side bit, which side of the track
+    side: bool,
+}
+
+/// Takes the FORMAT UNIT parameter list from the buffer and checks it
+///
+/// There's no initialization pattern or defect list support, the only defect list accepted is a
+/// UFI format descriptor for the current capacity
+fn receive_format_parameters<T: Transport, BD: BlockDevice, const BUFFER_BYTES: usize>(
+    inner: &mut T,
+    lu: &LogicalUnit<BD>,
+    f: FormatCommand,
+) -> Result<FormatParameters, Error> {
+    let (header_len, parameters, initialization_pattern, defect_list_length) = if f.long_list {
+        let len = LongFormatParameterListHeader::BYTES;
+        let h = LongFormatParameterListHeader::unpack(peek_parameter_list::<_, BUFFER_BYTES>(inner, len)?)?;
+        let parameters = FormatParameters {
+            immediate: h.immediate,
+            ..Default::default()
+        };
+        (len, parameters, h.initialization_pattern, h.defect_list_length as usize)
+    } else {
+        let len = FormatParameterListHeader::BYTES;
+        let h = FormatParameterListHeader::unpack(peek_parameter_list::<_, BUFFER_BYTES>(inner, len)?)?;
+        let parameters = FormatParameters {
+            immediate: h.immediate,
+            single_track: h.stop_format,
+            side: h.vendor_specific,
         };
+        (len, parameters, h.initialization_pattern, h.defect_list_length as usize)
+    };
 
-        if skip {
-            Err(UsbError::WouldBlock)?;
+    let len = header_len.saturating_add(defect_list_length);
+    peek_parameter_list::<_, BUFFER_BYTES>(inner, len)?;
+    let data = inner.take_buffered_data(len, false)?;
+
+    if initialization_pattern {
+        Err(Error::InvalidFieldInParameterList)?;
+    }
+
+    let defect_list = &data[header_len..];
+    if !defect_list.is_empty() {
+        if defect_list.len() != FormatDescriptor::BYTES || f.defect_list_format != UFI_FORMAT_DESCRIPTOR_DEFECT_LIST_FORMAT {
+            Err(Error::InvalidFieldInParameterList)?;
+        }
+        let d = FormatDescriptor::unpack(defect_list)?;
+        if d.number_of_blocks as u64 != lu.block_device.max_lba() + 1 || d.block_length as usize != BD::BLOCK_BYTES {
+            Err(Error::InvalidFieldInParameterList)?;
         }
+    }
 
-        let new_command = self.get_new_command()?;
+    Ok(parameters)
+}
 
-        match self.process_command(new_command) {
-            Ok(CommandState::Done) => {
-                // Command is done, send CommandOk
-                self.inner.send_command_ok()?;
-                // Clear the command so we don't try and execute it again
-                self.current_command = Command::None;
+/// Discards the ranges in UNMAP parameter list `data`, starting from descriptor `next` which is
+/// updated as they're done
+fn unmap<BD: BlockDevice>(lu: &mut LogicalUnit<BD>, data: &[u8], next: &mut u64) -> Result<(), Error> {
+    if data.len() < UnmapParameterListHeader::BYTES {
+        Err(Error::ParameterListLength)?;
+    }
+    let (header, descriptors) = data.split_at(UnmapParameterListHeader::BYTES);
+    let header = UnmapParameterListHeader::unpack(header)?;
 
-                // Reset sense code to good
-                self.request_sense_response.reset_status();
-            },
-            // WouldBlock error is handled the same as ongoing (i.e. do nothing)
-            Ok(CommandState::None) |
-            Ok(CommandState::Ongoing) |
-            Err(Error::BulkOnlyTransportError(
-                BulkOnlyTransportError::UsbError(
-                    UsbError::WouldBlock))) => {
-                // No command, command is ongoing or we couldn't get a buffer/some other WouldBlock issue
-                // Do nothing
-            },
-            Err(e) => {
-                // Command failed, send CommandErr
-                self.inner.send_command_error()?;
-                // Clear the command so we don't try and execute it again
-                // All errors immediately terminate the command and cause the host to
-                // retry or issue RequestSense to find out more info
-                self.current_command = Command::None;
+    // A truncated descriptor at the end is ignored
+    let descriptors_len = (header.unmap_block_descriptor_data_length as usize).min(descriptors.len());
+    let descriptors = descriptors[..descriptors_len].chunks_exact(UnmapBlockDescriptor::BYTES);
 
-                // Update the sense data so the host can find out what went wrong
-                self.map_error_to_sense_data(&e);
+    // Nothing is discarded unless every range is valid
+    let blocks = lu.block_device.max_lba().saturating_add(1);
+    for d in descriptors.clone() {
+        let d = UnmapBlockDescriptor::unpack(d)?;
+        if d.lba.saturating_add(d.number_of_blocks as u64) > blocks {
+            Err(BlockDeviceError::InvalidAddress)?;
+        }
+    }
 
-                // Return the error to the caller so it can get logged
-                Err(e)?;
-            },
+    for d in descriptors.skip(*next as usize) {
+        let d = UnmapBlockDescriptor::unpack(d)?;
+        if d.number_of_blocks > 0 {
+            lu.block_device.discard(d.lba..d.lba + d.number_of_blocks as u64)?;
         }
+        *next += 1;
+    }
+    Ok(())
+}
 
-        Ok(())
+/// Packs VPD page `page_code` for `lu` into `buf`, returning the number of bytes used.
+/// `buffer_bytes` is the size of the transport's buffer
+fn pack_vital_product_data<BD: BlockDevice>(
+    lu: &LogicalUnit<BD>, 
+    page_code: u8, 
+    buffer_bytes: usize,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let page = VitalProductDataPage::from_primitive(page_code)
+        .map_err(|_| Error::InvalidFieldInCommand)?;
+    let serial_number = lu.serial_number.as_bytes();
+    let discard_granularity = lu.block_device.discard_granularity();
+
+    let mut i = VitalProductDataHeader::BYTES;
+    match page {
+        VitalProductDataPage::SupportedVpdPages => {
+            let pages = SUPPORTED_VPD_PAGES.iter()
+                .filter(|&&p| p != VitalProductDataPage::UnitSerialNumber || !serial_number.is_empty())
+                .filter(|&&p| p != VitalProductDataPage::LogicalBlockProvisioning || discard_granularity.is_some());
+            for &p in pages {
+                buf[i] = p as u8;
+                i += 1;
+            }
+        },
+        VitalProductDataPage::UnitSerialNumber => {
+            if serial_number.is_empty() {
+                Err(Error::InvalidFieldInCommand)?;
+            }
+            buf[i..i + serial_number.len()].copy_from_slice(serial_number);
+            i += serial_number.len();
+        },
+        VitalProductDataPage::DeviceIdentification => {
+            // T10 vendor ID based designator: vendor identification followed by product
+            // identification and serial number to make it unique
+            let vendor = lu.inquiry_response.vendor_identification();
+            let product = lu.inquiry_response.product_identification();
+            let designator_length = vendor.len() + product.len() + serial_number.len();
+
+            let end = i + DesignationDescriptorHeader::BYTES;
+            DesignationDescriptorHeader::t10_vendor_id(designator_length as u8).pack(&mut buf[i..end])?;
+            i = end;
+
+            for part in [&vendor[..], &product[..], serial_number].iter() {
+                buf[i..i + part.len()].copy_from_slice(part);
+                i += part.len();
+            }
+        },
+        VitalProductDataPage::BlockLimits => {
+            // The header is part of the page struct
+            let mut page = BlockLimitsPage::default();
+            page.write_same_non_zero = true;
+            if let Some(granularity) = discard_granularity {
+                // The parameter list has to fit in the buffer, otherwise there's no limit
+                page.maximum_unmap_lba_count = u32::MAX;
+                page.maximum_unmap_block_descriptor_count =
+                    ((buffer_bytes - UnmapParameterListHeader::BYTES) / UnmapBlockDescriptor::BYTES) as u32;
+                page.optimal_unmap_granularity = granularity;
+            }
+            page.pack(&mut buf[..BlockLimitsPage::BYTES])?;
+            return Ok(BlockLimitsPage::BYTES);
+        },
+        VitalProductDataPage::BlockDeviceCharacteristics => {
+            BlockDeviceCharacteristicsPage::default().pack(&mut buf[..BlockDeviceCharacteristicsPage::BYTES])?;
+            return Ok(BlockDeviceCharacteristicsPage::BYTES);
+        },
+        VitalProductDataPage::LogicalBlockProvisioning => {
+            if discard_granularity.is_none() {
+                Err(Error::InvalidFieldInCommand)?;
+            }
+            let mut page = LogicalBlockProvisioningPage::default();
+            page.unmap_supported = true;
+            page.write_same_16_unmap_supported = true;
+            page.write_same_10_unmap_supported = true;
+            // The capacity is all there, blocks are only discarded to save erasing later
+            page.provisioning_type = 1;
+            page.pack(&mut buf[..LogicalBlockProvisioningPage::BYTES])?;
+            return Ok(LogicalBlockProvisioningPage::BYTES);
+        },
     }
 
-    fn map_error_to_sense_data(&mut self, err: &Error) {
-        let (sense_key, additional_sense_code) = match err {
-            Error::UnhandledOpCode => (
-                 SenseKey::IllegalRequest,
-                 AdditionalSenseCode::InvalidCommandOperationCode,
-            ),
-            
-            Error::InsufficientDataForCommand => (
-                SenseKey::IllegalRequest,
-                // Closest thing I could find. Some sources suggest OS does very little with ASC/ASCQ and it's
-                // most useful for debugging so as long as it's unique here it's probably ok.
-                AdditionalSenseCode::InvalidPacketSize,
-            ),
+    let page_length = (i - VitalProductDataHeader::BYTES) as u16;
+    VitalProductDataHeader::new(page, page_length).pack(&mut buf[..VitalProductDataHeader::BYTES])?;
 
-            Error::PackingError(p) |
-            Error::BulkOnlyTransportError(BulkOnlyTransportError::PackingError(p)) => match p {
-                PackingError::InsufficientBytes => panic!("PackingError::InsufficientBytes: Logical error in program"),
-                PackingError::Infallible(_) => unreachable!(),
-                PackingError::InvalidEnumDiscriminant => (
-                    SenseKey::IllegalRequest,
-                    AdditionalSenseCode::InvalidFieldInCdb,
-                ),
-            },
+    Ok(i)
+}
 
-            Error::BlockDeviceError(BlockDeviceError::HardwareError) => (
-                SenseKey::HardwareError,
-                //TODO: split errors up more?
-                AdditionalSenseCode::NoAdditionalSenseInformation,
-            ),
-            Error::BlockDeviceError(BlockDeviceError::WriteError) => (
-                SenseKey::MediumError,
-                AdditionalSenseCode::WriteError,
-            ),
-            Error::BlockDeviceError(BlockDeviceError::EraseError) => (
-                SenseKey::MediumError,
-                AdditionalSenseCode::EraseFailure,
-            ),
-            Error::BlockDeviceError(BlockDeviceError::InvalidAddress) => (
-                SenseKey::IllegalRequest,
-                AdditionalSenseCode::LogicalBlockAddressOutOfRange,
-            ),
+fn map_error_to_sense_data(err: &Error) -> SenseData {
+    let (sense_key, additional_sense_code) = match err {
+        Error::UnhandledOpCode => (
+             SenseKey::IllegalRequest,
+             AdditionalSenseCode::InvalidCommandOperationCode,
+        ),
+        
+        Error::LogicalUnitNotSupported => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::LogicalUnitNotSupported,
+        ),
+
+        Error::InvalidFieldInCommand => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::InvalidFieldInCdb,
+        ),
+
+        Error::InvalidFieldInParameterList => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::InvalidFieldInParameterList,
+        ),
+
+        Error::ParameterListLength => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::ParameterListLengthError,
+        ),
+
+        Error::SavingParametersNotSupported => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::SavingParametersNotSupported,
+        ),
+
+        Error::WriteProtected => (
+            SenseKey::DataProtect,
+            AdditionalSenseCode::WriteProtected,
+        ),
+
+        Error::Miscompare(_) => (
+            SenseKey::Miscompare,
+            AdditionalSenseCode::MiscompareDuringVerifyOperation,
+        ),
+
+        Error::MediumNotPresent => (
+            SenseKey::NotReady,
+            AdditionalSenseCode::MediumNotPresent,
+        ),
 
-            Error::BulkOnlyTransportError(BulkOnlyTransportError::DataError) => (
+        Error::MediumRemovalPrevented => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::MediumRemovalPrevented,
+        ),
+
+        Error::UnitAttention(condition) => (
+            SenseKey::UnitAttention,
+            condition.additional_sense_code(),
+        ),
+
+        Error::FormatInProgress(_) => (
+            SenseKey::NotReady,
+            AdditionalSenseCode::LogicalUnitNotReadyFormatInProgress,
+        ),
+
+        Error::MediumFormatCorrupted => (
+            SenseKey::MediumError,
+            AdditionalSenseCode::MediumFormatCorrupted,
+        ),
+
+        Error::FormatFailed => (
+            SenseKey::MediumError,
+            AdditionalSenseCode::FormatCommandFailed,
+        ),
+
+        Error::InsufficientDataForCommand => (
+            SenseKey::IllegalRequest,
+            // Closest thing I could find. Some sources suggest OS does very little with ASC/ASCQ and it's
+            // most useful for debugging so as long as it's unique here it's probably ok.
+            AdditionalSenseCode::InvalidPacketSize,
+        ),
+
+        Error::PackingError(p) |
+        Error::BulkOnlyTransportError(BulkOnlyTransportError::PackingError(p)) => match p {
+            PackingError::InsufficientBytes => panic!("PackingError::InsufficientBytes: Logical error in program"),
+            PackingError::Infallible(_) => unreachable!(),
+            PackingError::InvalidEnumDiscriminant => (
                 SenseKey::IllegalRequest,
                 AdditionalSenseCode::InvalidFieldInCdb,
             ),
+        },
 
-            // These USB errors are likely to result in a USB reset, it's unlikely a SCSI
-            // request sense will ever be issued in these cases but just-in-case
-            Error::BulkOnlyTransportError(BulkOnlyTransportError::UsbError(_)) => (
-                SenseKey::HardwareError,
-                AdditionalSenseCode::NoAdditionalSenseInformation,
-            ),
-        };
+        Error::BlockDeviceError(BlockDeviceError::HardwareError) => (
+            SenseKey::HardwareError,
+            //TODO: split errors up more?
+            AdditionalSenseCode::NoAdditionalSenseInformation,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::WriteError) => (
+            SenseKey::MediumError,
+            AdditionalSenseCode::WriteError,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::EraseError) => (
+            SenseKey::MediumError,
+            AdditionalSenseCode::EraseFailure,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::InvalidAddress) => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::LogicalBlockAddressOutOfRange,
+        ),
+        Error::BlockDeviceError(BlockDeviceError::Unsupported) => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::InvalidFieldInCdb,
+        ),
+        // Retried rather than failing the command so this shouldn't happen
+        Error::BlockDeviceError(BlockDeviceError::WouldBlock) => (
+            SenseKey::NotReady,
+            AdditionalSenseCode::LogicalUnitNotReadyOperationInProgress,
+        ),
 
-        info!("SENSE: {:?}, ASC: {} {}", sense_key, additional_sense_code.asc(), additional_sense_code.ascq());
-        self.request_sense_response.sense_key = sense_key;
-        self.request_sense_response.additional_sense_code = additional_sense_code;
-    }
+        Error::BulkOnlyTransportError(BulkOnlyTransportError::DataError) |
+        Error::BulkOnlyTransportError(BulkOnlyTransportError::InvalidCbw(_)) |
+        Error::BulkOnlyTransportError(BulkOnlyTransportError::PhaseError) => (
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::InvalidFieldInCdb,
+        ),
 
-    fn update(&mut self) -> Result<(), Error> {
+        // These USB errors are likely to result in a USB reset, it's unlikely a SCSI
+        // request sense will ever be issued in these cases but just-in-case
+        Error::BulkOnlyTransportError(BulkOnlyTransportError::UsbError(_)) => (
+            SenseKey::HardwareError,
+            AdditionalSenseCode::NoAdditionalSenseInformation,
+        ),
+    };
 
-        // Send anything that's already queued
-        accept_would_block(
-            self.inner.write()
-                .map_err(|e| e.into())
-        )?;
+    // Progress indication
+    let sense_key_specific = match err {
+        Error::FormatInProgress(progress) => progress.map(u32::from),
+        _ => None,
+    };
 
-        // Read new data if available
-        accept_would_block(
-            self.inner.read()
-                .map_err(|e| e.into())
-        )?;
+    info!("SENSE: {:?}, ASC: {} {}", sense_key, additional_sense_code.asc(), additional_sense_code.ascq());
+    SenseData {
+        sense_key_specific,
+        ..SenseData::new(sense_key, additional_sense_code)
+    }
+}
 
-        // Recieve and execute a command if one is available
-        accept_would_block(self.receive_command())?;
+/// Max LUN for the transport, panics if there are too many or too few logical units
+fn max_lun<L: LogicalUnits>() -> u8 {
+    assert!(L::COUNT > 0 && L::COUNT <= 16);
+    L::COUNT - 1
+}
 
-        // Send anything we may have generated this go around
-        accept_would_block(
-            self.inner.write()
-                .map_err(|e| e.into())
-        )?;
+/// The parts of `Scsi` that abort the command in progress, borrowed alongside the transport
+/// so it can call back into them
+struct CommandAborter<'s, L: LogicalUnits, E: EventSink> {
+    logical_units: &'s mut L,
+    event_sink: &'s mut E,
+    current_command: &'s mut Command,
+    current_lun: u8,
+    current_op_code: u8,
+    lba: &'s mut u64,
+    lba_end: &'s mut u64,
+}
 
-        Ok(())
+impl<L: LogicalUnits, E: EventSink> CommandAborter<'_, L, E> {
+    /// Drops the command in progress once its block device has finished with it, for
+    /// transports that abort commands. Returns false if the block device is still busy
+    fn abort(&mut self, reason: AbortReason) -> bool {
+        if *self.current_command != Command::None {
+            match self.logical_units.visit(self.current_lun, Abort) {
+                Some(Err(BlockDeviceError::WouldBlock)) => return false,
+                Some(Err(e)) => error!("Abort failed for LUN {}: {:?}", self.current_lun, e),
+                _ => {},
+            }
+
+            *self.current_command = Command::None;
+            *self.lba = 0;
+            *self.lba_end = 0;
+            self.event_sink.command_finished(self.current_lun, self.current_op_code, false);
+        }
+
+        match reason {
+            AbortReason::MassStorageReset |
+            AbortReason::CommandBlockReset => self.event_sink.mass_storage_reset(),
+            _ => {},
+        }
+        true
     }
 }
 
@@ -398,17 +1855,25 @@ fn accept_would_block(r: Result<(), Error>) -> Result<(), Error> {
     }
 }
 
-impl<B: UsbBus, BD: BlockDevice> UsbClass<B> for Scsi<'_, B, BD> {
+impl<B: UsbBus, L: LogicalUnits, const BUFFER_BYTES: usize, E: EventSink, H: CommandHandler, T: TransportClass<B>> UsbClass<B> for Scsi<'_, B, L, BUFFER_BYTES, E, H, T> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
         self.inner.get_configuration_descriptors(writer)
     }
 
     fn reset(&mut self) { 
         self.current_command = Command::None;
-        self.request_sense_response.reset_status();
+        for lun in 0..L::COUNT {
+            self.logical_units.visit(lun, BusReset);
+        }
         self.lba = 0;
         self.lba_end = 0;
 
+        // Anything cached is written out over the next few polls, flushing can take too long
+        // to do here
+        self.flush_pending = u16::MAX >> (16 - L::COUNT);
+
+        self.event_sink.bus_reset();
+
         self.inner.reset()
     }
 
@@ -416,8 +1881,11 @@ impl<B: UsbBus, BD: BlockDevice> UsbClass<B> for Scsi<'_, B, BD> {
         self.inner.control_in(xfer)
     }
 
+    // A reset isn't accepted until the current command has been aborted, if the block device
+    // can do that straight away
     fn control_out(&mut self, xfer: ControlOut<B>) {
-        self.inner.control_out(xfer)
+        let (inner, mut aborter) = self.transport_and_aborter();
+        inner.control_out_with_abort(xfer, |reason| aborter.abort(reason))
     }
 
     fn poll(&mut self) { 
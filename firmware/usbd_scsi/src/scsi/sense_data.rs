@@ -0,0 +1,199 @@
+use packing::{
+    Packed,
+    PackedSize,
+};
+
+use crate::scsi::{
+    enums::{
+        ResponseCode,
+        SenseKey,
+        AdditionalSenseCode,
+    },
+    responses::*,
+    Error,
+};
+
+/// Largest possible sense data in either format
+pub(crate) const MAX_SENSE_BYTES: usize = RequestSenseResponse::BYTES;
+
+/// Fixed format sense data without the additional sense bytes
+pub(crate) const SHORT_SENSE_BYTES: usize = 18;
+
+/// # Sense data for a logical unit
+///
+/// Holds what went wrong independent of the format it's reported in. REQUEST SENSE picks fixed
+/// or descriptor format depending on the DESC bit in the command.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub(crate) struct SenseData {
+    pub sense_key: SenseKey,
+    pub additional_sense_code: AdditionalSenseCode,
+
+    /// The error is from an earlier command rather than the most recent one
+    pub deferred: bool,
+
+    /// Depends on the command, for reads and writes it's the LBA that failed
+    pub information: Option<u64>,
+
+    pub command_specific_information: Option<u64>,
+
+    /// 23 bits whose meaning depends on `sense_key` (field pointer, progress indication, etc)
+    pub sense_key_specific: Option<u32>,
+}
+
+impl SenseData {
+    pub fn new(sense_key: SenseKey, additional_sense_code: AdditionalSenseCode) -> Self {
+        Self {
+            sense_key,
+            additional_sense_code,
+            ..Default::default()
+        }
+    }
+
+    /// Packs the sense data into `buf` in the requested format, returning the number of bytes used
+    ///
+    /// `buf` must be at least `MAX_SENSE_BYTES` long
+    pub fn pack(&self, descriptor_format: bool, buf: &mut [u8]) -> Result<usize, Error> {
+        if descriptor_format {
+            self.pack_descriptor_format(buf)
+        } else {
+            self.fixed_format().pack(&mut buf[..RequestSenseResponse::BYTES])?;
+            Ok(RequestSenseResponse::BYTES)
+        }
+    }
+
+    /// Packs fixed format sense data without the additional sense bytes into `buf`, returning
+    /// the number of bytes used. Short enough to go along with the status on transports that
+    /// can send it that way
+    ///
+    /// `buf` must be at least `SHORT_SENSE_BYTES` long
+    pub fn pack_short(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut bytes = [0; RequestSenseResponse::BYTES];
+        RequestSenseResponse {
+            additional_sense_length: (SHORT_SENSE_BYTES - 8) as u8,
+            ..self.fixed_format()
+        }.pack(&mut bytes)?;
+        buf[..SHORT_SENSE_BYTES].copy_from_slice(&bytes[..SHORT_SENSE_BYTES]);
+        Ok(SHORT_SENSE_BYTES)
+    }
+
+    fn fixed_format(&self) -> RequestSenseResponse {
+        // Fixed format only has room for 32 bit information fields, VALID is cleared if they
+        // don't fit
+        let information = self.information.filter(|&i| i <= u32::MAX as u64);
+        let command_specific_information = self.command_specific_information
+            .filter(|&i| i <= u32::MAX as u64);
+
+        RequestSenseResponse {
+            valid: information.is_some(),
+            response_code: if self.deferred {
+                ResponseCode::DeferredFixedSenseData
+            } else {
+                ResponseCode::FixedSenseData
+            },
+            sense_key: self.sense_key,
+            information: information.unwrap_or(0) as u32,
+            command_specifc_information: command_specific_information.unwrap_or(0) as u32,
+            additional_sense_code: self.additional_sense_code,
+            sense_key_specific_valid: self.sense_key_specific.is_some(),
+            sense_key_specific: self.sense_key_specific.unwrap_or(0),
+            ..Default::default()
+        }
+    }
+
+    fn pack_descriptor_format(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut i = DescriptorSenseHeader::BYTES;
+
+        if let Some(information) = self.information {
+            let end = i + InformationSenseDescriptor::BYTES;
+            InformationSenseDescriptor::new(information).pack(&mut buf[i..end])?;
+            i = end;
+        }
+
+        if let Some(command_specific_information) = self.command_specific_information {
+            let end = i + CommandSpecificInformationSenseDescriptor::BYTES;
+            CommandSpecificInformationSenseDescriptor::new(command_specific_information).pack(&mut buf[i..end])?;
+            i = end;
+        }
+
+        if let Some(sense_key_specific) = self.sense_key_specific {
+            let end = i + SenseKeySpecificSenseDescriptor::BYTES;
+            SenseKeySpecificSenseDescriptor::new(sense_key_specific).pack(&mut buf[i..end])?;
+            i = end;
+        }
+
+        let response_code = if self.deferred {
+            ResponseCode::DeferredDescriptorSenseData
+        } else {
+            ResponseCode::DescriptorSenseData
+        };
+
+        DescriptorSenseHeader::new(
+            response_code,
+            self.sense_key,
+            self.additional_sense_code,
+            (i - DescriptorSenseHeader::BYTES) as u8,
+        ).pack(&mut buf[..DescriptorSenseHeader::BYTES])?;
+
+        Ok(i)
+    }
+}
+
+#[test]
+fn test_fixed_format() {
+    let mut bytes = [0; MAX_SENSE_BYTES];
+
+    let len = SenseData::default().pack(false, &mut bytes).unwrap();
+    assert_eq!(len, RequestSenseResponse::BYTES);
+    assert_eq!(bytes[..18], [0x70, 0, 0, 0, 0, 0, 0, len as u8 - 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    let sense = SenseData {
+        information: Some(0x1234),
+        sense_key_specific: Some(0x00_0102),
+        ..SenseData::new(SenseKey::MediumError, AdditionalSenseCode::WriteError)
+    };
+    sense.pack(false, &mut bytes).unwrap();
+    assert_eq!(bytes[..18], [0xF0, 0, 0x03, 0, 0, 0x12, 0x34, len as u8 - 8, 0, 0, 0, 0, 0x0C, 0x00, 0, 0x80, 0x01, 0x02]);
+
+    // Too big for the fixed format information field
+    let sense = SenseData {
+        information: Some(0x1_0000_0000),
+        ..sense
+    };
+    sense.pack(false, &mut bytes).unwrap();
+    assert_eq!(bytes[..7], [0x70, 0, 0x03, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_short_format() {
+    let mut bytes = [0xFF; SHORT_SENSE_BYTES];
+
+    let sense = SenseData {
+        information: Some(0x1234),
+        ..SenseData::new(SenseKey::MediumError, AdditionalSenseCode::WriteError)
+    };
+    let len = sense.pack_short(&mut bytes).unwrap();
+    assert_eq!(bytes[..len], [0xF0, 0, 0x03, 0, 0, 0x12, 0x34, 10, 0, 0, 0, 0, 0x0C, 0x00, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_descriptor_format() {
+    let mut bytes = [0xFF; MAX_SENSE_BYTES];
+
+    let len = SenseData::default().pack(true, &mut bytes).unwrap();
+    assert_eq!(bytes[..len], [0x72, 0, 0, 0, 0, 0, 0, 0]);
+
+    let sense = SenseData {
+        deferred: true,
+        information: Some(0x1_0000_0000),
+        command_specific_information: Some(0x55),
+        sense_key_specific: Some(0x00_0102),
+        ..SenseData::new(SenseKey::MediumError, AdditionalSenseCode::WriteError)
+    };
+    let len = sense.pack(true, &mut bytes).unwrap();
+    assert_eq!(bytes[..len], [
+        0x73, 0x03, 0x0C, 0x00, 0, 0, 0, 32,
+        0x00, 0x0A, 0x80, 0, 0, 0, 0, 0x01, 0, 0, 0, 0,
+        0x01, 0x0A, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x55,
+        0x02, 0x06, 0, 0, 0x80, 0x01, 0x02, 0,
+    ][..]);
+}
@@ -0,0 +1,291 @@
+use packing::{
+    Packed,
+    PackedSize,
+};
+
+use crate::{
+    block_device::BlockDevice,
+    logical_unit::LogicalUnit,
+    scsi::{
+        commands::*,
+        enums::{
+            MediumType,
+            PageControl,
+        },
+        mode_parameters::{
+            split_page,
+            ALL_PAGES,
+        },
+        Error,
+        InquiryResponse,
+    },
+};
+
+/// UFI standard inquiry data stops after the product revision level
+pub(crate) const UFI_INQUIRY_BYTES: usize = 36;
+
+/// The pages a UFI device has, in the order they're returned for all pages
+const UFI_PAGES: [PageCode; 4] = [
+    PageCode::ReadWriteErrorRecoveryModePage,
+    PageCode::FlexibleDiskModePage,
+    PageCode::RemovableBlockAccessCapabilitiesModePage,
+    // UFI's timer and protect page
+    PageCode::InformationalExceptionsControlModePage,
+];
+
+/// Longest of the UFI pages, the flexible disk page
+const MAX_UFI_PAGE_BYTES: usize = FlexibleDiskModePage::BYTES;
+
+/// Motor delays reported in the flexible disk page, in 100ms units. There's no motor so they're
+/// just typical values for a floppy drive
+const MOTOR_ON_DELAY: u8 = 5;
+const MOTOR_OFF_DELAY: u8 = 30;
+
+/// What a floppy disk of a certain capacity looks like to the host
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub(crate) struct FloppyGeometry {
+    pub medium_type: MediumType,
+    /// In kbit/s
+    pub transfer_rate: u16,
+    pub heads: u8,
+    pub sectors_per_track: u8,
+    pub cylinders: u16,
+    /// In rpm
+    pub rotation_rate: u16,
+}
+
+/// The formats UFI gives a medium type code, by number of blocks and block length
+///
+/// UFI 4.5
+const STANDARD_FORMATS: [(u64, usize, FloppyGeometry); 3] = [
+    (1440, 512, FloppyGeometry {
+        medium_type: MediumType::Floppy720K,
+        transfer_rate: 250,
+        heads: 2,
+        sectors_per_track: 9,
+        cylinders: 80,
+        rotation_rate: 300,
+    }),
+    (1232, 1024, FloppyGeometry {
+        medium_type: MediumType::Floppy1M25,
+        transfer_rate: 500,
+        heads: 2,
+        sectors_per_track: 8,
+        cylinders: 77,
+        rotation_rate: 360,
+    }),
+    (2880, 512, FloppyGeometry {
+        medium_type: MediumType::Floppy1M44,
+        transfer_rate: 500,
+        heads: 2,
+        sectors_per_track: 18,
+        cylinders: 80,
+        rotation_rate: 300,
+    }),
+];
+
+impl FloppyGeometry {
+    /// The standard format with this capacity. Anything else is reported as a 1.44 MB style
+    /// disk with as many cylinders as it takes, the host only uses the capacity anyway
+    pub fn new(blocks: u64, block_bytes: usize) -> Self {
+        if let Some((_, _, geometry)) = STANDARD_FORMATS.iter().find(|(b, l, _)| *b == blocks && *l == block_bytes) {
+            return *geometry;
+        }
+
+        let heads = 2;
+        let sectors_per_track = 18;
+        let blocks_per_cylinder = heads as u64 * sectors_per_track as u64;
+        let cylinders = blocks.saturating_add(blocks_per_cylinder - 1) / blocks_per_cylinder;
+        FloppyGeometry {
+            medium_type: MediumType::Sbc,
+            transfer_rate: 500,
+            heads,
+            sectors_per_track,
+            cylinders: cylinders.min(u16::MAX as u64) as u16,
+            rotation_rate: 300,
+        }
+    }
+
+    pub fn of<BD: BlockDevice>(lu: &LogicalUnit<BD>) -> Self {
+        Self::new(lu.block_device.max_lba().saturating_add(1), BD::BLOCK_BYTES)
+    }
+}
+
+/// Packs the UFI standard inquiry data into `buf`, returning the number of bytes used. There
+/// are no VPD pages
+pub(crate) fn inquiry<BD: BlockDevice>(
+    lu: &LogicalUnit<BD>,
+    command: InquiryCommand,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    if command.enable_vital_product_data || command.page_code != 0 {
+        Err(Error::InvalidFieldInCommand)?;
+    }
+
+    let mut bytes = [0; InquiryResponse::BYTES];
+    lu.inquiry_response.ufi().pack(&mut bytes)?;
+    buf[..UFI_INQUIRY_BYTES].copy_from_slice(&bytes[..UFI_INQUIRY_BYTES]);
+    Ok(UFI_INQUIRY_BYTES)
+}
+
+/// Packs the UFI MODE SENSE(10) response for `command` into `buf`, returning the number of
+/// bytes used. UFI has no block descriptors, the medium type in the header says what kind of
+/// floppy it is instead
+pub(crate) fn mode_sense<BD: BlockDevice>(
+    lu: &LogicalUnit<BD>,
+    command: ModeSenseXCommand,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    if command.subpage_code != 0 {
+        Err(Error::InvalidFieldInCommand)?;
+    }
+
+    let mut i = ModeParameterHeader10::BYTES;
+    if command.page_code == ALL_PAGES {
+        for &page_code in UFI_PAGES.iter() {
+            i += sense_page(lu, page_code, command.page_control, &mut buf[i..])?;
+        }
+    } else {
+        let page_code = ufi_page_code(command.page_code).ok_or(Error::InvalidFieldInCommand)?;
+        i += sense_page(lu, page_code, command.page_control, &mut buf[i..])?;
+    }
+
+    ModeParameterHeader10 {
+        mode_data_length: (i - 2) as u16,
+        medium_type: FloppyGeometry::of(lu).medium_type,
+        device_specific_parameter: SbcDeviceSpecificParameter {
+            write_protect: lu.block_device.is_write_protected(),
+            ..Default::default()
+        },
+        ..Default::default()
+    }.pack(&mut buf[..ModeParameterHeader10::BYTES])?;
+
+    Ok(i)
+}
+
+/// Checks the UFI MODE SELECT(10) parameter list `data`
+///
+/// None of the UFI pages can be changed, they're only accepted if they match the current values
+pub(crate) fn mode_select<BD: BlockDevice>(
+    lu: &LogicalUnit<BD>,
+    command: ModeSelectXCommand,
+    data: &[u8],
+) -> Result<(), Error> {
+    if data.is_empty() {
+        // Nothing to change
+        return Ok(());
+    }
+    if command.save_pages {
+        Err(Error::SavingParametersNotSupported)?;
+    }
+
+    let header = ModeParameterHeader10::unpack(data.get(..ModeParameterHeader10::BYTES)
+        .ok_or(Error::ParameterListLength)?)
+        .map_err(|_| Error::InvalidFieldInParameterList)?;
+    if header.block_descriptor_length != 0 {
+        Err(Error::InvalidFieldInParameterList)?;
+    }
+
+    let mut remaining = &data[ModeParameterHeader10::BYTES..];
+    while !remaining.is_empty() {
+        let (page, rest) = split_page(remaining)?;
+        let page_code = ufi_page_code(page[0] & ALL_PAGES).ok_or(Error::InvalidFieldInParameterList)?;
+
+        let mut current = [0; MAX_UFI_PAGE_BYTES];
+        let len = current_page(lu, page_code, &mut current)?;
+        // The parameters saveable bit is reserved in MODE SELECT
+        if page.len() != len || page[0] & ALL_PAGES != current[0] || page[1..] != current[1..len] {
+            Err(Error::InvalidFieldInParameterList)?;
+        }
+        remaining = rest;
+    }
+
+    Ok(())
+}
+
+fn ufi_page_code(page_code: u8) -> Option<PageCode> {
+    PageCode::from_primitive(page_code).ok()
+        .filter(|page_code| UFI_PAGES.contains(page_code))
+}
+
+/// Packs page `page_code` into `buf`, returning the number of bytes used. Nothing can be
+/// changed or saved
+fn sense_page<BD: BlockDevice>(
+    lu: &LogicalUnit<BD>,
+    page_code: PageCode,
+    page_control: PageControl,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let len = match page_control {
+        PageControl::CurrentValues | PageControl::DefaultValues => current_page(lu, page_code, buf)?,
+        PageControl::ChangeableValues => {
+            let len = current_page(lu, page_code, buf)?;
+            for b in &mut buf[2..len] {
+                *b = 0;
+            }
+            len
+        },
+        PageControl::SavedValues => Err(Error::SavingParametersNotSupported)?,
+    };
+    Ok(len)
+}
+
+fn current_page<BD: BlockDevice>(
+    lu: &LogicalUnit<BD>,
+    page_code: PageCode,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    Ok(match page_code {
+        PageCode::ReadWriteErrorRecoveryModePage => {
+            ReadWriteErrorRecoveryModePage::default()
+                .pack(&mut buf[..ReadWriteErrorRecoveryModePage::BYTES])?;
+            ReadWriteErrorRecoveryModePage::BYTES
+        },
+        PageCode::FlexibleDiskModePage => {
+            let geometry = FloppyGeometry::of(lu);
+            FlexibleDiskModePage {
+                transfer_rate: geometry.transfer_rate,
+                number_of_heads: geometry.heads,
+                sectors_per_track: geometry.sectors_per_track,
+                data_bytes_per_sector: BD::BLOCK_BYTES as u16,
+                number_of_cylinders: geometry.cylinders,
+                motor_on_delay: MOTOR_ON_DELAY,
+                motor_off_delay: MOTOR_OFF_DELAY,
+                medium_rotation_rate: geometry.rotation_rate,
+                ..Default::default()
+            }.pack(&mut buf[..FlexibleDiskModePage::BYTES])?;
+            FlexibleDiskModePage::BYTES
+        },
+        PageCode::RemovableBlockAccessCapabilitiesModePage => {
+            RemovableBlockAccessCapabilitiesModePage {
+                // REQUEST SENSE has the progress while formatting
+                supports_reporting_format_progress: true,
+                total_luns: 1,
+                ..Default::default()
+            }.pack(&mut buf[..RemovableBlockAccessCapabilitiesModePage::BYTES])?;
+            RemovableBlockAccessCapabilitiesModePage::BYTES
+        },
+        PageCode::InformationalExceptionsControlModePage => {
+            TimerAndProtectModePage::default()
+                .pack(&mut buf[..TimerAndProtectModePage::BYTES])?;
+            TimerAndProtectModePage::BYTES
+        },
+        PageCode::CachingModePage => Err(Error::InvalidFieldInCommand)?,
+    })
+}
+
+#[test]
+fn test_floppy_geometry() {
+    let geometry = FloppyGeometry::new(2880, 512);
+    assert_eq!(geometry.medium_type, MediumType::Floppy1M44);
+    assert_eq!((geometry.heads, geometry.sectors_per_track, geometry.cylinders), (2, 18, 80));
+
+    assert_eq!(FloppyGeometry::new(1232, 1024).medium_type, MediumType::Floppy1M25);
+    // Same number of blocks, different block length
+    assert_eq!(FloppyGeometry::new(1440, 1024).medium_type, MediumType::Sbc);
+
+    // Partial cylinders are rounded up
+    let geometry = FloppyGeometry::new(100, 512);
+    assert_eq!(geometry.medium_type, MediumType::Sbc);
+    assert_eq!(geometry.cylinders, 3);
+}
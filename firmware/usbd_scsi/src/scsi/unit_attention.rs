@@ -0,0 +1,72 @@
+use crate::scsi::enums::AdditionalSenseCode;
+
+/// Conditions reported to the host with a UNIT ATTENTION sense key
+///
+/// Declared in priority order, the lowest is reported first
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub(crate) enum UnitAttention {
+    /// Power on or bus reset
+    PowerOnOrReset,
+    /// The medium was inserted and may not be the one the host last saw
+    MediumChanged,
+}
+
+impl UnitAttention {
+    pub fn additional_sense_code(self) -> AdditionalSenseCode {
+        match self {
+            UnitAttention::PowerOnOrReset => AdditionalSenseCode::PowerOnResetOrBusDeviceResetOccurred,
+            UnitAttention::MediumChanged => AdditionalSenseCode::NotReadyToReadyChangeMediumMayHaveChanged,
+        }
+    }
+}
+
+/// # Unit attention conditions waiting to be reported
+///
+/// Each condition is only held once and they're reported one at a time, highest priority first.
+/// A power on or reset clears everything else as the host starts from scratch after one anyway.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub(crate) struct UnitAttentionQueue {
+    /// One bit per condition, the bit number is the `UnitAttention` discriminant
+    pending: u8,
+}
+
+impl UnitAttentionQueue {
+    pub fn push(&mut self, condition: UnitAttention) {
+        let bit = 1 << condition as u8;
+        if condition == UnitAttention::PowerOnOrReset {
+            self.pending = bit;
+        } else {
+            self.pending |= bit;
+        }
+    }
+
+    /// Removes and returns the highest priority condition
+    pub fn pop(&mut self) -> Option<UnitAttention> {
+        let condition = match self.pending.trailing_zeros() {
+            0 => UnitAttention::PowerOnOrReset,
+            1 => UnitAttention::MediumChanged,
+            _ => return None,
+        };
+        self.pending &= self.pending - 1;
+        Some(condition)
+    }
+}
+
+#[test]
+fn test_unit_attention_queue() {
+    let mut queue = UnitAttentionQueue::default();
+    assert_eq!(queue.pop(), None);
+
+    queue.push(UnitAttention::MediumChanged);
+    queue.push(UnitAttention::MediumChanged);
+    assert_eq!(queue.pop(), Some(UnitAttention::MediumChanged));
+    assert_eq!(queue.pop(), None);
+
+    // Reset clears other conditions, later ones queue up behind it
+    queue.push(UnitAttention::MediumChanged);
+    queue.push(UnitAttention::PowerOnOrReset);
+    queue.push(UnitAttention::MediumChanged);
+    assert_eq!(queue.pop(), Some(UnitAttention::PowerOnOrReset));
+    assert_eq!(queue.pop(), Some(UnitAttention::MediumChanged));
+    assert_eq!(queue.pop(), None);
+}
@@ -0,0 +1,252 @@
+mod common;
+use common::*;
+use usb_device::{
+    class::UsbClass,
+    UsbError,
+};
+use usbd_scsi::{
+    CommandProfile,
+    LogicalUnit,
+};
+
+const CLASS_INTERFACE_OUT: u8 = 0x21;
+const ACCEPT_DEVICE_SPECIFIC_COMMAND: u8 = 0x00;
+
+/// UFI command blocks are always 12 bytes
+const UFI_COMMAND_BYTES: usize = 12;
+
+/// A 1.44 MB floppy
+const FLOPPY_BLOCKS: usize = 2880;
+
+/// How many times the device is polled waiting for it to do something before giving up
+const POLL_LIMIT: usize = 1000;
+
+fn mode_sense10(page_code: u8, allocation_length: u16) -> [u8; 10] {
+    let mut cdb = [0; 10];
+    cdb[0] = 0x5A;
+    cdb[2] = page_code;
+    cdb[7..9].copy_from_slice(&allocation_length.to_be_bytes());
+    cdb
+}
+
+fn command_block_reset() -> [u8; UFI_COMMAND_BYTES] {
+    let mut cdb = [0xFF; UFI_COMMAND_BYTES];
+    cdb[..2].copy_from_slice(&[0x1D, 0x04]);
+    cdb
+}
+
+/// A floppy sized disk on UFI, with the power on unit attention still there
+fn floppy(command_completion_interrupt: bool) -> (CbiScsi<LogicalUnit<SparseDisk>>, Host) {
+    cbi_device(disk_unit(SparseDisk::new(FLOPPY_BLOCKS as u64)), CommandProfile::Ufi, command_completion_interrupt)
+}
+
+/// Sends `cdb` padded to a UFI command block in an ADSC request. Returns false if the device
+/// rejected it
+fn adsc<C: UsbClass<MockBus>>(host: &mut Host, device: &mut C, cdb: &[u8]) -> bool {
+    let mut command_block = [0; UFI_COMMAND_BYTES];
+    command_block[..cdb.len()].copy_from_slice(cdb);
+    host.control_out(device, CLASS_INTERFACE_OUT, ACCEPT_DEVICE_SPECIFIC_COMMAND, 0, INTERFACE, &command_block)
+}
+
+/// Polls the device until it sends the interrupt data block
+fn receive_interrupt<C: UsbClass<MockBus>>(host: &mut Host, device: &mut C) -> Vec<u8> {
+    for _ in 0..POLL_LIMIT {
+        match host.bus().receive_packet(INTERRUPT_IN) {
+            Ok(data) => return data,
+            Err(UsbError::WouldBlock) => device.poll(),
+            Err(e) => panic!("Receiving interrupt failed: {:?}", e),
+        }
+    }
+    panic!("Device stopped writing to the interrupt endpoint");
+}
+
+/// Runs a complete command: ADSC, the data if there is any, then the interrupt. `data_in` is
+/// the most data the host accepts. Returns the data and the interrupt data block
+fn command<C: UsbClass<MockBus>>(
+    host: &mut Host,
+    device: &mut C,
+    cdb: &[u8],
+    data_in: usize,
+    data_out: &[u8],
+) -> (Vec<u8>, Vec<u8>) {
+    assert!(adsc(host, device, cdb), "ADSC was rejected");
+
+    let mut data = Vec::new();
+    if data_in > 0 {
+        data = host.receive_data(device, data_in).0;
+    } else if !data_out.is_empty() {
+        host.send_data(device, data_out);
+    }
+
+    (data, receive_interrupt(host, device))
+}
+
+/// A floppy with a command completion interrupt and the power on unit attention cleared
+fn ready_floppy() -> (CbiScsi<LogicalUnit<SparseDisk>>, Host) {
+    let (mut scsi, mut host) = floppy(true);
+    let (_, interrupt) = command(&mut host, &mut scsi, &TEST_UNIT_READY, 0, &[]);
+    assert_eq!(interrupt, [0x29, 0x00]);
+    let (sense, interrupt) = command(&mut host, &mut scsi, &REQUEST_SENSE, 18, &[]);
+    assert_eq!(interrupt, [0, 0]);
+    assert_eq!(sense[2] & 0x0F, 0x06);
+    (scsi, host)
+}
+
+#[test]
+fn descriptors_offer_cbi() {
+    for (command_completion_interrupt, protocol, endpoints) in [
+        (true, 0x00, vec![(0x01, 0x02), (0x81, 0x02), (0x82, 0x03)]),
+        (false, 0x01, vec![(0x01, 0x02), (0x81, 0x02)]),
+    ] {
+        let (mut scsi, mut host) = floppy(command_completion_interrupt);
+        let descriptor = host.configuration_descriptor(&mut scsi);
+
+        // (class, subclass, protocol, endpoints) for each interface descriptor and (address,
+        // attributes) for each endpoint descriptor
+        let mut interfaces = Vec::new();
+        let mut eps = Vec::new();
+        let mut i = 0;
+        while i < descriptor.len() {
+            let d = &descriptor[i..i + descriptor[i] as usize];
+            match d[1] {
+                0x04 => interfaces.push((d[5], d[6], d[7], d[4])),
+                0x05 => eps.push((d[2], d[3])),
+                _ => {},
+            }
+            i += d.len();
+        }
+
+        assert_eq!(interfaces, [(0x08, 0x04, protocol, endpoints.len() as u8)]);
+        assert_eq!(eps, endpoints);
+    }
+}
+
+#[test]
+fn ufi_inquiry() {
+    let (mut scsi, mut host) = floppy(true);
+
+    // INQUIRY doesn't report the unit attention
+    let (data, interrupt) = command(&mut host, &mut scsi, &INQUIRY, 36, &[]);
+    assert_eq!(interrupt, [0, 0]);
+    assert_eq!(data.len(), 36);
+    // Removable, UFI response data format, no further fields past the product revision
+    assert_eq!(&data[..5], [0x00, 0x80, 0x00, 0x01, 0x1F]);
+    assert_eq!(&data[8..16], b"Mock    ");
+    assert_eq!(&data[16..32], b"RamDisk         ");
+}
+
+#[test]
+fn read_and_write() {
+    let (mut scsi, mut host) = ready_floppy();
+
+    let data: Vec<u8> = (0..2 * BLOCK_BYTES).map(|i| (i as u8).wrapping_mul(5)).collect();
+    let (_, interrupt) = command(&mut host, &mut scsi, &write10(7, 2), 0, &data);
+    assert_eq!(interrupt, [0, 0]);
+    let disk = scsi.block_device_mut();
+    assert_eq!([&disk.blocks[&7][..], &disk.blocks[&8][..]].concat(), data);
+
+    let (read, interrupt) = command(&mut host, &mut scsi, &read10(7, 2), data.len(), &[]);
+    assert_eq!(interrupt, [0, 0]);
+    assert_eq!(read, data);
+    host.assert_idle(&mut scsi);
+}
+
+#[test]
+fn failure_reports_asc_in_interrupt() {
+    let (mut scsi, mut host) = ready_floppy();
+
+    // Past the end of the disk, LOGICAL BLOCK ADDRESS OUT OF RANGE
+    assert!(adsc(&mut host, &mut scsi, &read10(FLOPPY_BLOCKS as u32, 1)));
+    // Nothing was read so the data stage is stalled
+    assert!(host.receive_data(&mut scsi, BLOCK_BYTES).0.is_empty());
+    assert!(host.bus().in_stalled(BULK_IN), "Bulk IN endpoint isn't stalled");
+    assert_eq!(receive_interrupt(&mut host, &mut scsi), [0x21, 0x00]);
+    host.clear_halt(&mut scsi, 0x80 | BULK_IN as u8);
+
+    let (sense, interrupt) = command(&mut host, &mut scsi, &REQUEST_SENSE, 18, &[]);
+    assert_eq!(interrupt, [0, 0]);
+    assert_eq!((sense[2] & 0x0F, sense[12], sense[13]), (0x05, 0x21, 0x00));
+    host.assert_idle(&mut scsi);
+}
+
+#[test]
+fn failure_without_interrupt_stalls_data_stage() {
+    let (mut scsi, mut host) = floppy(false);
+
+    // Failing a command without a data stage can only be found out from the sense data
+    assert!(adsc(&mut host, &mut scsi, &TEST_UNIT_READY));
+    host.assert_idle(&mut scsi);
+    assert!(adsc(&mut host, &mut scsi, &REQUEST_SENSE));
+    let (sense, _) = host.receive_data(&mut scsi, 18);
+    assert_eq!((sense[2] & 0x0F, sense[12]), (0x06, 0x29));
+
+    assert!(adsc(&mut host, &mut scsi, &read10(FLOPPY_BLOCKS as u32, 1)));
+    assert!(host.receive_data(&mut scsi, BLOCK_BYTES).0.is_empty());
+    assert!(host.bus().in_stalled(BULK_IN), "Bulk IN endpoint isn't stalled");
+    host.clear_halt(&mut scsi, 0x80 | BULK_IN as u8);
+
+    assert!(adsc(&mut host, &mut scsi, &INQUIRY));
+    assert_eq!(host.receive_data(&mut scsi, 36).0.len(), 36);
+    host.assert_idle(&mut scsi);
+}
+
+#[test]
+fn flexible_disk_page() {
+    let (mut scsi, mut host) = ready_floppy();
+
+    let (data, interrupt) = command(&mut host, &mut scsi, &mode_sense10(0x05, 255), 255, &[]);
+    assert_eq!(interrupt, [0, 0]);
+    assert_eq!(data.len(), 8 + 32);
+    // 1.44 MB medium type and no block descriptors
+    assert_eq!(u16::from_be_bytes([data[0], data[1]]) as usize, data.len() - 2);
+    assert_eq!(data[2], 0x94);
+    assert_eq!(u16::from_be_bytes([data[6], data[7]]), 0);
+
+    let page = &data[8..];
+    assert_eq!(&page[..2], [0x05, 0x1E]);
+    // 500 kbit/s, 2 heads, 18 sectors of 512 bytes, 80 cylinders
+    assert_eq!(&page[2..10], [0x01, 0xF4, 2, 18, 0x02, 0x00, 0x00, 80]);
+    assert_eq!(&page[28..30], 300u16.to_be_bytes());
+
+    // The pages UFI doesn't have are rejected
+    let (_, interrupt) = command(&mut host, &mut scsi, &mode_sense10(0x08, 255), 255, &[]);
+    assert_eq!(interrupt, [0x24, 0x00]);
+    host.clear_bulk_halts(&mut scsi);
+    host.assert_idle(&mut scsi);
+}
+
+#[test]
+fn command_block_reset_aborts_command() {
+    let (mut scsi, mut host) = ready_floppy();
+
+    // Some of the data for a write, then the host gives up on it
+    assert!(adsc(&mut host, &mut scsi, &write10(0, 4)));
+    host.send_packet(&mut scsi, &[0xAA; MAX_PACKET_SIZE as usize]);
+    assert!(adsc(&mut host, &mut scsi, &command_block_reset()));
+    host.clear_bulk_halts(&mut scsi);
+    assert!(matches!(host.bus().receive_packet(INTERRUPT_IN), Err(UsbError::WouldBlock)));
+
+    // The device is ready for the next command
+    let (_, interrupt) = command(&mut host, &mut scsi, &TEST_UNIT_READY, 0, &[]);
+    assert_eq!(interrupt, [0, 0]);
+    let (data, interrupt) = command(&mut host, &mut scsi, &INQUIRY, 36, &[]);
+    assert_eq!(interrupt, [0, 0]);
+    assert_eq!(data.len(), 36);
+    host.assert_idle(&mut scsi);
+}
+
+#[test]
+fn command_before_completion_is_held() {
+    let (mut scsi, mut host) = ready_floppy();
+
+    // The second command waits for the first to complete, a third is one too many. The
+    // first has more data than the endpoint holds so it can't complete on its own
+    assert!(adsc(&mut host, &mut scsi, &read10(0, 4)));
+    assert!(adsc(&mut host, &mut scsi, &TEST_UNIT_READY));
+    assert!(!adsc(&mut host, &mut scsi, &TEST_UNIT_READY));
+
+    assert_eq!(host.receive_data(&mut scsi, 2048).0.len(), 2048);
+    assert_eq!(receive_interrupt(&mut host, &mut scsi), [0, 0]);
+    assert_eq!(receive_interrupt(&mut host, &mut scsi), [0, 0]);
+    host.assert_idle(&mut scsi);
+}
@@ -0,0 +1,422 @@
+//! Wrappers that add the optional parts of `BlockDevice` to a device that doesn't have them
+use packing::{
+    Packed,
+    PackedSize,
+};
+use std::ops::{
+    Deref,
+    DerefMut,
+    Range,
+};
+use usbd_scsi::{
+    BlockDevice,
+    BlockDeviceError,
+    CachingModePage,
+    DeviceHealth,
+    FirmwareUpdate,
+    ModePageError,
+    ModePages,
+    PageCode,
+    PageControl,
+};
+
+/// Provides a caching mode page with a changeable and saveable write cache enabled bit
+pub struct WithCachingPage<BD> {
+    inner: BD,
+    pub write_cache_enabled: bool,
+    pub saved_write_cache_enabled: bool,
+}
+deref_inner!(WithCachingPage);
+
+impl<BD> WithCachingPage<BD> {
+    pub fn new(inner: BD) -> WithCachingPage<BD> {
+        WithCachingPage {
+            inner,
+            write_cache_enabled: false,
+            saved_write_cache_enabled: false,
+        }
+    }
+}
+
+impl<BD: BlockDevice> BlockDevice for WithCachingPage<BD> {
+    const BLOCK_BYTES: usize = BD::BLOCK_BYTES;
+
+    fn mode_pages(&mut self) -> Option<&mut dyn ModePages> {
+        Some(self)
+    }
+
+    forward_block_device!(
+        read_block,
+        write_block,
+        max_lba,
+        compare_block,
+        flush,
+        abort,
+        discard,
+        discard_granularity,
+        format,
+        format_progress,
+        is_write_protected,
+        health,
+        firmware_update,
+    );
+}
+
+impl<BD> ModePages for WithCachingPage<BD> {
+    fn has_page(&self, page_code: u8) -> bool {
+        page_code == PageCode::CachingModePage as u8
+    }
+
+    fn sense_page(&self, _page_code: u8, page_control: PageControl, buf: &mut [u8]) -> Result<usize, ModePageError> {
+        let mut page = match page_control {
+            PageControl::CurrentValues => CachingModePage { write_cache_enabled: self.write_cache_enabled, ..Default::default() },
+            PageControl::ChangeableValues => CachingModePage { write_cache_enabled: true, ..CachingModePage::unchangeable() },
+            PageControl::DefaultValues => CachingModePage::default(),
+            PageControl::SavedValues => CachingModePage { write_cache_enabled: self.saved_write_cache_enabled, ..Default::default() },
+        };
+        page.parameters_saveable = page_control != PageControl::ChangeableValues;
+        page.pack(&mut buf[..CachingModePage::BYTES]).unwrap();
+        Ok(CachingModePage::BYTES)
+    }
+
+    fn select_page(&mut self, page: &[u8], save: bool) -> Result<(), ModePageError> {
+        let page = CachingModePage::unpack(page).map_err(|_| ModePageError::InvalidField)?;
+        self.write_cache_enabled = page.write_cache_enabled;
+        if save {
+            self.saved_write_cache_enabled = page.write_cache_enabled;
+        }
+        Ok(())
+    }
+}
+
+/// Provides a page for each of `page_codes`, `page_length` bytes long after the header and
+/// filled with the page code
+pub struct WithLargePages<BD> {
+    inner: BD,
+    pub page_codes: Vec<u8>,
+    pub page_length: u8,
+}
+deref_inner!(WithLargePages);
+
+impl<BD> WithLargePages<BD> {
+    pub fn new(inner: BD, page_codes: &[u8], page_length: u8) -> WithLargePages<BD> {
+        WithLargePages {
+            inner,
+            page_codes: page_codes.to_vec(),
+            page_length,
+        }
+    }
+}
+
+impl<BD: BlockDevice> BlockDevice for WithLargePages<BD> {
+    const BLOCK_BYTES: usize = BD::BLOCK_BYTES;
+
+    fn mode_pages(&mut self) -> Option<&mut dyn ModePages> {
+        Some(self)
+    }
+
+    forward_block_device!(
+        read_block,
+        write_block,
+        max_lba,
+        compare_block,
+        flush,
+        abort,
+        discard,
+        discard_granularity,
+        format,
+        format_progress,
+        is_write_protected,
+        health,
+        firmware_update,
+    );
+}
+
+impl<BD> ModePages for WithLargePages<BD> {
+    fn has_page(&self, page_code: u8) -> bool {
+        self.page_codes.contains(&page_code)
+    }
+
+    fn sense_page(&self, page_code: u8, _page_control: PageControl, buf: &mut [u8]) -> Result<usize, ModePageError> {
+        let len = 2 + self.page_length as usize;
+        let page = buf.get_mut(..len).ok_or(ModePageError::InsufficientSpace)?;
+        page.fill(page_code);
+        page[1] = self.page_length;
+        Ok(len)
+    }
+
+    fn select_page(&mut self, _page: &[u8], _save: bool) -> Result<(), ModePageError> {
+        Err(ModePageError::InvalidField)
+    }
+}
+
+/// Supports discarding `discard_granularity` blocks at a time, discarded blocks are filled with
+/// 0xFF like erased flash
+pub struct Discarding<BD> {
+    inner: BD,
+    pub discard_granularity: u32,
+    /// Ranges passed to successful calls to `discard`
+    pub discarded: Vec<Range<u64>>,
+}
+deref_inner!(Discarding);
+
+impl<BD> Discarding<BD> {
+    pub fn new(inner: BD, discard_granularity: u32) -> Discarding<BD> {
+        Discarding {
+            inner,
+            discard_granularity,
+            discarded: Vec::new(),
+        }
+    }
+}
+
+impl<BD: BlockDevice> BlockDevice for Discarding<BD> {
+    const BLOCK_BYTES: usize = BD::BLOCK_BYTES;
+
+    fn discard(&mut self, lbas: Range<u64>) -> Result<(), BlockDeviceError> {
+        if lbas.end > self.inner.max_lba() + 1 {
+            Err(BlockDeviceError::InvalidAddress)?;
+        }
+        let erased = vec![0xFF; BD::BLOCK_BYTES];
+        for lba in lbas.clone() {
+            self.inner.write_block(lba, &erased)?;
+        }
+        self.discarded.push(lbas);
+        Ok(())
+    }
+
+    fn discard_granularity(&self) -> Option<u32> {
+        Some(self.discard_granularity)
+    }
+
+    forward_block_device!(
+        read_block,
+        write_block,
+        max_lba,
+        compare_block,
+        flush,
+        abort,
+        format,
+        format_progress,
+        is_write_protected,
+        mode_pages,
+        health,
+        firmware_update,
+    );
+}
+
+/// Each format returns `WouldBlock` `format_calls` times before the device underneath is
+/// formatted, reporting progress as it goes
+pub struct SlowFormat<BD> {
+    inner: BD,
+    pub format_calls: usize,
+    format_remaining: usize,
+    /// Number of successful formats
+    pub formats: usize,
+    /// Formats fail with `EraseError`
+    pub fail_format: bool,
+}
+deref_inner!(SlowFormat);
+
+impl<BD> SlowFormat<BD> {
+    pub fn new(inner: BD, format_calls: usize) -> SlowFormat<BD> {
+        SlowFormat {
+            inner,
+            format_calls,
+            format_remaining: 0,
+            formats: 0,
+            fail_format: false,
+        }
+    }
+}
+
+impl<BD: BlockDevice> BlockDevice for SlowFormat<BD> {
+    const BLOCK_BYTES: usize = BD::BLOCK_BYTES;
+
+    fn format(&mut self) -> Result<(), BlockDeviceError> {
+        if self.format_remaining == 0 {
+            self.format_remaining = self.format_calls + 1;
+        }
+        self.format_remaining -= 1;
+        if self.format_remaining > 0 {
+            Err(BlockDeviceError::WouldBlock)?;
+        }
+        if self.fail_format {
+            Err(BlockDeviceError::EraseError)?;
+        }
+        self.inner.format()?;
+        self.formats += 1;
+        Ok(())
+    }
+
+    fn format_progress(&self) -> Option<u16> {
+        let done = self.format_calls + 1 - self.format_remaining;
+        Some((done * 0x10000 / (self.format_calls + 1)) as u16)
+    }
+
+    forward_block_device!(
+        read_block,
+        write_block,
+        max_lba,
+        compare_block,
+        flush,
+        abort,
+        discard,
+        discard_granularity,
+        is_write_protected,
+        mode_pages,
+        health,
+        firmware_update,
+    );
+}
+
+/// A firmware image held in memory, its length is the capacity
+pub struct FirmwareImage {
+    pub image: Vec<u8>,
+    /// Downloads with offsets are complete once a segment ends here
+    pub length: Option<u32>,
+    /// Number of calls to `save_firmware`
+    pub saves: usize,
+    /// Number of calls to `activate_firmware`
+    pub activations: usize,
+}
+
+impl FirmwareImage {
+    /// An erased image of `capacity` bytes
+    pub fn new(capacity: usize) -> FirmwareImage {
+        FirmwareImage {
+            image: vec![0xFF; capacity],
+            length: None,
+            saves: 0,
+            activations: 0,
+        }
+    }
+}
+
+impl FirmwareUpdate for FirmwareImage {
+    fn capacity(&self) -> u32 {
+        self.image.len() as u32
+    }
+
+    fn offset_boundary(&self) -> u8 {
+        4
+    }
+
+    fn write_firmware(&mut self, offset: u32, data: &[u8]) -> Result<(), BlockDeviceError> {
+        let offset = offset as usize;
+        self.image[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_firmware(&self, offset: u32, data: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let offset = offset as usize;
+        data.copy_from_slice(&self.image[offset..offset + data.len()]);
+        Ok(())
+    }
+
+    fn save_firmware(&mut self) -> Result<(), BlockDeviceError> {
+        self.saves += 1;
+        Ok(())
+    }
+
+    fn is_download_complete(&self, end: u32) -> bool {
+        self.length == Some(end)
+    }
+
+    fn activate_firmware(&mut self) {
+        self.activations += 1;
+    }
+}
+
+/// Accepts firmware updates into `firmware`
+pub struct WithFirmware<BD, F = FirmwareImage> {
+    inner: BD,
+    pub firmware: F,
+}
+
+impl<BD, F> WithFirmware<BD, F> {
+    pub fn new(inner: BD, firmware: F) -> WithFirmware<BD, F> {
+        WithFirmware {
+            inner,
+            firmware,
+        }
+    }
+}
+
+impl<BD, F> Deref for WithFirmware<BD, F> {
+    type Target = BD;
+
+    fn deref(&self) -> &BD {
+        &self.inner
+    }
+}
+
+impl<BD, F> DerefMut for WithFirmware<BD, F> {
+    fn deref_mut(&mut self) -> &mut BD {
+        &mut self.inner
+    }
+}
+
+impl<BD: BlockDevice, F: FirmwareUpdate> BlockDevice for WithFirmware<BD, F> {
+    const BLOCK_BYTES: usize = BD::BLOCK_BYTES;
+
+    fn firmware_update(&mut self) -> Option<&mut dyn FirmwareUpdate> {
+        Some(&mut self.firmware)
+    }
+
+    forward_block_device!(
+        read_block,
+        write_block,
+        max_lba,
+        compare_block,
+        flush,
+        abort,
+        discard,
+        discard_granularity,
+        format,
+        format_progress,
+        is_write_protected,
+        mode_pages,
+        health,
+    );
+}
+
+/// Reports `health` for the LOG SENSE pages
+pub struct WithHealth<BD> {
+    inner: BD,
+    pub health: DeviceHealth,
+}
+deref_inner!(WithHealth);
+
+impl<BD> WithHealth<BD> {
+    pub fn new(inner: BD) -> WithHealth<BD> {
+        WithHealth {
+            inner,
+            health: DeviceHealth::default(),
+        }
+    }
+}
+
+impl<BD: BlockDevice> BlockDevice for WithHealth<BD> {
+    const BLOCK_BYTES: usize = BD::BLOCK_BYTES;
+
+    fn health(&self) -> DeviceHealth {
+        self.health
+    }
+
+    forward_block_device!(
+        read_block,
+        write_block,
+        max_lba,
+        compare_block,
+        flush,
+        abort,
+        discard,
+        discard_granularity,
+        format,
+        format_progress,
+        is_write_protected,
+        mode_pages,
+        firmware_update,
+    );
+}
@@ -0,0 +1,19 @@
+//! Command blocks the tests send over every transport
+
+pub const TEST_UNIT_READY: [u8; 6] = [0x00, 0, 0, 0, 0, 0];
+pub const INQUIRY: [u8; 6] = [0x12, 0, 0, 0, 36, 0];
+pub const REQUEST_SENSE: [u8; 6] = [0x03, 0, 0, 0, 18, 0];
+
+pub fn read10(lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cdb = [0; 10];
+    cdb[0] = 0x28;
+    cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+    cdb[7..9].copy_from_slice(&blocks.to_be_bytes());
+    cdb
+}
+
+pub fn write10(lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cdb = read10(lba, blocks);
+    cdb[0] = 0x2A;
+    cdb
+}
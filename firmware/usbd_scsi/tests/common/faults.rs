@@ -0,0 +1,330 @@
+//! Wrappers that make a block device misbehave or keep count of what was asked of it
+use std::{
+    cell::Cell,
+    ops::Range,
+};
+use usbd_scsi::{
+    BlockDevice,
+    BlockDeviceError,
+    FirmwareUpdate,
+};
+
+/// Returns `WouldBlock` `busy_calls` times before each read, write, compare, flush, abort and
+/// discard goes through. Does the same for firmware updates when it wraps a `FirmwareUpdate`
+pub struct Busy<BD> {
+    inner: BD,
+    pub busy_calls: usize,
+    busy_remaining: Cell<usize>,
+}
+deref_inner!(Busy);
+
+impl<BD> Busy<BD> {
+    pub fn new(inner: BD, busy_calls: usize) -> Busy<BD> {
+        Busy {
+            inner,
+            busy_calls,
+            busy_remaining: Cell::new(0),
+        }
+    }
+
+    fn busy(&self) -> Result<(), BlockDeviceError> {
+        match self.busy_remaining.get() {
+            0 => {
+                self.busy_remaining.set(self.busy_calls);
+                Ok(())
+            },
+            n => {
+                self.busy_remaining.set(n - 1);
+                Err(BlockDeviceError::WouldBlock)
+            },
+        }
+    }
+}
+
+impl<BD: BlockDevice> BlockDevice for Busy<BD> {
+    const BLOCK_BYTES: usize = BD::BLOCK_BYTES;
+
+    fn read_block(&self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.busy()?;
+        self.inner.read_block(lba, block)
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.busy()?;
+        self.inner.write_block(lba, block)
+    }
+
+    fn compare_block(&self, lba: u64, data: &[u8]) -> Result<Option<usize>, BlockDeviceError> {
+        self.busy()?;
+        self.inner.compare_block(lba, data)
+    }
+
+    fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        self.busy()?;
+        self.inner.flush()
+    }
+
+    fn abort(&mut self) -> Result<(), BlockDeviceError> {
+        self.busy()?;
+        self.inner.abort()
+    }
+
+    fn discard(&mut self, lbas: Range<u64>) -> Result<(), BlockDeviceError> {
+        self.busy()?;
+        self.inner.discard(lbas)
+    }
+
+    forward_block_device!(
+        max_lba,
+        discard_granularity,
+        format,
+        format_progress,
+        is_write_protected,
+        mode_pages,
+        health,
+        firmware_update,
+    );
+}
+
+impl<F: FirmwareUpdate> FirmwareUpdate for Busy<F> {
+    fn capacity(&self) -> u32 {
+        self.inner.capacity()
+    }
+
+    fn offset_boundary(&self) -> u8 {
+        self.inner.offset_boundary()
+    }
+
+    fn write_firmware(&mut self, offset: u32, data: &[u8]) -> Result<(), BlockDeviceError> {
+        self.busy()?;
+        self.inner.write_firmware(offset, data)
+    }
+
+    fn read_firmware(&self, offset: u32, data: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.busy()?;
+        self.inner.read_firmware(offset, data)
+    }
+
+    fn save_firmware(&mut self) -> Result<(), BlockDeviceError> {
+        self.busy()?;
+        self.inner.save_firmware()
+    }
+
+    fn is_download_complete(&self, end: u32) -> bool {
+        self.inner.is_download_complete(end)
+    }
+
+    fn activate_firmware(&mut self) {
+        self.inner.activate_firmware()
+    }
+}
+
+/// Counts the reads, writes, flushes and aborts that went through
+pub struct Counted<BD> {
+    inner: BD,
+    /// Number of successful calls to `read_block`
+    pub blocks_read: Cell<usize>,
+    /// Number of successful calls to `write_block`
+    pub blocks_written: usize,
+    /// Number of successful calls to `flush`
+    pub flushes: usize,
+    /// Number of successful calls to `abort`
+    pub aborts: usize,
+}
+deref_inner!(Counted);
+
+impl<BD> Counted<BD> {
+    pub fn new(inner: BD) -> Counted<BD> {
+        Counted {
+            inner,
+            blocks_read: Cell::new(0),
+            blocks_written: 0,
+            flushes: 0,
+            aborts: 0,
+        }
+    }
+}
+
+impl<BD: BlockDevice> BlockDevice for Counted<BD> {
+    const BLOCK_BYTES: usize = BD::BLOCK_BYTES;
+
+    fn read_block(&self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.inner.read_block(lba, block)?;
+        self.blocks_read.set(self.blocks_read.get() + 1);
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.inner.write_block(lba, block)?;
+        self.blocks_written += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        self.inner.flush()?;
+        self.flushes += 1;
+        Ok(())
+    }
+
+    fn abort(&mut self) -> Result<(), BlockDeviceError> {
+        self.inner.abort()?;
+        self.aborts += 1;
+        Ok(())
+    }
+
+    forward_block_device!(
+        max_lba,
+        compare_block,
+        discard,
+        discard_granularity,
+        format,
+        format_progress,
+        is_write_protected,
+        mode_pages,
+        health,
+        firmware_update,
+    );
+}
+
+/// Each abort returns `WouldBlock` `abort_calls` times before it goes through. Writes return
+/// `WouldBlock` while `hold_writes` is set, which leaves the data waiting in the transport's
+/// buffer
+pub struct SlowAbort<BD> {
+    inner: BD,
+    pub abort_calls: usize,
+    abort_remaining: usize,
+    pub hold_writes: bool,
+}
+deref_inner!(SlowAbort);
+
+impl<BD> SlowAbort<BD> {
+    pub fn new(inner: BD, abort_calls: usize) -> SlowAbort<BD> {
+        SlowAbort {
+            inner,
+            abort_calls,
+            abort_remaining: 0,
+            hold_writes: false,
+        }
+    }
+}
+
+impl<BD: BlockDevice> BlockDevice for SlowAbort<BD> {
+    const BLOCK_BYTES: usize = BD::BLOCK_BYTES;
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        if self.hold_writes {
+            Err(BlockDeviceError::WouldBlock)?;
+        }
+        self.inner.write_block(lba, block)
+    }
+
+    fn abort(&mut self) -> Result<(), BlockDeviceError> {
+        if self.abort_remaining == 0 {
+            self.abort_remaining = self.abort_calls + 1;
+        }
+        self.abort_remaining -= 1;
+        if self.abort_remaining > 0 {
+            Err(BlockDeviceError::WouldBlock)?;
+        }
+        self.inner.abort()
+    }
+
+    forward_block_device!(
+        read_block,
+        max_lba,
+        compare_block,
+        flush,
+        discard,
+        discard_granularity,
+        format,
+        format_progress,
+        is_write_protected,
+        mode_pages,
+        health,
+        firmware_update,
+    );
+}
+
+/// Writes to `fail_write_lba` return `WriteError`
+pub struct FailingWrites<BD> {
+    inner: BD,
+    pub fail_write_lba: Option<u64>,
+}
+deref_inner!(FailingWrites);
+
+impl<BD> FailingWrites<BD> {
+    pub fn new(inner: BD, fail_write_lba: Option<u64>) -> FailingWrites<BD> {
+        FailingWrites {
+            inner,
+            fail_write_lba,
+        }
+    }
+}
+
+impl<BD: BlockDevice> BlockDevice for FailingWrites<BD> {
+    const BLOCK_BYTES: usize = BD::BLOCK_BYTES;
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        if self.fail_write_lba == Some(lba) {
+            Err(BlockDeviceError::WriteError)?;
+        }
+        self.inner.write_block(lba, block)
+    }
+
+    forward_block_device!(
+        read_block,
+        max_lba,
+        compare_block,
+        flush,
+        abort,
+        discard,
+        discard_granularity,
+        format,
+        format_progress,
+        is_write_protected,
+        mode_pages,
+        health,
+        firmware_update,
+    );
+}
+
+/// Reports the device as read only while `write_protected` is set. Writes still go through,
+/// it's up to the command set to reject them
+pub struct WriteProtect<BD> {
+    inner: BD,
+    pub write_protected: bool,
+}
+deref_inner!(WriteProtect);
+
+impl<BD> WriteProtect<BD> {
+    pub fn new(inner: BD, write_protected: bool) -> WriteProtect<BD> {
+        WriteProtect {
+            inner,
+            write_protected,
+        }
+    }
+}
+
+impl<BD: BlockDevice> BlockDevice for WriteProtect<BD> {
+    const BLOCK_BYTES: usize = BD::BLOCK_BYTES;
+
+    fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+
+    forward_block_device!(
+        read_block,
+        write_block,
+        max_lba,
+        compare_block,
+        flush,
+        abort,
+        discard,
+        discard_granularity,
+        format,
+        format_progress,
+        mode_pages,
+        health,
+        firmware_update,
+    );
+}
@@ -0,0 +1,97 @@
+/// Implements the listed `BlockDevice` methods by passing them on to `self.inner`, for
+/// wrappers that only change some of them
+macro_rules! forward_block_device {
+    ($($method:ident),* $(,)?) => {
+        $(forward_block_device!(@ $method);)*
+    };
+    (@ read_block) => {
+        fn read_block(&self, lba: u64, block: &mut [u8]) -> Result<(), ::usbd_scsi::BlockDeviceError> {
+            self.inner.read_block(lba, block)
+        }
+    };
+    (@ write_block) => {
+        fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), ::usbd_scsi::BlockDeviceError> {
+            self.inner.write_block(lba, block)
+        }
+    };
+    (@ max_lba) => {
+        fn max_lba(&self) -> u64 {
+            self.inner.max_lba()
+        }
+    };
+    (@ compare_block) => {
+        fn compare_block(&self, lba: u64, data: &[u8]) -> Result<Option<usize>, ::usbd_scsi::BlockDeviceError> {
+            self.inner.compare_block(lba, data)
+        }
+    };
+    (@ flush) => {
+        fn flush(&mut self) -> Result<(), ::usbd_scsi::BlockDeviceError> {
+            self.inner.flush()
+        }
+    };
+    (@ abort) => {
+        fn abort(&mut self) -> Result<(), ::usbd_scsi::BlockDeviceError> {
+            self.inner.abort()
+        }
+    };
+    (@ discard) => {
+        fn discard(&mut self, lbas: ::core::ops::Range<u64>) -> Result<(), ::usbd_scsi::BlockDeviceError> {
+            self.inner.discard(lbas)
+        }
+    };
+    (@ discard_granularity) => {
+        fn discard_granularity(&self) -> Option<u32> {
+            self.inner.discard_granularity()
+        }
+    };
+    (@ format) => {
+        fn format(&mut self) -> Result<(), ::usbd_scsi::BlockDeviceError> {
+            self.inner.format()
+        }
+    };
+    (@ format_progress) => {
+        fn format_progress(&self) -> Option<u16> {
+            self.inner.format_progress()
+        }
+    };
+    (@ is_write_protected) => {
+        fn is_write_protected(&self) -> bool {
+            self.inner.is_write_protected()
+        }
+    };
+    (@ mode_pages) => {
+        fn mode_pages(&mut self) -> Option<&mut dyn ::usbd_scsi::ModePages> {
+            self.inner.mode_pages()
+        }
+    };
+    (@ health) => {
+        fn health(&self) -> ::usbd_scsi::DeviceHealth {
+            self.inner.health()
+        }
+    };
+    (@ firmware_update) => {
+        fn firmware_update(&mut self) -> Option<&mut dyn ::usbd_scsi::FirmwareUpdate> {
+            self.inner.firmware_update()
+        }
+    };
+}
+
+/// Derefs a wrapper to the device it wraps so the test can get at the fields of everything
+/// underneath it
+macro_rules! deref_inner {
+    ($wrapper:ident) => {
+        impl<BD> ::std::ops::Deref for $wrapper<BD> {
+            type Target = BD;
+
+            fn deref(&self) -> &BD {
+                &self.inner
+            }
+        }
+
+        impl<BD> ::std::ops::DerefMut for $wrapper<BD> {
+            fn deref_mut(&mut self) -> &mut BD {
+                &mut self.inner
+            }
+        }
+    };
+}
@@ -0,0 +1,457 @@
+use std::convert::TryInto;
+use usb_device::{
+    UsbError,
+    class::UsbClass,
+    device::UsbDevice,
+};
+use super::{
+    MockBus,
+    MockBusHandle,
+};
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CBW_BYTES: usize = 31;
+const CSW_BYTES: usize = 13;
+
+/// How many times the device is polled waiting for it to do something before giving up
+const POLL_LIMIT: usize = 1000;
+
+/// Control endpoint index
+const EP0: usize = 0;
+
+/// bmRequestType for a standard request to an endpoint
+const STANDARD_ENDPOINT_OUT: u8 = 0x02;
+const CLEAR_FEATURE: u8 = 0x01;
+const ENDPOINT_HALT: u16 = 0x00;
+
+/// bmRequestType for a standard request to an interface
+const STANDARD_INTERFACE: u8 = 0x01;
+const GET_INTERFACE: u8 = 0x0A;
+const SET_INTERFACE: u8 = 0x0B;
+
+/// bmRequestType for a standard request to the device
+const STANDARD_DEVICE: u8 = 0x00;
+const GET_DESCRIPTOR: u8 = 0x06;
+const CONFIGURATION_DESCRIPTOR: u16 = 0x0200;
+
+/// bmRequestType for a class request to an interface
+const CLASS_INTERFACE_OUT: u8 = 0x21;
+const BULK_ONLY_MASS_STORAGE_RESET: u8 = 0xFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandStatus {
+    Passed,
+    Failed,
+    PhaseError,
+}
+
+/// A parsed command status wrapper
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Csw {
+    pub tag: u32,
+    pub data_residue: u32,
+    pub status: CommandStatus,
+}
+
+/// The data stage the host expects for a command, in CBW terms
+#[derive(Clone, Copy, Debug)]
+pub enum DataStage<'a> {
+    /// No data stage, data transfer length 0
+    None,
+    /// Device to host, with the data transfer length
+    In(u32),
+    /// Host to device, data transfer length is the length of the slice
+    Out(&'a [u8]),
+}
+
+/// Everything the host saw in response to a single command
+#[derive(Clone, Debug)]
+pub struct Response {
+    /// Data received during an IN data stage
+    pub data: Vec<u8>,
+    /// The IN data stage was terminated by a zero length packet
+    pub zlp: bool,
+    /// The device stalled a bulk endpoint to end the data stage, the host cleared it before
+    /// reading the CSW
+    pub stalled: bool,
+    pub csw: Csw,
+}
+
+/// # A scripted Bulk Only Transport host
+///
+/// Plays the host side of the CBW/data/CSW protocol against a class under test, polling the
+/// class whenever it's waiting for the device. Packets are sized using the max packet size
+/// the device allocated for the bulk endpoints.
+///
+/// `command` performs a whole transaction. The individual stages (`send_cbw`, `send_data`,
+/// `receive_data`, `receive_csw`) can be used to script transactions that don't follow the
+/// happy path. A stalled bulk endpoint ends the data stage and is cleared before the CSW is
+/// read, the same as a real host.
+///
+/// Control transfers go through `usb_device` so standard requests are answered by usb-device
+/// and class requests by the class under test.
+pub struct Host {
+    usb_device: UsbDevice<'static, MockBus>,
+    bus: MockBusHandle,
+    bulk_in: usize,
+    bulk_out: usize,
+    tag: u32,
+}
+
+impl Host {
+    pub fn new(usb_device: UsbDevice<'static, MockBus>, bus: MockBusHandle, bulk_in: usize, bulk_out: usize) -> Host {
+        Host {
+            usb_device,
+            bus,
+            bulk_in,
+            bulk_out,
+            tag: 0,
+        }
+    }
+
+    pub fn bus(&self) -> &MockBusHandle {
+        &self.bus
+    }
+
+    /// Runs a complete command: CBW, optional data stage then CSW
+    ///
+    /// Panics if the CSW tag doesn't match the CBW tag
+    pub fn command<C: UsbClass<MockBus>>(
+        &mut self,
+        device: &mut C,
+        lun: u8,
+        cdb: &[u8],
+        data_stage: DataStage,
+    ) -> Response {
+        let (direction_in, length) = match data_stage {
+            DataStage::None => (false, 0),
+            DataStage::In(len) => (true, len),
+            DataStage::Out(data) => (false, data.len() as u32),
+        };
+
+        let tag = self.send_cbw(device, lun, cdb, direction_in, length);
+
+        let (data, zlp) = match data_stage {
+            DataStage::In(len) => self.receive_data(device, len as usize),
+            DataStage::Out(data) => {
+                self.send_data(device, data);
+                (Vec::new(), false)
+            },
+            DataStage::None => (Vec::new(), false),
+        };
+
+        let stalled = self.clear_bulk_halts(device);
+
+        let csw = self.receive_csw(device);
+        assert_eq!(csw.tag, tag, "CSW tag doesn't match CBW tag");
+
+        Response { data, zlp, stalled, csw }
+    }
+
+    /// Sends a CBW, returns the tag used
+    pub fn send_cbw<C: UsbClass<MockBus>>(
+        &mut self,
+        device: &mut C,
+        lun: u8,
+        cdb: &[u8],
+        direction_in: bool,
+        data_transfer_length: u32,
+    ) -> u32 {
+        assert!(!cdb.is_empty() && cdb.len() <= 16, "CDB must be 1 to 16 bytes");
+
+        self.tag = self.tag.wrapping_add(1);
+
+        let mut cbw = [0; CBW_BYTES];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&data_transfer_length.to_le_bytes());
+        cbw[12] = if direction_in { 0x80 } else { 0x00 };
+        cbw[13] = lun;
+        cbw[14] = cdb.len() as u8;
+        cbw[15..15 + cdb.len()].copy_from_slice(cdb);
+
+        self.send_packet(device, &cbw);
+
+        self.tag
+    }
+
+    /// Sends `data` to the bulk OUT endpoint, split into max packet size packets
+    ///
+    /// Stops early if the device stalls the endpoint
+    pub fn send_data<C: UsbClass<MockBus>>(&mut self, device: &mut C, data: &[u8]) {
+        let packet_size = self.bus.out_max_packet_size(self.bulk_out);
+        for packet in data.chunks(packet_size) {
+            if self.try_send_packet(device, packet).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Receives up to `len` bytes from the bulk IN endpoint
+    ///
+    /// Stops early on a short packet or a stall the same way a real host controller does. The
+    /// returned bool indicates the transfer was terminated by a ZLP.
+    pub fn receive_data<C: UsbClass<MockBus>>(&mut self, device: &mut C, len: usize) -> (Vec<u8>, bool) {
+        let packet_size = self.bus.in_max_packet_size(self.bulk_in);
+        let mut data = Vec::new();
+
+        while data.len() < len {
+            let packet = match self.try_receive_packet(device) {
+                Ok(packet) => packet,
+                Err(_) => break,
+            };
+            assert!(data.len() + packet.len() <= len, "Device sent more data than the CBW asked for");
+
+            data.extend_from_slice(&packet);
+
+            if packet.len() < packet_size {
+                return (data, packet.is_empty());
+            }
+        }
+
+        (data, false)
+    }
+
+    /// Receives and parses a CSW
+    ///
+    /// If the bulk IN endpoint is stalled the halt is cleared and the CSW read again, as in
+    /// section 5.3.3 of the spec. Panics if the packet isn't a valid CSW
+    pub fn receive_csw<C: UsbClass<MockBus>>(&mut self, device: &mut C) -> Csw {
+        let packet = match self.try_receive_packet(device) {
+            Ok(packet) => packet,
+            Err(_) => {
+                self.clear_halt(device, 0x80 | self.bulk_in as u8);
+                self.receive_packet(device)
+            },
+        };
+        assert_eq!(packet.len(), CSW_BYTES, "Expected a CSW, got {:X?}", packet);
+
+        let signature = u32::from_le_bytes(packet[0..4].try_into().unwrap());
+        assert_eq!(signature, CSW_SIGNATURE, "Invalid CSW signature");
+
+        Csw {
+            tag: u32::from_le_bytes(packet[4..8].try_into().unwrap()),
+            data_residue: u32::from_le_bytes(packet[8..12].try_into().unwrap()),
+            status: match packet[12] {
+                0 => CommandStatus::Passed,
+                1 => CommandStatus::Failed,
+                2 => CommandStatus::PhaseError,
+                s => panic!("Invalid CSW status: {}", s),
+            },
+        }
+    }
+
+    /// Polls the device until it accepts `data` on the bulk OUT endpoint
+    pub fn send_packet<C: UsbClass<MockBus>>(&mut self, device: &mut C, data: &[u8]) {
+        if let Err(e) = self.try_send_packet(device, data) {
+            panic!("Sending packet failed: {:?}", e);
+        }
+    }
+
+    /// Same as `send_packet` but returns `InvalidState` if the endpoint is stalled
+    pub fn try_send_packet<C: UsbClass<MockBus>>(&mut self, device: &mut C, data: &[u8]) -> Result<(), UsbError> {
+        for _ in 0..POLL_LIMIT {
+            match self.bus.send_packet(self.bulk_out, data) {
+                Ok(()) => {
+                    // Give the device a chance to deal with it
+                    device.poll();
+                    return Ok(());
+                },
+                Err(UsbError::WouldBlock) => device.poll(),
+                Err(e) => return Err(e),
+            }
+        }
+        panic!("Device stopped reading from the bulk OUT endpoint");
+    }
+
+    /// Polls the device until it writes a packet to the bulk IN endpoint
+    pub fn receive_packet<C: UsbClass<MockBus>>(&mut self, device: &mut C) -> Vec<u8> {
+        match self.try_receive_packet(device) {
+            Ok(packet) => packet,
+            Err(e) => panic!("Receiving packet failed: {:?}", e),
+        }
+    }
+
+    /// Same as `receive_packet` but returns `InvalidState` if the endpoint is stalled
+    pub fn try_receive_packet<C: UsbClass<MockBus>>(&mut self, device: &mut C) -> Result<Vec<u8>, UsbError> {
+        for _ in 0..POLL_LIMIT {
+            match self.bus.receive_packet(self.bulk_in) {
+                Err(UsbError::WouldBlock) => device.poll(),
+                r => return r,
+            }
+        }
+        panic!("Device stopped writing to the bulk IN endpoint");
+    }
+
+    /// Bulk-Only Mass Storage Reset followed by clearing the halts on both bulk endpoints, what
+    /// the host does after a phase error (section 5.3.4 of the spec)
+    pub fn reset_recovery<C: UsbClass<MockBus>>(&mut self, device: &mut C, interface: u16) {
+        let accepted = self.control_out(device, CLASS_INTERFACE_OUT, BULK_ONLY_MASS_STORAGE_RESET, 0, interface, &[]);
+        assert!(accepted, "Bulk-Only Mass Storage Reset was rejected");
+        self.clear_halt(device, 0x80 | self.bulk_in as u8);
+        self.clear_halt(device, self.bulk_out as u8);
+    }
+
+    /// Clears the halt on whichever bulk endpoints are stalled, returns true if there were any
+    pub fn clear_bulk_halts<C: UsbClass<MockBus>>(&mut self, device: &mut C) -> bool {
+        let mut stalled = false;
+        if self.bus.in_stalled(self.bulk_in) {
+            self.clear_halt(device, 0x80 | self.bulk_in as u8);
+            stalled = true;
+        }
+        if self.bus.out_stalled(self.bulk_out) {
+            self.clear_halt(device, self.bulk_out as u8);
+            stalled = true;
+        }
+        stalled
+    }
+
+    /// Sends CLEAR_FEATURE(ENDPOINT_HALT) for endpoint address `ep_addr`
+    pub fn clear_halt<C: UsbClass<MockBus>>(&mut self, device: &mut C, ep_addr: u8) {
+        let accepted = self.control_out(device, STANDARD_ENDPOINT_OUT, CLEAR_FEATURE, ENDPOINT_HALT, ep_addr as u16, &[]);
+        assert!(accepted, "CLEAR_FEATURE(ENDPOINT_HALT) was rejected");
+    }
+
+    /// Sends SET_INTERFACE, returns false if the device rejected the alternate setting
+    pub fn set_interface<C: UsbClass<MockBus>>(&mut self, device: &mut C, interface: u16, alternate_setting: u8) -> bool {
+        self.control_out(device, STANDARD_INTERFACE, SET_INTERFACE, alternate_setting as u16, interface, &[])
+    }
+
+    /// Sends GET_INTERFACE, returns the current alternate setting
+    pub fn get_interface<C: UsbClass<MockBus>>(&mut self, device: &mut C, interface: u16) -> u8 {
+        let data = self.control_in(device, STANDARD_INTERFACE, GET_INTERFACE, 0, interface, 1)
+            .expect("GET_INTERFACE was rejected");
+        data[0]
+    }
+
+    /// Reads the whole configuration descriptor, interface and endpoint descriptors included
+    pub fn configuration_descriptor<C: UsbClass<MockBus>>(&mut self, device: &mut C) -> Vec<u8> {
+        self.control_in(device, STANDARD_DEVICE, GET_DESCRIPTOR, CONFIGURATION_DESCRIPTOR, 0, 255)
+            .expect("GET_DESCRIPTOR was rejected")
+    }
+
+    /// Performs a control transfer with an OUT data stage, or none if `data` is empty. Returns
+    /// false if the device stalled it
+    pub fn control_out<C: UsbClass<MockBus>>(
+        &mut self,
+        device: &mut C,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> bool {
+        self.send_setup(device, request_type, request, value, index, data.len() as u16);
+
+        let packet_size = self.bus.out_max_packet_size(EP0);
+        for packet in data.chunks(packet_size) {
+            if !self.control_poll_until(device, |bus| bus.send_packet(EP0, packet)) {
+                return false;
+            }
+        }
+
+        // Status stage, a ZLP from the device
+        let mut status = None;
+        if !self.control_poll_until(device, |bus| bus.receive_packet(EP0).map(|p| status = Some(p))) {
+            return false;
+        }
+        assert_eq!(status, Some(Vec::new()), "Expected a ZLP for the status stage");
+
+        // Let the device see the status stage finish
+        self.control_poll(device);
+        true
+    }
+
+    /// Performs a control transfer with an IN data stage of up to `length` bytes. Returns None
+    /// if the device stalled it
+    pub fn control_in<C: UsbClass<MockBus>>(
+        &mut self,
+        device: &mut C,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> Option<Vec<u8>> {
+        self.send_setup(device, request_type | 0x80, request, value, index, length);
+
+        let packet_size = self.bus.in_max_packet_size(EP0);
+        let mut data = Vec::new();
+        loop {
+            let mut packet = Vec::new();
+            if !self.control_poll_until(device, |bus| bus.receive_packet(EP0).map(|p| packet = p)) {
+                return None;
+            }
+            data.extend_from_slice(&packet);
+            if packet.len() < packet_size || data.len() >= length as usize {
+                break;
+            }
+        }
+
+        // Status stage, a ZLP from the host
+        self.control_poll(device);
+        assert!(self.control_poll_until(device, |bus| bus.send_packet(EP0, &[])));
+        Some(data)
+    }
+
+    fn send_setup<C: UsbClass<MockBus>>(
+        &mut self,
+        device: &mut C,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) {
+        let mut setup = [0; 8];
+        setup[0] = request_type;
+        setup[1] = request;
+        setup[2..4].copy_from_slice(&value.to_le_bytes());
+        setup[4..6].copy_from_slice(&index.to_le_bytes());
+        setup[6..8].copy_from_slice(&length.to_le_bytes());
+
+        self.bus.send_setup(EP0, &setup);
+        self.control_poll(device);
+    }
+
+    /// Polls `usb_device` until `f` succeeds. Returns false if the control endpoint is stalled
+    fn control_poll_until<C, F>(&mut self, device: &mut C, mut f: F) -> bool
+    where
+        C: UsbClass<MockBus>,
+        F: FnMut(&MockBusHandle) -> Result<(), UsbError>,
+    {
+        for _ in 0..POLL_LIMIT {
+            match f(&self.bus) {
+                Ok(()) => {
+                    self.control_poll(device);
+                    return true;
+                },
+                Err(UsbError::WouldBlock) => self.control_poll(device),
+                Err(UsbError::InvalidState) => return false,
+                Err(e) => panic!("Control transfer failed: {:?}", e),
+            }
+        }
+        panic!("Device stopped responding on the control endpoint");
+    }
+
+    fn control_poll<C: UsbClass<MockBus>>(&mut self, device: &mut C) {
+        self.usb_device.poll(&mut [device as &mut dyn UsbClass<MockBus>]);
+    }
+
+    /// Polls the device a few times
+    pub fn poll<C: UsbClass<MockBus>>(&mut self, device: &mut C) {
+        for _ in 0..10 {
+            device.poll();
+        }
+    }
+
+    /// Polls the device a few times and checks it didn't write anything to the bulk IN endpoint
+    pub fn assert_idle<C: UsbClass<MockBus>>(&mut self, device: &mut C) {
+        self.poll(device);
+        match self.bus.receive_packet(self.bulk_in) {
+            Err(UsbError::WouldBlock) => {},
+            r => panic!("Device sent unexpected data: {:X?}", r),
+        }
+    }
+}
@@ -0,0 +1,291 @@
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+};
+use usb_device::{
+    Result as UsbResult,
+    UsbDirection,
+    UsbError,
+    bus::{
+        PollResult,
+        UsbBus,
+    },
+    endpoint::{
+        EndpointAddress,
+        EndpointType,
+    },
+};
+
+/// Endpoint 0 plus up to 15 others in each direction
+const ENDPOINTS: usize = 16;
+
+#[derive(Default)]
+struct Endpoint {
+    allocated: bool,
+    max_packet_size: u16,
+    stalled: bool,
+    /// Packets from the host waiting for the device to read them (OUT endpoints). Only one
+    /// packet is queued at a time, like the single buffered endpoints on real hardware
+    out_packet: Option<Vec<u8>>,
+    /// `out_packet` is a SETUP packet (control endpoint only)
+    setup: bool,
+    /// Packet written by the device waiting for the host to collect it (IN endpoints)
+    in_packet: Option<Vec<u8>>,
+    /// The host has collected `in_packet` and the device hasn't been told yet
+    in_complete: bool,
+}
+
+#[derive(Default)]
+struct State {
+    enabled: bool,
+    address: u8,
+    in_eps: [Endpoint; ENDPOINTS],
+    out_eps: [Endpoint; ENDPOINTS],
+}
+
+impl State {
+    fn endpoint(&mut self, ep_addr: EndpointAddress) -> &mut Endpoint {
+        let ep = match ep_addr.direction() {
+            UsbDirection::In => &mut self.in_eps[ep_addr.index()],
+            UsbDirection::Out => &mut self.out_eps[ep_addr.index()],
+        };
+        assert!(ep.allocated, "Endpoint {:?} used without being allocated", ep_addr);
+        ep
+    }
+}
+
+/// # A simulated USB peripheral
+///
+/// Implements `UsbBus` on top of per-endpoint packet buffers. The device side (usb-device
+/// and the classes under test) reads and writes through the `UsbBus` trait while the test
+/// plays the host via the [MockBusHandle](struct.MockBusHandle.html) returned by `new`.
+pub struct MockBus {
+    state: Arc<Mutex<State>>,
+}
+
+/// The host side of a [MockBus](struct.MockBus.html)
+#[derive(Clone)]
+pub struct MockBusHandle {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockBus {
+    pub fn new() -> (MockBus, MockBusHandle) {
+        let state = Arc::new(Mutex::new(State::default()));
+        (
+            MockBus { state: state.clone() },
+            MockBusHandle { state },
+        )
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl UsbBus for MockBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> UsbResult<EndpointAddress> {
+        let mut state = self.state();
+        let eps = match ep_dir {
+            UsbDirection::In => &mut state.in_eps,
+            UsbDirection::Out => &mut state.out_eps,
+        };
+
+        let index = match ep_addr {
+            Some(addr) => addr.index(),
+            None => eps.iter()
+                .enumerate()
+                .skip(1)
+                .find(|(_, ep)| !ep.allocated)
+                .map(|(i, _)| i)
+                .ok_or(UsbError::EndpointOverflow)?,
+        };
+
+        let ep = eps.get_mut(index).ok_or(UsbError::InvalidEndpoint)?;
+        if ep.allocated {
+            Err(UsbError::InvalidEndpoint)?;
+        }
+
+        ep.allocated = true;
+        ep.max_packet_size = max_packet_size;
+
+        Ok(EndpointAddress::from_parts(index, ep_dir))
+    }
+
+    fn enable(&mut self) {
+        self.state().enabled = true;
+    }
+
+    fn reset(&self) {
+        let state = &mut *self.state();
+        state.address = 0;
+        for ep in state.in_eps.iter_mut().chain(state.out_eps.iter_mut()) {
+            ep.stalled = false;
+            ep.in_packet = None;
+            ep.out_packet = None;
+            ep.setup = false;
+            ep.in_complete = false;
+        }
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        self.state().address = addr;
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> UsbResult<usize> {
+        let mut state = self.state();
+        let ep = state.endpoint(ep_addr);
+
+        if buf.len() > ep.max_packet_size as usize {
+            Err(UsbError::BufferOverflow)?;
+        }
+        if ep.in_packet.is_some() {
+            Err(UsbError::WouldBlock)?;
+        }
+
+        ep.in_packet = Some(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> UsbResult<usize> {
+        let mut state = self.state();
+        let ep = state.endpoint(ep_addr);
+
+        let len = match &ep.out_packet {
+            None => Err(UsbError::WouldBlock)?,
+            Some(packet) if packet.len() > buf.len() => Err(UsbError::BufferOverflow)?,
+            Some(packet) => packet.len(),
+        };
+
+        let packet = ep.out_packet.take().unwrap();
+        ep.setup = false;
+        buf[..len].copy_from_slice(&packet);
+        Ok(len)
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        self.state().endpoint(ep_addr).stalled = stalled;
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.state().endpoint(ep_addr).stalled
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        let mut state = self.state();
+        let mut ep_out = 0;
+        let mut ep_setup = 0;
+        for (i, ep) in state.out_eps.iter().enumerate() {
+            match (&ep.out_packet, ep.setup) {
+                (Some(_), true) => ep_setup |= 1 << i,
+                (Some(_), false) => ep_out |= 1 << i,
+                (None, _) => {},
+            }
+        }
+
+        let mut ep_in_complete = 0;
+        for (i, ep) in state.in_eps.iter_mut().enumerate() {
+            if ep.in_complete {
+                ep.in_complete = false;
+                ep_in_complete |= 1 << i;
+            }
+        }
+
+        if ep_out == 0 && ep_setup == 0 && ep_in_complete == 0 {
+            PollResult::None
+        } else {
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
+    }
+}
+
+impl MockBusHandle {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Max packet size the device allocated for IN endpoint `index`
+    pub fn in_max_packet_size(&self, index: usize) -> usize {
+        self.state().endpoint(EndpointAddress::from_parts(index, UsbDirection::In)).max_packet_size as usize
+    }
+
+    /// Max packet size the device allocated for OUT endpoint `index`
+    pub fn out_max_packet_size(&self, index: usize) -> usize {
+        self.state().endpoint(EndpointAddress::from_parts(index, UsbDirection::Out)).max_packet_size as usize
+    }
+
+    /// Sends a packet to OUT endpoint `index`
+    ///
+    /// Returns `WouldBlock` if the device hasn't read the previous packet yet and `InvalidState`
+    /// if the endpoint is stalled
+    pub fn send_packet(&self, index: usize, data: &[u8]) -> UsbResult<()> {
+        let mut state = self.state();
+        let ep = state.endpoint(EndpointAddress::from_parts(index, UsbDirection::Out));
+
+        assert!(data.len() <= ep.max_packet_size as usize, "Host sent packet larger than max packet size");
+        if ep.stalled {
+            Err(UsbError::InvalidState)?;
+        }
+        if ep.out_packet.is_some() {
+            Err(UsbError::WouldBlock)?;
+        }
+
+        ep.out_packet = Some(data.to_vec());
+        Ok(())
+    }
+
+    /// Sends a SETUP packet to control endpoint `index`. Like real hardware this is accepted
+    /// even if the endpoint is stalled and clears the stall
+    pub fn send_setup(&self, index: usize, data: &[u8]) {
+        let mut state = self.state();
+        state.endpoint(EndpointAddress::from_parts(index, UsbDirection::In)).stalled = false;
+
+        let ep = state.endpoint(EndpointAddress::from_parts(index, UsbDirection::Out));
+        ep.stalled = false;
+        ep.out_packet = Some(data.to_vec());
+        ep.setup = true;
+    }
+
+    /// Collects a packet the device wrote to IN endpoint `index`
+    ///
+    /// Returns `WouldBlock` if the device hasn't written anything and `InvalidState` if the
+    /// endpoint is stalled
+    pub fn receive_packet(&self, index: usize) -> UsbResult<Vec<u8>> {
+        let mut state = self.state();
+        let ep = state.endpoint(EndpointAddress::from_parts(index, UsbDirection::In));
+
+        if ep.stalled {
+            Err(UsbError::InvalidState)?;
+        }
+
+        let packet = ep.in_packet.take().ok_or(UsbError::WouldBlock)?;
+        ep.in_complete = true;
+        Ok(packet)
+    }
+
+    /// Is IN endpoint `index` stalled
+    pub fn in_stalled(&self, index: usize) -> bool {
+        self.state().endpoint(EndpointAddress::from_parts(index, UsbDirection::In)).stalled
+    }
+
+    /// Is OUT endpoint `index` stalled
+    pub fn out_stalled(&self, index: usize) -> bool {
+        self.state().endpoint(EndpointAddress::from_parts(index, UsbDirection::Out)).stalled
+    }
+}
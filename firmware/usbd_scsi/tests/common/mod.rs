@@ -0,0 +1,154 @@
+//! Host side test harness for the BOT + SCSI stack
+//!
+//! [MockBus](struct.MockBus.html) stands in for the USB peripheral and [Host](struct.Host.html)
+//! drives it the same way a host's mass storage driver would.
+#![allow(dead_code, unused_imports)]
+
+use usb_device::{
+    bus::UsbBusAllocator,
+    device::{
+        UsbDeviceBuilder,
+        UsbVidPid,
+    },
+};
+use usbd_scsi::{
+    BlockDevice,
+    CommandProfile,
+    LogicalUnit,
+    LogicalUnits,
+    Scsi,
+    DEFAULT_BUFFER_BYTES,
+};
+use usbd_mass_storage::InterfaceSubclass;
+use usbd_uas_transport::UasTransport;
+use usbd_cbi_transport::CbiTransport;
+
+#[macro_use]
+mod forward;
+
+mod mock_bus;
+pub use mock_bus::*;
+
+mod host;
+pub use host::*;
+
+mod ram_disk;
+pub use ram_disk::*;
+
+mod sparse_disk;
+pub use sparse_disk::*;
+
+mod faults;
+pub use faults::*;
+
+mod capabilities;
+pub use capabilities::*;
+
+mod cdb;
+pub use cdb::*;
+
+pub const MAX_PACKET_SIZE: u16 = 64;
+
+/// The mass storage interface is the only one
+pub const INTERFACE: u16 = 0;
+
+/// The bulk endpoints are the first ones allocated after the control endpoint
+pub const BULK_IN: usize = 1;
+pub const BULK_OUT: usize = 1;
+
+/// The UAS command and status pipes are allocated after the bulk endpoints
+pub const COMMAND_PIPE: usize = 2;
+pub const STATUS_PIPE: usize = 2;
+
+pub const UAS_ALTERNATE_SETTING: u8 = 1;
+
+/// The CBI command completion interrupt endpoint is allocated after the bulk endpoints
+pub const INTERRUPT_IN: usize = 2;
+
+pub type UasScsi<L> = Scsi<'static, MockBus, L, DEFAULT_BUFFER_BYTES, (), (), UasTransport<'static, MockBus>>;
+
+pub type CbiScsi<L> = Scsi<'static, MockBus, L, DEFAULT_BUFFER_BYTES, (), (), CbiTransport<'static, MockBus>>;
+
+/// Builds a `Scsi` instance on a mock bus and a host to talk to it
+///
+/// The power on unit attention has already been cleared from every LUN, the same as a host
+/// does before anything else
+pub fn scsi_device<L: LogicalUnits>(logical_units: L) -> (Scsi<'static, MockBus, L>, Host) {
+    scsi_device_with_buffer(logical_units)
+}
+
+/// Same as [scsi_device](fn.scsi_device.html) with a `BUFFER_BYTES` transport buffer
+pub fn scsi_device_with_buffer<L: LogicalUnits, const BUFFER_BYTES: usize>(
+    logical_units: L,
+) -> (Scsi<'static, MockBus, L, BUFFER_BYTES>, Host) {
+    let (mut scsi, mut host) = powered_on_scsi_device(logical_units);
+
+    for lun in 0..L::COUNT {
+        let r = host.command(&mut scsi, lun, &[0x00, 0, 0, 0, 0, 0], DataStage::None);
+        assert_eq!(r.csw.status, CommandStatus::Failed, "Expected a power on unit attention");
+
+        let r = host.command(&mut scsi, lun, &[0x03, 0, 0, 0, 18, 0], DataStage::In(18));
+        assert_eq!(r.data[2] & 0x0F, 0x06, "Expected a power on unit attention");
+    }
+
+    (scsi, host)
+}
+
+/// Same as [scsi_device_with_buffer](fn.scsi_device_with_buffer.html) without clearing the power
+/// on unit attention
+pub fn powered_on_scsi_device<L: LogicalUnits, const BUFFER_BYTES: usize>(
+    logical_units: L,
+) -> (Scsi<'static, MockBus, L, BUFFER_BYTES>, Host) {
+    let (bus, handle) = MockBus::new();
+
+    // Scsi borrows the allocator for its whole life so leak it rather than tie every test
+    // up in lifetimes
+    let alloc: &'static UsbBusAllocator<MockBus> = Box::leak(Box::new(UsbBusAllocator::new(bus)));
+
+    let scsi = Scsi::with_logical_units(alloc, MAX_PACKET_SIZE, logical_units);
+
+    // Building the device freezes the allocator, after which the endpoints are usable
+    let usb_device = UsbDeviceBuilder::new(alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    (scsi, Host::new(usb_device, handle, BULK_IN, BULK_OUT))
+}
+
+/// Builds a `Scsi` instance offering UAS on a mock bus and a host to talk to it. The host hasn't
+/// selected UAS yet and no unit attentions have been cleared
+pub fn uas_device<L: LogicalUnits>(logical_units: L) -> (UasScsi<L>, Host) {
+    let (bus, handle) = MockBus::new();
+    let alloc: &'static UsbBusAllocator<MockBus> = Box::leak(Box::new(UsbBusAllocator::new(bus)));
+
+    let transport = UasTransport::new(alloc, MAX_PACKET_SIZE, InterfaceSubclass::ScsiTransparentCommandSet, L::COUNT - 1);
+    let scsi = Scsi::with_transport(transport, logical_units);
+    let usb_device = UsbDeviceBuilder::new(alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    (scsi, Host::new(usb_device, handle, BULK_IN, BULK_OUT))
+}
+
+/// Builds a `Scsi` instance on CBI with the `profile` command set on a mock bus and a host to
+/// talk to it. No unit attentions have been cleared
+pub fn cbi_device<L: LogicalUnits>(
+    logical_units: L,
+    profile: CommandProfile,
+    command_completion_interrupt: bool,
+) -> (CbiScsi<L>, Host) {
+    let (bus, handle) = MockBus::new();
+    let alloc: &'static UsbBusAllocator<MockBus> = Box::leak(Box::new(UsbBusAllocator::new(bus)));
+
+    let transport = CbiTransport::new(alloc, MAX_PACKET_SIZE, profile.subclass(), command_completion_interrupt);
+    let scsi = Scsi::with_transport(transport, logical_units).with_command_profile(profile);
+    let usb_device = UsbDeviceBuilder::new(alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    (scsi, Host::new(usb_device, handle, BULK_IN, BULK_OUT))
+}
+
+/// A logical unit backed by a plain 16 block [RamDisk](type.RamDisk.html)
+pub fn ram_disk_unit() -> LogicalUnit<RamDisk> {
+    disk_unit(ram_disk())
+}
+
+/// A logical unit backed by `block_device`, usually a RAM disk with some wrappers around it
+pub fn disk_unit<BD: BlockDevice>(block_device: BD) -> LogicalUnit<BD> {
+    LogicalUnit::new(block_device, "Mock", "RamDisk", "1.0")
+}
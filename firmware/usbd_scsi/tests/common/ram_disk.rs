@@ -0,0 +1,34 @@
+use usbd_scsi::RamBlockDevice;
+
+pub const BLOCK_BYTES: usize = 512;
+
+/// The RAM disk most tests use, 16 blocks unless specified
+pub type RamDisk<const N: usize = 16> = RamBlockDevice<N>;
+
+/// A zeroed [RamDisk](type.RamDisk.html) of the usual size
+pub fn ram_disk() -> RamDisk {
+    RamDisk::new()
+}
+
+/// The contents of a RAM disk as bytes rather than blocks
+///
+/// Wrappers deref to what they wrap so these work on a RAM disk underneath any of them
+pub trait Contents {
+    fn data(&self) -> &[u8];
+    fn data_mut(&mut self) -> &mut [u8];
+
+    fn block(&self, lba: u64) -> &[u8] {
+        let start = lba as usize * BLOCK_BYTES;
+        &self.data()[start..start + BLOCK_BYTES]
+    }
+}
+
+impl<const N: usize> Contents for RamBlockDevice<N> {
+    fn data(&self) -> &[u8] {
+        self.blocks().as_flattened()
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        self.blocks_mut().as_flattened_mut()
+    }
+}
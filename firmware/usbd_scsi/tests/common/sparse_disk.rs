@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use usbd_scsi::{
+    BlockDevice,
+    BlockDeviceError,
+};
+
+/// A huge block device that only stores the blocks that have been written
+///
+/// Blocks are `BLOCK_BYTES` long, 512 unless specified
+pub struct SparseDisk<const BLOCK_BYTES: usize = { super::BLOCK_BYTES }> {
+    pub blocks: HashMap<u64, Vec<u8>>,
+    max_lba: u64,
+}
+
+impl<const BLOCK_BYTES: usize> SparseDisk<BLOCK_BYTES> {
+    pub fn new(blocks: u64) -> SparseDisk<BLOCK_BYTES> {
+        SparseDisk {
+            blocks: HashMap::new(),
+            max_lba: blocks - 1,
+        }
+    }
+}
+
+impl<const BLOCK_BYTES: usize> BlockDevice for SparseDisk<BLOCK_BYTES> {
+    const BLOCK_BYTES: usize = BLOCK_BYTES;
+
+    fn read_block(&self, lba: u64, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        if lba > self.max_lba {
+            Err(BlockDeviceError::InvalidAddress)?;
+        }
+        match self.blocks.get(&lba) {
+            Some(data) => block.copy_from_slice(data),
+            None => block.iter_mut().for_each(|b| *b = 0),
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u64, block: &[u8]) -> Result<(), BlockDeviceError> {
+        if lba > self.max_lba {
+            Err(BlockDeviceError::InvalidAddress)?;
+        }
+        self.blocks.insert(lba, block.to_vec());
+        Ok(())
+    }
+
+    fn max_lba(&self) -> u64 {
+        self.max_lba
+    }
+}
@@ -0,0 +1,1853 @@
+mod common;
+use common::*;
+use usbd_scsi::MediumState;
+
+const READ_CAPACITY: [u8; 10] = [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+const REPORT_LUNS: [u8; 12] = [0xA0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 0, 0];
+
+/// Returns (sense key, ASC, ASCQ) from fixed format sense data
+fn request_sense<C: usb_device::class::UsbClass<MockBus>>(host: &mut Host, device: &mut C, lun: u8) -> (u8, u8, u8) {
+    let r = host.command(device, lun, &REQUEST_SENSE, DataStage::In(18));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data.len(), 18);
+    (r.data[2] & 0x0F, r.data[12], r.data[13])
+}
+
+fn pattern(blocks: usize, seed: u8) -> Vec<u8> {
+    (0..blocks * BLOCK_BYTES)
+        .map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed))
+        .collect()
+}
+
+#[test]
+fn test_unit_ready() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.csw.data_residue, 0);
+    host.assert_idle(&mut scsi);
+}
+
+#[test]
+fn inquiry() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    let r = host.command(&mut scsi, 0, &INQUIRY, DataStage::In(36));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.csw.data_residue, 0);
+    assert_eq!(r.data.len(), 36);
+    assert_eq!(r.data[0], 0x00);
+    assert_eq!(&r.data[8..16], b"Mock    ");
+    assert_eq!(&r.data[16..32], b"RamDisk         ");
+    assert_eq!(&r.data[32..36], b"1.0 ");
+}
+
+#[test]
+fn read_capacity() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    let r = host.command(&mut scsi, 0, &READ_CAPACITY, DataStage::In(8));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, [0, 0, 0, 15, 0, 0, 2, 0]);
+}
+
+#[test]
+fn short_data_in_reports_residue() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    // Host allows for more sense data than the device has so the transfer ends on a short packet
+    let r = host.command(&mut scsi, 0, &[0x03, 0, 0, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert!(r.data.len() < 255);
+    assert!(!r.zlp);
+    assert_eq!(r.csw.data_residue as usize, 255 - r.data.len());
+}
+
+#[test]
+fn short_data_in_on_packet_boundary_ends_with_zlp() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    // One block is a whole number of packets, the host asked for two blocks worth
+    let r = host.command(&mut scsi, 0, &read10(0, 1), DataStage::In(2 * BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data.len(), BLOCK_BYTES);
+    assert!(r.zlp);
+    assert_eq!(r.csw.data_residue as usize, BLOCK_BYTES);
+
+    // And the device is still in sync
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+}
+
+/// The host/device expectation cases from section 6.7 of the BOT spec that aren't covered
+/// elsewhere. The device stays in sync whatever the host asks for
+#[test]
+fn thirteen_cases() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+    let data = pattern(2, 0);
+
+    // (4) Hi > Dn, the IN endpoint is stalled instead of sending anything
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::In(18));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert!(r.data.is_empty() && r.stalled);
+    assert_eq!(r.csw.data_residue, 18);
+
+    // (9) Ho > Dn, the OUT endpoint is stalled once the command is done
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::Out(&data[..100]));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert!(r.stalled);
+    assert_eq!(r.csw.data_residue, 100);
+
+    // (11) Ho > Do, the rest of the data is refused
+    let r = host.command(&mut scsi, 0, &write10(2, 1), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert!(r.stalled);
+    assert_eq!(r.csw.data_residue as usize, BLOCK_BYTES);
+    assert_eq!(scsi.block_device_mut().block(2), &data[..BLOCK_BYTES]);
+
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    // Phase errors: (2) Hn < Di, (3) Hn < Do, (7) Hi < Di, (8) Hi <> Do, (10) Ho <> Di and
+    // (13) Ho < Do. None of the commands are executed
+    for (cdb, data_stage, stalled) in [
+        (&INQUIRY[..], DataStage::None, false),
+        (&write10(0, 1)[..], DataStage::None, false),
+        (&read10(0, 2)[..], DataStage::In(BLOCK_BYTES as u32), true),
+        (&write10(0, 1)[..], DataStage::In(BLOCK_BYTES as u32), true),
+        (&INQUIRY[..], DataStage::Out(&data[..36]), false),
+        (&write10(0, 2)[..], DataStage::Out(&data[..BLOCK_BYTES]), true),
+    ].iter() {
+        let r = host.command(&mut scsi, 0, cdb, *data_stage);
+        assert_eq!(r.csw.status, CommandStatus::PhaseError, "{:X?}", cdb);
+        assert!(r.data.is_empty());
+        assert_eq!(r.stalled, *stalled, "{:X?}", cdb);
+
+        host.reset_recovery(&mut scsi, INTERFACE);
+
+        let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+        assert_eq!(r.csw.status, CommandStatus::Passed);
+    }
+    assert!(scsi.block_device_mut().block(0).iter().all(|b| *b == 0));
+    // Phase errors don't touch the sense data
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x00, 0x00, 0x00));
+}
+
+#[test]
+fn failed_command_with_data_out() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    // The data the host is still trying to send is refused with a stall
+    let data = pattern(4, 0);
+    let r = host.command(&mut scsi, 0, &write10(14, 4), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert!(r.stalled);
+    assert_eq!(r.csw.data_residue as usize, data.len());
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x21, 0x00));
+    // The range is checked before anything is written
+    assert!(scsi.block_device_mut().data().iter().all(|&b| b == 0));
+
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+}
+
+#[test]
+fn exact_data_in_on_packet_boundary_has_no_zlp() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    // receive_csw would choke on a ZLP between the data and the CSW
+    let r = host.command(&mut scsi, 0, &read10(0, 1), DataStage::In(BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data.len(), BLOCK_BYTES);
+    assert!(!r.zlp);
+    assert_eq!(r.csw.data_residue, 0);
+    host.assert_idle(&mut scsi);
+}
+
+#[test]
+fn write_then_read_back() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+    let data = pattern(4, 3);
+
+    let r = host.command(&mut scsi, 0, &write10(5, 4), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.csw.data_residue, 0);
+
+    assert_eq!(&scsi.block_device_mut().data()[5 * BLOCK_BYTES..9 * BLOCK_BYTES], &data[..]);
+    assert!(scsi.block_device_mut().block(4).iter().all(|&b| b == 0));
+    assert!(scsi.block_device_mut().block(9).iter().all(|&b| b == 0));
+
+    let r = host.command(&mut scsi, 0, &read10(5, 4), DataStage::In(data.len() as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.csw.data_residue, 0);
+    assert_eq!(r.data, data);
+}
+
+#[test]
+fn unsupported_op_code_fails_with_sense() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    let r = host.command(&mut scsi, 0, &[0xC7, 0, 0, 0, 0, 0], DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+
+    // ILLEGAL REQUEST, INVALID COMMAND OPERATION CODE
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x20, 0x00));
+
+    // Sense is cleared by a successful command
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x00, 0x00, 0x00));
+}
+
+#[test]
+fn read_out_of_range_fails_with_sense() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    let r = host.command(&mut scsi, 0, &read10(16, 1), DataStage::In(BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+
+    // ILLEGAL REQUEST, LOGICAL BLOCK ADDRESS OUT OF RANGE
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x21, 0x00));
+
+    // A range that starts on the disk but runs off the end fails before any data is sent
+    let r = host.command(&mut scsi, 0, &read10(14, 4), DataStage::In(4 * BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert!(r.data.is_empty());
+    assert_eq!(r.csw.data_residue as usize, 4 * BLOCK_BYTES);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x21, 0x00));
+}
+
+#[test]
+fn write_error_fails_with_sense() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(FailingWrites::new(ram_disk(), Some(3))));
+
+    let data = pattern(1, 0);
+    let r = host.command(&mut scsi, 0, &write10(3, 1), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+
+    // MEDIUM ERROR, WRITE ERROR
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x03, 0x0C, 0x00));
+}
+
+#[test]
+fn write_error_reports_failing_lba() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(FailingWrites::new(ram_disk(), Some(7))));
+
+    let data = pattern(2, 0);
+    let r = host.command(&mut scsi, 0, &write10(6, 2), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    // The block that failed wasn't written so it's part of the residue
+    assert_eq!(r.csw.data_residue as usize, BLOCK_BYTES);
+    assert_eq!(scsi.block_device_mut().block(6), &data[..BLOCK_BYTES]);
+
+    // Fixed format, VALID set and the LBA in the information field
+    let r = host.command(&mut scsi, 0, &REQUEST_SENSE, DataStage::In(18));
+    assert_eq!(r.data[0], 0xF0);
+    assert_eq!(r.data[2], 0x03);
+    assert_eq!(r.data[3..7], [0, 0, 0, 7]);
+
+    // Sense data is cleared by REQUEST SENSE so fail again
+    let r = host.command(&mut scsi, 0, &write10(6, 2), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+
+    // Descriptor format with an information descriptor
+    let r = host.command(&mut scsi, 0, &[0x03, 0x01, 0, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, [
+        0x72, 0x03, 0x0C, 0x00, 0, 0, 0, 12,
+        0x00, 0x0A, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 7,
+    ]);
+    assert_eq!(r.csw.data_residue as usize, 255 - r.data.len());
+}
+
+#[test]
+fn request_sense_honours_allocation_length() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    let r = host.command(&mut scsi, 0, &[0x03, 0, 0, 0, 4, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, [0x70, 0, 0, 0]);
+    assert_eq!(r.csw.data_residue, 251);
+}
+
+fn assert_bulk_stalled(host: &Host) {
+    assert!(host.bus().in_stalled(BULK_IN), "Bulk IN endpoint isn't stalled");
+    assert!(host.bus().out_stalled(BULK_OUT), "Bulk OUT endpoint isn't stalled");
+}
+
+/// Invalid and non-meaningful CBWs stall both bulk endpoints until a reset recovery
+#[test]
+fn invalid_cbw_needs_reset_recovery() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    let mut cbw = [0; 32];
+    cbw[0..4].copy_from_slice(&[0x55, 0x53, 0x42, 0x43]);
+    cbw[14] = 6;
+    let mut no_command_block = cbw;
+    no_command_block[14] = 0;
+    let mut long_command_block = cbw;
+    long_command_block[14] = 17;
+    let mut reserved_flags = cbw;
+    reserved_flags[12] = 0x81;
+
+    for packet in [
+        &[0xAA; 7][..],
+        &[0xAA; 31][..],
+        &cbw[..30],
+        &cbw[..],
+        &no_command_block[..31],
+        &long_command_block[..31],
+        &reserved_flags[..31],
+    ].iter() {
+        host.send_packet(&mut scsi, packet);
+        host.poll(&mut scsi);
+        assert_bulk_stalled(&host);
+
+        // Clearing the halts doesn't do anything before the reset
+        host.clear_halt(&mut scsi, 0x80 | BULK_IN as u8);
+        host.clear_halt(&mut scsi, BULK_OUT as u8);
+        assert_bulk_stalled(&host);
+
+        host.reset_recovery(&mut scsi, INTERFACE);
+        assert!(!host.bus().in_stalled(BULK_IN) && !host.bus().out_stalled(BULK_OUT));
+
+        let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+        assert_eq!(r.csw.status, CommandStatus::Passed);
+        assert!(!r.stalled);
+    }
+}
+
+#[test]
+fn multiple_luns() {
+    let (mut scsi, mut host) = scsi_device((disk_unit(RamDisk::<8>::new()), ram_disk_unit()));
+
+    let r = host.command(&mut scsi, 0, &REPORT_LUNS, DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, [
+        0, 0, 0, 16, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0,
+        0, 1, 0, 0, 0, 0, 0, 0,
+    ]);
+
+    let r = host.command(&mut scsi, 1, &READ_CAPACITY, DataStage::In(8));
+    assert_eq!(r.data[..4], [0, 0, 0, 15]);
+    let r = host.command(&mut scsi, 0, &READ_CAPACITY, DataStage::In(8));
+    assert_eq!(r.data[..4], [0, 0, 0, 7]);
+
+    let data = pattern(1, 9);
+    let r = host.command(&mut scsi, 1, &write10(2, 1), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    let (lun0, lun1) = scsi.logical_units_mut();
+    assert!(lun0.block_device_mut().block(2).iter().all(|&b| b == 0));
+    assert_eq!(lun1.block_device_mut().block(2), &data[..]);
+
+    // A LUN past max LUN isn't meaningful
+    host.send_cbw(&mut scsi, 5, &INQUIRY, true, 36);
+    host.poll(&mut scsi);
+    assert_bulk_stalled(&host);
+    host.reset_recovery(&mut scsi, INTERFACE);
+
+    let r = host.command(&mut scsi, 1, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+}
+
+#[test]
+fn ram_block_device() {
+    let logical_unit = usbd_scsi::LogicalUnit::new(usbd_scsi::RamBlockDevice::<32>::new(), "Mock", "Ram", "1.0");
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+
+    let r = host.command(&mut scsi, 0, &READ_CAPACITY, DataStage::In(8));
+    assert_eq!(r.data, [0, 0, 0, 31, 0, 0, 2, 0]);
+
+    let data = pattern(2, 1);
+    let r = host.command(&mut scsi, 0, &write10(30, 2), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().blocks()[31][..], data[BLOCK_BYTES..]);
+
+    let r = host.command(&mut scsi, 0, &read10(30, 2), DataStage::In(data.len() as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, data);
+}
+
+/// Returns the VPD page, checking the header
+fn vpd_page<C: usb_device::class::UsbClass<MockBus>>(host: &mut Host, device: &mut C, page: u8) -> Vec<u8> {
+    let r = host.command(device, 0, &[0x12, 0x01, page, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data[..2], [0x00, page]);
+    assert_eq!(u16::from_be_bytes([r.data[2], r.data[3]]) as usize, r.data.len() - 4);
+    r.data[4..].to_vec()
+}
+
+#[test]
+fn vital_product_data() {
+    let mut logical_unit = ram_disk_unit();
+    logical_unit.set_serial_number("0123456789AB");
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+
+    assert_eq!(vpd_page(&mut host, &mut scsi, 0x00), [0x00, 0x80, 0x83, 0xB0, 0xB1]);
+    assert_eq!(vpd_page(&mut host, &mut scsi, 0x80), b"0123456789AB");
+
+    let designator = vpd_page(&mut host, &mut scsi, 0x83);
+    assert_eq!(designator[..4], [0x02, 0x01, 0x00, 8 + 16 + 12]);
+    assert_eq!(&designator[4..], &b"Mock    RamDisk         0123456789AB"[..]);
+
+    let block_limits = vpd_page(&mut host, &mut scsi, 0xB0);
+    assert_eq!(block_limits.len(), 0x3C);
+
+    let characteristics = vpd_page(&mut host, &mut scsi, 0xB1);
+    assert_eq!(characteristics.len(), 0x3C);
+    // Non-rotating medium
+    assert_eq!(characteristics[..2], [0x00, 0x01]);
+
+    // Truncated to the allocation length
+    let r = host.command(&mut scsi, 0, &[0x12, 0x01, 0x80, 0, 6, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, [0x00, 0x80, 0x00, 12, b'0', b'1']);
+}
+
+#[test]
+fn vital_product_data_without_serial_number() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    assert_eq!(vpd_page(&mut host, &mut scsi, 0x00), [0x00, 0x83, 0xB0, 0xB1]);
+
+    let r = host.command(&mut scsi, 0, &[0x12, 0x01, 0x80, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+}
+
+#[test]
+fn unsupported_vital_product_data_page() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    for &(evpd, page) in [(0x01, 0x89), (0x01, 0xC0), (0x00, 0x80)].iter() {
+        let r = host.command(&mut scsi, 0, &[0x12, evpd, page, 0, 0, 0], DataStage::None);
+        assert_eq!(r.csw.status, CommandStatus::Failed);
+
+        // ILLEGAL REQUEST, INVALID FIELD IN CDB
+        assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+    }
+}
+
+/// Caching mode page as reported by a device without mode pages of its own
+const BUILT_IN_CACHING_PAGE: [u8; 20] = [0x08, 0x12, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+#[test]
+fn mode_sense() {
+    let logical_unit = usbd_scsi::LogicalUnit::new(usbd_scsi::RamBlockDevice::<4>::new(), "Mock", "Ram", "1.0");
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+
+    // MODE SENSE(6), caching page with short block descriptor
+    let r = host.command(&mut scsi, 0, &[0x1A, 0, 0x08, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data[..4], [4 - 1 + 8 + 20, 0, 0, 8]);
+    assert_eq!(r.data[4..12], [0, 0, 0, 4, 0, 0, 0x02, 0x00]);
+    assert_eq!(r.data[12..], BUILT_IN_CACHING_PAGE);
+
+    // MODE SENSE(10), all pages with long block descriptor
+    let r = host.command(&mut scsi, 0, &[0x5A, 0x10, 0x3F, 0, 0, 0, 0, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data[..8], [0, 8 - 2 + 16 + 20, 0, 0, 0x01, 0, 0, 16]);
+    assert_eq!(r.data[8..24], [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0x02, 0x00]);
+    assert_eq!(r.data[24..], BUILT_IN_CACHING_PAGE);
+
+    // Block descriptors disabled, changeable values
+    let r = host.command(&mut scsi, 0, &[0x1A, 0x08, 0x48, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data[..4], [4 - 1 + 20, 0, 0, 0]);
+    assert_eq!(r.data[4..7], [0x08, 0x12, 0x04]);
+    assert!(r.data[7..].iter().all(|&b| b == 0));
+
+    // Truncated to the allocation length
+    let r = host.command(&mut scsi, 0, &[0x1A, 0, 0x3F, 0, 4, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, [4 - 1 + 8 + 20, 0, 0, 8]);
+}
+
+#[test]
+fn mode_sense_unsupported_page() {
+    let logical_unit = usbd_scsi::LogicalUnit::new(usbd_scsi::RamBlockDevice::<4>::new(), "Mock", "Ram", "1.0");
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+
+    // Page that doesn't exist, subpage that doesn't exist
+    for cdb in [[0x1A, 0, 0x01, 0, 255, 0], [0x1A, 0, 0x08, 0x01, 255, 0]].iter() {
+        let r = host.command(&mut scsi, 0, cdb, DataStage::In(255));
+        assert_eq!(r.csw.status, CommandStatus::Failed);
+        // ILLEGAL REQUEST, INVALID FIELD IN CDB
+        assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+    }
+
+    // Saved values of the built in caching page
+    let r = host.command(&mut scsi, 0, &[0x1A, 0, 0xC8, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    // ILLEGAL REQUEST, SAVING PARAMETERS NOT SUPPORTED
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x39, 0x00));
+}
+
+#[test]
+fn mode_sense_pages_that_dont_fit() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(WithLargePages::new(ram_disk(), &[0x01, 0x02], 120)));
+
+    // MODE SENSE(10), all pages without block descriptors. Both large pages fit in the 256
+    // bytes, the caching page after them doesn't and is left out
+    let r = host.command(&mut scsi, 0, &[0x5A, 0x08, 0x3F, 0, 0, 0, 0, 0x02, 0, 0], DataStage::In(0x200));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data.len(), 8 + 122 + 122);
+    assert_eq!(r.data[..2], [0, (8 - 2 + 122 + 122) as u8]);
+    assert_eq!(r.data[8..10], [0x01, 120]);
+    assert_eq!(r.data[130..132], [0x02, 120]);
+
+    // With the block descriptor the second large page doesn't fit either
+    let r = host.command(&mut scsi, 0, &[0x5A, 0, 0x3F, 0, 0, 0, 0, 0x02, 0, 0], DataStage::In(0x200));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data.len(), 8 + 8 + 122);
+    assert_eq!(r.data[16..18], [0x01, 120]);
+
+    // A single page that can't fit at all
+    let (mut scsi, mut host) = scsi_device(disk_unit(WithLargePages::new(ram_disk(), &[0x01], 255)));
+    let r = host.command(&mut scsi, 0, &[0x5A, 0x08, 0x01, 0, 0, 0, 0, 0x02, 0, 0], DataStage::In(0x200));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    // ILLEGAL REQUEST, INVALID FIELD IN CDB
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+
+    // All pages leaves it out
+    let r = host.command(&mut scsi, 0, &[0x5A, 0x08, 0x3F, 0, 0, 0, 0, 0x02, 0, 0], DataStage::In(0x200));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data.len(), 8);
+}
+
+/// Returns the caching mode page for the page control, without block descriptors
+fn caching_page<C: usb_device::class::UsbClass<MockBus>>(host: &mut Host, device: &mut C, page_control: u8) -> Vec<u8> {
+    let r = host.command(device, 0, &[0x1A, 0x08, page_control << 6 | 0x08, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data.len(), 4 + 20);
+    r.data[4..].to_vec()
+}
+
+#[test]
+fn mode_select() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(WithCachingPage::new(ram_disk())));
+
+    assert_eq!(caching_page(&mut host, &mut scsi, 0)[..3], [0x88, 0x12, 0x01]);
+    assert_eq!(caching_page(&mut host, &mut scsi, 1)[..3], [0x08, 0x12, 0x04]);
+
+    // MODE SELECT(6) enabling the write cache, with a block descriptor
+    let mut parameters = vec![0, 0, 0, 8, 0, 0, 0, 16, 0, 0, 0x02, 0x00];
+    let mut page = caching_page(&mut host, &mut scsi, 0);
+    page[2] |= 0x04;
+    parameters.extend_from_slice(&page);
+
+    let r = host.command(&mut scsi, 0, &[0x15, 0x10, 0, 0, parameters.len() as u8, 0], DataStage::Out(&parameters));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert!(scsi.block_device_mut().write_cache_enabled);
+    assert!(!scsi.block_device_mut().saved_write_cache_enabled);
+    assert_eq!(caching_page(&mut host, &mut scsi, 0)[2], 0x05);
+
+    // MODE SELECT(10) saving a disabled write cache
+    let mut parameters = vec![0, 0, 0, 0, 0, 0, 0, 0];
+    let mut page = caching_page(&mut host, &mut scsi, 0);
+    page[2] &= !0x04;
+    parameters.extend_from_slice(&page);
+
+    let r = host.command(&mut scsi, 0, &[0x55, 0x11, 0, 0, 0, 0, 0, 0, parameters.len() as u8, 0], DataStage::Out(&parameters));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert!(!scsi.block_device_mut().write_cache_enabled);
+    assert_eq!(caching_page(&mut host, &mut scsi, 3)[2], 0x01);
+}
+
+#[test]
+fn mode_select_invalid_parameters() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(WithCachingPage::new(ram_disk())));
+    let page = caching_page(&mut host, &mut scsi, 0);
+
+    let mut unchangeable = page.clone();
+    unchangeable[2] &= !0x01;
+    let mut short = page.clone();
+    short[1] = 0x10;
+    short.truncate(0x12);
+
+    // (parameter list, expected ASC)
+    let cases = [
+        // Unchangeable read cache disable bit changed
+        ([&[0, 0, 0, 0][..], &unchangeable].concat(), 0x26),
+        // Page length doesn't match
+        ([&[0, 0, 0, 0][..], &short].concat(), 0x26),
+        // Page that doesn't exist
+        (vec![0, 0, 0, 0, 0x01, 0x0A, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 0x26),
+        // Block length that isn't the current one
+        ([&[0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0x04, 0x00][..], &page].concat(), 0x26),
+        // Page cut short
+        ([&[0, 0, 0, 0][..], &page[..10]].concat(), 0x1A),
+    ];
+
+    for (parameters, asc) in cases.iter() {
+        let r = host.command(&mut scsi, 0, &[0x15, 0x10, 0, 0, parameters.len() as u8, 0], DataStage::Out(parameters));
+        assert_eq!(r.csw.status, CommandStatus::Failed);
+        assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, *asc, 0x00));
+    }
+
+    // Nothing was applied
+    assert_eq!(caching_page(&mut host, &mut scsi, 0), page);
+}
+
+#[test]
+fn write_protect() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(WriteProtect::new(ram_disk(), true)));
+
+    // Write protect bit in the device specific parameter of both mode parameter headers
+    let r = host.command(&mut scsi, 0, &[0x1A, 0x08, 0x08, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data[2], 0x80);
+    let r = host.command(&mut scsi, 0, &[0x5A, 0x08, 0x08, 0, 0, 0, 0, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data[3], 0x80);
+
+    for &blocks in [1, 2].iter() {
+        let data = pattern(blocks, 1);
+        let r = host.command(&mut scsi, 0, &write10(4, blocks as u16), DataStage::Out(&data));
+        assert_eq!(r.csw.status, CommandStatus::Failed);
+        // DATA PROTECT, WRITE PROTECTED
+        assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x07, 0x27, 0x00));
+        assert!(scsi.block_device_mut().data().iter().all(|&b| b == 0));
+    }
+
+    // Reading is still fine
+    let r = host.command(&mut scsi, 0, &read10(4, 1), DataStage::In(BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    // Once unlocked writes go through and the bit is cleared
+    scsi.block_device_mut().write_protected = false;
+    let data = pattern(1, 1);
+    let r = host.command(&mut scsi, 0, &write10(4, 1), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().block(4), &data[..]);
+
+    let r = host.command(&mut scsi, 0, &[0x1A, 0x08, 0x08, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.data[2], 0x00);
+}
+
+fn read16(lba: u64, blocks: u32) -> [u8; 16] {
+    let mut cdb = [0; 16];
+    cdb[0] = 0x88;
+    cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+    cdb[10..14].copy_from_slice(&blocks.to_be_bytes());
+    cdb
+}
+
+fn write16(lba: u64, blocks: u32) -> [u8; 16] {
+    let mut cdb = read16(lba, blocks);
+    cdb[0] = 0x8A;
+    cdb
+}
+
+/// 3 TiB, too big for READ CAPACITY(10)
+const HUGE_DISK_BLOCKS: u64 = 3 << (40 - 9);
+
+#[test]
+fn read_capacity_16() {
+    let logical_unit = usbd_scsi::LogicalUnit::new(SparseDisk::<BLOCK_BYTES>::new(HUGE_DISK_BLOCKS), "Mock", "Sparse", "1.0");
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+
+    // READ CAPACITY(10) saturates the max LBA
+    let r = host.command(&mut scsi, 0, &READ_CAPACITY, DataStage::In(8));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, [0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 2, 0]);
+
+    let r = host.command(&mut scsi, 0, &[0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0], DataStage::In(32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data.len(), 32);
+    assert_eq!(r.data[..12], [0, 0, 0, 1, 0x7F, 0xFF, 0xFF, 0xFF, 0, 0, 2, 0]);
+
+    // Truncated to the allocation length
+    let r = host.command(&mut scsi, 0, &[0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 12, 0, 0], DataStage::In(32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data.len(), 12);
+
+    // Unsupported service action
+    let r = host.command(&mut scsi, 0, &[0x9E, 0x11, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0], DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+}
+
+#[test]
+fn read_write_16() {
+    let logical_unit = usbd_scsi::LogicalUnit::new(SparseDisk::<BLOCK_BYTES>::new(HUGE_DISK_BLOCKS), "Mock", "Sparse", "1.0");
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+
+    // Beyond 2 TiB
+    let lba = HUGE_DISK_BLOCKS - 3;
+    let data = pattern(2, 3);
+    let r = host.command(&mut scsi, 0, &write16(lba, 2), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().blocks[&(lba + 1)], &data[BLOCK_BYTES..]);
+
+    let r = host.command(&mut scsi, 0, &read16(lba, 2), DataStage::In(data.len() as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, data);
+
+    // Off the end
+    let r = host.command(&mut scsi, 0, &read16(HUGE_DISK_BLOCKS, 1), DataStage::In(BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x21, 0x00));
+
+    // Zero length transfers do nothing
+    let r = host.command(&mut scsi, 0, &read16(lba, 0), DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    let r = host.command(&mut scsi, 0, &write16(0, 0), DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().blocks.len(), 2);
+}
+
+#[test]
+fn reads_fill_the_buffer() {
+    let data = pattern(8, 5);
+
+    // The default buffer holds one block at a time
+    let mut logical_unit = disk_unit(Counted::new(ram_disk()));
+    logical_unit.block_device_mut().data_mut()[..data.len()].copy_from_slice(&data);
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+
+    host.send_cbw(&mut scsi, 0, &read10(0, 8), true, data.len() as u32);
+    assert_eq!(scsi.block_device_mut().blocks_read.get(), 1);
+    let (received, _) = host.receive_data(&mut scsi, data.len());
+    assert_eq!(received, data);
+    assert_eq!(host.receive_csw(&mut scsi).status, CommandStatus::Passed);
+
+    // A bigger one gets all 8 queued straight away
+    let mut logical_unit = disk_unit(Counted::new(ram_disk()));
+    logical_unit.block_device_mut().data_mut()[..data.len()].copy_from_slice(&data);
+    let (mut scsi, mut host) = scsi_device_with_buffer::<_, 4096>(logical_unit);
+
+    host.send_cbw(&mut scsi, 0, &read10(0, 8), true, data.len() as u32);
+    assert_eq!(scsi.block_device_mut().blocks_read.get(), 8);
+    let (received, _) = host.receive_data(&mut scsi, data.len());
+    assert_eq!(received, data);
+    assert_eq!(host.receive_csw(&mut scsi).status, CommandStatus::Passed);
+}
+
+#[test]
+fn buffer_not_a_multiple_of_block_size() {
+    // Room for one and a half blocks so the buffer has to be compacted to fit the next block
+    let (mut scsi, mut host) = scsi_device_with_buffer::<_, 768>(ram_disk_unit());
+    let data = pattern(5, 9);
+
+    let r = host.command(&mut scsi, 0, &write10(3, 5), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(&scsi.block_device_mut().data()[3 * BLOCK_BYTES..8 * BLOCK_BYTES], &data[..]);
+
+    let r = host.command(&mut scsi, 0, &read10(3, 5), DataStage::In(data.len() as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.csw.data_residue, 0);
+    assert_eq!(r.data, data);
+
+    // Still in sync
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+}
+
+#[test]
+fn large_blocks() {
+    const LARGE_BLOCK_BYTES: usize = 4096;
+    let logical_unit = usbd_scsi::LogicalUnit::new(SparseDisk::<LARGE_BLOCK_BYTES>::new(64), "Mock", "Sparse", "1.0");
+    let (mut scsi, mut host) = scsi_device_with_buffer::<_, { 2 * LARGE_BLOCK_BYTES }>(logical_unit);
+
+    let r = host.command(&mut scsi, 0, &READ_CAPACITY, DataStage::In(8));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, [0, 0, 0, 63, 0, 0, 0x10, 0]);
+
+    let data = pattern(3 * LARGE_BLOCK_BYTES / BLOCK_BYTES, 1);
+    let r = host.command(&mut scsi, 0, &write10(10, 3), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().blocks[&12], &data[2 * LARGE_BLOCK_BYTES..]);
+
+    let r = host.command(&mut scsi, 0, &read10(9, 4), DataStage::In(4 * LARGE_BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert!(r.data[..LARGE_BLOCK_BYTES].iter().all(|&b| b == 0));
+    assert_eq!(&r.data[LARGE_BLOCK_BYTES..], &data[..]);
+}
+
+#[test]
+fn busy_block_device() {
+    let logical_unit = disk_unit(Busy::new(Counted::new(ram_disk()), 5));
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+    let data = pattern(4, 11);
+
+    let r = host.command(&mut scsi, 0, &write10(2, 4), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(&scsi.block_device_mut().data()[2 * BLOCK_BYTES..6 * BLOCK_BYTES], &data[..]);
+    assert_eq!(scsi.block_device_mut().flushes, 1);
+
+    let r = host.command(&mut scsi, 0, &read10(2, 4), DataStage::In(data.len() as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, data);
+    assert_eq!(scsi.block_device_mut().blocks_read.get(), 4);
+
+    // Same again with room for all the blocks in the buffer
+    let logical_unit = disk_unit(Busy::new(Counted::new(ram_disk()), 5));
+    let (mut scsi, mut host) = scsi_device_with_buffer::<_, 2048>(logical_unit);
+
+    let r = host.command(&mut scsi, 0, &write10(2, 4), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    let r = host.command(&mut scsi, 0, &read10(2, 4), DataStage::In(data.len() as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, data);
+
+    // Busy doesn't get in the way of errors
+    let r = host.command(&mut scsi, 0, &read10(15, 2), DataStage::In(2 * BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert!(r.data.is_empty());
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x21, 0x00));
+}
+
+/// A Bulk-Only Mass Storage Reset in the middle of a write waits for the block device to abort
+#[test]
+fn mass_storage_reset_aborts_command() {
+    let logical_unit = disk_unit(Busy::new(Counted::new(ram_disk()), 5));
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+    let data = pattern(4, 3);
+
+    // Nothing to abort between commands
+    host.reset_recovery(&mut scsi, INTERFACE);
+    assert_eq!(scsi.block_device_mut().aborts, 0);
+
+    // Only the first of four blocks arrives before the reset
+    host.send_cbw(&mut scsi, 0, &write10(0, 4), false, data.len() as u32);
+    host.send_data(&mut scsi, &data[..BLOCK_BYTES]);
+    host.poll(&mut scsi);
+
+    // The block device is busy so the reset is acknowledged anyway, the abort finishes over the
+    // next few polls
+    host.reset_recovery(&mut scsi, INTERFACE);
+    host.assert_idle(&mut scsi);
+    assert_eq!(scsi.block_device_mut().aborts, 1);
+
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    let r = host.command(&mut scsi, 0, &write10(0, 4), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(&scsi.block_device_mut().data()[..4 * BLOCK_BYTES], &data[..]);
+    assert_eq!(scsi.block_device_mut().aborts, 1);
+}
+
+/// While the block device is still aborting nothing more of the command is written or sent, even
+/// though the block device would take the data waiting in the buffer
+#[test]
+fn mass_storage_reset_holds_command_until_aborted() {
+    let logical_unit = disk_unit(SlowAbort::new(Counted::new(ram_disk()), 50));
+    let (mut scsi, mut host) = scsi_device_with_buffer::<_, 2048>(logical_unit);
+    let data = pattern(4, 6);
+
+    // All four blocks wait in the buffer while the block device is busy
+    scsi.block_device_mut().hold_writes = true;
+    host.send_cbw(&mut scsi, 0, &write10(0, 4), false, data.len() as u32);
+    host.send_data(&mut scsi, &data);
+    host.poll(&mut scsi);
+    assert_eq!(scsi.block_device_mut().blocks_written, 0);
+
+    // The block device could write them now but the command is being aborted
+    scsi.block_device_mut().hold_writes = false;
+    host.reset_recovery(&mut scsi, INTERFACE);
+    for _ in 0..3 {
+        host.assert_idle(&mut scsi);
+        assert_eq!(scsi.block_device_mut().blocks_written, 0);
+        assert_eq!(scsi.block_device_mut().aborts, 0);
+    }
+
+    // Once the abort goes through the data is dropped along with the command
+    for _ in 0..3 {
+        host.assert_idle(&mut scsi);
+    }
+    assert_eq!(scsi.block_device_mut().aborts, 1);
+    assert_eq!(scsi.block_device_mut().blocks_written, 0);
+    assert_eq!(&scsi.block_device_mut().data()[..4 * BLOCK_BYTES], &[0; 4 * BLOCK_BYTES][..]);
+
+    let r = host.command(&mut scsi, 0, &write10(0, 4), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(&scsi.block_device_mut().data()[..4 * BLOCK_BYTES], &data[..]);
+}
+
+fn verify16(lba: u64, blocks: u32, byte_check: u8) -> [u8; 16] {
+    let mut cdb = read16(lba, blocks);
+    cdb[0] = 0x8F;
+    cdb[1] = byte_check << 1;
+    cdb
+}
+
+#[test]
+fn verify() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(Counted::new(ram_disk())));
+    let data = pattern(3, 4);
+    scsi.block_device_mut().data_mut()[4 * BLOCK_BYTES..7 * BLOCK_BYTES].copy_from_slice(&data);
+
+    // No byte check, just reads
+    let r = host.command(&mut scsi, 0, &[0x2F, 0, 0, 0, 0, 0, 0, 0, 16, 0], DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().blocks_read.get(), 16);
+
+    // Off the end, fails before any blocks are read with the start of the range reported
+    let r = host.command(&mut scsi, 0, &verify16(14, 3, 0), DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(scsi.block_device_mut().blocks_read.get(), 16);
+    let r = host.command(&mut scsi, 0, &REQUEST_SENSE, DataStage::In(18));
+    assert_eq!((r.data[2], r.data[12]), (0x05, 0x21));
+    assert_eq!(r.data[3..7], [0, 0, 0, 14]);
+
+    // None of the host's data is taken either
+    let r = host.command(&mut scsi, 0, &verify16(14, 3, 1), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert!(r.stalled);
+    assert_eq!(r.csw.data_residue as usize, data.len());
+    assert_eq!(scsi.block_device_mut().blocks_read.get(), 16);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x21, 0x00));
+
+    // Compare with the host's data
+    let r = host.command(&mut scsi, 0, &[0x2F, 0x02, 0, 0, 0, 4, 0, 0, 3, 0], DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    let mut different = data.clone();
+    different[2 * BLOCK_BYTES + 17] ^= 0xFF;
+    let r = host.command(&mut scsi, 0, &verify16(4, 3, 1), DataStage::Out(&different));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+
+    // MISCOMPARE, MISCOMPARE DURING VERIFY OPERATION with the offset into the data
+    let r = host.command(&mut scsi, 0, &REQUEST_SENSE, DataStage::In(18));
+    assert_eq!((r.data[0], r.data[2], r.data[12], r.data[13]), (0xF0, 0x0E, 0x1D, 0x00));
+    assert_eq!(u32::from_be_bytes([r.data[3], r.data[4], r.data[5], r.data[6]]) as usize, 2 * BLOCK_BYTES + 17);
+
+    // Reserved byte check value
+    let r = host.command(&mut scsi, 0, &verify16(4, 1, 2), DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+}
+
+#[test]
+fn verify_single_block() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(Busy::new(ram_disk(), 2)));
+    let block = pattern(1, 8);
+    for lba in 8..12 {
+        let start = lba * BLOCK_BYTES;
+        scsi.block_device_mut().data_mut()[start..start + BLOCK_BYTES].copy_from_slice(&block);
+    }
+
+    let r = host.command(&mut scsi, 0, &verify16(8, 4, 3), DataStage::Out(&block));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    // Block 12 is still zeroed
+    let r = host.command(&mut scsi, 0, &verify16(8, 5, 3), DataStage::Out(&block));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    let r = host.command(&mut scsi, 0, &REQUEST_SENSE, DataStage::In(18));
+    assert_eq!((r.data[2], r.data[12]), (0x0E, 0x1D));
+    let offset = block.iter().position(|&b| b != 0).unwrap();
+    assert_eq!(u32::from_be_bytes([r.data[3], r.data[4], r.data[5], r.data[6]]) as usize, offset);
+
+    // Still in sync
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+}
+
+#[test]
+fn verify_unsupported_byte_check() {
+    // SparseDisk can't compare blocks
+    let logical_unit = usbd_scsi::LogicalUnit::new(SparseDisk::<BLOCK_BYTES>::new(16), "Mock", "Sparse", "1.0");
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+
+    let r = host.command(&mut scsi, 0, &verify16(0, 16, 0), DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    let r = host.command(&mut scsi, 0, &verify16(0, 1, 1), DataStage::Out(&pattern(1, 0)));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+}
+
+/// Sets the write cache enabled bit of the current caching page with MODE SELECT(6)
+fn select_write_cache<C: usb_device::class::UsbClass<MockBus>>(host: &mut Host, device: &mut C, enabled: bool) {
+    let mut page = caching_page(host, device, 0);
+    page[0] &= !0x80;
+    page[2] = (page[2] & !0x04) | if enabled { 0x04 } else { 0 };
+    let parameters = [&[0, 0, 0, 0][..], &page].concat();
+
+    let r = host.command(device, 0, &[0x15, 0x10, 0, 0, parameters.len() as u8, 0], DataStage::Out(&parameters));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+}
+
+#[test]
+fn write_cache() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(Counted::new(WithCachingPage::new(ram_disk()))));
+    let data = pattern(2, 5);
+
+    // Write cache disabled, every write is flushed
+    let r = host.command(&mut scsi, 0, &write10(1, 2), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().flushes, 1);
+
+    // Write cache enabled, only when forcing unit access
+    select_write_cache(&mut host, &mut scsi, true);
+    let r = host.command(&mut scsi, 0, &write10(1, 2), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().flushes, 1);
+
+    let mut fua = write10(1, 2);
+    fua[1] = 0x08;
+    let r = host.command(&mut scsi, 0, &fua, DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().flushes, 2);
+
+    // SYNCHRONIZE CACHE(10), whole medium
+    let r = host.command(&mut scsi, 0, &[0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0], DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().flushes, 3);
+
+    // SYNCHRONIZE CACHE(16), range past the end
+    let mut cdb = read16(15, 2);
+    cdb[0] = 0x91;
+    let r = host.command(&mut scsi, 0, &cdb, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    // ILLEGAL REQUEST, LOGICAL BLOCK ADDRESS OUT OF RANGE
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x21, 0x00));
+    assert_eq!(scsi.block_device_mut().flushes, 3);
+
+    // START STOP UNIT flushes when stopping or ejecting unless told not to
+    for (cdb4, flushes) in [(0x01, 3), (0x00, 4), (0x02, 5), (0x06, 5)].iter() {
+        let r = host.command(&mut scsi, 0, &[0x1B, 0, 0, 0, *cdb4, 0], DataStage::None);
+        assert_eq!(r.csw.status, CommandStatus::Passed);
+        assert_eq!(scsi.block_device_mut().flushes, *flushes);
+    }
+
+    // Bus reset, flushed once the device is polled
+    usb_device::class::UsbClass::reset(&mut scsi);
+    host.assert_idle(&mut scsi);
+    assert_eq!(scsi.block_device_mut().flushes, 6);
+}
+
+#[test]
+fn write_cache_busy_flush() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(Busy::new(Counted::new(ram_disk()), 3)));
+
+    let r = host.command(&mut scsi, 0, &[0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0], DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().flushes, 1);
+
+    usb_device::class::UsbClass::reset(&mut scsi);
+    host.assert_idle(&mut scsi);
+    assert_eq!(scsi.block_device_mut().flushes, 2);
+}
+
+#[test]
+fn write_cache_built_in_page() {
+    let logical_unit = usbd_scsi::LogicalUnit::new(usbd_scsi::RamBlockDevice::<4>::new(), "Mock", "Ram", "1.0");
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+
+    // Only the write cache enabled bit can be changed
+    assert_eq!(caching_page(&mut host, &mut scsi, 1)[2], 0x04);
+
+    select_write_cache(&mut host, &mut scsi, true);
+    assert_eq!(caching_page(&mut host, &mut scsi, 0)[2], 0x05);
+    assert_eq!(caching_page(&mut host, &mut scsi, 2)[2], 0x01);
+
+    select_write_cache(&mut host, &mut scsi, false);
+    assert_eq!(caching_page(&mut host, &mut scsi, 0), BUILT_IN_CACHING_PAGE);
+
+    // Still can't be saved
+    let mut page = caching_page(&mut host, &mut scsi, 0);
+    page[2] |= 0x04;
+    let parameters = [&[0, 0, 0, 0][..], &page].concat();
+    let r = host.command(&mut scsi, 0, &[0x15, 0x11, 0, 0, parameters.len() as u8, 0], DataStage::Out(&parameters));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x39, 0x00));
+    assert_eq!(caching_page(&mut host, &mut scsi, 0)[2], 0x01);
+}
+
+#[test]
+fn unit_attention_on_power_on_and_reset() {
+    let (mut scsi, mut host) = powered_on_scsi_device::<_, 512>(ram_disk_unit());
+
+    // Inquiry doesn't report or clear it, request sense does
+    let r = host.command(&mut scsi, 0, &INQUIRY, DataStage::In(36));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    // UNIT ATTENTION, POWER ON, RESET, OR BUS DEVICE RESET OCCURRED
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x06, 0x29, 0x00));
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x00, 0x00, 0x00));
+
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    // Any other command fails with it after a bus reset, once
+    usb_device::class::UsbClass::reset(&mut scsi);
+    let r = host.command(&mut scsi, 0, &read10(0, 1), DataStage::In(BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x06, 0x29, 0x00));
+
+    let r = host.command(&mut scsi, 0, &read10(0, 1), DataStage::In(BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+}
+
+#[test]
+fn remove_and_insert_medium() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(Counted::new(ram_disk())));
+
+    scsi.logical_units_mut().remove_medium();
+    assert_eq!(scsi.logical_units_mut().medium_state(), MediumState::Removed);
+
+    // NOT READY, MEDIUM NOT PRESENT for anything that needs the medium
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x02, 0x3A, 0x00));
+
+    let r = host.command(&mut scsi, 0, &read10(0, 1), DataStage::In(BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert!(r.data.is_empty());
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x02, 0x3A, 0x00));
+    assert_eq!(scsi.block_device_mut().blocks_read.get(), 0);
+
+    let r = host.command(&mut scsi, 0, &INQUIRY, DataStage::In(36));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    caching_page(&mut host, &mut scsi, 0);
+
+    // The host is told the medium may have changed when it comes back
+    scsi.logical_units_mut().insert_medium();
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    // UNIT ATTENTION, NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x06, 0x28, 0x00));
+
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    // Inserting what's already there doesn't
+    scsi.logical_units_mut().insert_medium();
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+}
+
+const PREVENT_REMOVAL: [u8; 6] = [0x1E, 0, 0, 0, 1, 0];
+const ALLOW_REMOVAL: [u8; 6] = [0x1E, 0, 0, 0, 0, 0];
+const EJECT: [u8; 6] = [0x1B, 0, 0, 0, 0x02, 0];
+const LOAD: [u8; 6] = [0x1B, 0, 0, 0, 0x03, 0];
+
+#[test]
+fn eject_and_load() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(Counted::new(ram_disk())));
+
+    let r = host.command(&mut scsi, 0, &PREVENT_REMOVAL, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert!(scsi.logical_units_mut().is_medium_removal_prevented());
+
+    let r = host.command(&mut scsi, 0, &EJECT, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    // ILLEGAL REQUEST, MEDIUM REMOVAL PREVENTED
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x53, 0x02));
+    assert_eq!(scsi.logical_units_mut().medium_state(), MediumState::Present);
+    assert_eq!(scsi.block_device_mut().flushes, 0);
+
+    let r = host.command(&mut scsi, 0, &ALLOW_REMOVAL, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    let r = host.command(&mut scsi, 0, &EJECT, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.logical_units_mut().medium_state(), MediumState::Ejected);
+    assert_eq!(scsi.block_device_mut().flushes, 1);
+
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x02, 0x3A, 0x00));
+
+    let r = host.command(&mut scsi, 0, &LOAD, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.logical_units_mut().medium_state(), MediumState::Present);
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    // Can't load a medium the application has removed
+    scsi.logical_units_mut().remove_medium();
+    let r = host.command(&mut scsi, 0, &LOAD, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x02, 0x3A, 0x00));
+
+    // Obsolete prevent value
+    let r = host.command(&mut scsi, 0, &[0x1E, 0, 0, 0, 2, 0], DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+}
+
+#[test]
+fn reset_allows_removal() {
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+
+    let r = host.command(&mut scsi, 0, &PREVENT_REMOVAL, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    usb_device::class::UsbClass::reset(&mut scsi);
+    assert!(!scsi.logical_units_mut().is_medium_removal_prevented());
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x06, 0x29, 0x00));
+
+    let r = host.command(&mut scsi, 0, &EJECT, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Event {
+    Started(u8, u8),
+    Finished(u8, u8, bool),
+    Read(u8, u64, u32),
+    Written(u8, u64, u32),
+    Ejected(u8),
+    RemovalPrevented(u8, bool),
+    BusReset,
+}
+
+#[derive(Default)]
+struct EventLog(Vec<Event>);
+
+impl EventLog {
+    fn take(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.0)
+    }
+}
+
+impl usbd_scsi::EventSink for EventLog {
+    fn command_started(&mut self, lun: u8, op_code: u8) {
+        self.0.push(Event::Started(lun, op_code));
+    }
+    fn command_finished(&mut self, lun: u8, op_code: u8, passed: bool) {
+        self.0.push(Event::Finished(lun, op_code, passed));
+    }
+    fn blocks_read(&mut self, lun: u8, lba: u64, blocks: u32) {
+        self.0.push(Event::Read(lun, lba, blocks));
+    }
+    fn blocks_written(&mut self, lun: u8, lba: u64, blocks: u32) {
+        self.0.push(Event::Written(lun, lba, blocks));
+    }
+    fn medium_ejected(&mut self, lun: u8) {
+        self.0.push(Event::Ejected(lun));
+    }
+    fn medium_removal_prevented(&mut self, lun: u8, prevented: bool) {
+        self.0.push(Event::RemovalPrevented(lun, prevented));
+    }
+    fn bus_reset(&mut self) {
+        self.0.push(Event::BusReset);
+    }
+}
+
+/// Checks the read or write events cover `blocks` blocks from `lba` in order, however they were
+/// split up
+fn assert_contiguous(events: &[Event], lba: u64, blocks: u32) {
+    let mut next = lba;
+    for e in events {
+        match *e {
+            Event::Read(_, l, b) | Event::Written(_, l, b) => {
+                assert_eq!(l, next);
+                next += b as u64;
+            },
+            _ => {},
+        }
+    }
+    assert_eq!(next, lba + blocks as u64);
+}
+
+#[test]
+fn event_sink() {
+    let (scsi, mut host) = scsi_device((ram_disk_unit(), ram_disk_unit()));
+    let mut scsi = scsi.with_event_sink(EventLog::default());
+
+    let r = host.command(&mut scsi, 1, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.event_sink_mut().take(), [Event::Started(1, 0x00), Event::Finished(1, 0x00, true)]);
+
+    let r = host.command(&mut scsi, 0, &[0xFF, 0, 0, 0, 0, 0], DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(scsi.event_sink_mut().take(), [Event::Started(0, 0xFF), Event::Finished(0, 0xFF, false)]);
+
+    // Activity is reported as it happens
+    let data = pattern(3, 2);
+    let r = host.command(&mut scsi, 1, &write10(4, 3), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    let events = scsi.event_sink_mut().take();
+    assert_eq!(events.first(), Some(&Event::Started(1, 0x2A)));
+    assert_eq!(events.last(), Some(&Event::Finished(1, 0x2A, true)));
+    assert!(events.iter().all(|e| !matches!(e, Event::Read(..) | Event::Written(0, ..))));
+    assert_contiguous(&events, 4, 3);
+
+    let r = host.command(&mut scsi, 1, &read10(3, 4), DataStage::In(4 * BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    let events = scsi.event_sink_mut().take();
+    assert!(events.iter().all(|e| !matches!(e, Event::Written(..) | Event::Read(0, ..))));
+    assert_contiguous(&events, 3, 4);
+
+    // A read off the end fails before anything is read
+    let r = host.command(&mut scsi, 1, &read10(14, 4), DataStage::In(4 * BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(scsi.event_sink_mut().take(), [Event::Started(1, 0x28), Event::Finished(1, 0x28, false)]);
+
+    // Safely removing the drive
+    for cdb in [ALLOW_REMOVAL, EJECT].iter() {
+        let r = host.command(&mut scsi, 0, cdb, DataStage::None);
+        assert_eq!(r.csw.status, CommandStatus::Passed);
+    }
+    assert_eq!(scsi.event_sink_mut().take(), [
+        Event::Started(0, 0x1E),
+        Event::RemovalPrevented(0, false),
+        Event::Finished(0, 0x1E, true),
+        Event::Started(0, 0x1B),
+        Event::Ejected(0),
+        Event::Finished(0, 0x1B, true),
+    ]);
+
+    usb_device::class::UsbClass::reset(&mut scsi);
+    assert_eq!(scsi.event_sink_mut().take(), [Event::BusReset]);
+}
+
+fn write_same16(lba: u64, blocks: u32, flags: u8) -> [u8; 16] {
+    let mut cdb = read16(lba, blocks);
+    cdb[0] = 0x93;
+    cdb[1] = flags;
+    cdb
+}
+
+const WRITE_SAME_UNMAP: u8 = 0x08;
+const WRITE_SAME_NO_DATA_OUT: u8 = 0x01;
+
+/// UNMAP parameter list for `ranges` of (LBA, blocks)
+fn unmap_parameters(ranges: &[(u64, u32)]) -> Vec<u8> {
+    let descriptors_len = ranges.len() * 16;
+    let mut parameters = Vec::new();
+    parameters.extend_from_slice(&(descriptors_len as u16 + 6).to_be_bytes());
+    parameters.extend_from_slice(&(descriptors_len as u16).to_be_bytes());
+    parameters.extend_from_slice(&[0; 4]);
+    for &(lba, blocks) in ranges {
+        parameters.extend_from_slice(&lba.to_be_bytes());
+        parameters.extend_from_slice(&blocks.to_be_bytes());
+        parameters.extend_from_slice(&[0; 4]);
+    }
+    parameters
+}
+
+fn unmap(parameter_list_length: u16) -> [u8; 10] {
+    let mut cdb = [0x42, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    cdb[7..9].copy_from_slice(&parameter_list_length.to_be_bytes());
+    cdb
+}
+
+#[test]
+fn logical_block_provisioning() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(Discarding::new(ram_disk(), 4)));
+
+    assert_eq!(vpd_page(&mut host, &mut scsi, 0x00), [0x00, 0x83, 0xB0, 0xB1, 0xB2]);
+    // UNMAP and both WRITE SAMEs, resource provisioned
+    assert_eq!(vpd_page(&mut host, &mut scsi, 0xB2), [0x00, 0xE0, 0x01, 0x00]);
+
+    let block_limits = vpd_page(&mut host, &mut scsi, 0xB0);
+    assert_eq!(block_limits[0], 0x01);
+    assert_eq!(block_limits[16..20], [0xFF, 0xFF, 0xFF, 0xFF]);
+    // As many descriptors as fit in the 512 byte buffer
+    assert_eq!(block_limits[20..24], [0, 0, 0, 31]);
+    assert_eq!(block_limits[24..28], [0, 0, 0, 4]);
+
+    // LBPME
+    let r = host.command(&mut scsi, 0, &[0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0], DataStage::In(32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data[14], 0x80);
+
+    // None of it without discard support
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+    assert_eq!(vpd_page(&mut host, &mut scsi, 0x00), [0x00, 0x83, 0xB0, 0xB1]);
+    assert_eq!(vpd_page(&mut host, &mut scsi, 0xB0)[16..28], [0; 12]);
+
+    let r = host.command(&mut scsi, 0, &[0x12, 0x01, 0xB2, 0, 255, 0], DataStage::In(255));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+
+    let r = host.command(&mut scsi, 0, &[0x9E, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0], DataStage::In(32));
+    assert_eq!(r.data[14], 0x00);
+}
+
+#[test]
+fn unmap_blocks() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(Busy::new(Discarding::new(ram_disk(), 1), 2)));
+
+    let parameters = unmap_parameters(&[(2, 3), (8, 0), (12, 4)]);
+    let r = host.command(&mut scsi, 0, &unmap(parameters.len() as u16), DataStage::Out(&parameters));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().discarded, [2..5, 12..16]);
+    assert!(scsi.block_device_mut().block(4).iter().all(|&b| b == 0xFF));
+    assert!(scsi.block_device_mut().block(5).iter().all(|&b| b == 0));
+
+    // Nothing is discarded if any range is out of bounds
+    scsi.block_device_mut().discarded.clear();
+    let parameters = unmap_parameters(&[(0, 1), (15, 2)]);
+    let r = host.command(&mut scsi, 0, &unmap(parameters.len() as u16), DataStage::Out(&parameters));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x21, 0x00));
+    assert!(scsi.block_device_mut().discarded.is_empty());
+
+    // Too short to hold the header
+    let r = host.command(&mut scsi, 0, &unmap(4), DataStage::Out(&[0; 4]));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x1A, 0x00));
+
+    // An empty list does nothing
+    let r = host.command(&mut scsi, 0, &unmap(0), DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert!(scsi.block_device_mut().discarded.is_empty());
+
+    // Unknown without discard support
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+    let r = host.command(&mut scsi, 0, &unmap(24), DataStage::Out(&[0; 24]));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x20, 0x00));
+}
+
+#[test]
+fn write_same() {
+    let disk = Counted::new(Discarding::new(WriteProtect::new(ram_disk(), false), 1));
+    let (mut scsi, mut host) = scsi_device(disk_unit(Busy::new(disk, 1)));
+    let block = pattern(1, 6);
+
+    // More blocks than are written in one go
+    let r = host.command(&mut scsi, 0, &write_same16(1, 5, 0), DataStage::Out(&block));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    for lba in 1..6 {
+        assert_eq!(scsi.block_device_mut().block(lba), &block[..]);
+    }
+    assert_eq!(scsi.block_device_mut().block(6), &[0; BLOCK_BYTES][..]);
+    assert_eq!(scsi.block_device_mut().flushes, 1);
+
+    // Data that isn't zeros is still written with the unmap bit
+    let r = host.command(&mut scsi, 0, &[0x41, WRITE_SAME_UNMAP, 0, 0, 0, 8, 0, 0, 2, 0], DataStage::Out(&block));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().block(9), &block[..]);
+    assert!(scsi.block_device_mut().discarded.is_empty());
+
+    // Zeros are discarded
+    let r = host.command(&mut scsi, 0, &write_same16(1, 2, WRITE_SAME_UNMAP), DataStage::Out(&[0; BLOCK_BYTES]));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    let r = host.command(&mut scsi, 0, &write_same16(8, 2, WRITE_SAME_UNMAP | WRITE_SAME_NO_DATA_OUT), DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().discarded, [1..3, 8..10]);
+
+    // Out of range
+    let r = host.command(&mut scsi, 0, &write_same16(15, 2, 0), DataStage::Out(&block));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x21, 0x00));
+
+    // Write protected once the data is in
+    scsi.block_device_mut().write_protected = true;
+    let r = host.command(&mut scsi, 0, &write_same16(0, 1, 0), DataStage::Out(&block));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x07, 0x27, 0x00));
+    scsi.block_device_mut().write_protected = false;
+
+    // Zero blocks (to the end of the medium) isn't supported
+    let r = host.command(&mut scsi, 0, &write_same16(0, 0, 0), DataStage::Out(&block));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+
+    // Without discard support zeros are written and there has to be data
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+    scsi.block_device_mut().data_mut().iter_mut().for_each(|b| *b = 0x55);
+    let r = host.command(&mut scsi, 0, &write_same16(3, 2, WRITE_SAME_UNMAP), DataStage::Out(&[0; BLOCK_BYTES]));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().block(4), &[0; BLOCK_BYTES][..]);
+
+    let r = host.command(&mut scsi, 0, &write_same16(3, 2, WRITE_SAME_UNMAP | WRITE_SAME_NO_DATA_OUT), DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+}
+
+const FORMAT_UNIT: [u8; 6] = [0x04, 0, 0, 0, 0, 0];
+/// FMTDATA with the short parameter list header
+const FORMAT_UNIT_WITH_DATA: [u8; 6] = [0x04, 0x10, 0, 0, 0, 0];
+
+#[test]
+fn format_unit() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(SlowFormat::new(ram_disk(), 3)));
+    scsi.block_device_mut().data_mut().iter_mut().for_each(|b| *b = 0x55);
+
+    let r = host.command(&mut scsi, 0, &FORMAT_UNIT, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().formats, 1);
+    assert!(scsi.block_device_mut().data().iter().all(|&b| b == 0));
+
+    // UFI style, with a format descriptor for the current capacity
+    let parameters = [0, 0, 0, 8, 0, 0, 0, 16, 0, 0, 0x02, 0];
+    let r = host.command(&mut scsi, 0, &[0x04, 0x17, 0, 0, 0, 0], DataStage::Out(&parameters));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().formats, 2);
+
+    // ILLEGAL REQUEST, INVALID FIELD IN PARAMETER LIST
+    for (cdb, parameters) in [
+        // A different capacity
+        ([0x04, 0x17, 0, 0, 0, 0], &[0, 0, 0, 8, 0, 0, 0, 8, 0, 0, 0x02, 0][..]),
+        // Defect list
+        (FORMAT_UNIT_WITH_DATA, &[0, 0, 0, 4, 0, 0, 0, 1][..]),
+        // Initialization pattern, long header
+        ([0x04, 0x30, 0, 0, 0, 0], &[0, 0x88, 0, 0, 0, 0, 0, 0][..]),
+    ].iter() {
+        let r = host.command(&mut scsi, 0, cdb, DataStage::Out(parameters));
+        assert_eq!(r.csw.status, CommandStatus::Failed);
+        assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x26, 0x00));
+    }
+    assert_eq!(scsi.block_device_mut().formats, 2);
+
+    // Protection information isn't supported
+    let r = host.command(&mut scsi, 0, &[0x04, 0x40, 0, 0, 0, 0], DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+
+    // Devices that can't format don't support the command
+    let logical_unit = usbd_scsi::LogicalUnit::new(SparseDisk::<BLOCK_BYTES>::new(16), "Mock", "Sparse", "1.0");
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+    let r = host.command(&mut scsi, 0, &FORMAT_UNIT, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x20, 0x00));
+}
+
+/// Returns the progress indication from the sense data while a format is running
+fn format_progress<C: usb_device::class::UsbClass<MockBus>>(host: &mut Host, device: &mut C) -> u16 {
+    let r = host.command(device, 0, &REQUEST_SENSE, DataStage::In(18));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    // NOT READY, LOGICAL UNIT NOT READY, FORMAT IN PROGRESS with SKSV set
+    assert_eq!((r.data[2], r.data[12], r.data[13], r.data[15]), (0x02, 0x04, 0x04, 0x80));
+    u16::from_be_bytes([r.data[16], r.data[17]])
+}
+
+#[test]
+fn format_unit_immediate() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(SlowFormat::new(ram_disk(), 1000)));
+    scsi.block_device_mut().data_mut().iter_mut().for_each(|b| *b = 0x55);
+
+    let r = host.command(&mut scsi, 0, &FORMAT_UNIT_WITH_DATA, DataStage::Out(&[0, 0x02, 0, 0]));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.block_device_mut().formats, 0);
+    assert!(scsi.logical_units_mut().is_formatting());
+
+    // Progress is reported by REQUEST SENSE and commands that need the medium fail
+    let start = format_progress(&mut host, &mut scsi);
+    let r = host.command(&mut scsi, 0, &read10(0, 1), DataStage::In(BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert!(format_progress(&mut host, &mut scsi) > start);
+
+    let r = host.command(&mut scsi, 0, &INQUIRY, DataStage::In(36));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    // Carries on through a reset
+    usb_device::class::UsbClass::reset(&mut scsi);
+    let r = host.command(&mut scsi, 0, &REQUEST_SENSE, DataStage::In(18));
+    assert_eq!(r.data[2], 0x06);
+
+    let mut polls = 0;
+    while host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None).csw.status != CommandStatus::Passed {
+        polls += 1;
+        assert!(polls < 1000);
+    }
+    assert!(!scsi.logical_units_mut().is_formatting());
+    assert_eq!(scsi.block_device_mut().formats, 1);
+    assert!(scsi.block_device_mut().data().iter().all(|&b| b == 0));
+}
+
+#[test]
+fn format_unit_failure() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(SlowFormat::new(ram_disk(), 2)));
+    scsi.block_device_mut().fail_format = true;
+
+    // MEDIUM ERROR, FORMAT COMMAND FAILED
+    let r = host.command(&mut scsi, 0, &FORMAT_UNIT, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x03, 0x31, 0x01));
+
+    // MEDIUM ERROR, MEDIUM FORMAT CORRUPTED until it's formatted again
+    for cdb in [&TEST_UNIT_READY[..], &READ_CAPACITY[..]].iter() {
+        let r = host.command(&mut scsi, 0, cdb, DataStage::In(8));
+        assert_eq!(r.csw.status, CommandStatus::Failed);
+        assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x03, 0x31, 0x00));
+    }
+
+    scsi.block_device_mut().fail_format = false;
+    let r = host.command(&mut scsi, 0, &FORMAT_UNIT, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+}
+
+const READ_FORMAT_CAPACITIES: [u8; 12] = [0x23, 0, 0, 0, 0, 0, 0, 0, 0xFC, 0, 0, 0];
+
+#[test]
+fn read_format_capacities() {
+    let (mut scsi, mut host) = scsi_device(disk_unit(SlowFormat::new(ram_disk(), 0)));
+    scsi.block_device_mut().fail_format = true;
+
+    // Formatted, and can be formatted to the same capacity
+    let r = host.command(&mut scsi, 0, &READ_FORMAT_CAPACITIES, DataStage::In(0xFC));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, [
+        0, 0, 0, 16,
+        0, 0, 0, 16, 0x02, 0, 0x02, 0,
+        0, 0, 0, 16, 0x00, 0, 0x02, 0,
+    ]);
+
+    // Truncated to the allocation length
+    let r = host.command(&mut scsi, 0, &[0x23, 0, 0, 0, 0, 0, 0, 0, 12, 0, 0, 0], DataStage::In(0xFC));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data.len(), 12);
+
+    // Unformatted after a failed format
+    let r = host.command(&mut scsi, 0, &FORMAT_UNIT, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    let r = host.command(&mut scsi, 0, &READ_FORMAT_CAPACITIES, DataStage::In(0xFC));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data[4..12], [0, 0, 0, 16, 0x01, 0, 0x02, 0]);
+
+    // No medium, no formattable capacities
+    scsi.logical_units_mut().remove_medium();
+    let r = host.command(&mut scsi, 0, &READ_FORMAT_CAPACITIES, DataStage::In(0xFC));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, [0, 0, 0, 8, 0, 0, 0, 16, 0x03, 0, 0x02, 0]);
+}
+
+/// Vendor specific commands for a scratch area: 0xC0 stores the data sent with it, 0xC1 reads
+/// it back. CDB byte 4 is the length, 0xC2 fails
+#[derive(Default)]
+struct Scratchpad {
+    data: Vec<u8>,
+}
+
+impl usbd_scsi::CommandHandler for Scratchpad {
+    fn process_command<T: usbd_scsi::Transport>(
+        &mut self,
+        _lun: u8,
+        cdb: &[u8],
+        _new_command: bool,
+        transport: &mut T,
+    ) -> Result<usbd_scsi::CommandProgress, usbd_scsi::CommandHandlerError> {
+        let len = cdb[4] as usize;
+        match cdb[0] {
+            0xC0 => self.data = transport.take_buffered_data(len, false)?.to_vec(),
+            0xC1 => {
+                if len > self.data.len() {
+                    Err(usbd_scsi::CommandHandlerError::InvalidFieldInCommand)?;
+                }
+                transport.take_buffer_space(len)?.copy_from_slice(&self.data[..len]);
+            },
+            0xC2 => Err(usbd_scsi::CommandHandlerError::HardwareError)?,
+            _ => Err(usbd_scsi::CommandHandlerError::UnsupportedOpCode)?,
+        }
+        Ok(usbd_scsi::CommandProgress::Done)
+    }
+}
+
+#[test]
+fn command_handler() {
+    let (scsi, mut host) = scsi_device(ram_disk_unit());
+    let mut scsi = scsi.with_command_handler(Scratchpad::default());
+
+    let data = pattern(1, 7)[..100].to_vec();
+    let r = host.command(&mut scsi, 0, &[0xC0, 0, 0, 0, 100, 0], DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(scsi.command_handler_mut().data, data);
+
+    let r = host.command(&mut scsi, 0, &[0xC1, 0, 0, 0, 100, 0], DataStage::In(100));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, data);
+
+    // Errors from the handler are reported in the sense data
+    let r = host.command(&mut scsi, 0, &[0xC1, 0, 0, 0, 200, 0], DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+
+    let r = host.command(&mut scsi, 0, &[0xC2, 0, 0, 0, 0, 0], DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0).0, 0x04);
+
+    let r = host.command(&mut scsi, 0, &[0xC7, 0, 0, 0, 0, 0], DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x20, 0x00));
+
+    // Standard commands still work
+    let r = host.command(&mut scsi, 0, &TEST_UNIT_READY, DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+}
+
+fn write_buffer(mode: u8, offset: u32, len: u32) -> [u8; 10] {
+    let o = offset.to_be_bytes();
+    let l = len.to_be_bytes();
+    [0x3B, mode, 0, o[1], o[2], o[3], l[1], l[2], l[3], 0]
+}
+
+fn read_buffer(mode: u8, offset: u32, len: u32) -> [u8; 10] {
+    let o = offset.to_be_bytes();
+    let l = len.to_be_bytes();
+    [0x3C, mode, 0, o[1], o[2], o[3], l[1], l[2], l[3], 0]
+}
+
+const DOWNLOAD_MICROCODE: u8 = 0x05;
+const DOWNLOAD_MICROCODE_WITH_OFFSETS: u8 = 0x06;
+const DOWNLOAD_MICROCODE_WITH_OFFSETS_SAVE: u8 = 0x07;
+
+fn firmware_unit(capacity: usize) -> usbd_scsi::LogicalUnit<WithFirmware<RamDisk, Busy<FirmwareImage>>> {
+    disk_unit(WithFirmware::new(ram_disk(), Busy::new(FirmwareImage::new(capacity), 0)))
+}
+
+#[test]
+fn write_buffer_download_microcode() {
+    let (mut scsi, mut host) = scsi_device(firmware_unit(4096));
+
+    // Capacity and offset boundary
+    let r = host.command(&mut scsi, 0, &read_buffer(0x03, 0, 4), DataStage::In(4));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, [4, 0x00, 0x10, 0x00]);
+
+    // The whole image at once, more than fits in the buffer
+    let image = pattern(3, 9);
+    let r = host.command(&mut scsi, 0, &write_buffer(DOWNLOAD_MICROCODE, 0, image.len() as u32), DataStage::Out(&image));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    let disk = scsi.block_device_mut();
+    assert_eq!(&disk.firmware.image[..image.len()], &image[..]);
+    assert_eq!((disk.firmware.saves, disk.firmware.activations), (1, 1));
+
+    let r = host.command(&mut scsi, 0, &read_buffer(0x02, 0, image.len() as u32), DataStage::In(image.len() as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data, image);
+
+    // Reads stop at the end of the image
+    let r = host.command(&mut scsi, 0, &read_buffer(0x02, 4000, 200), DataStage::In(200));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data.len(), 96);
+    assert_eq!(r.csw.data_residue, 104);
+}
+
+#[test]
+fn write_buffer_with_offsets() {
+    let mut logical_unit = firmware_unit(4096);
+    logical_unit.block_device_mut().firmware.length = Some(1024);
+    logical_unit.block_device_mut().firmware.busy_calls = 2;
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+    let image = pattern(2, 4);
+
+    // Activated once the last segment arrives, saved after each one in mode 7
+    for (i, segment) in image.chunks(256).enumerate() {
+        let cdb = write_buffer(DOWNLOAD_MICROCODE_WITH_OFFSETS_SAVE, i as u32 * 256, 256);
+        let r = host.command(&mut scsi, 0, &cdb, DataStage::Out(segment));
+        assert_eq!(r.csw.status, CommandStatus::Passed);
+        let disk = scsi.block_device_mut();
+        assert_eq!(disk.firmware.saves, i + 1);
+        assert_eq!(disk.firmware.activations, if i == 3 { 1 } else { 0 });
+    }
+    assert_eq!(&scsi.block_device_mut().firmware.image[..1024], &image[..]);
+
+    // Mode 6 doesn't save
+    let r = host.command(&mut scsi, 0, &write_buffer(DOWNLOAD_MICROCODE_WITH_OFFSETS, 768, 256), DataStage::Out(&image[..256]));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    let disk = scsi.block_device_mut();
+    assert_eq!((disk.firmware.saves, disk.firmware.activations), (4, 2));
+    assert_eq!(&disk.firmware.image[768..1024], &image[..256]);
+}
+
+#[test]
+fn write_buffer_invalid() {
+    let (mut scsi, mut host) = scsi_device(firmware_unit(4096));
+
+    // Past the end, off the offset boundary, unsupported modes and buffer IDs
+    for (cdb, data_stage) in [
+        (write_buffer(DOWNLOAD_MICROCODE_WITH_OFFSETS_SAVE, 4096 - 256, 512), DataStage::Out(&[0; 512])),
+        (write_buffer(DOWNLOAD_MICROCODE_WITH_OFFSETS_SAVE, 8, 0), DataStage::None),
+        (write_buffer(0x02, 0, 0), DataStage::None),
+        ([0x3B, DOWNLOAD_MICROCODE, 1, 0, 0, 0, 0, 0, 0, 0], DataStage::None),
+        (read_buffer(0x02, 4097, 0), DataStage::None),
+        (read_buffer(0x0A, 0, 0), DataStage::None),
+    ].iter() {
+        let r = host.command(&mut scsi, 0, cdb, *data_stage);
+        assert_eq!(r.csw.status, CommandStatus::Failed);
+        assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+    }
+    assert_eq!(scsi.block_device_mut().firmware.activations, 0);
+
+    // Without firmware update support the commands aren't recognised
+    let (mut scsi, mut host) = scsi_device(ram_disk_unit());
+    let r = host.command(&mut scsi, 0, &read_buffer(0x03, 0, 4), DataStage::In(4));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x20, 0x00));
+}
+
+fn log_sense<C: usb_device::class::UsbClass<MockBus>>(host: &mut Host, device: &mut C, page: u8, pointer: u16) -> Vec<u8> {
+    let p = pointer.to_be_bytes();
+    let r = host.command(device, 0, &[0x4D, 0, 0x40 | page, 0, 0, p[0], p[1], 0x02, 0x00, 0], DataStage::In(512));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(r.data[0] & 0x3F, page);
+    assert_eq!(u16::from_be_bytes([r.data[2], r.data[3]]) as usize, r.data.len() - 4);
+    r.data
+}
+
+/// Splits a log page into (parameter code, value) pairs
+fn log_parameters(page: &[u8]) -> Vec<(u16, Vec<u8>)> {
+    let mut parameters = Vec::new();
+    let mut rest = &page[4..];
+    while !rest.is_empty() {
+        let len = rest[3] as usize;
+        parameters.push((u16::from_be_bytes([rest[0], rest[1]]), rest[4..4 + len].to_vec()));
+        rest = &rest[4 + len..];
+    }
+    parameters
+}
+
+#[test]
+fn log_pages() {
+    let mut logical_unit = disk_unit(WithHealth::new(FailingWrites::new(ram_disk(), None)));
+    logical_unit.block_device_mut().health.write_errors_corrected = 7;
+    let (mut scsi, mut host) = scsi_device(logical_unit);
+
+    assert_eq!(log_sense(&mut host, &mut scsi, 0x00, 0), [0x80, 0, 0, 7, 0x00, 0x02, 0x03, 0x05, 0x0D, 0x10, 0x2F]);
+
+    // Bytes are counted as they're moved, errors when they're reported
+    let data = pattern(3, 1);
+    let r = host.command(&mut scsi, 0, &write10(0, 3), DataStage::Out(&data));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    let r = host.command(&mut scsi, 0, &read10(0, 2), DataStage::In(2 * BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    let r = host.command(&mut scsi, 0, &verify16(0, 4, 0), DataStage::None);
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+
+    scsi.block_device_mut().fail_write_lba = Some(5);
+    let r = host.command(&mut scsi, 0, &write10(4, 2), DataStage::Out(&data[..2 * BLOCK_BYTES]));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+    // Not a medium error
+    let r = host.command(&mut scsi, 0, &read10(16, 1), DataStage::In(BLOCK_BYTES as u32));
+    assert_eq!(r.csw.status, CommandStatus::Failed);
+
+    let counters = scsi.logical_units_mut().error_counters();
+    assert_eq!((counters.write.bytes_processed, counters.write.uncorrected_errors), (4 * BLOCK_BYTES as u64, 1));
+    assert_eq!((counters.read.bytes_processed, counters.read.uncorrected_errors), (2 * BLOCK_BYTES as u64, 0));
+    assert_eq!((counters.verify.bytes_processed, counters.verify.uncorrected_errors), (4 * BLOCK_BYTES as u64, 0));
+
+    assert_eq!(log_parameters(&log_sense(&mut host, &mut scsi, 0x02, 0)), [
+        (0x0003, vec![0, 0, 0, 7]),
+        (0x0005, (4 * BLOCK_BYTES as u64).to_be_bytes().to_vec()),
+        (0x0006, vec![0, 0, 0, 1]),
+    ]);
+    assert_eq!(log_parameters(&log_sense(&mut host, &mut scsi, 0x05, 0x0006)), [(0x0006, vec![0, 0, 0, 0])]);
+
+    // Health from the block device
+    assert_eq!(log_parameters(&log_sense(&mut host, &mut scsi, 0x0D, 0)), [(0x0000, vec![0, 0xFF]), (0x0001, vec![0, 0xFF])]);
+    assert_eq!(log_parameters(&log_sense(&mut host, &mut scsi, 0x2F, 0)), [(0x0000, vec![0, 0, 0xFF])]);
+    scsi.block_device_mut().health.temperature = Some(41);
+    scsi.block_device_mut().health.failure_predicted = true;
+    assert_eq!(log_parameters(&log_sense(&mut host, &mut scsi, 0x0D, 0))[0], (0x0000, vec![0, 41]));
+    assert_eq!(log_parameters(&log_sense(&mut host, &mut scsi, 0x2F, 0)), [(0x0000, vec![0x5D, 0x00, 41])]);
+
+    let self_tests = log_parameters(&log_sense(&mut host, &mut scsi, 0x10, 0));
+    assert_eq!(self_tests.len(), 20);
+    assert!(self_tests.iter().enumerate().all(|(i, (code, value))| *code == i as u16 + 1 && *value == [0; 16]));
+
+    // Unsupported pages, page control, saving and pointing past the last parameter
+    for cdb in [
+        [0x4D, 0, 0x40 | 0x01, 0, 0, 0, 0, 0x02, 0x00, 0],
+        [0x4D, 0, 0x02, 0, 0, 0, 0, 0x02, 0x00, 0],
+        [0x4D, 0x01, 0x40 | 0x02, 0, 0, 0, 0, 0x02, 0x00, 0],
+        [0x4D, 0, 0x40 | 0x02, 0, 0, 0, 0x07, 0x02, 0x00, 0],
+    ].iter() {
+        let r = host.command(&mut scsi, 0, cdb, DataStage::In(0x200));
+        assert_eq!(r.csw.status, CommandStatus::Failed);
+        assert_eq!(request_sense(&mut host, &mut scsi, 0), (0x05, 0x24, 0x00));
+    }
+}
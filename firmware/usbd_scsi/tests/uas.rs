@@ -0,0 +1,299 @@
+mod common;
+use common::*;
+use usb_device::{
+    class::UsbClass,
+    UsbError,
+};
+use usbd_scsi::BlockDevice;
+
+const SENSE_IU: u8 = 0x03;
+const RESPONSE_IU: u8 = 0x04;
+const READ_READY_IU: u8 = 0x06;
+const WRITE_READY_IU: u8 = 0x07;
+
+const GOOD: u8 = 0x00;
+const CHECK_CONDITION: u8 = 0x02;
+const TASK_SET_FULL: u8 = 0x28;
+
+const ABORT_TASK: u8 = 0x01;
+const QUERY_TASK: u8 = 0x80;
+
+const TASK_MANAGEMENT_FUNCTION_COMPLETE: u8 = 0x00;
+const TASK_MANAGEMENT_FUNCTION_SUCCEEDED: u8 = 0x08;
+const INCORRECT_LOGICAL_UNIT_NUMBER: u8 = 0x09;
+const OVERLAPPED_TAG_ATTEMPTED: u8 = 0x0A;
+
+/// How many times the device is polled waiting for it to do something before giving up
+const POLL_LIMIT: usize = 1000;
+
+fn command_iu(tag: u16, lun: u8, cdb: &[u8]) -> [u8; 32] {
+    let mut iu = [0; 32];
+    iu[0] = 0x01;
+    iu[2..4].copy_from_slice(&tag.to_be_bytes());
+    iu[9] = lun;
+    iu[16..16 + cdb.len()].copy_from_slice(cdb);
+    iu
+}
+
+fn task_management_iu(tag: u16, function: u8, task_tag: u16, lun: u8) -> [u8; 16] {
+    let mut iu = [0; 16];
+    iu[0] = 0x05;
+    iu[2..4].copy_from_slice(&tag.to_be_bytes());
+    iu[4] = function;
+    iu[6..8].copy_from_slice(&task_tag.to_be_bytes());
+    iu[9] = lun;
+    iu
+}
+
+fn tag(iu: &[u8]) -> u16 {
+    u16::from_be_bytes([iu[2], iu[3]])
+}
+
+/// Polls the device until it accepts `iu` on the command pipe
+fn send_iu<C: UsbClass<MockBus>>(host: &mut Host, device: &mut C, iu: &[u8]) {
+    for _ in 0..POLL_LIMIT {
+        match host.bus().send_packet(COMMAND_PIPE, iu) {
+            Ok(()) => {
+                device.poll();
+                return;
+            },
+            Err(UsbError::WouldBlock) => device.poll(),
+            Err(e) => panic!("Sending IU failed: {:?}", e),
+        }
+    }
+    panic!("Device stopped reading from the command pipe");
+}
+
+/// Polls the device until it sends an IU on the status pipe
+fn receive_iu<C: UsbClass<MockBus>>(host: &mut Host, device: &mut C) -> Vec<u8> {
+    for _ in 0..POLL_LIMIT {
+        match host.bus().receive_packet(STATUS_PIPE) {
+            Ok(iu) => return iu,
+            Err(UsbError::WouldBlock) => device.poll(),
+            Err(e) => panic!("Receiving IU failed: {:?}", e),
+        }
+    }
+    panic!("Device stopped writing to the status pipe");
+}
+
+/// Returns (status, sense data) from a sense IU for `tag`
+fn receive_sense<C: UsbClass<MockBus>>(host: &mut Host, device: &mut C, tag: u16) -> (u8, Vec<u8>) {
+    let iu = receive_iu(host, device);
+    assert_eq!(iu[0], SENSE_IU, "Expected a sense IU: {:X?}", iu);
+    assert_eq!(self::tag(&iu), tag);
+    let len = u16::from_be_bytes([iu[14], iu[15]]) as usize;
+    assert_eq!(iu.len(), 16 + len);
+    (iu[6], iu[16..].to_vec())
+}
+
+/// Returns the response code from a response IU for `tag`
+fn receive_response<C: UsbClass<MockBus>>(host: &mut Host, device: &mut C, tag: u16) -> u8 {
+    let iu = receive_iu(host, device);
+    assert_eq!(iu[0], RESPONSE_IU, "Expected a response IU: {:X?}", iu);
+    assert_eq!(self::tag(&iu), tag);
+    iu[7]
+}
+
+fn receive_ready<C: UsbClass<MockBus>>(host: &mut Host, device: &mut C, iu_id: u8, tag: u16) {
+    let iu = receive_iu(host, device);
+    assert_eq!(iu, [iu_id, 0, (tag >> 8) as u8, tag as u8]);
+}
+
+/// Everything the host saw in response to a single command
+struct Response {
+    data: Vec<u8>,
+    status: u8,
+    sense: Vec<u8>,
+}
+
+/// Runs a complete command: command IU, READ READY or WRITE READY with the data if there is any
+/// then the sense IU. `data_in` is the most data the host accepts
+fn command<C: UsbClass<MockBus>>(
+    host: &mut Host,
+    device: &mut C,
+    tag: u16,
+    cdb: &[u8],
+    data_in: usize,
+    data_out: &[u8],
+) -> Response {
+    send_iu(host, device, &command_iu(tag, 0, cdb));
+
+    let mut data = Vec::new();
+    if data_in > 0 {
+        receive_ready(host, device, READ_READY_IU, tag);
+        data = host.receive_data(device, data_in).0;
+    } else if !data_out.is_empty() {
+        receive_ready(host, device, WRITE_READY_IU, tag);
+        host.send_data(device, data_out);
+    }
+
+    let (status, sense) = receive_sense(host, device, tag);
+    Response { data, status, sense }
+}
+
+/// A device with UAS selected and the power on unit attention cleared
+fn uas_device_selected<BD: BlockDevice>(block_device: BD) -> (UasScsi<usbd_scsi::LogicalUnit<BD>>, Host) {
+    let (mut scsi, mut host) = uas_device(disk_unit(block_device));
+    assert!(host.set_interface(&mut scsi, INTERFACE, UAS_ALTERNATE_SETTING));
+
+    let r = command(&mut host, &mut scsi, 1, &TEST_UNIT_READY, 0, &[]);
+    assert_eq!(r.status, CHECK_CONDITION);
+
+    (scsi, host)
+}
+
+#[test]
+fn descriptors_offer_bot_and_uas() {
+    let (mut scsi, mut host) = uas_device(ram_disk_unit());
+    let descriptor = host.configuration_descriptor(&mut scsi);
+
+    // (alternate setting, protocol, endpoints) for each interface descriptor and the pipe IDs
+    // from the pipe usage descriptors
+    let mut interfaces = Vec::new();
+    let mut pipes = Vec::new();
+    let mut i = 0;
+    while i < descriptor.len() {
+        let d = &descriptor[i..i + descriptor[i] as usize];
+        match d[1] {
+            0x04 => interfaces.push((d[3], d[7], d[4])),
+            0x24 => pipes.push(d[2]),
+            _ => {},
+        }
+        i += d.len();
+    }
+
+    assert_eq!(interfaces, [(0, 0x50, 2), (1, 0x62, 4)]);
+    assert_eq!(pipes, [1, 2, 3, 4]);
+}
+
+#[test]
+fn alternate_setting_selects_transport() {
+    let (mut scsi, mut host) = uas_device(ram_disk_unit());
+    assert_eq!(host.get_interface(&mut scsi, INTERFACE), 0);
+    assert!(!host.set_interface(&mut scsi, INTERFACE, 2));
+
+    assert!(host.set_interface(&mut scsi, INTERFACE, UAS_ALTERNATE_SETTING));
+    assert_eq!(host.get_interface(&mut scsi, INTERFACE), UAS_ALTERNATE_SETTING);
+
+    // Sense data goes with the status
+    let r = command(&mut host, &mut scsi, 1, &TEST_UNIT_READY, 0, &[]);
+    assert_eq!(r.status, CHECK_CONDITION);
+    assert_eq!(r.sense.len(), 18);
+    assert_eq!((r.sense[2] & 0x0F, r.sense[12]), (0x06, 0x29));
+
+    let r = command(&mut host, &mut scsi, 1, &TEST_UNIT_READY, 0, &[]);
+    assert_eq!(r.status, GOOD);
+    assert!(r.sense.is_empty());
+
+    // Back to BOT
+    assert!(host.set_interface(&mut scsi, INTERFACE, 0));
+    let r = host.command(&mut scsi, 0, &INQUIRY, DataStage::In(36));
+    assert_eq!(r.csw.status, CommandStatus::Passed);
+    assert_eq!(&r.data[8..16], b"Mock    ");
+    host.assert_idle(&mut scsi);
+}
+
+#[test]
+fn read_and_write() {
+    let (mut scsi, mut host) = uas_device_selected(ram_disk());
+
+    let r = command(&mut host, &mut scsi, 2, &INQUIRY, 36, &[]);
+    assert_eq!(r.status, GOOD);
+    assert_eq!(&r.data[16..32], b"RamDisk         ");
+
+    let data: Vec<u8> = (0..2 * BLOCK_BYTES).map(|i| (i as u8).wrapping_mul(3)).collect();
+    let r = command(&mut host, &mut scsi, 3, &write10(4, 2), 0, &data);
+    assert_eq!(r.status, GOOD);
+    assert_eq!(&scsi.block_device_mut().data()[4 * BLOCK_BYTES..6 * BLOCK_BYTES], &data[..]);
+
+    let r = command(&mut host, &mut scsi, 4, &read10(4, 2), data.len(), &[]);
+    assert_eq!(r.status, GOOD);
+    assert_eq!(r.data, data);
+}
+
+#[test]
+fn short_data_in() {
+    let (mut scsi, mut host) = uas_device_selected(ram_disk());
+
+    // The host allows for more sense data than there is, a short packet ends the transfer
+    send_iu(&mut host, &mut scsi, &command_iu(2, 0, &[0x03, 0, 0, 0, 255, 0]));
+    receive_ready(&mut host, &mut scsi, READ_READY_IU, 2);
+    let (data, zlp) = host.receive_data(&mut scsi, 255);
+    assert!(data.len() < 255);
+    assert!(!zlp);
+    assert_eq!(receive_sense(&mut host, &mut scsi, 2).0, GOOD);
+    host.assert_idle(&mut scsi);
+}
+
+#[test]
+fn queued_commands_run_in_order() {
+    let (mut scsi, mut host) = uas_device_selected(ram_disk());
+
+    send_iu(&mut host, &mut scsi, &command_iu(10, 0, &TEST_UNIT_READY));
+    send_iu(&mut host, &mut scsi, &command_iu(11, 0, &INQUIRY));
+    send_iu(&mut host, &mut scsi, &command_iu(12, 0, &TEST_UNIT_READY));
+
+    assert_eq!(receive_sense(&mut host, &mut scsi, 10).0, GOOD);
+    receive_ready(&mut host, &mut scsi, READ_READY_IU, 11);
+    assert_eq!(host.receive_data(&mut scsi, 36).0.len(), 36);
+    assert_eq!(receive_sense(&mut host, &mut scsi, 11).0, GOOD);
+    assert_eq!(receive_sense(&mut host, &mut scsi, 12).0, GOOD);
+    host.assert_idle(&mut scsi);
+}
+
+#[test]
+fn rejected_command_ius() {
+    let (mut scsi, mut host) = uas_device_selected(RamDisk::<64>::new());
+
+    // The read can't finish until the host takes the data so the tag stays in use
+    send_iu(&mut host, &mut scsi, &command_iu(5, 0, &read10(0, 32)));
+    receive_ready(&mut host, &mut scsi, READ_READY_IU, 5);
+
+    send_iu(&mut host, &mut scsi, &command_iu(5, 0, &TEST_UNIT_READY));
+    assert_eq!(receive_response(&mut host, &mut scsi, 5), OVERLAPPED_TAG_ATTEMPTED);
+
+    send_iu(&mut host, &mut scsi, &command_iu(6, 3, &TEST_UNIT_READY));
+    assert_eq!(receive_response(&mut host, &mut scsi, 6), INCORRECT_LOGICAL_UNIT_NUMBER);
+
+    // Four fit in the queue
+    for tag in 7..11 {
+        send_iu(&mut host, &mut scsi, &command_iu(tag, 0, &TEST_UNIT_READY));
+    }
+    send_iu(&mut host, &mut scsi, &command_iu(11, 0, &TEST_UNIT_READY));
+    assert_eq!(receive_sense(&mut host, &mut scsi, 11), (TASK_SET_FULL, Vec::new()));
+
+    // Everything that was accepted still completes
+    assert_eq!(host.receive_data(&mut scsi, 32 * BLOCK_BYTES).0.len(), 32 * BLOCK_BYTES);
+    assert_eq!(receive_sense(&mut host, &mut scsi, 5).0, GOOD);
+    for tag in 7..11 {
+        assert_eq!(receive_sense(&mut host, &mut scsi, tag).0, GOOD);
+    }
+}
+
+#[test]
+fn abort_task() {
+    let (mut scsi, mut host) = uas_device_selected(Counted::new(RamDisk::<64>::new()));
+
+    send_iu(&mut host, &mut scsi, &command_iu(5, 0, &read10(0, 32)));
+    receive_ready(&mut host, &mut scsi, READ_READY_IU, 5);
+    send_iu(&mut host, &mut scsi, &command_iu(6, 0, &TEST_UNIT_READY));
+
+    send_iu(&mut host, &mut scsi, &task_management_iu(20, QUERY_TASK, 5, 0));
+    assert_eq!(receive_response(&mut host, &mut scsi, 20), TASK_MANAGEMENT_FUNCTION_SUCCEEDED);
+
+    send_iu(&mut host, &mut scsi, &task_management_iu(21, ABORT_TASK, 5, 0));
+    assert_eq!(receive_response(&mut host, &mut scsi, 21), TASK_MANAGEMENT_FUNCTION_COMPLETE);
+    assert_eq!(scsi.block_device_mut().aborts, 1);
+
+    // The aborted read doesn't get a status, the next command carries on. A packet of read
+    // data may have been left on the data in pipe
+    let _ = host.bus().receive_packet(BULK_IN);
+    assert_eq!(receive_sense(&mut host, &mut scsi, 6).0, GOOD);
+
+    send_iu(&mut host, &mut scsi, &task_management_iu(22, QUERY_TASK, 5, 0));
+    assert_eq!(receive_response(&mut host, &mut scsi, 22), TASK_MANAGEMENT_FUNCTION_COMPLETE);
+
+    let r = command(&mut host, &mut scsi, 5, &read10(0, 1), BLOCK_BYTES, &[]);
+    assert_eq!(r.status, GOOD);
+    assert_eq!(r.data.len(), BLOCK_BYTES);
+}
@@ -0,0 +1,272 @@
+use packing::Packed;
+
+/// The first byte of every information unit, says what it is
+///
+/// UAS 6.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum IuId {
+    Command = 0x01,
+    Sense = 0x03,
+    Response = 0x04,
+    TaskManagement = 0x05,
+    ReadReady = 0x06,
+    WriteReady = 0x07,
+}
+
+/// Task attribute of a command, how it's ordered against the other commands in the task set
+///
+/// SAM-5 8.6
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum TaskAttribute {
+    Simple = 0x00,
+    HeadOfQueue = 0x01,
+    Ordered = 0x02,
+    Aca = 0x04,
+}
+
+/// A command, sent on the command pipe
+///
+/// UAS 6.2.2
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct CommandIu {
+    #[pkd(7, 0, 0, 0)]
+    pub iu_id: u8,
+
+    /// Chosen by the host to identify the command, every IU about it has the same tag
+    #[pkd(7, 0, 2, 3)]
+    pub tag: u16,
+
+    #[pkd(6, 3, 4, 4)]
+    pub command_priority: u8,
+
+    /// See `TaskAttribute`
+    #[pkd(2, 0, 4, 4)]
+    pub task_attribute: u8,
+
+    /// Length of the CDB past the first 16 bytes, in 4 byte words
+    #[pkd(7, 2, 6, 6)]
+    pub additional_cdb_length: u8,
+
+    #[pkd(7, 0, 8, 15)]
+    pub lun: [u8; 8],
+
+    /// Padded to 16 bytes whatever the length of the command
+    #[pkd(7, 0, 16, 31)]
+    pub cdb: [u8; 16],
+}
+
+/// Status of a command, sent on the status pipe. Followed by `sense_data_length` bytes of sense
+/// data if the command failed
+///
+/// UAS 6.2.5
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct SenseIuHeader {
+    #[pkd(7, 0, 0, 0)]
+    pub iu_id: u8,
+
+    #[pkd(7, 0, 2, 3)]
+    pub tag: u16,
+
+    #[pkd(7, 0, 4, 5)]
+    pub status_qualifier: u16,
+
+    /// SAM status code, see `Status`
+    #[pkd(7, 0, 6, 6)]
+    pub status: u8,
+
+    #[pkd(7, 0, 14, 15)]
+    pub sense_data_length: u16,
+}
+
+impl SenseIuHeader {
+    pub fn new(tag: u16, status: Status, sense_data_length: u16) -> Self {
+        Self {
+            iu_id: IuId::Sense.to_primitive(),
+            tag,
+            status: status.to_primitive(),
+            sense_data_length,
+            ..Default::default()
+        }
+    }
+}
+
+/// SAM status codes that can be sent in a `SenseIuHeader`
+///
+/// SAM-5 5.3
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum Status {
+    Good = 0x00,
+    CheckCondition = 0x02,
+    TaskSetFull = 0x28,
+}
+
+/// Reply to a task management function or an IU that couldn't be accepted, sent on the status
+/// pipe
+///
+/// UAS 6.2.6
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ResponseIu {
+    #[pkd(7, 0, 0, 0)]
+    pub iu_id: u8,
+
+    #[pkd(7, 0, 2, 3)]
+    pub tag: u16,
+
+    #[pkd(7, 0, 4, 6)]
+    pub additional_response_information: [u8; 3],
+
+    /// See `ResponseCode`
+    #[pkd(7, 0, 7, 7)]
+    pub response_code: u8,
+}
+
+impl ResponseIu {
+    pub fn new(tag: u16, response_code: ResponseCode) -> Self {
+        Self {
+            iu_id: IuId::Response.to_primitive(),
+            tag,
+            response_code: response_code.to_primitive(),
+            ..Default::default()
+        }
+    }
+}
+
+/// UAS 6.2.6
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum ResponseCode {
+    TaskManagementFunctionComplete = 0x00,
+    InvalidInformationUnit = 0x02,
+    TaskManagementFunctionNotSupported = 0x04,
+    TaskManagementFunctionFailed = 0x05,
+    TaskManagementFunctionSucceeded = 0x08,
+    IncorrectLogicalUnitNumber = 0x09,
+    OverlappedTagAttempted = 0x0A,
+}
+
+/// A task management function, sent on the command pipe
+///
+/// UAS 6.2.3
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct TaskManagementIu {
+    #[pkd(7, 0, 0, 0)]
+    pub iu_id: u8,
+
+    /// Tag of the IU itself, the response has the same tag
+    #[pkd(7, 0, 2, 3)]
+    pub tag: u16,
+
+    /// See `TaskManagementFunction`
+    #[pkd(7, 0, 4, 4)]
+    pub function: u8,
+
+    /// The command the function applies to, for the ones that apply to a single command
+    #[pkd(7, 0, 6, 7)]
+    pub task_tag: u16,
+
+    #[pkd(7, 0, 8, 15)]
+    pub lun: [u8; 8],
+}
+
+/// UAS 6.2.3
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Packed)]
+pub enum TaskManagementFunction {
+    AbortTask = 0x01,
+    AbortTaskSet = 0x02,
+    ClearTaskSet = 0x04,
+    LogicalUnitReset = 0x08,
+    ITNexusReset = 0x10,
+    ClearAca = 0x40,
+    QueryTask = 0x80,
+    QueryTaskSet = 0x81,
+    QueryAsynchronousEvent = 0x82,
+}
+
+/// READ READY and WRITE READY, sent on the status pipe when the device is ready for the data
+/// stage of a command
+///
+/// UAS 6.2.7 and 6.2.8
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default, Packed)]
+#[packed(big_endian, lsb0)]
+pub struct ReadyIu {
+    #[pkd(7, 0, 0, 0)]
+    pub iu_id: u8,
+
+    #[pkd(7, 0, 2, 3)]
+    pub tag: u16,
+}
+
+impl ReadyIu {
+    pub fn read_ready(tag: u16) -> Self {
+        Self { iu_id: IuId::ReadReady.to_primitive(), tag }
+    }
+
+    pub fn write_ready(tag: u16) -> Self {
+        Self { iu_id: IuId::WriteReady.to_primitive(), tag }
+    }
+}
+
+/// The LUN from a single level LUN field using the peripheral device addressing method, the
+/// only kind used here (see `LunListEntry`). None for anything else
+pub fn single_level_lun(lun: &[u8; 8]) -> Option<u8> {
+    match lun {
+        [0, lun, 0, 0, 0, 0, 0, 0] => Some(*lun),
+        _ => None,
+    }
+}
+
+/// Length of a CDB from the group code in its operation code. The variable length group and the
+/// vendor specific groups are assumed to fill the command IU
+///
+/// SPC-4 4.2.5.1
+pub fn cdb_length(op_code: u8) -> u8 {
+    match op_code >> 5 {
+        0 => 6,
+        1 | 2 => 10,
+        5 => 12,
+        _ => 16,
+    }
+}
+
+#[test]
+fn test_command_iu() {
+    use packing::PackedSize;
+
+    let mut bytes = [0; CommandIu::BYTES];
+    bytes[0] = 0x01;
+    bytes[2..4].copy_from_slice(&0x1234u16.to_be_bytes());
+    bytes[4] = (3 << 3) | 1;
+    bytes[9] = 2;
+    bytes[16] = 0x28;
+    bytes[31] = 0xAB;
+
+    let iu = CommandIu::unpack(&bytes).unwrap();
+    assert_eq!(iu.iu_id, IuId::Command.to_primitive());
+    assert_eq!(iu.tag, 0x1234);
+    assert_eq!(iu.command_priority, 3);
+    assert_eq!(TaskAttribute::from_primitive(iu.task_attribute).unwrap(), TaskAttribute::HeadOfQueue);
+    assert_eq!(iu.additional_cdb_length, 0);
+    assert_eq!(single_level_lun(&iu.lun), Some(2));
+    assert_eq!(cdb_length(iu.cdb[0]), 10);
+    assert_eq!(iu.cdb[15], 0xAB);
+}
+
+#[test]
+fn test_status_ius() {
+    use packing::PackedSize;
+
+    let mut bytes = [0xFF; SenseIuHeader::BYTES];
+    SenseIuHeader::new(0x0102, Status::CheckCondition, 18).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x03, 0, 0x01, 0x02, 0, 0, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 18]);
+
+    let mut bytes = [0xFF; ResponseIu::BYTES];
+    ResponseIu::new(7, ResponseCode::OverlappedTagAttempted).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x04, 0, 0, 7, 0, 0, 0, 0x0A]);
+
+    let mut bytes = [0xFF; ReadyIu::BYTES];
+    ReadyIu::write_ready(0xBEEF).pack(&mut bytes).unwrap();
+    assert_eq!(bytes, [0x07, 0, 0xBE, 0xEF]);
+}
@@ -0,0 +1,16 @@
+#![no_std]
+
+mod information_unit;
+mod queue;
+
+mod uas_transport;
+pub use uas_transport::UasTransport;
+
+mod logging {
+    pub use itm_logger::*;
+
+    #[cfg(feature = "trace-uas")]
+    pub use itm_logger::trace as trace_uas;
+    #[cfg(not(feature = "trace-uas"))]
+    pub use itm_logger::stub as trace_uas;
+}
@@ -0,0 +1,94 @@
+/// A small fixed size queue, oldest first
+pub struct Queue<T: Copy, const N: usize> {
+    items: [Option<T>; N],
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> Queue<T, N> {
+    pub fn new() -> Self {
+        Self {
+            items: [None; N],
+            len: 0,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn front(&self) -> Option<T> {
+        self.items[0]
+    }
+
+    /// Returns false if the queue is full
+    pub fn push_back(&mut self, item: T) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.items[self.len] = Some(item);
+        self.len += 1;
+        true
+    }
+
+    /// Returns false if the queue is full
+    pub fn push_front(&mut self, item: T) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.items.copy_within(0..self.len, 1);
+        self.items[0] = Some(item);
+        self.len += 1;
+        true
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        let item = self.items[0]?;
+        self.items.copy_within(1..self.len, 0);
+        self.len -= 1;
+        self.items[self.len] = None;
+        Some(item)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items[..self.len].iter().flatten()
+    }
+
+    /// Keeps only the items `f` returns true for, in the same order
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            if let Some(item) = self.items[i] {
+                if f(&item) {
+                    self.items[kept] = Some(item);
+                    kept += 1;
+                }
+            }
+        }
+        for item in &mut self.items[kept..self.len] {
+            *item = None;
+        }
+        self.len = kept;
+    }
+
+    pub fn clear(&mut self) {
+        self.items = [None; N];
+        self.len = 0;
+    }
+}
+
+#[test]
+fn test_queue() {
+    let mut q = Queue::<u8, 3>::new();
+    assert!(q.push_back(1));
+    assert!(q.push_back(2));
+    assert!(q.push_front(0));
+    assert!(!q.push_back(3));
+    assert!(q.iter().copied().eq([0, 1, 2].iter().copied()));
+
+    q.retain(|&i| i != 1);
+    assert!(q.iter().copied().eq([0, 2].iter().copied()));
+
+    assert_eq!(q.pop_front(), Some(0));
+    assert_eq!(q.pop_front(), Some(2));
+    assert_eq!(q.pop_front(), None);
+}
@@ -0,0 +1,736 @@
+use packing::{
+    Packed,
+    PackedSize,
+    Error as PackingError,
+};
+use usb_device::class_prelude::*;
+use usb_device::Result as UsbResult;
+use usbd_bulk_only_transport::{
+    AbortReason,
+    BulkOnlyTransport,
+    CommandBlockWrapper,
+    DataStage,
+    Error as BulkOnlyTransportError,
+    TransferState,
+    Transport,
+    TransportClass,
+    DEFAULT_BUFFER_BYTES,
+};
+use usbd_mass_storage::{
+    InterfaceSubclass,
+    MscClass,
+    UAS_ALTERNATE_SETTING,
+};
+
+use crate::{
+    logging::*,
+    information_unit::*,
+    queue::Queue,
+};
+
+/// Commands that can be waiting behind the one being executed
+const TASK_QUEUE_LENGTH: usize = 4;
+
+/// Replies to task management functions and rejected IUs that can be waiting to be sent
+const REPLY_QUEUE_LENGTH: usize = 4;
+
+/// Largest IU the command pipe accepts, a command IU with no additional CDB bytes fits easily
+const COMMAND_PACKET_BYTES: usize = 64;
+
+/// Fixed format sense data without the additional sense bytes, as much as a sense IU carries
+const SHORT_SENSE_BYTES: usize = 18;
+
+/// Largest IU sent on the status pipe, a sense IU with short fixed format sense data
+const STATUS_PACKET_BYTES: usize = SenseIuHeader::BYTES + SHORT_SENSE_BYTES;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum State {
+    /// Nothing being executed, `read` starts the next queued command
+    Idle,
+    /// The command set has the command but hasn't said what data stage it needs
+    Started,
+    /// Sending data on the data in pipe once READ READY has gone
+    SendingDataToHost,
+    /// Reading data from the data out pipe once WRITE READY has gone. Commands without a data
+    /// stage stay here with nothing to read until their status is set
+    ReceivingDataFromHost,
+    /// The data in stage was cut short on a packet boundary, a ZLP ends it
+    NeedZlp,
+    /// Waiting for the sense IU to go
+    NeedToSendStatus,
+}
+
+/// Status IUs about the current command, in the order they're sent
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum CommandStatus {
+    ReadReady,
+    WriteReady,
+    Sense(Status),
+}
+
+/// Status IUs that aren't about the current command
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+enum Reply {
+    /// A task management function finished or an IU was rejected
+    Response(u16, ResponseCode),
+    /// A command arrived when the queue was full
+    TaskSetFull(u16),
+}
+
+/// # USB Attached SCSI transport
+///
+/// Offers `BulkOnlyTransport` as alternate setting 0 and UAS as alternate setting 1, the host
+/// picks whichever it supports. Until it selects UAS everything is passed to the
+/// `BulkOnlyTransport`. A command set runs on it through `TransportClass`, with `usbd_scsi` that's
+/// `Scsi::with_transport`.
+///
+/// ## Functionality overview
+/// 1. Reading command IUs from the command pipe and queueing them up by tag. HEAD OF QUEUE
+///    commands go to the front, any other task attribute is executed in the order it arrived
+/// 1. Passing them to the command set one at a time, in the same form as a CBW
+/// 1. Sending READ READY or WRITE READY on the status pipe once the command set has said what
+///    data stage it needs, then moving the data on the data pipes
+/// 1. Sending a sense IU with the status, including sense data if the command failed
+/// 1. Rejecting IUs with a response IU: overlapped tags, LUNs greater than max LUN, additional
+///    CDB bytes and anything that isn't a command or task management function. Commands that
+///    don't fit in the queue get TASK SET FULL status
+/// 1. Task management: ABORT TASK, ABORT TASK SET, CLEAR TASK SET, LOGICAL UNIT RESET,
+///    I_T NEXUS RESET, QUERY TASK and QUERY TASK SET
+///
+/// ## Limitations
+/// usb-device has no support for streams so this is UAS as it works over USB 2.0, one command's
+/// data stage at a time. Commands from the queue are started once the last one's status has
+/// gone.
+///
+/// The host doesn't say how much data it expects so the data stage is whatever the command set
+/// passes to `check_data_stage`, without it the command has no data stage.
+///
+/// The buffer and data endpoints belong to the `BulkOnlyTransport`, so do the endpoint
+/// descriptors.
+pub struct UasTransport<'a, B: UsbBus, const BUFFER_BYTES: usize = DEFAULT_BUFFER_BYTES> {
+    bot: BulkOnlyTransport<'a, B, BUFFER_BYTES>,
+    /// The host has selected the UAS alternate setting
+    active: bool,
+    max_packet_size: u16,
+    max_lun: u8,
+    state: State,
+    /// The command being executed, as a CBW so it looks the same as one from BOT
+    command: CommandBlockWrapper,
+    queue: Queue<CommandBlockWrapper, TASK_QUEUE_LENGTH>,
+    /// Waiting to be sent on the status pipe, None once it has gone
+    status: Option<CommandStatus>,
+    /// Status of the current command once the command set has set it
+    command_status: Status,
+    sense: [u8; SHORT_SENSE_BYTES],
+    sense_len: usize,
+    replies: Queue<Reply, REPLY_QUEUE_LENGTH>,
+    /// A task management function waiting for the command set to abort the current command
+    task_management: Option<TaskManagementIu>,
+    /// Bytes of the data stage left to move
+    data_residue: u32,
+    data_length: u32,
+    last_packet_full: bool,
+    /// The command set has set the status, no more data is coming
+    data_done: bool,
+}
+
+impl<'a, B: UsbBus, const BUFFER_BYTES: usize> UasTransport<'a, B, BUFFER_BYTES> {
+    /// Allocates the BOT and UAS endpoints. LUNs up to and including `max_lun` are accepted
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        max_packet_size: u16,
+        subclass: InterfaceSubclass,
+        max_lun: u8,
+    ) -> UasTransport<'a, B, BUFFER_BYTES> {
+        // Status IUs are always a single packet
+        assert!(max_packet_size as usize >= STATUS_PACKET_BYTES);
+
+        UasTransport {
+            bot: BulkOnlyTransport::from_msc_class(
+                MscClass::with_uas(alloc, max_packet_size, subclass),
+                max_lun,
+            ),
+            active: false,
+            max_packet_size,
+            max_lun,
+            state: State::Idle,
+            command: Default::default(),
+            queue: Queue::new(),
+            status: None,
+            command_status: Status::Good,
+            sense: [0; SHORT_SENSE_BYTES],
+            sense_len: 0,
+            replies: Queue::new(),
+            task_management: None,
+            data_residue: 0,
+            data_length: 0,
+            last_packet_full: false,
+            data_done: false,
+        }
+    }
+
+    fn change_state(&mut self, state: State) {
+        trace_uas!("UAS> {:?} -> {:?}", self.state, state);
+        self.state = state;
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        self.bot.peek_buffered_data(BUFFER_BYTES, true)
+            .map(|data| data.len())
+            .unwrap_or(0)
+    }
+
+    /// Forgets every command and anything waiting to be sent
+    fn reset_uas(&mut self) {
+        self.end_command();
+        self.queue.clear();
+        self.replies.clear();
+        self.task_management = None;
+    }
+
+    /// Drops the current command whatever it's doing, along with any data in the buffer
+    fn end_command(&mut self) {
+        let bytes = self.buffered_bytes();
+        self.bot.consume_buffered_data(bytes);
+        self.status = None;
+        self.data_done = false;
+        self.change_state(State::Idle);
+    }
+
+    /// The command being executed, if there is one
+    fn current_task(&self) -> Option<&CommandBlockWrapper> {
+        match self.state {
+            State::Idle => None,
+            _ => Some(&self.command),
+        }
+    }
+
+    fn tag_in_use(&self, tag: u16) -> bool {
+        let tag = tag as u32;
+        self.current_task().is_some_and(|cbw| cbw.tag == tag) ||
+        self.queue.iter().any(|cbw| cbw.tag == tag) ||
+        self.task_management.is_some_and(|iu| iu.tag as u32 == tag)
+    }
+
+    fn reply(&mut self, reply: Reply) {
+        // Nothing is read from the command pipe unless there's room
+        if !self.replies.push_back(reply) {
+            error!("UAS reply dropped: {:?}", reply);
+        }
+    }
+
+    fn read_uas<F: FnMut(AbortReason) -> bool>(&mut self, mut abort: F) -> Result<(), BulkOnlyTransportError> {
+        // A task management function waiting on the command set holds everything else up
+        if let Some(iu) = self.task_management {
+            self.task_management(iu, &mut abort);
+        }
+
+        // Any IU might need a reply
+        while self.task_management.is_none() && !self.replies.is_full() {
+            let mut packet = [0; COMMAND_PACKET_BYTES];
+            let len = match self.bot.msc_class_mut().read_command_packet(&mut packet) {
+                Ok(len) => len,
+                Err(UsbError::WouldBlock) => break,
+                Err(e) => Err(e)?,
+            };
+            self.receive_iu(&packet[..len], &mut abort);
+        }
+
+        // The command being aborted goes no further until the command set has finished with it
+        if self.task_management.is_some() {
+            return Ok(());
+        }
+
+        if self.state == State::Idle {
+            self.start_next_command();
+        }
+
+        match self.state {
+            // The host only sends data once it has WRITE READY
+            State::ReceivingDataFromHost if self.status.is_none() => self.receive_data(),
+            State::Idle => self.discard_data(),
+            _ => Ok(()),
+        }
+    }
+
+    fn receive_iu<F: FnMut(AbortReason) -> bool>(&mut self, packet: &[u8], abort: &mut F) {
+        // Every IU has its tag in the same place
+        let tag = match packet {
+            [_, _, t0, t1, ..] => u16::from_be_bytes([*t0, *t1]),
+            _ => 0,
+        };
+
+        let result = match packet.first().map(|&id| IuId::from_primitive(id)) {
+            Some(Ok(IuId::Command)) => CommandIu::unpack(packet)
+                .map(|iu| self.receive_command(iu)),
+            Some(Ok(IuId::TaskManagement)) => TaskManagementIu::unpack(packet)
+                .map(|iu| if self.tag_in_use(iu.tag) {
+                    warn!("UAS task management function with overlapped tag {}", iu.tag);
+                    self.reply(Reply::Response(iu.tag, ResponseCode::OverlappedTagAttempted));
+                } else {
+                    self.task_management(iu, abort);
+                }),
+            _ => Err(PackingError::InvalidEnumDiscriminant),
+        };
+
+        if result.is_err() {
+            warn!("Invalid UAS IU: {:X?}", packet);
+            self.reply(Reply::Response(tag, ResponseCode::InvalidInformationUnit));
+        }
+    }
+
+    fn receive_command(&mut self, iu: CommandIu) {
+        trace_uas!("UAS> {:X?}", iu);
+
+        let task_attribute = TaskAttribute::from_primitive(iu.task_attribute);
+        let lun = match single_level_lun(&iu.lun) {
+            Some(lun) if lun <= self.max_lun => lun,
+            _ => {
+                warn!("UAS command for unsupported LUN: {:X?}", iu.lun);
+                self.reply(Reply::Response(iu.tag, ResponseCode::IncorrectLogicalUnitNumber));
+                return;
+            },
+        };
+
+        if self.tag_in_use(iu.tag) {
+            warn!("UAS command with overlapped tag {}", iu.tag);
+            self.reply(Reply::Response(iu.tag, ResponseCode::OverlappedTagAttempted));
+            return;
+        }
+
+        if iu.additional_cdb_length != 0 || task_attribute.is_err() {
+            warn!("Unsupported UAS command: {:X?}", iu);
+            self.reply(Reply::Response(iu.tag, ResponseCode::InvalidInformationUnit));
+            return;
+        }
+
+        let cbw = CommandBlockWrapper {
+            tag: iu.tag as u32,
+            lun,
+            data_length: cdb_length(iu.cdb[0]),
+            data: iu.cdb,
+            ..Default::default()
+        };
+
+        let queued = match task_attribute {
+            Ok(TaskAttribute::HeadOfQueue) => self.queue.push_front(cbw),
+            _ => self.queue.push_back(cbw),
+        };
+        if !queued {
+            warn!("UAS task set full, rejecting tag {}", iu.tag);
+            self.reply(Reply::TaskSetFull(iu.tag));
+        }
+    }
+
+    /// Carries out a task management function. If the command set isn't ready to abort the
+    /// current command it's left in `task_management` to try again on the next read
+    fn task_management<F: FnMut(AbortReason) -> bool>(&mut self, iu: TaskManagementIu, abort: &mut F) {
+        use TaskManagementFunction::*;
+
+        trace_uas!("UAS> {:X?}", iu);
+        self.task_management = None;
+
+        let function = match TaskManagementFunction::from_primitive(iu.function) {
+            Ok(function) => function,
+            Err(_) => {
+                self.reply(Reply::Response(iu.tag, ResponseCode::TaskManagementFunctionNotSupported));
+                return;
+            },
+        };
+
+        // An I_T nexus reset is for every LUN
+        let lun = match (function, single_level_lun(&iu.lun)) {
+            (ITNexusReset, _) => None,
+            (_, Some(lun)) if lun <= self.max_lun => Some(lun),
+            _ => {
+                self.reply(Reply::Response(iu.tag, ResponseCode::IncorrectLogicalUnitNumber));
+                return;
+            },
+        };
+
+        let task_tag = iu.task_tag as u32;
+        let applies = |cbw: &CommandBlockWrapper| match function {
+            AbortTask | QueryTask => Some(cbw.lun) == lun && cbw.tag == task_tag,
+            _ => lun.is_none_or(|lun| cbw.lun == lun),
+        };
+        let current = self.current_task().is_some_and(applies);
+
+        let response = match function {
+            QueryTask | QueryTaskSet => {
+                if current || self.queue.iter().any(applies) {
+                    ResponseCode::TaskManagementFunctionSucceeded
+                } else {
+                    ResponseCode::TaskManagementFunctionComplete
+                }
+            },
+            AbortTask | AbortTaskSet | ClearTaskSet | LogicalUnitReset | ITNexusReset => {
+                if current {
+                    if !abort(AbortReason::TaskManagement) {
+                        self.task_management = Some(iu);
+                        return;
+                    }
+                    // Aborted commands don't get a status
+                    self.end_command();
+                }
+                self.queue.retain(|cbw| !applies(cbw));
+                ResponseCode::TaskManagementFunctionComplete
+            },
+            ClearAca | QueryAsynchronousEvent => ResponseCode::TaskManagementFunctionNotSupported,
+        };
+
+        self.reply(Reply::Response(iu.tag, response));
+    }
+
+    fn start_next_command(&mut self) {
+        if let Some(cbw) = self.queue.pop_front() {
+            trace_uas!("UAS> Starting tag {}", cbw.tag);
+            self.command = cbw;
+            self.command_status = Status::Good;
+            self.sense_len = 0;
+            self.data_residue = 0;
+            self.data_length = 0;
+            self.last_packet_full = false;
+            self.data_done = false;
+            self.change_state(State::Started);
+        }
+    }
+
+    fn receive_data(&mut self) -> Result<(), BulkOnlyTransportError> {
+        if self.data_residue > 0 {
+            let bytes = self.bot.read_packet_to_buffer()?;
+            self.data_residue = self.data_residue.saturating_sub(bytes as u32);
+        }
+        self.check_end_data_transfer();
+        Ok(())
+    }
+
+    /// Drops data out packets that arrive between commands, left over from a command that
+    /// ended early
+    fn discard_data(&mut self) -> Result<(), BulkOnlyTransportError> {
+        loop {
+            let bytes = self.bot.read_packet_to_buffer()?;
+            warn!("Discarding {} bytes of UAS data with no command", bytes);
+            self.bot.consume_buffered_data(bytes);
+        }
+    }
+
+    fn write_uas(&mut self) -> Result<(), BulkOnlyTransportError> {
+        // Status and data go on different endpoints, one being busy doesn't hold up the other.
+        // Replies still go while a task management function waits, nothing for the command
+        // it's aborting does
+        let status = self.send_status();
+        if self.task_management.is_some() {
+            return status;
+        }
+        let data = self.send_data();
+        status.and(data)
+    }
+
+    fn send_status(&mut self) -> Result<(), BulkOnlyTransportError> {
+        // Replies go first, the host is usually waiting on them before it carries on. The sense
+        // IU waits for the end of the data in stage
+        let reply = self.replies.front();
+        let status = self.status.filter(|_| {
+            reply.is_none() && self.state != State::NeedZlp && self.task_management.is_none()
+        });
+
+        let mut bytes = [0; STATUS_PACKET_BYTES];
+        let len = match (reply, status) {
+            (Some(reply), _) => pack_reply(reply, &mut bytes)?,
+            (None, Some(status)) => self.pack_status(status, &mut bytes)?,
+            (None, None) => return Ok(()),
+        };
+
+        self.bot.msc_class_mut().write_status_packet(&bytes[..len])?;
+        trace_uas!("UAS> Sent status: {:X?}", &bytes[..len]);
+
+        match status {
+            None => { self.replies.pop_front(); },
+            Some(CommandStatus::Sense(_)) => self.end_command(),
+            Some(_) => self.status = None,
+        }
+        Ok(())
+    }
+
+    fn pack_status(&self, status: CommandStatus, bytes: &mut [u8]) -> Result<usize, BulkOnlyTransportError> {
+        let tag = self.command.tag as u16;
+        Ok(match status {
+            CommandStatus::ReadReady => {
+                ReadyIu::read_ready(tag).pack(&mut bytes[..ReadyIu::BYTES])?;
+                ReadyIu::BYTES
+            },
+            CommandStatus::WriteReady => {
+                ReadyIu::write_ready(tag).pack(&mut bytes[..ReadyIu::BYTES])?;
+                ReadyIu::BYTES
+            },
+            CommandStatus::Sense(status) => {
+                let len = SenseIuHeader::BYTES + self.sense_len;
+                SenseIuHeader::new(tag, status, self.sense_len as u16)
+                    .pack(&mut bytes[..SenseIuHeader::BYTES])?;
+                bytes[SenseIuHeader::BYTES..len].copy_from_slice(&self.sense[..self.sense_len]);
+                len
+            },
+        })
+    }
+
+    fn send_data(&mut self) -> Result<(), BulkOnlyTransportError> {
+        match self.state {
+            // The host only reads data once it has READ READY
+            State::SendingDataToHost if self.status.is_none() => {
+                if self.data_residue > 0 && self.buffered_bytes() > 0 {
+                    let bytes = self.bot.write_packet_from_buffer(self.data_residue as usize)?;
+                    self.last_packet_full = bytes == self.max_packet_size as usize;
+                    self.data_residue -= bytes as u32;
+                }
+                self.check_end_data_transfer();
+                Ok(())
+            },
+            State::NeedZlp => {
+                self.bot.write_packet_from_buffer(0)?;
+                self.change_state(State::NeedToSendStatus);
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn check_end_data_transfer(&mut self) {
+        let end = match self.state {
+            State::Started | State::ReceivingDataFromHost => self.data_done,
+            // Anything the command set queued past the end of the data stage is dropped
+            State::SendingDataToHost => {
+                self.data_done && (self.data_residue == 0 || self.buffered_bytes() == 0)
+            },
+            _ => false,
+        };
+
+        if end {
+            self.end_data_transfer();
+        }
+    }
+
+    fn end_data_transfer(&mut self) {
+        // READ READY or WRITE READY that hasn't gone yet is dropped, the host doesn't expect
+        // any data without it. If it has gone a short data in stage that ended on a full packet
+        // (or didn't have any packets) needs a ZLP so the host knows it's over
+        let ready_sent = self.status.is_none();
+        let need_zlp = self.state == State::SendingDataToHost &&
+            ready_sent &&
+            self.data_residue > 0 &&
+            (self.last_packet_full || self.data_residue == self.data_length);
+
+        let bytes = self.buffered_bytes();
+        self.bot.consume_buffered_data(bytes);
+
+        self.status = Some(CommandStatus::Sense(self.command_status));
+        self.change_state(if need_zlp {
+            State::NeedZlp
+        } else {
+            State::NeedToSendStatus
+        });
+    }
+
+    fn check_data_stage_uas(&mut self, data_stage: DataStage) {
+        if self.state != State::Started {
+            warn!("UAS data stage set twice for tag {}", self.command.tag);
+            return;
+        }
+
+        let (state, status, len) = match data_stage {
+            DataStage::In(len) if len > 0 => (State::SendingDataToHost, Some(CommandStatus::ReadReady), len),
+            DataStage::Out(len) if len > 0 => (State::ReceivingDataFromHost, Some(CommandStatus::WriteReady), len),
+            _ => (State::ReceivingDataFromHost, None, 0),
+        };
+        self.status = status;
+        self.data_residue = len;
+        self.data_length = len;
+        self.change_state(state);
+    }
+
+    fn transfer_state_uas(&self) -> TransferState {
+        let bytes = self.buffered_bytes();
+        match self.state {
+            State::ReceivingDataFromHost => TransferState::ReceivingDataFromHost {
+                bytes_available: bytes,
+                // Not enough space for another packet, even after compacting
+                full: BUFFER_BYTES - bytes < self.max_packet_size as usize,
+                done: self.data_residue == 0,
+            },
+            State::SendingDataToHost => TransferState::SendingDataToHost {
+                bytes_remaining: bytes,
+                empty: bytes == 0,
+            },
+            _ => TransferState::NotTransferring {
+                bytes_remaining: bytes,
+                empty: bytes == 0,
+            },
+        }
+    }
+
+    fn set_command_status(&mut self, status: Status, sense: &[u8]) {
+        let len = sense.len().min(SHORT_SENSE_BYTES);
+        self.sense[..len].copy_from_slice(&sense[..len]);
+        self.sense_len = len;
+        self.command_status = status;
+        self.data_done = true;
+        self.check_end_data_transfer();
+    }
+}
+
+fn pack_reply(reply: Reply, bytes: &mut [u8]) -> Result<usize, BulkOnlyTransportError> {
+    Ok(match reply {
+        Reply::Response(tag, response_code) => {
+            ResponseIu::new(tag, response_code).pack(&mut bytes[..ResponseIu::BYTES])?;
+            ResponseIu::BYTES
+        },
+        Reply::TaskSetFull(tag) => {
+            SenseIuHeader::new(tag, Status::TaskSetFull, 0).pack(&mut bytes[..SenseIuHeader::BYTES])?;
+            SenseIuHeader::BYTES
+        },
+    })
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> Transport for UasTransport<'_, B, BUFFER_BYTES> {
+    fn get_current_command(&self) -> Option<&CommandBlockWrapper> {
+        if !self.active {
+            return self.bot.get_current_command();
+        }
+        match self.state {
+            State::Started | State::SendingDataToHost | State::ReceivingDataFromHost if !self.data_done => {
+                Some(&self.command)
+            },
+            _ => None,
+        }
+    }
+
+    fn check_data_stage(&mut self, data_stage: DataStage) -> Result<(), BulkOnlyTransportError> {
+        if !self.active {
+            return self.bot.check_data_stage(data_stage);
+        }
+        // Whatever the command needs is what the host gets, there's nothing to check against
+        self.check_data_stage_uas(data_stage);
+        Ok(())
+    }
+
+    fn transfer_state(&self) -> TransferState {
+        if self.active {
+            self.transfer_state_uas()
+        } else {
+            self.bot.transfer_state()
+        }
+    }
+
+    fn take_buffer_space(&mut self, len: usize) -> Result<&mut [u8], BulkOnlyTransportError> {
+        self.bot.take_buffer_space(len)
+    }
+
+    fn peek_buffer_space(&mut self, len: usize) -> Result<&mut [u8], BulkOnlyTransportError> {
+        self.bot.peek_buffer_space(len)
+    }
+
+    fn commit_buffer_space(&mut self, len: usize) {
+        self.bot.commit_buffer_space(len)
+    }
+
+    fn take_buffered_data(&mut self, len: usize, take_available: bool) -> Result<&[u8], BulkOnlyTransportError> {
+        self.bot.take_buffered_data(len, take_available)
+    }
+
+    fn peek_buffered_data(&self, len: usize, take_available: bool) -> Result<&[u8], BulkOnlyTransportError> {
+        self.bot.peek_buffered_data(len, take_available)
+    }
+
+    fn consume_buffered_data(&mut self, len: usize) {
+        self.bot.consume_buffered_data(len)
+    }
+
+    fn send_command_ok(&mut self) -> Result<(), BulkOnlyTransportError> {
+        if !self.active {
+            return self.bot.send_command_ok();
+        }
+        self.set_command_status(Status::Good, &[]);
+        Ok(())
+    }
+
+    fn send_command_error(&mut self, sense: &[u8]) -> Result<(), BulkOnlyTransportError> {
+        if !self.active {
+            return self.bot.send_command_error();
+        }
+        self.set_command_status(Status::CheckCondition, sense);
+        Ok(())
+    }
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> TransportClass<B> for UasTransport<'_, B, BUFFER_BYTES> {
+    fn read<F: FnMut(AbortReason) -> bool>(&mut self, mut abort: F) -> Result<(), BulkOnlyTransportError> {
+        // Whichever setting the host has picked starts from scratch once the command set has
+        // dropped the current command
+        if self.bot.msc_class_mut().alt_setting_selected() {
+            if !abort(AbortReason::AlternateSetting) {
+                return Ok(());
+            }
+            let msc = self.bot.msc_class_mut();
+            msc.clear_alt_setting_selected();
+            self.active = msc.alt_setting() == UAS_ALTERNATE_SETTING;
+            trace_uas!("UAS> Alternate setting selected, UAS {}", self.active);
+            self.reset_uas();
+            self.bot.reset_transport();
+        }
+
+        if self.active {
+            self.read_uas(abort)
+        } else {
+            self.bot.read_with_abort(abort)
+        }
+    }
+
+    fn control_out_with_abort<F: FnMut(AbortReason) -> bool>(&mut self, xfer: ControlOut<B>, abort: F) {
+        if self.active {
+            // There's no bulk only mass storage reset under UAS, task management does that job
+            self.bot.msc_class_mut().control_out(xfer)
+        } else {
+            self.bot.control_out_with_abort(xfer, abort)
+        }
+    }
+
+    fn abort_pending(&self) -> bool {
+        self.bot.abort_pending() || self.task_management.is_some()
+    }
+
+    fn write(&mut self) -> Result<(), BulkOnlyTransportError> {
+        // Nothing more goes for commands the host has abandoned
+        if self.bot.msc_class_mut().alt_setting_selected() {
+            return Ok(());
+        }
+
+        if self.active {
+            self.write_uas()
+        } else {
+            self.bot.write()
+        }
+    }
+}
+
+impl<B: UsbBus, const BUFFER_BYTES: usize> UsbClass<B> for UasTransport<'_, B, BUFFER_BYTES> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> UsbResult<()> {
+        self.bot.get_configuration_descriptors(writer)
+    }
+
+    fn reset(&mut self) {
+        self.reset_uas();
+        self.active = false;
+        self.bot.reset()
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        self.bot.control_in(xfer)
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        self.control_out_with_abort(xfer, |_| false)
+    }
+
+    fn poll(&mut self) {
+        panic!("UasTransport::poll should never be called. Consumers (SCSI for example) should use TransportClass::read and TransportClass::write");
+    }
+}